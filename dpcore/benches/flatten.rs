@@ -0,0 +1,59 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dpcore::paint::layerstack::{LayerFill, LayerInsertion};
+use dpcore::paint::{Color, LayerStack};
+
+// Run with `cargo bench --features parallel --bench flatten` to compare
+// the parallel and serial paths.
+
+fn make_layerstack() -> LayerStack {
+    let mut stack = LayerStack::new(3000, 2000);
+    for id in 1..=4 {
+        stack.add_layer(
+            id,
+            LayerFill::Solid(Color {
+                r: id as f32 / 4.0,
+                g: 0.5,
+                b: 1.0 - id as f32 / 4.0,
+                a: 0.5,
+            }),
+            LayerInsertion::Top,
+        );
+        stack.get_layer_mut(id).unwrap().opacity = 0.8;
+    }
+    stack
+}
+
+fn flatten_benchmark(c: &mut Criterion) {
+    let stack = make_layerstack();
+
+    let mut group = c.benchmark_group("flatten");
+    group.sample_size(10);
+    group.bench_function("to_image", |b| b.iter(|| stack.to_image()));
+    group.bench_function("to_image_serial", |b| b.iter(|| stack.to_image_serial()));
+    group.finish();
+}
+
+criterion_group!(benches, flatten_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,959 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::filters;
+use crate::protocol::message::*;
+
+use num_enum::IntoPrimitive;
+use num_enum::TryFromPrimitive;
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+/// User permission tiers. A lower value means more privileges.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Tier {
+    Operator = 0,
+    Trusted,
+    Authenticated,
+    Guest,
+}
+
+/// Features whose access can be restricted to a tier.
+/// The order matches the one used in the FeatureAccessLevels message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Feature {
+    PutImage = 0,
+    RegionMove,
+    Resize,
+    Background,
+    EditLayers,
+    OwnLayers,
+    CreateAnnotation,
+    Laser,
+    Undo,
+}
+
+pub const FEATURE_COUNT: usize = 9;
+
+const DEFAULT_FEATURE_TIERS: [Tier; FEATURE_COUNT] = [
+    Tier::Guest,    // PutImage
+    Tier::Guest,    // RegionMove
+    Tier::Operator, // Resize
+    Tier::Operator, // Background
+    Tier::Operator, // EditLayers
+    Tier::Guest,    // OwnLayers
+    Tier::Guest,    // CreateAnnotation
+    Tier::Guest,    // Laser
+    Tier::Guest,    // Undo
+];
+
+/// Access controls of a single layer
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerAcl {
+    /// Layer is locked for everyone (except operators)
+    pub locked: bool,
+    /// Layer can only be edited by users of this tier or above
+    pub tier: Tier,
+    /// If not empty, only these users may edit the layer
+    pub exclusive: Vec<u8>,
+}
+
+impl LayerAcl {
+    const LOCKED_FLAG: u8 = 0x80;
+    const TIER_MASK: u8 = 0x07;
+
+    /// Returns None if the message's tier is not valid
+    fn from_message(msg: &LayerACLMessage) -> Option<Self> {
+        Some(LayerAcl {
+            locked: msg.flags & Self::LOCKED_FLAG != 0,
+            tier: Tier::try_from_primitive(msg.flags & Self::TIER_MASK).ok()?,
+            exclusive: msg.exclusive.clone(),
+        })
+    }
+
+    fn is_default(&self) -> bool {
+        !self.locked && self.tier == Tier::Guest && self.exclusive.is_empty()
+    }
+}
+
+/// The access control list filter.
+///
+/// The filter tracks the session's permission state (operators, trusted users,
+/// layer and user locks, feature access tiers) as messages pass through it
+/// and decides for each message whether it is allowed or should be dropped.
+/// The filter must see every message, in order, for its state to stay in sync
+/// with the session.
+///
+/// User 0 is reserved for the server and is never filtered.
+pub struct AclFilter {
+    /// Session operators
+    operators: HashSet<u8>,
+    /// Users marked as trusted by an operator
+    trusted: HashSet<u8>,
+    /// Users who are logged in with a registered account
+    authenticated: HashSet<u8>,
+    /// Individually locked users
+    locked_users: HashSet<u8>,
+    /// The general canvas lock
+    session_locked: bool,
+    /// Per layer access controls. Layers not in this map have default access.
+    layers: HashMap<u16, LayerAcl>,
+    /// Layer mask IDs and the layers they belong to
+    layer_masks: HashMap<u16, u16>,
+    /// The group each layer is in (0 for the root level)
+    layer_parents: HashMap<u16, u16>,
+    /// Layer IDs that are groups
+    groups: HashSet<u16>,
+    /// Minimum tier required to use each feature
+    feature_tiers: [Tier; FEATURE_COUNT],
+    /// Annotations whose owner has protected them from editing by others
+    protected_annotations: HashSet<u16>,
+}
+
+impl AclFilter {
+    pub fn new() -> Self {
+        AclFilter {
+            operators: HashSet::new(),
+            trusted: HashSet::new(),
+            authenticated: HashSet::new(),
+            locked_users: HashSet::new(),
+            session_locked: false,
+            layers: HashMap::new(),
+            layer_masks: HashMap::new(),
+            layer_parents: HashMap::new(),
+            groups: HashSet::new(),
+            feature_tiers: DEFAULT_FEATURE_TIERS,
+            protected_annotations: HashSet::new(),
+        }
+    }
+
+    /// Reset the filter to its initial state
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn is_operator(&self, user: u8) -> bool {
+        user == 0 || self.operators.contains(&user)
+    }
+
+    pub fn is_trusted(&self, user: u8) -> bool {
+        self.trusted.contains(&user)
+    }
+
+    pub fn is_authenticated(&self, user: u8) -> bool {
+        self.authenticated.contains(&user)
+    }
+
+    pub fn is_user_locked(&self, user: u8) -> bool {
+        self.locked_users.contains(&user)
+    }
+
+    pub fn is_session_locked(&self) -> bool {
+        self.session_locked
+    }
+
+    /// Get the effective permission tier of the given user
+    pub fn user_tier(&self, user: u8) -> Tier {
+        if self.is_operator(user) {
+            Tier::Operator
+        } else if self.is_trusted(user) {
+            Tier::Trusted
+        } else if self.is_authenticated(user) {
+            Tier::Authenticated
+        } else {
+            Tier::Guest
+        }
+    }
+
+    pub fn feature_tier(&self, feature: Feature) -> Tier {
+        self.feature_tiers[u8::from(feature) as usize]
+    }
+
+    /// Can the given user use this feature
+    pub fn can_use(&self, user: u8, feature: Feature) -> bool {
+        self.user_tier(user) <= self.feature_tier(feature)
+    }
+
+    pub fn layer_acl(&self, layer: u16) -> Option<&LayerAcl> {
+        self.layers.get(&layer)
+    }
+
+    /// Is the given layer locked for this user?
+    ///
+    /// This only considers the layer specific lock, not the general
+    /// session lock or user locks.
+    /// A layer mask is locked if the layer it belongs to is.
+    /// Layers are never locked for operators.
+    pub fn is_layer_locked_for(&self, layer: u16, user: u8) -> bool {
+        if self.is_operator(user) {
+            return false;
+        }
+        let layer = *self.layer_masks.get(&layer).unwrap_or(&layer);
+        match self.layers.get(&layer) {
+            Some(acl) => {
+                acl.locked
+                    || self.user_tier(user) > acl.tier
+                    || (!acl.exclusive.is_empty() && !acl.exclusive.contains(&user))
+            }
+            None => false,
+        }
+    }
+
+    /// Generate messages that reproduce the current ACL state.
+    ///
+    /// This is used when compacting session history: the user list itself
+    /// (and thus the authenticated status) comes from Join messages, but
+    /// the rest of the access controls must be restored with these.
+    pub fn state_messages(&self) -> Vec<Message> {
+        let mut msgs: Vec<Message> = Vec::new();
+
+        let sorted = |set: &HashSet<u8>| {
+            let mut v: Vec<u8> = set.iter().copied().collect();
+            v.sort_unstable();
+            v
+        };
+
+        if !self.operators.is_empty() {
+            msgs.push(ServerMetaMessage::SessionOwner(0, sorted(&self.operators)).into());
+        }
+
+        if !self.trusted.is_empty() {
+            msgs.push(ServerMetaMessage::TrustedUsers(0, sorted(&self.trusted)).into());
+        }
+
+        if !self.locked_users.is_empty() {
+            msgs.push(ClientMetaMessage::UserACL(0, sorted(&self.locked_users)).into());
+        }
+
+        if self.feature_tiers != DEFAULT_FEATURE_TIERS {
+            msgs.push(
+                ClientMetaMessage::FeatureAccessLevels(
+                    0,
+                    self.feature_tiers.iter().map(|&t| t.into()).collect(),
+                )
+                .into(),
+            );
+        }
+
+        if self.session_locked {
+            msgs.push(
+                ClientMetaMessage::LayerACL(
+                    0,
+                    LayerACLMessage {
+                        id: 0,
+                        flags: LayerAcl::LOCKED_FLAG,
+                        exclusive: Vec::new(),
+                    },
+                )
+                .into(),
+            );
+        }
+
+        let mut layers: Vec<(&u16, &LayerAcl)> = self.layers.iter().collect();
+        layers.sort_unstable_by_key(|(&id, _)| id);
+        for (&id, acl) in layers {
+            msgs.push(
+                ClientMetaMessage::LayerACL(
+                    0,
+                    LayerACLMessage {
+                        id,
+                        flags: if acl.locked { LayerAcl::LOCKED_FLAG } else { 0 }
+                            | u8::from(acl.tier),
+                        exclusive: acl.exclusive.clone(),
+                    },
+                )
+                .into(),
+            );
+        }
+
+        msgs
+    }
+
+    /// Process a message and return true if it should be let through.
+    ///
+    /// Messages that modify the ACL state update the filter only when
+    /// they are accepted.
+    pub fn filter_message(&mut self, msg: &Message) -> bool {
+        let accepted = match msg {
+            Message::Control(_) => true,
+            Message::ServerMeta(m) => self.handle_servermeta(m),
+            Message::ClientMeta(m) => self.handle_clientmeta(m),
+            Message::Command(m) => self.handle_command(m),
+        };
+
+        if !accepted {
+            warn!("ACL filter rejected {:?}", msg);
+        }
+
+        accepted
+    }
+
+    /// Process a message and return it as is if it was accepted,
+    /// or wrapped in a Filtered message if it was rejected.
+    pub fn filter(&mut self, msg: Message) -> Message {
+        if self.filter_message(&msg) {
+            msg
+        } else {
+            ClientMetaMessage::Filtered(msg.user(), msg.serialize()).into()
+        }
+    }
+
+    fn handle_servermeta(&mut self, msg: &ServerMetaMessage) -> bool {
+        use ServerMetaMessage::*;
+        match msg {
+            Join(user, m) => {
+                if m.flags & JoinMessage::FLAGS_AUTH != 0 {
+                    self.authenticated.insert(*user);
+                }
+                if m.flags & JoinMessage::FLAGS_MOD != 0 {
+                    self.operators.insert(*user);
+                }
+                true
+            }
+            Leave(user) => {
+                self.operators.remove(user);
+                self.trusted.remove(user);
+                self.authenticated.remove(user);
+                self.locked_users.remove(user);
+                true
+            }
+            SessionOwner(user, ids) => {
+                if !self.is_operator(*user) {
+                    return false;
+                }
+                self.operators = ids.iter().copied().filter(|&u| u > 0).collect();
+                if *user > 0 {
+                    // An operator cannot remove their own status
+                    self.operators.insert(*user);
+                }
+                true
+            }
+            TrustedUsers(user, ids) => {
+                if !self.is_operator(*user) {
+                    return false;
+                }
+                self.trusted = ids.iter().copied().collect();
+                true
+            }
+            Chat(..) | SoftReset(_) | PrivateChat(..) => true,
+        }
+    }
+
+    fn handle_clientmeta(&mut self, msg: &ClientMetaMessage) -> bool {
+        use ClientMetaMessage::*;
+        match msg {
+            UserACL(user, ids) => {
+                if !self.is_operator(*user) {
+                    return false;
+                }
+                self.locked_users = ids.iter().copied().collect();
+                true
+            }
+            LayerACL(user, m) => {
+                if m.id == 0 {
+                    // Layer zero is the general canvas lock
+                    if !self.is_operator(*user) {
+                        return false;
+                    }
+                    self.session_locked = m.flags & LayerAcl::LOCKED_FLAG != 0;
+                    return true;
+                }
+
+                if !self.can_edit_layer(*user, m.id) {
+                    return false;
+                }
+
+                let acl = match LayerAcl::from_message(m) {
+                    Some(acl) => acl,
+                    None => return false,
+                };
+                if acl.is_default() {
+                    self.layers.remove(&m.id);
+                } else {
+                    self.layers.insert(m.id, acl);
+                }
+                true
+            }
+            FeatureAccessLevels(user, tiers) => {
+                if !self.is_operator(*user) || tiers.len() != FEATURE_COUNT {
+                    return false;
+                }
+                let mut new_tiers = self.feature_tiers;
+                for (ft, &t) in new_tiers.iter_mut().zip(tiers.iter()) {
+                    *ft = match Tier::try_from_primitive(t) {
+                        Ok(t) => t,
+                        Err(_) => return false,
+                    };
+                }
+                self.feature_tiers = new_tiers;
+                true
+            }
+            DefaultLayer(user, _) => self.is_operator(*user),
+            LaserTrail(user, _) => self.can_use(*user, Feature::Laser),
+            Interval(..) | MovePointer(..) | Marker(..) | Filtered(..) => true,
+        }
+    }
+
+    fn handle_command(&mut self, msg: &CommandMessage) -> bool {
+        use CommandMessage::*;
+
+        let user = msg.user();
+        if user == 0 {
+            // Commands from the server are always allowed (e.g. session reset)
+            self.update_command_state(msg);
+            return true;
+        }
+
+        // The general lock and individual user locks apply to all commands
+        if self.session_locked || self.locked_users.contains(&user) {
+            return false;
+        }
+
+        let accepted = match msg {
+            UndoPoint(_) | PenUp(_) | Selection(..) => true,
+            CanvasResize(..) => self.can_use(user, Feature::Resize),
+            LayerCreate(_, m) => {
+                (self.can_use(user, Feature::EditLayers) || self.can_use(user, Feature::OwnLayers))
+                    && (self.is_operator(user) || is_owned_by(m.id, user))
+            }
+            LayerAttributes(_, m) => {
+                if m.sublayer > 0 {
+                    // Sublayers are only used in session resets
+                    self.is_operator(user)
+                } else {
+                    self.can_edit_layer(user, m.id) && !self.is_layer_locked_for(m.id, user)
+                }
+            }
+            LayerRetitle(_, m) => self.can_edit_layer(user, m.id),
+            LayerOrder(..) | LayerTreeMove(..) => self.can_use(user, Feature::EditLayers),
+            LayerDelete(_, m) => self.can_edit_layer(user, m.id),
+            LayerMask(_, m) => {
+                self.can_edit_layer(user, m.id)
+                    && !self.is_layer_locked_for(m.id, user)
+                    && (self.is_operator(user) || m.mask == 0 || is_owned_by(m.mask, user))
+            }
+            LayerVisibility(..) => true,
+            PutImage(_, m) => {
+                self.can_use(user, Feature::PutImage) && !self.is_layer_locked_for(m.layer, user)
+            }
+            FillRect(_, m) => {
+                self.can_use(user, Feature::PutImage) && !self.is_layer_locked_for(m.layer, user)
+            }
+            TransformRegion(_, m) => {
+                self.can_use(user, Feature::PutImage)
+                    && !self.is_layer_locked_for(m.layer, user)
+                    && (m.source == 0 || !self.is_layer_locked_for(m.source, user))
+            }
+            BoxBlur(..)
+            | GaussianBlur(..)
+            | UnsharpMask(..)
+            | BrightnessContrast(..)
+            | HueSaturation(..)
+            | Levels(..)
+            | Invert(..) => {
+                let layer = filters::filter_command(msg).unwrap().layer;
+                self.can_use(user, Feature::PutImage)
+                    && !self.is_layer_locked_for(layer as u16, user)
+            }
+            AnnotationCreate(_, m) => {
+                self.can_use(user, Feature::CreateAnnotation) && is_owned_by(m.id, user)
+            }
+            AnnotationReshape(_, m) => self.can_edit_annotation(user, m.id),
+            AnnotationEdit(_, m) => self.can_edit_annotation(user, m.id),
+            AnnotationDelete(_, id) => self.can_edit_annotation(user, *id),
+            PutTile(..) => self.is_operator(user),
+            CanvasBackground(..) => self.can_use(user, Feature::Background),
+            DrawDabsClassic(_, m) => !self.is_layer_locked_for(m.layer, user),
+            DrawDabsPixel(_, m) | DrawDabsPixelSquare(_, m) => {
+                !self.is_layer_locked_for(m.layer, user)
+            }
+            Undo(_, m) => {
+                if m.override_user > 0 {
+                    self.is_operator(user)
+                } else {
+                    self.can_use(user, Feature::Undo)
+                }
+            }
+        };
+
+        if accepted {
+            self.update_command_state(msg);
+        }
+
+        accepted
+    }
+
+    /// Update ACL state affected by (accepted) commands
+    fn update_command_state(&mut self, msg: &CommandMessage) {
+        use CommandMessage::*;
+        match msg {
+            LayerCreate(_, m) => self.add_layer(m),
+            LayerTreeMove(_, m) => self.move_layer(m),
+            LayerDelete(_, m) => {
+                if m.merge && self.groups.contains(&m.id) {
+                    // Groups cannot be merged
+                    return;
+                }
+                for id in self.remove_layer(m.id) {
+                    self.layers.remove(&id);
+                    self.layer_masks.retain(|_, layer| *layer != id);
+                }
+            }
+            LayerMask(_, m) => {
+                self.layer_masks.retain(|_, layer| *layer != m.id);
+                if m.mask != 0 {
+                    self.layer_masks.insert(m.mask, m.id);
+                }
+            }
+            AnnotationEdit(_, m) => {
+                if m.flags & ANNOTATION_PROTECT_FLAG != 0 {
+                    self.protected_annotations.insert(m.id);
+                } else {
+                    self.protected_annotations.remove(&m.id);
+                }
+            }
+            AnnotationDelete(_, id) => {
+                self.protected_annotations.remove(id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Track the position of a new layer in the layer tree
+    fn add_layer(&mut self, msg: &LayerCreateMessage) {
+        if self.layer_parents.contains_key(&msg.id) || self.layer_masks.contains_key(&msg.id) {
+            return;
+        }
+
+        let group = msg.flags & LayerCreateMessage::FLAGS_GROUP != 0;
+        if msg.flags & LayerCreateMessage::FLAGS_COPY != 0
+            && (group
+                || self.groups.contains(&msg.source)
+                || !self.layer_parents.contains_key(&msg.source))
+        {
+            return;
+        }
+
+        let parent = if msg.flags & LayerCreateMessage::FLAGS_INSERT != 0 && msg.source != 0 {
+            match self.layer_parents.get(&msg.source) {
+                Some(&p) => p,
+                None => return,
+            }
+        } else {
+            0
+        };
+
+        self.layer_parents.insert(msg.id, parent);
+        if group {
+            self.groups.insert(msg.id);
+        }
+    }
+
+    /// Track a layer's move to another group
+    fn move_layer(&mut self, msg: &LayerTreeMoveMessage) {
+        if !self.layer_parents.contains_key(&msg.layer) {
+            return;
+        }
+
+        if msg.parent != 0 {
+            // A layer can only be moved into a group that is not inside itself
+            if !self.groups.contains(&msg.parent) {
+                return;
+            }
+            let mut ancestor = msg.parent;
+            while ancestor != 0 {
+                if ancestor == msg.layer {
+                    return;
+                }
+                ancestor = self.layer_parents.get(&ancestor).copied().unwrap_or(0);
+            }
+        }
+
+        self.layer_parents.insert(msg.layer, msg.parent);
+    }
+
+    /// Forget a layer and everything inside it.
+    /// Returns the IDs of the removed layers.
+    fn remove_layer(&mut self, id: u16) -> Vec<u16> {
+        let mut removed = vec![id];
+        let mut i = 0;
+        while i < removed.len() {
+            let parent = removed[i];
+            if self.groups.remove(&parent) {
+                removed.extend(
+                    self.layer_parents
+                        .iter()
+                        .filter(|(_, &p)| p == parent)
+                        .map(|(&child, _)| child),
+                );
+            }
+            i += 1;
+        }
+
+        for id in &removed {
+            self.layer_parents.remove(id);
+        }
+        removed
+    }
+
+    /// Can the user change the layer's attributes, title, ACL or delete it?
+    fn can_edit_layer(&self, user: u8, layer: u16) -> bool {
+        self.is_operator(user)
+            || self.can_use(user, Feature::EditLayers)
+            || (self.can_use(user, Feature::OwnLayers) && is_owned_by(layer, user))
+    }
+
+    fn can_edit_annotation(&self, user: u8, id: u16) -> bool {
+        !self.protected_annotations.contains(&id) || is_owned_by(id, user) || self.is_operator(user)
+    }
+}
+
+impl Default for AclFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const ANNOTATION_PROTECT_FLAG: u8 = 0x01;
+
+/// Layer and annotation IDs are prefixed with the creating user's ID
+fn is_owned_by(id: u16, user: u8) -> bool {
+    (id >> 8) as u8 == user
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::CanvasState;
+
+    fn msg(text: &str) -> Message {
+        Message::from_text(&text.parse().unwrap()).unwrap()
+    }
+
+    fn filter_text(acl: &mut AclFilter, text: &str) -> bool {
+        acl.filter_message(&msg(text))
+    }
+
+    #[test]
+    fn test_operator_status() {
+        let mut acl = AclFilter::new();
+        assert!(filter_text(&mut acl, "1 join name=op flags=mod"));
+        assert!(filter_text(&mut acl, "2 join name=guest"));
+
+        assert!(acl.is_operator(1));
+        assert!(!acl.is_operator(2));
+
+        // Non-operators cannot grant op status
+        assert!(!filter_text(&mut acl, "2 sessionowner users=2"));
+        assert!(!acl.is_operator(2));
+
+        // Operators can
+        assert!(filter_text(&mut acl, "1 sessionowner users=2"));
+        assert!(acl.is_operator(1));
+        assert!(acl.is_operator(2));
+
+        // Leaving clears status
+        assert!(filter_text(&mut acl, "2 leave"));
+        assert!(!acl.is_operator(2));
+    }
+
+    #[test]
+    fn test_feature_tiers() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=trusted");
+        filter_text(&mut acl, "3 join name=guest");
+        filter_text(&mut acl, "1 trusted users=2");
+
+        assert_eq!(acl.user_tier(1), Tier::Operator);
+        assert_eq!(acl.user_tier(2), Tier::Trusted);
+        assert_eq!(acl.user_tier(3), Tier::Guest);
+
+        // Resize is operator only by default
+        assert!(!filter_text(&mut acl, "2 resize top=1"));
+        assert!(filter_text(&mut acl, "1 resize top=1"));
+
+        // Non-operators cannot change the feature tiers
+        assert!(!filter_text(
+            &mut acl,
+            "3 featureaccess feature_tiers=3,3,3,3,3,3,3,3,3"
+        ));
+
+        // Restrict undo to trusted users and allow them to resize
+        assert!(filter_text(
+            &mut acl,
+            "1 featureaccess feature_tiers=3,3,1,0,0,3,3,3,1"
+        ));
+        assert!(filter_text(&mut acl, "2 resize top=1"));
+        assert!(!filter_text(&mut acl, "3 resize top=1"));
+        assert!(filter_text(&mut acl, "2 undo"));
+        assert!(!filter_text(&mut acl, "3 undo"));
+
+        // Invalid tiers are rejected and the old ones kept
+        assert!(!filter_text(
+            &mut acl,
+            "1 featureaccess feature_tiers=3,3,3,3,3,3,3,3,7"
+        ));
+        assert_eq!(acl.feature_tier(Feature::Resize), Tier::Trusted);
+        assert_eq!(acl.feature_tier(Feature::Undo), Tier::Trusted);
+    }
+
+    #[test]
+    fn test_layer_locks() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user1");
+        filter_text(&mut acl, "3 join name=user2");
+
+        assert!(filter_text(&mut acl, "2 newlayer id=0x0201"));
+        assert!(!filter_text(&mut acl, "2 newlayer id=0x0301"));
+        assert!(filter_text(
+            &mut acl,
+            "2 fillrect layer=0x0201 x=0 y=0 w=1 h=1 color=#ff000000 mode=1"
+        ));
+
+        // Other users cannot lock someone else's layer
+        assert!(!filter_text(&mut acl, "3 layeracl id=0x0201 flags=0x80"));
+
+        // But the owner can
+        assert!(filter_text(&mut acl, "2 layeracl id=0x0201 flags=0x80"));
+        assert!(acl.is_layer_locked_for(0x0201, 2));
+        assert!(!filter_text(
+            &mut acl,
+            "2 fillrect layer=0x0201 x=0 y=0 w=1 h=1 color=#ff000000 mode=1"
+        ));
+
+        // Exclusive access
+        assert!(filter_text(
+            &mut acl,
+            "1 layeracl id=0x0201 flags=0x03 exclusive=3"
+        ));
+        assert!(acl.is_layer_locked_for(0x0201, 2));
+        assert!(!acl.is_layer_locked_for(0x0201, 3));
+
+        // Tier restricted
+        assert!(filter_text(&mut acl, "1 layeracl id=0x0201 flags=0x01"));
+        assert!(acl.is_layer_locked_for(0x0201, 3));
+        assert!(!acl.is_layer_locked_for(0x0201, 1));
+
+        // Operators are exempt from the lock
+        assert!(filter_text(&mut acl, "1 layeracl id=0x0201 flags=0x80"));
+        assert!(acl.is_layer_locked_for(0x0201, 2));
+        assert!(!acl.is_layer_locked_for(0x0201, 1));
+        assert!(filter_text(
+            &mut acl,
+            "1 fillrect layer=0x0201 x=0 y=0 w=1 h=1 color=#ff000000 mode=1"
+        ));
+
+        // An invalid tier is rejected
+        assert!(!filter_text(&mut acl, "1 layeracl id=0x0201 flags=0x07"));
+        assert!(acl.layer_acl(0x0201).unwrap().locked);
+
+        // Deleting the layer clears the ACL
+        assert!(filter_text(&mut acl, "1 deletelayer id=0x0201"));
+        assert!(acl.layer_acl(0x0201).is_none());
+    }
+
+    #[test]
+    fn test_layer_mask_locks() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user1");
+
+        assert!(filter_text(&mut acl, "2 newlayer id=0x0201"));
+
+        // Mask IDs are owned like layer IDs
+        assert!(!filter_text(&mut acl, "2 layermask id=0x0201 mask=0x0102"));
+        assert!(filter_text(&mut acl, "2 layermask id=0x0201 mask=0x0202"));
+        assert!(filter_text(
+            &mut acl,
+            "2 fillrect layer=0x0202 x=0 y=0 w=1 h=1 color=#ff000000 mode=1"
+        ));
+
+        // Locking the layer locks its mask as well
+        assert!(filter_text(&mut acl, "2 layeracl id=0x0201 flags=0x80"));
+        assert!(acl.is_layer_locked_for(0x0202, 2));
+        assert!(!filter_text(
+            &mut acl,
+            "2 fillrect layer=0x0202 x=0 y=0 w=1 h=1 color=#ff000000 mode=1"
+        ));
+        assert!(!filter_text(&mut acl, "2 layermask id=0x0201 mask=0"));
+
+        // Removing the mask releases the ID
+        assert!(filter_text(&mut acl, "1 layermask id=0x0201 mask=0"));
+        assert!(!acl.is_layer_locked_for(0x0202, 2));
+    }
+
+    #[test]
+    fn test_group_delete_releases_masks() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user1");
+
+        assert!(filter_text(&mut acl, "1 newlayer id=0x0101 flags=group"));
+        assert!(filter_text(&mut acl, "1 newlayer id=0x0102 flags=group"));
+        assert!(filter_text(&mut acl, "1 newlayer id=0x0103"));
+        assert!(filter_text(
+            &mut acl,
+            "1 movelayer layer=0x0102 parent=0x0101 sibling=0"
+        ));
+        assert!(filter_text(
+            &mut acl,
+            "1 movelayer layer=0x0103 parent=0x0102 sibling=0"
+        ));
+        assert!(filter_text(&mut acl, "1 layermask id=0x0103 mask=0x0104"));
+        assert!(filter_text(&mut acl, "1 layeracl id=0x0103 flags=0x80"));
+        assert!(acl.is_layer_locked_for(0x0104, 2));
+
+        // Deleting the outer group removes everything inside it
+        assert!(filter_text(&mut acl, "1 deletelayer id=0x0101"));
+        assert!(acl.layer_acl(0x0103).is_none());
+        assert!(!acl.is_layer_locked_for(0x0104, 2));
+    }
+
+    #[test]
+    fn test_merge_without_layer_below() {
+        let mut acl = AclFilter::new();
+        let mut canvas = CanvasState::new();
+        let mut send = |text: &str| {
+            let m = msg(text);
+            let accepted = acl.filter_message(&m);
+            if let Message::Command(c) = &m {
+                canvas.receive_message(c);
+            }
+            accepted
+        };
+        send("1 join name=op flags=mod");
+        send("1 resize right=64 bottom=64");
+
+        assert!(send("1 newlayer id=0x0101"));
+        assert!(send("1 newlayer id=0x0102 flags=group"));
+        assert!(send("1 newlayer id=0x0103"));
+        assert!(send("1 layeracl id=0x0101 flags=0x80"));
+        assert!(send("1 layeracl id=0x0103 flags=0x80"));
+
+        // Merging onto a group deletes the layer without merging
+        assert!(send("1 deletelayer id=0x0103 merge=true"));
+        // Merging the bottom-most layer deletes it too
+        assert!(send("1 deletelayer id=0x0101 merge=true"));
+        // Groups cannot be merged
+        assert!(send("1 deletelayer id=0x0102 merge=true"));
+
+        assert!(acl.layer_acl(0x0101).is_none());
+        assert!(acl.layer_acl(0x0103).is_none());
+        assert!(canvas.layerstack().get_layer(0x0101).is_none());
+        assert!(canvas.layerstack().get_layer(0x0103).is_none());
+        assert!(canvas.layerstack().get_layer(0x0102).is_some());
+        assert!(acl.groups.contains(&0x0102));
+    }
+
+    #[test]
+    fn test_general_lock() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user");
+
+        assert!(!filter_text(&mut acl, "2 layeracl id=0 flags=0x80"));
+        assert!(filter_text(&mut acl, "1 layeracl id=0 flags=0x80"));
+        assert!(acl.is_session_locked());
+        assert!(!filter_text(&mut acl, "2 undopoint"));
+        assert!(!filter_text(&mut acl, "1 undopoint"));
+        assert!(filter_text(&mut acl, "0 undopoint"));
+
+        assert!(filter_text(&mut acl, "1 layeracl id=0 flags=0"));
+        assert!(filter_text(&mut acl, "2 undopoint"));
+
+        // Individual user locks
+        assert!(filter_text(&mut acl, "1 useracl users=2"));
+        assert!(!filter_text(&mut acl, "2 undopoint"));
+        assert!(filter_text(&mut acl, "1 undopoint"));
+    }
+
+    #[test]
+    fn test_protected_annotation() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user1");
+        filter_text(&mut acl, "3 join name=user2");
+
+        assert!(filter_text(
+            &mut acl,
+            "2 newannotation id=0x0201 x=0 y=0 w=10 h=10"
+        ));
+        assert!(!filter_text(
+            &mut acl,
+            "3 newannotation id=0x0201 x=0 y=0 w=10 h=10"
+        ));
+        assert!(filter_text(
+            &mut acl,
+            "3 editannotation id=0x0201 flags=0x01 text=hello"
+        ));
+        assert!(!filter_text(
+            &mut acl,
+            "3 editannotation id=0x0201 flags=0 text=hello"
+        ));
+        assert!(filter_text(
+            &mut acl,
+            "2 editannotation id=0x0201 flags=0 text=hello"
+        ));
+        assert!(filter_text(&mut acl, "3 deleteannotation id=0x0201"));
+    }
+
+    #[test]
+    fn test_state_messages() {
+        let mut acl = AclFilter::new();
+        filter_text(&mut acl, "1 join name=op flags=mod");
+        filter_text(&mut acl, "2 join name=user");
+        filter_text(&mut acl, "1 trusted users=2");
+        filter_text(&mut acl, "1 useracl users=3");
+        filter_text(&mut acl, "1 featureaccess feature_tiers=3,3,1,0,0,3,3,3,1");
+        filter_text(&mut acl, "1 layeracl id=0x0101 flags=0x81 exclusive=1,2");
+        filter_text(&mut acl, "1 layeracl id=0 flags=0x80");
+
+        let mut restored = AclFilter::new();
+        for msg in acl.state_messages() {
+            assert!(restored.filter_message(&msg));
+        }
+
+        assert!(restored.is_operator(1));
+        assert!(restored.is_trusted(2));
+        assert!(restored.is_user_locked(3));
+        assert!(restored.is_session_locked());
+        assert_eq!(restored.feature_tier(Feature::Resize), Tier::Trusted);
+        assert_eq!(restored.layer_acl(0x0101), acl.layer_acl(0x0101));
+    }
+
+    #[test]
+    fn test_filtered_wrapper() {
+        let mut acl = AclFilter::new();
+        let m = msg("2 resize top=1");
+        let expected = m.serialize();
+        match acl.filter(m) {
+            Message::ClientMeta(ClientMetaMessage::Filtered(2, data)) => {
+                assert_eq!(data, expected)
+            }
+            other => panic!("Expected a filtered message, got {:?}", other),
+        }
+    }
+}
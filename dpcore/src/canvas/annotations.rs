@@ -0,0 +1,107 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::compression::put_image;
+use crate::paint::annotation::Annotation;
+use crate::paint::textrender::AnnotationRenderer;
+use crate::paint::{Blendmode, UserID};
+use crate::protocol::message::CommandMessage;
+
+/// Make the messages that merge an annotation onto a layer.
+///
+/// The rendered annotation is drawn with one or more PutImage messages
+/// (large images are split to fit the message length limit) and the
+/// annotation itself is then deleted. Only the part of the annotation
+/// that is inside the canvas is rendered.
+pub fn merge_annotation(
+    user: UserID,
+    layer: u16,
+    annotation: &Annotation,
+    renderer: &AnnotationRenderer,
+    canvas_width: u32,
+    canvas_height: u32,
+) -> Vec<CommandMessage> {
+    let mut msgs = Vec::new();
+
+    if let Some(visible) = annotation.rect.cropped(canvas_width, canvas_height) {
+        let pixels = renderer.render_area(annotation, &visible);
+        put_image(&mut msgs, user, layer, Blendmode::Normal, &visible, &pixels);
+    }
+
+    msgs.push(CommandMessage::AnnotationDelete(user, annotation.id));
+    msgs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::annotation::VAlign;
+    use crate::paint::{Color, Rectangle};
+
+    #[test]
+    fn test_merge_annotation() {
+        let annotation = Annotation {
+            id: 0x0101,
+            text: "Hello".to_string(),
+            rect: Rectangle::new(-10, 0, 50, 20),
+            background: Color::rgb8(255, 0, 0),
+            protect: false,
+            valign: VAlign::Top,
+        };
+
+        let renderer = AnnotationRenderer::new();
+        let msgs = merge_annotation(1, 0x0102, &annotation, &renderer, 100, 100);
+        assert_eq!(msgs.len(), 2);
+        match &msgs[0] {
+            CommandMessage::PutImage(1, pi) => {
+                assert_eq!((pi.x, pi.y, pi.w, pi.h), (0, 0, 40, 20));
+                assert_eq!(pi.layer, 0x0102);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert!(matches!(
+            msgs[1],
+            CommandMessage::AnnotationDelete(1, 0x0101)
+        ));
+
+        // Only the part inside the canvas is rendered
+        let huge = Annotation {
+            rect: Rectangle::new(90, 95, 65535, 65535),
+            ..annotation.clone()
+        };
+        let msgs = merge_annotation(1, 0x0102, &huge, &renderer, 100, 100);
+        match &msgs[0] {
+            CommandMessage::PutImage(1, pi) => {
+                assert_eq!((pi.x, pi.y, pi.w, pi.h), (90, 95, 10, 5));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // An annotation outside the canvas is just deleted
+        let outside = Annotation {
+            rect: Rectangle::new(200, 0, 10, 10),
+            ..annotation
+        };
+        let msgs = merge_annotation(1, 0x0102, &outside, &renderer, 100, 100);
+        assert_eq!(msgs.len(), 1);
+    }
+}
@@ -20,6 +20,7 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::paint::layermask::{self, LayerMask};
 use crate::paint::{
     editlayer, AoE, Blendmode, BrushMask, ClassicBrushCache, Color, Layer, LayerID, UserID,
 };
@@ -63,6 +64,33 @@ fn drawdabs_classic_draw(
     dabs: &DrawDabsClassicMessage,
     cache: &mut ClassicBrushCache,
 ) -> AoE {
+    for_each_classic_dab(dabs, cache, |x, y, mask| {
+        editlayer::draw_brush_dab(layer, user, x, y, mask, &color, mode)
+    })
+}
+
+/// Draw classic brush dabs onto a layer mask
+///
+/// Masks are always drawn in direct mode: the alpha channel of the color is ignored.
+pub fn drawdabs_classic_mask(
+    mask: &mut LayerMask,
+    dabs: &DrawDabsClassicMessage,
+    cache: &mut ClassicBrushCache,
+) -> AoE {
+    let value = mask_value(dabs.color, dabs.mode);
+    for_each_classic_dab(dabs, cache, |x, y, brush| {
+        mask.draw_brush_dab(x, y, brush, value)
+    })
+}
+
+fn for_each_classic_dab<F>(
+    dabs: &DrawDabsClassicMessage,
+    cache: &mut ClassicBrushCache,
+    mut draw: F,
+) -> AoE
+where
+    F: FnMut(i32, i32, &BrushMask) -> AoE,
+{
     let mut last_x = dabs.x;
     let mut last_y = dabs.y;
     let mut aoe = AoE::Nothing;
@@ -78,9 +106,7 @@ fn drawdabs_classic_draw(
             dab.opacity as f32 / 255.0,
             cache,
         );
-        aoe = aoe.merge(editlayer::draw_brush_dab(
-            layer, user, mx, my, &mask, &color, mode,
-        ));
+        aoe = aoe.merge(draw(mx, my, &mask));
 
         last_x = x;
         last_y = y;
@@ -125,6 +151,25 @@ fn drawdabs_pixel_draw(
     dabs: &DrawDabsPixelMessage,
     square: bool,
 ) -> AoE {
+    for_each_pixel_dab(dabs, square, |x, y, mask| {
+        editlayer::draw_brush_dab(layer, user, x, y, mask, &color, mode)
+    })
+}
+
+/// Draw pixel brush dabs onto a layer mask
+///
+/// Masks are always drawn in direct mode: the alpha channel of the color is ignored.
+pub fn drawdabs_pixel_mask(mask: &mut LayerMask, dabs: &DrawDabsPixelMessage, square: bool) -> AoE {
+    let value = mask_value(dabs.color, dabs.mode);
+    for_each_pixel_dab(dabs, square, |x, y, brush| {
+        mask.draw_brush_dab(x, y, brush, value)
+    })
+}
+
+fn for_each_pixel_dab<F>(dabs: &DrawDabsPixelMessage, square: bool, mut draw: F) -> AoE
+where
+    F: FnMut(i32, i32, &BrushMask) -> AoE,
+{
     let mut mask = BrushMask {
         diameter: 0,
         mask: Vec::new(),
@@ -151,15 +196,7 @@ fn drawdabs_pixel_draw(
         }
 
         let offset = dab.size as i32 / 2;
-        aoe = aoe.merge(editlayer::draw_brush_dab(
-            layer,
-            user,
-            x - offset,
-            y - offset,
-            &mask,
-            &color,
-            mode,
-        ));
+        aoe = aoe.merge(draw(x - offset, y - offset, &mask));
 
         last_x = x;
         last_y = y;
@@ -167,3 +204,12 @@ fn drawdabs_pixel_draw(
 
     aoe
 }
+
+/// Get the mask value to paint with the given color and blending mode
+pub fn mask_value(color: u32, mode: u8) -> u8 {
+    if matches!(Blendmode::try_from(mode), Ok(Blendmode::Erase)) {
+        0
+    } else {
+        layermask::color_value(&Color::from_argb32(color))
+    }
+}
@@ -21,14 +21,139 @@
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::paint::tile::{Tile, TILE_LENGTH};
-use crate::paint::{Color, Pixel, UserID};
+use crate::paint::{Blendmode, Color, Pixel, Rectangle, UserID};
+use crate::protocol::message::{CommandMessage, PutImageMessage};
 
 use std::convert::TryInto;
 use std::mem;
 use tracing::warn;
 
+use deflate::deflate_bytes_zlib;
 use inflate::inflate_bytes_zlib;
 
+/// Maximum length of the compressed image in a PutImage message
+const MAX_IMAGE_LEN: usize = 0xffff - 19;
+
+/// Compress a Tile.
+///
+/// If every pixel of the tile is the same color and that color
+/// survives the round trip through an ARGB value unchanged, the
+/// 4 byte solid color shortcut is used. Otherwise the output is
+/// the zlib compressed pixel data prefixed with the uncompressed length.
+pub fn compress_tile(tile: &Tile) -> Vec<u8> {
+    match tile {
+        Tile::Bitmap(td) => {
+            let pix = td.pixels[0];
+            if td.pixels.iter().all(|&p| p == pix) {
+                let argb = Color::from_pixel(pix).as_argb32();
+                if Color::from_argb32(argb).as_pixel() == pix {
+                    return argb.to_be_bytes().to_vec();
+                }
+            }
+            compress_pixels(&td.pixels)
+        }
+        Tile::Blank => 0u32.to_be_bytes().to_vec(),
+    }
+}
+
+/// Compress an image for use in a PutImage message.
+/// The output can be decompressed with `decompress_image`.
+pub fn compress_image(pixels: &[Pixel]) -> Vec<u8> {
+    compress_pixels(pixels)
+}
+
+/// Make PutImage messages for an image, splitting it as needed.
+///
+/// An image too big to fit in one message even when compressed is split
+/// in half (by height, or by width for a single row) until the pieces fit.
+pub fn put_image(
+    msgs: &mut Vec<CommandMessage>,
+    user: UserID,
+    layer: u16,
+    mode: Blendmode,
+    rect: &Rectangle,
+    pixels: &[Pixel],
+) {
+    // Deflate compresses at most about 1032:1, so an image larger than this
+    // cannot fit in one message and is split without compressing it first
+    let image = if mem::size_of_val(pixels) > MAX_IMAGE_LEN * 1032 {
+        None
+    } else {
+        Some(compress_image(pixels))
+    };
+
+    match image {
+        Some(image) if image.len() <= MAX_IMAGE_LEN || rect.w * rect.h == 1 => {
+            msgs.push(CommandMessage::PutImage(
+                user,
+                PutImageMessage {
+                    layer,
+                    mode: mode.into(),
+                    x: rect.x as u32,
+                    y: rect.y as u32,
+                    w: rect.w as u32,
+                    h: rect.h as u32,
+                    image,
+                },
+            ));
+        }
+        _ if rect.h > 1 => {
+            let h = rect.h / 2;
+            let (top, bottom) = pixels.split_at((h * rect.w) as usize);
+            put_image(
+                msgs,
+                user,
+                layer,
+                mode,
+                &Rectangle::new(rect.x, rect.y, rect.w, h),
+                top,
+            );
+            put_image(
+                msgs,
+                user,
+                layer,
+                mode,
+                &Rectangle::new(rect.x, rect.y + h, rect.w, rect.h - h),
+                bottom,
+            );
+        }
+        _ => {
+            // A single row that does not compress is split by width
+            let w = rect.w / 2;
+            let (left, right) = pixels.split_at(w as usize);
+            put_image(
+                msgs,
+                user,
+                layer,
+                mode,
+                &Rectangle::new(rect.x, rect.y, w, 1),
+                left,
+            );
+            put_image(
+                msgs,
+                user,
+                layer,
+                mode,
+                &Rectangle::new(rect.x + w, rect.y, rect.w - w, 1),
+                right,
+            );
+        }
+    }
+}
+
+fn compress_pixels(pixels: &[Pixel]) -> Vec<u8> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(pixels.as_ptr() as *const u8, mem::size_of_val(pixels))
+    };
+
+    let compressed = deflate_bytes_zlib(bytes);
+
+    let mut data = Vec::with_capacity(4 + compressed.len());
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(&compressed);
+    data
+}
+
 /// Decompress a Tile.
 /// The input data should be prefixed with a 4 byte big endian
 /// number indicating the expected length of the uncompressed data.
@@ -116,3 +241,125 @@ pub fn decompress_image(data: &[u8], expected_len: usize) -> Option<Vec<Pixel>>
         .collect();
     return Some(pixels);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::tile::TILE_SIZE;
+
+    #[test]
+    fn test_solid_tile_roundtrip() {
+        let tile = Tile::new_solid(&Color::rgb8(255, 128, 0), 1);
+        let data = compress_tile(&tile);
+        assert_eq!(data, 0xffff8000u32.to_be_bytes());
+        assert_eq!(decompress_tile(&data, 1), Some(tile));
+
+        let data = compress_tile(&Tile::Blank);
+        assert_eq!(data, [0, 0, 0, 0]);
+        assert_eq!(decompress_tile(&data, 1), Some(Tile::Blank));
+    }
+
+    #[test]
+    fn test_bitmap_tile_roundtrip() {
+        let mut pixels = [[0u8; 4]; TILE_LENGTH];
+        for (i, p) in pixels.iter_mut().enumerate() {
+            let x = (i as u32 % TILE_SIZE) as u8;
+            let y = (i as u32 / TILE_SIZE) as u8;
+            *p = [x, y, 0, 255];
+        }
+        let tile = Tile::from_data(&pixels, 1);
+        let data = compress_tile(&tile);
+        assert_ne!(data.len(), 4);
+        assert_eq!(decompress_tile(&data, 1), Some(tile));
+    }
+
+    #[test]
+    fn test_lossy_solid_tile() {
+        // A semi-transparent premultiplied color that cannot be
+        // represented exactly as an unpremultiplied ARGB value
+        let tile = Tile::from_data(&[[1, 2, 3, 128]; TILE_LENGTH], 1);
+        let data = compress_tile(&tile);
+        assert_eq!(decompress_tile(&data, 1), Some(tile));
+    }
+
+    #[test]
+    fn test_image_roundtrip() {
+        let pixels: Vec<Pixel> = (0..100u32)
+            .map(|i| [i as u8, (i * 2) as u8, (i * 3) as u8, 255])
+            .collect();
+        let data = compress_image(&pixels);
+        assert_eq!(decompress_image(&data, pixels.len()), Some(pixels));
+    }
+
+    /// Pseudorandom noise that doesn't compress
+    fn noise(len: usize) -> Vec<Pixel> {
+        let mut seed = 1u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let b = seed.to_be_bytes();
+                [b[0] / 2, b[1] / 2, b[2] / 2, 255]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_put_image_split() {
+        let pixels = noise(200 * 200);
+
+        let mut msgs = Vec::new();
+        put_image(
+            &mut msgs,
+            1,
+            0x0101,
+            Blendmode::Normal,
+            &Rectangle::new(5, 5, 200, 200),
+            &pixels,
+        );
+        assert!(msgs.len() > 1);
+
+        let mut y = 5;
+        for m in msgs {
+            match m {
+                CommandMessage::PutImage(_, pi) => {
+                    assert!(pi.image.len() <= MAX_IMAGE_LEN);
+                    assert_eq!(pi.y, y);
+                    assert_eq!(pi.w, 200);
+                    y += pi.h;
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+        assert_eq!(y, 205);
+    }
+
+    #[test]
+    fn test_put_image_split_row() {
+        // A single row too long to fit in one message even when compressed
+        let pixels = noise(40000);
+
+        let mut msgs = Vec::new();
+        put_image(
+            &mut msgs,
+            1,
+            0x0101,
+            Blendmode::Normal,
+            &Rectangle::new(5, 5, 40000, 1),
+            &pixels,
+        );
+        assert!(msgs.len() > 1);
+
+        let mut x = 5;
+        for m in msgs {
+            match m {
+                CommandMessage::PutImage(_, pi) => {
+                    assert!(pi.image.len() <= MAX_IMAGE_LEN);
+                    assert_eq!((pi.x, pi.y, pi.h), (x, 5, 1));
+                    x += pi.w;
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+        assert_eq!(x, 40005);
+    }
+}
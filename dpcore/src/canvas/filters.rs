@@ -0,0 +1,131 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::paint::filter::Filter;
+use crate::paint::{LayerID, Rectangle};
+use crate::protocol::message::CommandMessage;
+
+/// A filter command's parameters
+pub struct FilterCommand {
+    pub layer: LayerID,
+    /// The area to filter (None if the area is empty or invalid)
+    pub rect: Option<Rectangle>,
+    pub filter: Filter,
+}
+
+impl FilterCommand {
+    /// The area the filter reads from: the target rectangle plus the filter's margin
+    pub fn sampled_area(&self) -> Option<Rectangle> {
+        let m = self.filter.margin() as i32;
+        self.rect
+            .map(|r| Rectangle::new(r.x - m, r.y - m, r.w + m * 2, r.h + m * 2))
+    }
+}
+
+/// Get the filter parameters of a filter command.
+///
+/// Returns None if this is not a filter command.
+pub fn filter_command(msg: &CommandMessage) -> Option<FilterCommand> {
+    use CommandMessage::*;
+
+    let (layer, x, y, w, h, filter) = match msg {
+        BoxBlur(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::BoxBlur(m.radius as u32),
+        ),
+        GaussianBlur(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::GaussianBlur(m.radius as u32),
+        ),
+        UnsharpMask(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::UnsharpMask {
+                radius: m.radius as u32,
+                amount: m.amount as u32,
+                threshold: m.threshold,
+            },
+        ),
+        BrightnessContrast(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::BrightnessContrast {
+                brightness: m.brightness,
+                contrast: m.contrast,
+            },
+        ),
+        HueSaturation(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::HueSaturation {
+                hue: m.hue,
+                saturation: m.saturation,
+                lightness: m.lightness,
+            },
+        ),
+        Levels(_, m) => (
+            m.layer,
+            m.x,
+            m.y,
+            m.w,
+            m.h,
+            Filter::Levels {
+                in_black: m.in_black,
+                in_white: m.in_white,
+                gamma: m.gamma as u32,
+                out_black: m.out_black,
+                out_white: m.out_white,
+            },
+        ),
+        Invert(_, m) => (m.layer, m.x, m.y, m.w, m.h, Filter::Invert),
+        _ => return None,
+    };
+
+    let rect = if w == 0 || h == 0 || w > 65535 || h > 65535 || x > 65535 || y > 65535 {
+        None
+    } else {
+        Some(Rectangle::new(x as i32, y as i32, w as i32, h as i32))
+    };
+
+    Some(FilterCommand {
+        layer: layer as LayerID,
+        rect,
+        filter,
+    })
+}
@@ -20,12 +20,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
+mod aclfilter;
+#[cfg(feature = "text-render")]
+pub mod annotations;
 mod brushes;
-mod compression;
+pub mod compression;
+mod filters;
 mod history;
 mod observable;
 mod retcon;
+mod snapshot;
 mod state;
 
+pub use aclfilter::{AclFilter, Feature, LayerAcl, Tier};
 pub use observable::{CanvasObserver, ObservableCanvasState};
+pub use snapshot::make_reset_image;
 pub use state::CanvasState;
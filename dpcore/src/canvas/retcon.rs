@@ -20,7 +20,9 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::filters;
 use crate::paint::tile::TILE_SIZEI;
+use crate::paint::transform::{quad_bounds, Quad};
 use crate::paint::{Color, LayerID, Rectangle};
 use crate::protocol::message::*;
 
@@ -93,6 +95,9 @@ enum AffectedArea {
     /// Layer content changed
     Pixels(LayerID, Rectangle),
 
+    /// Layer content moved from one area (and layer) to another
+    MovedPixels(LayerID, Rectangle, LayerID, Rectangle),
+
     /// Fallback
     Everything,
 }
@@ -230,15 +235,44 @@ impl LocalFork {
             LayerCreate(_, m) => AffectedArea::LayerAttrs(m.id as LayerID),
             LayerAttributes(_, m) => AffectedArea::LayerAttrs(m.id as LayerID),
             LayerRetitle(_, m) => AffectedArea::LayerAttrs(m.id as LayerID),
-            LayerOrder(_, _) => AffectedArea::LayerAttrs(0),
+            LayerOrder(_, _) | LayerTreeMove(_, _) => AffectedArea::LayerAttrs(0),
+            LayerMask(_, m) => AffectedArea::LayerAttrs(m.id as LayerID),
             LayerDelete(_, m) => AffectedArea::LayerAttrs(m.id as LayerID), // TODO this can affect the layer below as well
             LayerVisibility(_, _) => AffectedArea::UserAttrs,
+            Selection(_, _) => AffectedArea::UserAttrs,
             PutImage(_, m) => {
                 AffectedArea::Pixels(m.layer as LayerID, make_rect(m.x, m.y, m.w, m.h))
             }
             FillRect(_, m) => {
                 AffectedArea::Pixels(m.layer as LayerID, make_rect(m.x, m.y, m.w, m.h))
             }
+            BoxBlur(..)
+            | GaussianBlur(..)
+            | UnsharpMask(..)
+            | BrightnessContrast(..)
+            | HueSaturation(..)
+            | Levels(..)
+            | Invert(..) => {
+                let cmd = filters::filter_command(msg).unwrap();
+                match cmd.sampled_area() {
+                    Some(rect) => AffectedArea::Pixels(cmd.layer, rect),
+                    None => AffectedArea::UserAttrs,
+                }
+            }
+            TransformRegion(_, m) => AffectedArea::MovedPixels(
+                match m.source {
+                    0 => m.layer as LayerID,
+                    s => s as LayerID,
+                },
+                quad_area(&[
+                    (m.sx1, m.sy1),
+                    (m.sx2, m.sy2),
+                    (m.sx3, m.sy3),
+                    (m.sx4, m.sy4),
+                ]),
+                m.layer as LayerID,
+                quad_area(&[(m.x1, m.y1), (m.x2, m.y2), (m.x3, m.y3), (m.x4, m.y4)]),
+            ),
             PenUp(u) => self
                 .indirect_area
                 .remove(u)
@@ -291,6 +325,10 @@ impl AffectedArea {
             (LayerAttrs(a), LayerAttrs(b)) => a != b,
             (Annotation(a), Annotation(b)) => a != b,
             (Pixels(al, ar), Pixels(bl, br)) => al != bl || ar.intersected(br).is_none(),
+            (MovedPixels(sl, sr, tl, tr), other) | (other, MovedPixels(sl, sr, tl, tr)) => {
+                Pixels(*sl, *sr).is_concurrent_with(other)
+                    && Pixels(*tl, *tr).is_concurrent_with(other)
+            }
             _ => true,
         }
     }
@@ -305,6 +343,10 @@ fn make_rect(x: u32, y: u32, w: u32, h: u32) -> Rectangle {
     }
 }
 
+fn quad_area(quad: &Quad) -> Rectangle {
+    quad_bounds(quad).unwrap_or_else(|| Rectangle::new(quad[0].0, quad[0].1, 1, 1))
+}
+
 fn puttile_rect(msg: &PutTileMessage) -> Rectangle {
     Rectangle::tile(msg.col as i32, msg.row as i32, TILE_SIZEI) // TODO repeat
 }
@@ -455,6 +497,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_transform_conflict() {
+        let mut lf = LocalFork::new();
+
+        let transform = CommandMessage::TransformRegion(
+            1,
+            TransformRegionMessage {
+                layer: 1,
+                source: 0,
+                sx1: 0,
+                sy1: 0,
+                sx2: 10,
+                sy2: 0,
+                sx3: 10,
+                sy3: 10,
+                sx4: 0,
+                sy4: 10,
+                x1: 200,
+                y1: 200,
+                x2: 210,
+                y2: 200,
+                x3: 210,
+                y3: 210,
+                x4: 200,
+                y4: 210,
+                mode: 0,
+            },
+        );
+        lf.add_local_message(&transform, 0);
+
+        // Neither the source nor the target area is touched
+        assert_eq!(
+            lf.receive_remote_message(&rectmsg(2, 100, 100, 10, 10)),
+            RetconAction::Concurrent
+        );
+
+        // Drawing over the target area
+        assert_eq!(
+            lf.receive_remote_message(&rectmsg(2, 205, 205, 10, 10)),
+            RetconAction::Rollback(0)
+        );
+    }
+
     fn dabmsg(user: u8, x: i32, y: i32, d: u8, indirect: bool) -> CommandMessage {
         CommandMessage::DrawDabsPixel(
             user,
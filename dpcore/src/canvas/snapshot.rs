@@ -0,0 +1,376 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::compression::compress_tile;
+use crate::paint::annotation::{Annotation, VAlign};
+use crate::paint::layermask::{LayerMask, MaskTile};
+use crate::paint::selection::Selection;
+use crate::paint::tile::Tile;
+use crate::paint::{Blendmode, Layer, LayerStack, UserID};
+use crate::protocol::message::*;
+
+/// Generate the messages needed to rebuild the given layer stack from scratch.
+///
+/// Replaying the returned messages on an empty canvas produces a layer stack
+/// that is pixel-identical to the original. This is used to build session
+/// reset images, compacted recordings and snapshots for joining users.
+///
+/// Positive ID sublayers (indirect strokes in progress) and the users'
+/// selections are included, but local-only state, such as hidden layers
+/// and negative ID preview sublayers, is not.
+pub fn make_reset_image(layerstack: &LayerStack, user: UserID) -> Vec<CommandMessage> {
+    let mut msgs = Vec::new();
+
+    if layerstack.width() == 0 || layerstack.height() == 0 {
+        return msgs;
+    }
+
+    msgs.push(CommandMessage::CanvasResize(
+        user,
+        CanvasResizeMessage {
+            top: 0,
+            right: layerstack.width() as i32,
+            bottom: layerstack.height() as i32,
+            left: 0,
+        },
+    ));
+
+    if layerstack.background != Tile::Blank {
+        msgs.push(CommandMessage::CanvasBackground(
+            user,
+            compress_tile(&layerstack.background),
+        ));
+    }
+
+    for layer in layerstack.iter_layers() {
+        layer_messages(&mut msgs, layer, user);
+    }
+
+    for annotation in layerstack.iter_annotations() {
+        annotation_messages(&mut msgs, annotation, user);
+    }
+
+    // Selections are per user, so these are sent in the selecting user's name
+    for (owner, selection) in layerstack.iter_selections() {
+        msgs.push(CommandMessage::Selection(
+            owner,
+            selection_message(selection),
+        ));
+    }
+
+    msgs
+}
+
+fn selection_message(selection: &Selection) -> SelectionMessage {
+    let rect = selection.rect();
+    SelectionMessage {
+        x: rect.x,
+        y: rect.y,
+        w: rect.w as u16,
+        h: rect.h as u16,
+        points: selection
+            .points()
+            .iter()
+            .flat_map(|&(x, y)| [(x - rect.x) as u16, (y - rect.y) as u16])
+            .collect(),
+    }
+}
+
+fn layer_messages(msgs: &mut Vec<CommandMessage>, layer: &Layer, user: UserID) {
+    let id = layer.id as u16;
+
+    // If the whole layer is filled with a color that can be expressed
+    // as a fill color, no tiles need to be sent at all.
+    let fill = layer.same_tile().and_then(|t| solid_argb(&t));
+
+    msgs.push(CommandMessage::LayerCreate(
+        user,
+        LayerCreateMessage {
+            id,
+            source: 0,
+            fill: fill.unwrap_or(0),
+            flags: if layer.is_group() {
+                LayerCreateMessage::FLAGS_GROUP
+            } else {
+                0
+            },
+            name: layer.title.clone(),
+        },
+    ));
+
+    let opacity = opacity_u8(layer.opacity);
+    if opacity != 255
+        || layer.blendmode != Blendmode::Normal
+        || layer.censored
+        || layer.fixed
+        || layer.passthrough
+        || layer.clip
+    {
+        msgs.push(CommandMessage::LayerAttributes(
+            user,
+            LayerAttributesMessage {
+                id,
+                sublayer: 0,
+                flags: if layer.censored {
+                    LayerAttributesMessage::FLAGS_CENSOR
+                } else {
+                    0
+                } | if layer.fixed {
+                    LayerAttributesMessage::FLAGS_FIXED
+                } else {
+                    0
+                } | if layer.passthrough {
+                    LayerAttributesMessage::FLAGS_PASSTHROUGH
+                } else {
+                    0
+                } | if layer.clip {
+                    LayerAttributesMessage::FLAGS_CLIP
+                } else {
+                    0
+                },
+                opacity,
+                blend: layer.blendmode.into(),
+            },
+        ));
+    }
+
+    if let Some(mask) = layer.mask() {
+        mask_messages(msgs, mask, id, user);
+    }
+
+    if layer.is_group() {
+        // Children are created at the top of the stack and then moved into
+        // the group, each one above the previous.
+        let mut sibling = 0;
+        for child in layer.iter_children() {
+            layer_messages(msgs, child, user);
+            msgs.push(CommandMessage::LayerTreeMove(
+                user,
+                LayerTreeMoveMessage {
+                    layer: child.id as u16,
+                    parent: id,
+                    sibling,
+                },
+            ));
+            sibling = child.id as u16;
+        }
+        return;
+    }
+
+    if fill.is_none() {
+        tile_messages(msgs, layer, id, 0, user);
+    }
+
+    for sublayer in layer.iter_sublayers() {
+        if sublayer.id <= 0 || sublayer.id > 255 {
+            continue;
+        }
+        msgs.push(CommandMessage::LayerAttributes(
+            user,
+            LayerAttributesMessage {
+                id,
+                sublayer: sublayer.id as u8,
+                flags: 0,
+                opacity: opacity_u8(sublayer.opacity),
+                blend: sublayer.blendmode.into(),
+            },
+        ));
+        tile_messages(msgs, sublayer, id, sublayer.id as u8, user);
+    }
+}
+
+/// Generate PutTile messages for all non-blank tiles.
+/// Runs of identical tiles are merged into a single message.
+fn tile_messages(
+    msgs: &mut Vec<CommandMessage>,
+    layer: &Layer,
+    id: u16,
+    sublayer: u8,
+    user: UserID,
+) {
+    let tiles = layer.tilevec();
+    let xtiles = Tile::div_up(layer.width()) as usize;
+
+    for (i, end) in tile_runs(tiles, |a, b| a.ptr_eq(b) || a == b) {
+        let tile = &tiles[i];
+        if *tile != Tile::Blank {
+            msgs.push(CommandMessage::PutTile(
+                user,
+                PutTileMessage {
+                    layer: id,
+                    sublayer,
+                    col: (i % xtiles) as u16,
+                    row: (i / xtiles) as u16,
+                    repeat: (end - i - 1) as u16,
+                    image: compress_tile(tile),
+                },
+            ));
+        }
+    }
+}
+
+/// Generate the messages for creating a layer mask.
+/// The mask is created filled with the value of the first tile (if uniform)
+/// and the rest of the tiles are sent as grayscale PutTiles.
+fn mask_messages(msgs: &mut Vec<CommandMessage>, mask: &LayerMask, layer: u16, user: UserID) {
+    let tiles = mask.tilevec();
+    let xtiles = Tile::div_up(mask.width()) as usize;
+
+    let fill = match tiles.first() {
+        Some(MaskTile::Uniform(v)) => *v,
+        _ => 0,
+    };
+
+    msgs.push(CommandMessage::LayerMask(
+        user,
+        LayerMaskMessage {
+            id: layer,
+            mask: mask.id as u16,
+            fill,
+        },
+    ));
+
+    for (i, end) in tile_runs(tiles, |a, b| a.ptr_eq(b) || a == b) {
+        let tile = &tiles[i];
+        if *tile != MaskTile::Uniform(fill) {
+            msgs.push(CommandMessage::PutTile(
+                user,
+                PutTileMessage {
+                    layer: mask.id as u16,
+                    sublayer: 0,
+                    col: (i % xtiles) as u16,
+                    row: (i / xtiles) as u16,
+                    repeat: (end - i - 1) as u16,
+                    image: compress_tile(&tile.to_tile()),
+                },
+            ));
+        }
+    }
+}
+
+/// Split the tile vector into runs of identical tiles.
+/// Returns (start, end) index pairs. The length of a run is limited to what
+/// the PutTile message's repeat field can express.
+fn tile_runs<T>(tiles: &[T], same: impl Fn(&T, &T) -> bool) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < tiles.len() {
+        let mut end = i + 1;
+        while end < tiles.len() && end - i <= u16::MAX as usize && same(&tiles[end], &tiles[i]) {
+            end += 1;
+        }
+        runs.push((i, end));
+        i = end;
+    }
+    runs
+}
+
+fn annotation_messages(msgs: &mut Vec<CommandMessage>, annotation: &Annotation, user: UserID) {
+    msgs.push(CommandMessage::AnnotationCreate(
+        user,
+        AnnotationCreateMessage {
+            id: annotation.id,
+            x: annotation.rect.x,
+            y: annotation.rect.y,
+            w: annotation.rect.w as u16,
+            h: annotation.rect.h as u16,
+        },
+    ));
+
+    let valign = match annotation.valign {
+        VAlign::Top => 0,
+        VAlign::Center => 0x02,
+        VAlign::Bottom => 0x06,
+    };
+
+    msgs.push(CommandMessage::AnnotationEdit(
+        user,
+        AnnotationEditMessage {
+            id: annotation.id,
+            bg: annotation.background.as_argb32(),
+            flags: if annotation.protect { 0x01 } else { 0 } | valign,
+            border: 0,
+            text: annotation.text.clone(),
+        },
+    ));
+}
+
+/// Get the color of a solid tile, if the solid color shortcut can represent it exactly
+fn solid_argb(tile: &Tile) -> Option<u32> {
+    if *tile == Tile::Blank {
+        return Some(0);
+    }
+    let data = compress_tile(tile);
+    if data.len() == 4 {
+        Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    } else {
+        None
+    }
+}
+
+fn opacity_u8(opacity: f32) -> u8 {
+    (opacity * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::layerstack::{LayerFill, LayerInsertion};
+    use crate::paint::tile::TILE_SIZE;
+    use crate::paint::Color;
+
+    #[test]
+    fn test_empty_stack() {
+        let stack = LayerStack::new(0, 0);
+        assert!(make_reset_image(&stack, 0).is_empty());
+    }
+
+    #[test]
+    fn test_tile_runs() {
+        let mut stack = LayerStack::new(TILE_SIZE * 4, TILE_SIZE * 2);
+        let layer = stack
+            .add_layer(1, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top)
+            .unwrap();
+        let red = Tile::new(&Color::rgb8(255, 0, 0), 0);
+        let green = Tile::new(&Color::rgb8(0, 255, 0), 0);
+        let tv = layer.tilevec_mut();
+        tv[1] = red.clone();
+        tv[2] = red.clone();
+        tv[3] = red;
+        tv[4] = green;
+
+        let msgs = make_reset_image(&stack, 0);
+        assert_eq!(msgs.len(), 4); // resize, create, 2 x puttile
+        match &msgs[2] {
+            CommandMessage::PutTile(_, m) => {
+                assert_eq!((m.col, m.row, m.repeat), (1, 0, 2));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        match &msgs[3] {
+            CommandMessage::PutTile(_, m) => {
+                assert_eq!((m.col, m.row, m.repeat), (0, 1, 0));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+}
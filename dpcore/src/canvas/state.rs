@@ -22,22 +22,27 @@
 
 use super::brushes;
 use super::compression;
+use super::filters;
 use super::history::History;
 use super::retcon::{LocalFork, RetconAction};
 use crate::paint::annotation::{AnnotationID, VAlign};
+use crate::paint::layermask::{LayerMask, MaskTile};
 use crate::paint::layerstack::{LayerFill, LayerInsertion, LayerStack};
+use crate::paint::selection::Selection;
+use crate::paint::transform::{quad_bounds, Interpolation, Quad, Transform};
 use crate::paint::{
-    editlayer, AoE, Blendmode, ClassicBrushCache, Color, LayerID, Rectangle, UserID,
+    editlayer, rasterop, AoE, Blendmode, ClassicBrushCache, Color, Layer, LayerID, Rectangle,
+    UserID,
 };
 use crate::protocol::message::*;
 
 use std::convert::TryFrom;
 use std::mem;
-use std::rc::Rc;
+use std::sync::Arc;
 use tracing::{error, warn};
 
 pub struct CanvasState {
-    layerstack: Rc<LayerStack>,
+    layerstack: Arc<LayerStack>,
     history: History,
     brushcache: ClassicBrushCache,
     localfork: LocalFork,
@@ -47,7 +52,7 @@ pub struct CanvasState {
 impl CanvasState {
     pub fn new() -> CanvasState {
         CanvasState {
-            layerstack: Rc::new(LayerStack::new(0, 0)),
+            layerstack: Arc::new(LayerStack::new(0, 0)),
             history: History::new(),
             brushcache: ClassicBrushCache::new(),
             localfork: LocalFork::new().set_fallbehind(1000),
@@ -59,6 +64,15 @@ impl CanvasState {
         &self.layerstack
     }
 
+    /// Get a shared reference to the current layer stack.
+    ///
+    /// The snapshot is a cheap copy-on-write clone, so it can be sent
+    /// to another thread (e.g. for rendering) while this canvas keeps
+    /// processing messages.
+    pub fn layerstack_snapshot(&self) -> Arc<LayerStack> {
+        self.layerstack.clone()
+    }
+
     /// Receive a message from the canonical session history and execute it
     pub fn receive_message(&mut self, msg: &CommandMessage) -> AoE {
         self.history.add(msg.clone());
@@ -109,6 +123,16 @@ impl CanvasState {
         self.handle_message(msg)
     }
 
+    /// Truncate the undo history at a soft reset point.
+    ///
+    /// This should be called when a SoftReset message is received.
+    /// Undos cannot cross the reset boundary, so the history before
+    /// this point is no longer needed.
+    pub fn soft_reset(&mut self) {
+        self.history.truncate();
+        self.make_savepoint_if_needed();
+    }
+
     fn handle_message(&mut self, msg: &CommandMessage) -> AoE {
         use CommandMessage::*;
         match &msg {
@@ -118,9 +142,20 @@ impl CanvasState {
             LayerAttributes(_, m) => self.handle_layer_attributes(m),
             LayerRetitle(_, m) => self.handle_layer_retitle(m),
             LayerOrder(_, order) => self.handle_layer_order(order),
+            LayerTreeMove(_, m) => self.handle_layer_tree_move(m),
+            LayerMask(_, m) => self.handle_layer_mask(m),
+            Selection(u, m) => self.handle_selection(*u, m),
             LayerDelete(_, m) => self.handle_layer_delete(m),
             LayerVisibility(u, m) => self.handle_layer_visibility(*u, m),
             PutImage(u, m) => self.handle_putimage(*u, m),
+            TransformRegion(u, m) => self.handle_transform_region(*u, m),
+            BoxBlur(u, _)
+            | GaussianBlur(u, _)
+            | UnsharpMask(u, _)
+            | BrightnessContrast(u, _)
+            | HueSaturation(u, _)
+            | Levels(u, _)
+            | Invert(u, _) => self.handle_filter(*u, msg),
             FillRect(user, m) => self.handle_fillrect(*user, m),
             PenUp(user) => self.handle_penup(*user),
             AnnotationCreate(_, m) => self.handle_annotation_create(m),
@@ -175,16 +210,21 @@ impl CanvasState {
     fn handle_penup(&mut self, user_id: UserID) -> AoE {
         let sublayer_id = user_id as LayerID;
 
-        // Note: we could do a read-only pass first to check if
-        // this is necesary at all, but we can just as well simply
-        // not send unnecessary PenUps.
-
-        Rc::make_mut(&mut self.layerstack)
-            .iter_layers_mut()
-            .filter(|l| l.has_sublayer(sublayer_id)) // avoid unnecessary clones
-            .fold(AoE::Nothing, |aoe, l| {
-                aoe.merge(editlayer::merge_sublayer(Rc::make_mut(l), sublayer_id))
-            })
+        // Find the layers first to avoid unnecessary clones
+        let layers: Vec<LayerID> = self
+            .layerstack
+            .iter_layers_recursive()
+            .filter(|l| l.has_sublayer(sublayer_id))
+            .map(|l| l.id)
+            .collect();
+
+        let stack = Arc::make_mut(&mut self.layerstack);
+        layers.iter().fold(AoE::Nothing, |aoe, &id| {
+            aoe.merge(editlayer::merge_sublayer(
+                stack.get_layer_mut(id).unwrap(),
+                sublayer_id,
+            ))
+        })
     }
 
     fn handle_canvas_resize(&mut self, msg: &CanvasResizeMessage) -> AoE {
@@ -192,7 +232,7 @@ impl CanvasState {
             .layerstack
             .resized(msg.top, msg.right, msg.bottom, msg.left)
         {
-            self.layerstack = Rc::new(ls);
+            self.layerstack = Arc::new(ls);
             AoE::Resize(msg.left, msg.top)
         } else {
             warn!("Invalid resize: {:?}", msg);
@@ -210,19 +250,26 @@ impl CanvasState {
             (false, _) => LayerInsertion::Top,
         };
 
-        let fill = if msg.flags & LayerCreateMessage::FLAGS_COPY != 0 {
+        let fill = if msg.flags & LayerCreateMessage::FLAGS_GROUP != 0 {
+            if msg.flags & LayerCreateMessage::FLAGS_COPY != 0 {
+                warn!("LayerCreate: cannot copy a group ({:04x})", msg.id);
+                return AoE::Nothing;
+            }
+            LayerFill::Group
+        } else if msg.flags & LayerCreateMessage::FLAGS_COPY != 0 {
             LayerFill::Copy(msg.source as LayerID)
         } else {
             LayerFill::Solid(Color::from_argb32(msg.fill))
         };
 
         if let Some(layer) =
-            Rc::make_mut(&mut self.layerstack).add_layer(msg.id as LayerID, fill.clone(), pos)
+            Arc::make_mut(&mut self.layerstack).add_layer(msg.id as LayerID, fill.clone(), pos)
         {
             layer.title = msg.name.clone();
 
             match fill {
                 LayerFill::Copy(_) => layer.nonblank_tilemap().into(),
+                LayerFill::Group => AoE::Nothing,
                 LayerFill::Solid(c) => {
                     if c.is_transparent() {
                         AoE::Nothing
@@ -239,14 +286,18 @@ impl CanvasState {
     }
 
     fn handle_layer_attributes(&mut self, msg: &LayerAttributesMessage) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID) {
+        if let Some(layer) = Arc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID) {
             editlayer::change_attributes(
                 layer,
                 msg.sublayer as LayerID,
-                msg.opacity as f32 / 255.0,
-                Blendmode::try_from(msg.blend).unwrap_or(Blendmode::Normal),
-                (msg.flags & LayerAttributesMessage::FLAGS_CENSOR) != 0,
-                (msg.flags & LayerAttributesMessage::FLAGS_FIXED) != 0,
+                &editlayer::LayerAttributes {
+                    opacity: msg.opacity as f32 / 255.0,
+                    blendmode: Blendmode::try_from(msg.blend).unwrap_or(Blendmode::Normal),
+                    censored: (msg.flags & LayerAttributesMessage::FLAGS_CENSOR) != 0,
+                    fixed: (msg.flags & LayerAttributesMessage::FLAGS_FIXED) != 0,
+                    passthrough: (msg.flags & LayerAttributesMessage::FLAGS_PASSTHROUGH) != 0,
+                    clip: (msg.flags & LayerAttributesMessage::FLAGS_CLIP) != 0,
+                },
             )
         } else {
             warn!("LayerAttributes: Layer {:04x} not found!", msg.id);
@@ -255,7 +306,7 @@ impl CanvasState {
     }
 
     fn handle_layer_retitle(&mut self, msg: &LayerRetitleMessage) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID) {
+        if let Some(layer) = Arc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID) {
             layer.title = msg.title.clone()
         } else {
             warn!("LayerRetitle: Layer {:04x} not found!", msg.id);
@@ -265,28 +316,135 @@ impl CanvasState {
 
     fn handle_layer_order(&mut self, new_order: &[u16]) -> AoE {
         let order: Vec<LayerID> = new_order.iter().map(|i| *i as LayerID).collect();
-        self.layerstack = Rc::new(self.layerstack.reordered(&order));
+        self.layerstack = Arc::new(self.layerstack.reordered(&order));
 
         AoE::Everything
     }
 
+    fn handle_layer_tree_move(&mut self, msg: &LayerTreeMoveMessage) -> AoE {
+        let parent = match msg.parent {
+            0 => None,
+            p => Some(p as LayerID),
+        };
+        let sibling = match msg.sibling {
+            0 => None,
+            s => Some(s as LayerID),
+        };
+
+        if Arc::make_mut(&mut self.layerstack).move_layer(msg.layer as LayerID, parent, sibling) {
+            AoE::Everything
+        } else {
+            warn!(
+                "LayerTreeMove: cannot move {:04x} into {:04x}",
+                msg.layer, msg.parent
+            );
+            AoE::Nothing
+        }
+    }
+
+    fn handle_layer_mask(&mut self, msg: &LayerMaskMessage) -> AoE {
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let layer_id = msg.id as LayerID;
+        let mask_id = msg.mask as LayerID;
+
+        // A layer's existing mask may be replaced with a new one with the same ID
+        if mask_id != 0
+            && (stack.get_layer(mask_id).is_some()
+                || stack
+                    .iter_layers_recursive()
+                    .any(|l| l.id != layer_id && l.mask().map(|m| m.id) == Some(mask_id)))
+        {
+            warn!("LayerMask: ID {:04x} is already in use", mask_id);
+            return AoE::Nothing;
+        }
+
+        if let Some(layer) = stack.get_layer_mut(layer_id) {
+            let aoe = if layer.mask().is_some() || mask_id != 0 {
+                AoE::Everything
+            } else {
+                AoE::Nothing
+            };
+            layer.set_mask(match mask_id {
+                0 => None,
+                id => Some(LayerMask::new(id, layer.width(), layer.height(), msg.fill)),
+            });
+            aoe
+        } else {
+            warn!("LayerMask: Layer {:04x} not found!", msg.id);
+            AoE::Nothing
+        }
+    }
+
+    fn handle_selection(&mut self, user: UserID, msg: &SelectionMessage) -> AoE {
+        let stack = Arc::make_mut(&mut self.layerstack);
+
+        let selection = if msg.w == 0 || msg.h == 0 {
+            None
+        } else {
+            if msg.x.checked_add(msg.w as i32).is_none()
+                || msg.y.checked_add(msg.h as i32).is_none()
+            {
+                warn!("Selection: out of range ({}, {})", msg.x, msg.y);
+                return AoE::Nothing;
+            }
+
+            let rect = Rectangle::new(msg.x, msg.y, msg.w as i32, msg.h as i32);
+            if msg.points.is_empty() {
+                Some(Selection::rectangle(rect))
+            } else {
+                let points: Option<Vec<(i32, i32)>> = msg
+                    .points
+                    .chunks_exact(2)
+                    .map(|p| {
+                        Some((
+                            msg.x.checked_add(p[0] as i32)?,
+                            msg.y.checked_add(p[1] as i32)?,
+                        ))
+                    })
+                    .collect();
+                let points = match points {
+                    Some(p) => p,
+                    None => {
+                        warn!("Selection: point out of range ({}, {})", msg.x, msg.y);
+                        return AoE::Nothing;
+                    }
+                };
+                Some(Selection::polygon(
+                    rect,
+                    points,
+                    stack.width(),
+                    stack.height(),
+                ))
+            }
+        };
+
+        stack.set_selection(user, selection);
+        AoE::Nothing
+    }
+
     fn handle_layer_delete(&mut self, msg: &LayerDeleteMessage) -> AoE {
-        let stack = Rc::make_mut(&mut self.layerstack);
+        let stack = Arc::make_mut(&mut self.layerstack);
         let id = msg.id as LayerID;
-        let aoe = if msg.merge {
-            if let Some(below) = stack.find_layer_below(id) {
-                let above = stack.get_layer_rc(id).unwrap();
+        if msg.merge && stack.get_layer(id).map_or(false, |l| l.is_group()) {
+            warn!("LayerDelete: Cannot merge a group ({:04x})", id);
+            return AoE::Nothing;
+        }
+
+        // Without a (non-group) layer below, the layer is just deleted
+        let below = stack
+            .find_layer_below(id)
+            .filter(|&below| !stack.get_layer(below).unwrap().is_group());
+
+        let aoe = match below {
+            Some(below) if msg.merge => {
+                let above = stack.get_layer_arc(id).unwrap();
                 editlayer::merge(stack.get_layer_mut(below).unwrap(), &above);
-            } else {
-                warn!("LayerDelete: Cannot merge {:04x}", id);
-                return AoE::Nothing;
+                AoE::Nothing
             }
-            AoE::Nothing
-        } else {
-            stack
+            _ => stack
                 .get_layer(id)
                 .map(|l| l.nonblank_tilemap().into())
-                .unwrap_or(AoE::Nothing)
+                .unwrap_or(AoE::Nothing),
         };
 
         stack.remove_layer(id);
@@ -295,7 +453,8 @@ impl CanvasState {
 
     fn handle_layer_visibility(&mut self, user: UserID, msg: &LayerVisibilityMessage) -> AoE {
         if user == self.local_user_id {
-            if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID)
+            if let Some(layer) =
+                Arc::make_mut(&mut self.layerstack).get_layer_mut(msg.id as LayerID)
             {
                 layer.hidden = !msg.visible;
                 return layer.nonblank_tilemap().into();
@@ -305,7 +464,7 @@ impl CanvasState {
     }
 
     fn handle_annotation_create(&mut self, msg: &AnnotationCreateMessage) -> AoE {
-        Rc::make_mut(&mut self.layerstack).add_annotation(
+        Arc::make_mut(&mut self.layerstack).add_annotation(
             msg.id,
             Rectangle::new(msg.x, msg.y, msg.w.max(1) as i32, msg.h.max(1) as i32),
         );
@@ -313,14 +472,14 @@ impl CanvasState {
     }
 
     fn handle_annotation_reshape(&mut self, msg: &AnnotationReshapeMessage) -> AoE {
-        if let Some(a) = Rc::make_mut(&mut self.layerstack).get_annotation_mut(msg.id) {
+        if let Some(a) = Arc::make_mut(&mut self.layerstack).get_annotation_mut(msg.id) {
             a.rect = Rectangle::new(msg.x, msg.y, msg.w.max(1) as i32, msg.h.max(1) as i32);
         }
         AoE::Nothing
     }
 
     fn handle_annotation_edit(&mut self, msg: &AnnotationEditMessage) -> AoE {
-        if let Some(a) = Rc::make_mut(&mut self.layerstack).get_annotation_mut(msg.id) {
+        if let Some(a) = Arc::make_mut(&mut self.layerstack).get_annotation_mut(msg.id) {
             a.background = Color::from_argb32(msg.bg);
             a.protect = (msg.flags & 0x01) != 0;
             a.valign = match msg.flags & 0x06 {
@@ -335,22 +494,43 @@ impl CanvasState {
     }
 
     fn handle_annotation_delete(&mut self, id: AnnotationID) -> AoE {
-        Rc::make_mut(&mut self.layerstack).remove_annotation(id);
+        Arc::make_mut(&mut self.layerstack).remove_annotation(id);
         AoE::Nothing
     }
 
     fn handle_puttile(&mut self, user_id: UserID, msg: &PutTileMessage) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.layer as LayerID)
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let selection = stack.get_selection(user_id).cloned();
+
+        if let Some(mask) = stack.get_mask_mut(msg.layer as LayerID) {
+            if let Some(tile) = compression::decompress_tile(&msg.image, user_id) {
+                return edit_mask_selected(mask, selection.as_ref(), |mask| {
+                    mask.put_tile(
+                        msg.col.into(),
+                        msg.row.into(),
+                        msg.repeat.into(),
+                        &MaskTile::from_tile(&tile),
+                    )
+                });
+            }
+            return AoE::Nothing;
+        }
+
+        if let Some(layer) = stack
+            .get_layer_mut(msg.layer as LayerID)
+            .filter(|l| !l.is_group())
         {
             if let Some(tile) = compression::decompress_tile(&msg.image, user_id) {
-                return editlayer::put_tile(
-                    layer,
-                    msg.sublayer as LayerID,
-                    msg.col.into(),
-                    msg.row.into(),
-                    msg.repeat.into(),
-                    &tile,
-                );
+                return edit_selected(layer, selection.as_ref(), |layer| {
+                    editlayer::put_tile(
+                        layer,
+                        msg.sublayer as LayerID,
+                        msg.col.into(),
+                        msg.row.into(),
+                        msg.repeat.into(),
+                        &tile,
+                    )
+                });
             }
         } else {
             warn!("PutTile: Layer {:04x} not found!", msg.layer);
@@ -359,7 +539,12 @@ impl CanvasState {
     }
 
     fn handle_putimage(&mut self, user_id: UserID, msg: &PutImageMessage) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.layer as LayerID)
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let selection = stack.get_selection(user_id).cloned();
+
+        if let Some(layer) = stack
+            .get_layer_mut(msg.layer as LayerID)
+            .filter(|l| !l.is_group())
         {
             if msg.w == 0 || msg.h == 0 {
                 warn!("PutImage: zero size!");
@@ -373,14 +558,16 @@ impl CanvasState {
                 compression::decompress_image(&msg.image, (msg.w * msg.h) as usize)
             {
                 let mode = Blendmode::try_from(msg.mode).unwrap_or_default();
-                let aoe = editlayer::draw_image(
-                    layer,
-                    user_id,
-                    &imagedata,
-                    &Rectangle::new(msg.x as i32, msg.y as i32, msg.w as i32, msg.h as i32),
-                    1.0,
-                    mode,
-                );
+                let aoe = edit_selected(layer, selection.as_ref(), |layer| {
+                    editlayer::draw_image(
+                        layer,
+                        user_id,
+                        &imagedata,
+                        &Rectangle::new(msg.x as i32, msg.y as i32, msg.w as i32, msg.h as i32),
+                        1.0,
+                        mode,
+                    )
+                });
 
                 if mode.can_decrease_opacity() {
                     layer.optimize(&aoe);
@@ -393,9 +580,123 @@ impl CanvasState {
         AoE::Nothing
     }
 
+    fn handle_transform_region(&mut self, user_id: UserID, msg: &TransformRegionMessage) -> AoE {
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let target_id = msg.layer as LayerID;
+        let source_id = match msg.source {
+            0 => target_id,
+            s => s as LayerID,
+        };
+
+        let source: Quad = [
+            (msg.sx1, msg.sy1),
+            (msg.sx2, msg.sy2),
+            (msg.sx3, msg.sy3),
+            (msg.sx4, msg.sy4),
+        ];
+        let target: Quad = [
+            (msg.x1, msg.y1),
+            (msg.x2, msg.y2),
+            (msg.x3, msg.y3),
+            (msg.x4, msg.y4),
+        ];
+
+        if Transform::quad_to_quad(&source, &target).is_none() {
+            warn!("TransformRegion: degenerate quad!");
+            return AoE::Nothing;
+        }
+
+        // The source must be on the canvas and both quads must be small
+        // enough for their bounding rectangles to fit in the i32 range
+        let source_on_canvas = stack.width() > 0
+            && stack.height() > 0
+            && quad_bounds(&source)
+                .and_then(|r| r.cropped(stack.width(), stack.height()))
+                .is_some();
+        if !source_on_canvas || quad_bounds(&target).is_none() {
+            warn!("TransformRegion: quad out of range!");
+            return AoE::Nothing;
+        }
+
+        if stack
+            .get_layer(target_id)
+            .filter(|l| !l.is_group())
+            .is_none()
+        {
+            warn!("TransformRegion: Layer {:04x} not found!", msg.layer);
+            return AoE::Nothing;
+        }
+
+        // Only the selected part of the source region is lifted
+        let selection = stack.get_selection(user_id).cloned();
+        let (rect, pixels) = match stack.get_layer_mut(source_id).filter(|l| !l.is_group()) {
+            Some(layer) => {
+                let mut lifted = None;
+                let aoe = edit_selected(layer, selection.as_ref(), |layer| {
+                    lifted = editlayer::lift_region(layer, user_id, &source);
+                    lifted
+                        .as_ref()
+                        .map_or(AoE::Nothing, |(rect, _)| (*rect).into())
+                });
+                match lifted {
+                    Some((rect, mut pixels)) => {
+                        if let Some(mask) = selection.as_ref().and_then(|s| s.mask_for(&rect)) {
+                            rasterop::apply_mask(&mut pixels, &mask);
+                        }
+                        layer.optimize(&aoe);
+                        (rect, pixels)
+                    }
+                    None => return AoE::Nothing,
+                }
+            }
+            None => {
+                warn!("TransformRegion: Layer {:04x} not found!", msg.source);
+                return AoE::Nothing;
+            }
+        };
+
+        let layer = stack.get_layer_mut(target_id).unwrap();
+        let aoe = editlayer::draw_transformed(
+            layer,
+            user_id,
+            &pixels,
+            &rect,
+            &source,
+            &target,
+            Interpolation::try_from(msg.mode).unwrap_or(Interpolation::Nearest),
+        );
+
+        AoE::from(rect).merge(aoe)
+    }
+
+    fn handle_filter(&mut self, user_id: UserID, msg: &CommandMessage) -> AoE {
+        let cmd = filters::filter_command(msg).unwrap();
+        let rect = match cmd.rect {
+            Some(r) => r,
+            None => {
+                warn!("Filter: invalid area!");
+                return AoE::Nothing;
+            }
+        };
+
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let selection = stack.get_selection(user_id).cloned();
+
+        if let Some(layer) = stack.get_layer_mut(cmd.layer).filter(|l| !l.is_group()) {
+            let aoe = edit_selected(layer, selection.as_ref(), |layer| {
+                editlayer::apply_filter(layer, user_id, &cmd.filter, &rect)
+            });
+            layer.optimize(&aoe);
+            aoe
+        } else {
+            warn!("Filter: Layer {:04x} not found!", cmd.layer);
+            AoE::Nothing
+        }
+    }
+
     fn handle_background(&mut self, pixels: &[u8]) -> AoE {
         if let Some(tile) = compression::decompress_tile(pixels, 0) {
-            Rc::make_mut(&mut self.layerstack).background = tile;
+            Arc::make_mut(&mut self.layerstack).background = tile;
             AoE::Everything
         } else {
             AoE::Nothing
@@ -408,16 +709,28 @@ impl CanvasState {
             return AoE::Nothing;
         }
 
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.layer as LayerID)
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let selection = stack.get_selection(user).cloned();
+        let rect = Rectangle::new(msg.x as i32, msg.y as i32, msg.w as i32, msg.h as i32);
+
+        if let Some(mask) = stack.get_mask_mut(msg.layer as LayerID) {
+            return edit_mask_selected(mask, selection.as_ref(), |mask| {
+                mask.fill_rect(
+                    brushes::mask_value(msg.color, msg.mode),
+                    Color::argb32_alpha(msg.color),
+                    &rect,
+                )
+            });
+        }
+
+        if let Some(layer) = stack
+            .get_layer_mut(msg.layer as LayerID)
+            .filter(|l| !l.is_group())
         {
             let mode = Blendmode::try_from(msg.mode).unwrap_or_default();
-            let aoe = editlayer::fill_rect(
-                layer,
-                user,
-                &Color::from_argb32(msg.color),
-                mode,
-                &Rectangle::new(msg.x as i32, msg.y as i32, msg.w as i32, msg.h as i32),
-            );
+            let aoe = edit_selected(layer, selection.as_ref(), |layer| {
+                editlayer::fill_rect(layer, user, &Color::from_argb32(msg.color), mode, &rect)
+            });
 
             if mode.can_decrease_opacity() {
                 layer.optimize(&aoe);
@@ -425,15 +738,29 @@ impl CanvasState {
 
             return aoe;
         } else {
-            warn!("DrawDabsClassic: Layer {:04x} not found!", msg.layer);
+            warn!("FillRect: Layer {:04x} not found!", msg.layer);
         }
         AoE::Nothing
     }
 
     fn handle_drawdabs_classic(&mut self, user: UserID, msg: &DrawDabsClassicMessage) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.layer as LayerID)
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let cache = &mut self.brushcache;
+        let selection = stack.get_selection(user).cloned();
+
+        if let Some(mask) = stack.get_mask_mut(msg.layer as LayerID) {
+            return edit_mask_selected(mask, selection.as_ref(), |mask| {
+                brushes::drawdabs_classic_mask(mask, msg, cache)
+            });
+        }
+
+        if let Some(layer) = stack
+            .get_layer_mut(msg.layer as LayerID)
+            .filter(|l| !l.is_group())
         {
-            brushes::drawdabs_classic(layer, user, &msg, &mut self.brushcache)
+            edit_selected(layer, selection.as_ref(), |layer| {
+                brushes::drawdabs_classic(layer, user, msg, cache)
+            })
         } else {
             warn!("DrawDabsClassic: Layer {:04x} not found!", msg.layer);
             AoE::Nothing
@@ -446,9 +773,22 @@ impl CanvasState {
         msg: &DrawDabsPixelMessage,
         square: bool,
     ) -> AoE {
-        if let Some(layer) = Rc::make_mut(&mut self.layerstack).get_layer_mut(msg.layer as LayerID)
+        let stack = Arc::make_mut(&mut self.layerstack);
+        let selection = stack.get_selection(user).cloned();
+
+        if let Some(mask) = stack.get_mask_mut(msg.layer as LayerID) {
+            return edit_mask_selected(mask, selection.as_ref(), |mask| {
+                brushes::drawdabs_pixel_mask(mask, msg, square)
+            });
+        }
+
+        if let Some(layer) = stack
+            .get_layer_mut(msg.layer as LayerID)
+            .filter(|l| !l.is_group())
         {
-            brushes::drawdabs_pixel(layer, user, &msg, square)
+            edit_selected(layer, selection.as_ref(), |layer| {
+                brushes::drawdabs_pixel(layer, user, msg, square)
+            })
         } else {
             warn!("DrawDabsPixel: Layer {:04x} not found!", msg.layer);
             AoE::Nothing
@@ -464,3 +804,33 @@ impl CanvasState {
         }
     }
 }
+
+/// Run an editing function on the layer, clipping its changes to the selection (if any)
+fn edit_selected<F>(layer: &mut Layer, selection: Option<&Selection>, edit: F) -> AoE
+where
+    F: FnOnce(&mut Layer) -> AoE,
+{
+    match selection {
+        Some(sel) => {
+            let original = layer.clone();
+            let aoe = edit(layer);
+            editlayer::clip_to_selection(layer, &original, sel, aoe)
+        }
+        None => edit(layer),
+    }
+}
+
+/// Run an editing function on a layer mask, clipping its changes to the selection (if any)
+fn edit_mask_selected<F>(mask: &mut LayerMask, selection: Option<&Selection>, edit: F) -> AoE
+where
+    F: FnOnce(&mut LayerMask) -> AoE,
+{
+    match selection {
+        Some(sel) => {
+            let original = mask.clone();
+            let aoe = edit(mask);
+            mask.clip_to_selection(&original, sel, aoe)
+        }
+        None => edit(mask),
+    }
+}
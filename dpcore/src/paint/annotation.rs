@@ -29,10 +29,10 @@ pub type AnnotationID = u16;
 /// These are not strictly part of the canvas pixel data,
 /// but they belong to the layerstack.
 ///
-/// The core paint engine has no opinion on how annotations
-/// are rasterized. To merge an annotation, it must be converted
-/// to a bitmap on the client side, using fonts available there,
-/// then merged using the PutImage command.
+/// To merge an annotation, it must be converted to a bitmap on the
+/// client side, using fonts available there, then merged using the
+/// PutImage command. Clients without a text renderer of their own can
+/// use `textrender::AnnotationRenderer` (requires the `text-render` feature.)
 #[derive(Clone)]
 pub struct Annotation {
     pub id: AnnotationID,
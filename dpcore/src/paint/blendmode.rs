@@ -39,9 +39,46 @@ pub enum Blendmode {
     Recolor,
     Behind,
     ColorErase,
+    Screen,
+    Overlay,
+    SoftLight,
+    HardLight,
+    Difference,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
     Replace = 255,
 }
 
+/// OpenRaster composite-op names. Modes that have no SVG equivalent
+/// use the same "-dp-" prefixed names as Drawpile's own ORA files.
+const SVG_NAMES: [(Blendmode, &str); 23] = [
+    (Blendmode::Erase, "svg:dst-out"),
+    (Blendmode::Normal, "svg:src-over"),
+    (Blendmode::Multiply, "svg:multiply"),
+    (Blendmode::Divide, "-dp-divide"),
+    (Blendmode::Burn, "svg:color-burn"),
+    (Blendmode::Dodge, "svg:color-dodge"),
+    (Blendmode::Darken, "svg:darken"),
+    (Blendmode::Lighten, "svg:lighten"),
+    (Blendmode::Subtract, "-dp-minus"),
+    (Blendmode::Add, "svg:plus"),
+    (Blendmode::Recolor, "svg:src-atop"),
+    (Blendmode::Behind, "svg:dst-over"),
+    (Blendmode::ColorErase, "-dp-cerase"),
+    (Blendmode::Screen, "svg:screen"),
+    (Blendmode::Overlay, "svg:overlay"),
+    (Blendmode::SoftLight, "svg:soft-light"),
+    (Blendmode::HardLight, "svg:hard-light"),
+    (Blendmode::Difference, "svg:difference"),
+    (Blendmode::Hue, "svg:hue"),
+    (Blendmode::Saturation, "svg:saturation"),
+    (Blendmode::Color, "svg:color"),
+    (Blendmode::Luminosity, "svg:luminosity"),
+    (Blendmode::Replace, "-dp-replace"),
+];
+
 impl Blendmode {
     pub fn can_decrease_opacity(self) -> bool {
         match self {
@@ -56,6 +93,20 @@ impl Blendmode {
             _ => false,
         }
     }
+
+    /// The OpenRaster composite-op name of this mode
+    pub fn svg_name(self) -> &'static str {
+        SVG_NAMES
+            .iter()
+            .find(|(m, _)| *m == self)
+            .map(|(_, name)| *name)
+            .unwrap()
+    }
+
+    /// Find the mode matching an OpenRaster composite-op name
+    pub fn from_svg_name(name: &str) -> Option<Blendmode> {
+        SVG_NAMES.iter().find(|(_, n)| *n == name).map(|(m, _)| *m)
+    }
 }
 
 impl Default for Blendmode {
@@ -20,10 +20,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use super::aoe::{AoE, TileMap};
+use super::color::ZERO_PIXEL;
+use super::filter::Filter;
+use super::floodfill::{FillSource, FloodFill};
 use super::rectiter::RectIterator;
+use super::selection::Selection;
 use super::tile::{Tile, TILE_SIZE, TILE_SIZEI};
-use super::{rasterop, Blendmode, BrushMask, Color, Layer, LayerID, Pixel, Rectangle, UserID};
+use super::transform::{quad_bounds, resample, Interpolation, Quad, Transform};
+use super::{
+    rasterop, Blendmode, BrushMask, Color, Layer, LayerID, LayerStack, Pixel, Rectangle, UserID,
+};
 
 /// Fills a rectangle with a solid color using the given blending mode
 ///
@@ -182,6 +191,167 @@ pub fn draw_image(
     destrect.into()
 }
 
+/// Flood fill an area with a solid color
+///
+/// The colors are sampled from the given layer stack's merged image,
+/// or from the target layer itself if no layer stack is given.
+/// To send the fill to other users instead, compute the fill mask with
+/// `FloodFill::mask` and make the messages with `FillMask::to_putimages`.
+///
+/// # Arguments
+///
+/// * `layer` - The target layer
+/// * `user` - User ID tag to attach to the changed tiles
+/// * `source` - Layer stack to sample (None to sample the target layer)
+/// * `fill` - Flood fill parameters
+/// * `color` - Fill color
+/// * `mode` - Fill blending mode
+pub fn flood_fill(
+    layer: &mut Layer,
+    user: UserID,
+    source: Option<&LayerStack>,
+    fill: &FloodFill,
+    color: &Color,
+    mode: Blendmode,
+) -> AoE {
+    let mask = match source {
+        Some(stack) => fill.mask(FillSource::Merged(stack)),
+        None => fill.mask(FillSource::Layer(layer)),
+    };
+
+    match mask {
+        Some(m) => draw_image(layer, user, &m.to_image(color), &m.rect, 1.0, mode),
+        None => AoE::Nothing,
+    }
+}
+
+/// Cut out the pixels inside a quad
+///
+/// The pixels are erased from the layer and returned along with their
+/// bounding rectangle. Pixels outside the quad are transparent in the
+/// returned image. Returns None if the quad has no area or is completely
+/// outside the layer.
+///
+/// Use `draw_transformed` to put the pixels back at a new location.
+pub fn lift_region(
+    layer: &mut Layer,
+    user: UserID,
+    quad: &Quad,
+) -> Option<(Rectangle, Vec<Pixel>)> {
+    let selection = Selection::polygon(
+        quad_bounds(quad)?,
+        quad.to_vec(),
+        layer.width(),
+        layer.height(),
+    );
+    let rect = *selection.bounds()?;
+
+    let mut pixels = read_pixels(layer, &rect);
+
+    if let Some(mask) = selection.mask_for(&rect) {
+        rasterop::apply_mask(&mut pixels, &mask);
+        let eraser: Vec<Pixel> = mask.iter().map(|&m| [m, m, m, m]).collect();
+        draw_image(layer, user, &eraser, &rect, 1.0, Blendmode::Erase);
+    } else {
+        fill_rect(layer, user, &Color::TRANSPARENT, Blendmode::Replace, &rect);
+    }
+
+    Some((rect, pixels))
+}
+
+/// Draw an image transformed to fit the target quad
+///
+/// # Arguments
+///
+/// * `layer` - The target layer
+/// * `user` - User ID tag to attach to the changed tiles
+/// * `image` - The source image (typically returned by `lift_region`)
+/// * `image_rect` - Position and size of the source image
+/// * `source` - The quad in the source image coordinate space to transform
+/// * `target` - Where to place the source quad
+/// * `interpolation` - Resampling method
+pub fn draw_transformed(
+    layer: &mut Layer,
+    user: UserID,
+    image: &[Pixel],
+    image_rect: &Rectangle,
+    source: &Quad,
+    target: &Quad,
+    interpolation: Interpolation,
+) -> AoE {
+    let transform = match Transform::quad_to_quad(source, target) {
+        Some(t) => t,
+        None => return AoE::Nothing,
+    };
+
+    let rect = match quad_bounds(target).and_then(|r| r.cropped(layer.width(), layer.height())) {
+        Some(r) => r,
+        None => return AoE::Nothing,
+    };
+
+    let pixels = resample(image, image_rect, &transform, &rect, interpolation);
+    draw_image(layer, user, &pixels, &rect, 1.0, Blendmode::Normal)
+}
+
+/// Apply a filter to a rectangular area of the layer
+///
+/// Filters that sample neighbouring pixels (such as blurs) read pixels
+/// outside the rectangle as well. At the edges of the layer, the edge
+/// pixels are extended.
+pub fn apply_filter(layer: &mut Layer, user: UserID, filter: &Filter, rect: &Rectangle) -> AoE {
+    let rect = match rect.cropped(layer.width(), layer.height()) {
+        Some(r) => r,
+        None => return AoE::Nothing,
+    };
+
+    let margin = filter.margin() as i32;
+    let source = Rectangle::new(
+        rect.x - margin,
+        rect.y - margin,
+        rect.w + margin * 2,
+        rect.h + margin * 2,
+    )
+    .cropped(layer.width(), layer.height())
+    .unwrap();
+
+    let mut pixels = read_pixels(layer, &source);
+    filter.apply(&mut pixels, source.w as usize, source.h as usize);
+
+    let result: Vec<Pixel> = RectIterator::from_rectangle(
+        &pixels,
+        source.w as usize,
+        &rect.offset(-source.x, -source.y),
+    )
+    .flatten()
+    .copied()
+    .collect();
+
+    draw_image(layer, user, &result, &rect, 1.0, Blendmode::Replace)
+}
+
+/// Read the pixels of a rectangle that is inside the layer
+pub(super) fn read_pixels(layer: &Layer, rect: &Rectangle) -> Vec<Pixel> {
+    let mut pixels = vec![ZERO_PIXEL; (rect.w * rect.h) as usize];
+
+    for j in rect.y / TILE_SIZEI..=rect.bottom() / TILE_SIZEI {
+        for i in rect.x / TILE_SIZEI..=rect.right() / TILE_SIZEI {
+            let tilerect = Rectangle::tile(i, j, TILE_SIZEI);
+            let subrect = tilerect.intersected(rect).unwrap();
+            let tile = layer.tile(i as u32, j as u32);
+
+            for (row, y) in tile
+                .rect_iter(&subrect.offset(-tilerect.x, -tilerect.y))
+                .zip(subrect.y..)
+            {
+                let start = ((y - rect.y) * rect.w + subrect.x - rect.x) as usize;
+                pixels[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+    }
+
+    pixels
+}
+
 /// Replace a tile or a stretch of tiles.
 /// This is typically used to set the initial canvas content
 /// at the start of a session.
@@ -230,6 +400,81 @@ pub fn put_tile(
     }
 }
 
+/// Limit the changes made to a layer to the selected area
+///
+/// The editing functions in this module change everything inside their
+/// rectangle. To constrain an edit to a selection, take a (cheap, copy-on-write)
+/// copy of the layer before editing and pass it to this function afterwards.
+/// Changed tiles outside the selection are restored from the copy and partially
+/// selected tiles are blended between the two using the selection mask.
+/// Sublayers are clipped too.
+///
+/// Returns the area of effect limited to the selection.
+pub fn clip_to_selection(
+    layer: &mut Layer,
+    original: &Layer,
+    selection: &Selection,
+    aoe: AoE,
+) -> AoE {
+    assert_eq!(layer.size(), original.size());
+
+    clip_tiles(layer, original, selection);
+
+    let sublayer_ids: Vec<LayerID> = layer.iter_sublayers().map(|sl| sl.id).collect();
+    for id in sublayer_ids {
+        let blank;
+        let original_sublayer = match original.iter_sublayers().find(|sl| sl.id == id) {
+            Some(sl) => sl,
+            None => {
+                blank = Layer::new(id, original.width(), original.height(), &Color::TRANSPARENT);
+                &blank
+            }
+        };
+        clip_tiles(
+            layer.get_or_create_sublayer(id),
+            original_sublayer,
+            selection,
+        );
+    }
+
+    match (aoe, selection.bounds()) {
+        (_, None) => AoE::Nothing,
+        (AoE::Bounds(r), Some(b)) => r.intersected(b).map_or(AoE::Nothing, AoE::Bounds),
+        (aoe, _) => aoe,
+    }
+}
+
+fn clip_tiles(layer: &mut Layer, original: &Layer, selection: &Selection) {
+    if std::ptr::eq(layer.tilevec(), original.tilevec()) {
+        return;
+    }
+
+    let xtiles = Tile::div_up(layer.width()) as usize;
+    let original_tiles = original.tilevec();
+
+    for (idx, (tile, orig)) in layer
+        .tilevec_mut()
+        .iter_mut()
+        .zip(original_tiles.iter())
+        .enumerate()
+    {
+        if tile.ptr_eq(orig) {
+            continue;
+        }
+
+        let rect = Rectangle::tile((idx % xtiles) as i32, (idx / xtiles) as i32, TILE_SIZEI);
+        if selection.excludes(&rect) {
+            *tile = orig.clone();
+        } else if let Some(mask) = selection.mask_for(&rect) {
+            let edited = tile.clone_data();
+            let mut pixels = orig.clone_data().pixels;
+            rasterop::mask_interpolate(&mut pixels, &edited.pixels, &mask);
+            *tile = Tile::from_data(&pixels, edited.last_touched_by);
+            tile.optimize();
+        }
+    }
+}
+
 /// Merge another layer to this one
 ///
 /// The other layer's opacity and blending mode are used.
@@ -242,10 +487,29 @@ pub fn merge(target_layer: &mut Layer, source_layer: &Layer) -> AoE {
     let source_tiles = source_layer.tilevec();
 
     // TODO this is parallelizable
-    target_tiles
-        .iter_mut()
-        .zip(source_tiles.iter())
-        .for_each(|(d, s)| d.merge(s, source_layer.opacity, source_layer.blendmode));
+    if let Some(mask) = source_layer.mask() {
+        let xtiles = Tile::div_up(source_layer.width()) as usize;
+        target_tiles
+            .iter_mut()
+            .zip(source_tiles.iter())
+            .enumerate()
+            .for_each(|(idx, (d, s))| {
+                if let Tile::Bitmap(td) = s {
+                    let mut masked = td.as_ref().clone();
+                    mask.apply((idx % xtiles) as u32, (idx / xtiles) as u32, &mut masked);
+                    d.merge(
+                        &Tile::Bitmap(Arc::new(masked)),
+                        source_layer.opacity,
+                        source_layer.blendmode,
+                    );
+                }
+            });
+    } else {
+        target_tiles
+            .iter_mut()
+            .zip(source_tiles.iter())
+            .for_each(|(d, s)| d.merge(s, source_layer.opacity, source_layer.blendmode));
+    }
 
     if source_layer.is_visible() {
         source_layer.nonblank_tilemap().into()
@@ -272,25 +536,32 @@ pub fn remove_sublayer(layer: &mut Layer, sublayer_id: LayerID) -> AoE {
     }
 }
 
-pub fn change_attributes(
-    layer: &mut Layer,
-    sublayer: LayerID,
-    opacity: f32,
-    blend: Blendmode,
-    censored: bool,
-    fixed: bool,
-) -> AoE {
+/// Layer attributes that can be changed with `change_attributes`
+pub struct LayerAttributes {
+    pub opacity: f32,
+    pub blendmode: Blendmode,
+    pub censored: bool,
+    pub fixed: bool,
+    pub passthrough: bool,
+    pub clip: bool,
+}
+
+/// Change the attributes of a layer or a sublayer.
+/// The flags are ignored for sublayers.
+pub fn change_attributes(layer: &mut Layer, sublayer: LayerID, attrs: &LayerAttributes) -> AoE {
     if sublayer != 0 {
         let sl = layer.get_or_create_sublayer(sublayer);
-        sl.blendmode = blend;
-        sl.opacity = opacity;
+        sl.blendmode = attrs.blendmode;
+        sl.opacity = attrs.opacity;
 
         sl.nonblank_tilemap().into()
     } else {
-        layer.blendmode = blend;
-        layer.opacity = opacity;
-        layer.censored = censored;
-        layer.fixed = fixed;
+        layer.blendmode = attrs.blendmode;
+        layer.opacity = attrs.opacity;
+        layer.censored = attrs.censored;
+        layer.fixed = attrs.fixed;
+        layer.passthrough = attrs.passthrough;
+        layer.clip = attrs.clip;
 
         layer.nonblank_tilemap().into()
     }
@@ -380,4 +651,106 @@ mod tests {
 
         assert_eq!(btm.pixel_at(0, 0), Color::rgb8(127, 0, 0).as_pixel());
     }
+
+    #[test]
+    fn test_clip_to_selection() {
+        let mut layer = Layer::new(0, 200, 200, &Color::TRANSPARENT);
+        let sel = Selection::polygon(
+            Rectangle::new(0, 0, 200, 200),
+            vec![(10, 10), (100, 10), (100, 100), (10, 100)],
+            200,
+            200,
+        );
+
+        let original = layer.clone();
+        let aoe = fill_rect(
+            &mut layer,
+            0,
+            &Color::from_pixel(WHITE_PIXEL),
+            Blendmode::Normal,
+            &Rectangle::new(0, 0, 200, 200),
+        );
+        let aoe = clip_to_selection(&mut layer, &original, &sel, aoe);
+
+        assert_eq!(aoe, AoE::Bounds(Rectangle::new(10, 10, 90, 90)));
+        assert_eq!(layer.pixel_at(9, 10), ZERO_PIXEL);
+        assert_eq!(layer.pixel_at(10, 10), WHITE_PIXEL);
+        assert_eq!(layer.pixel_at(99, 99), WHITE_PIXEL);
+        assert_eq!(layer.pixel_at(100, 99), ZERO_PIXEL);
+        assert_eq!(layer.pixel_at(150, 150), ZERO_PIXEL);
+        assert_eq!(*layer.tile(3, 3), Tile::Blank);
+
+        // Indirect drawing to a new sublayer is clipped as well
+        let original = layer.clone();
+        let brush = BrushMask::new_square_pixel(20, 1.0);
+        draw_brush_dab(
+            layer.get_or_create_sublayer(1),
+            0,
+            90,
+            90,
+            &brush,
+            &Color::rgb8(255, 0, 0),
+            Blendmode::Normal,
+        );
+        clip_to_selection(&mut layer, &original, &sel, AoE::Nothing);
+        let sublayer = layer.iter_sublayers().next().unwrap();
+        assert_eq!(sublayer.pixel_at(99, 99), Color::rgb8(255, 0, 0).as_pixel());
+        assert_eq!(sublayer.pixel_at(100, 100), ZERO_PIXEL);
+    }
+
+    #[test]
+    fn test_transform_region() {
+        let mut layer = Layer::new(0, 200, 200, &Color::TRANSPARENT);
+        let red = Color::rgb8(255, 0, 0);
+        fill_rect(
+            &mut layer,
+            0,
+            &red,
+            Blendmode::Normal,
+            &Rectangle::new(10, 10, 20, 10),
+        );
+
+        // Lift a triangle: only the lower left half is cut out
+        let source = [(10, 10), (30, 10), (30, 20), (10, 20)];
+        let (rect, pixels) =
+            lift_region(&mut layer, 0, &[(10, 10), (30, 20), (10, 20), (10, 20)]).unwrap();
+        assert_eq!(rect, Rectangle::new(10, 10, 20, 10));
+        assert_eq!(layer.pixel_at(11, 18), ZERO_PIXEL);
+        assert_eq!(layer.pixel_at(28, 11), red.as_pixel());
+        assert_eq!(pixels[(8 * 20 + 1) as usize], red.as_pixel());
+        assert_eq!(pixels[(20 + 18) as usize], ZERO_PIXEL);
+
+        // Move and scale it to double size
+        let target = [(100, 100), (140, 100), (140, 120), (100, 120)];
+        let aoe = draw_transformed(
+            &mut layer,
+            0,
+            &pixels,
+            &rect,
+            &source,
+            &target,
+            Interpolation::Nearest,
+        );
+        assert_eq!(aoe, AoE::Bounds(Rectangle::new(100, 100, 40, 20)));
+        assert_eq!(layer.pixel_at(102, 117), red.as_pixel());
+        assert_eq!(layer.pixel_at(137, 102), ZERO_PIXEL);
+        assert_eq!(layer.pixel_at(99, 117), ZERO_PIXEL);
+
+        // Quads completely outside the layer
+        assert!(
+            lift_region(&mut layer, 0, &[(-10, -10), (-5, -10), (-5, -5), (-10, -5)]).is_none()
+        );
+        assert_eq!(
+            draw_transformed(
+                &mut layer,
+                0,
+                &pixels,
+                &rect,
+                &source,
+                &[(300, 300), (310, 300), (310, 310), (300, 310)],
+                Interpolation::Bilinear,
+            ),
+            AoE::Nothing
+        );
+    }
 }
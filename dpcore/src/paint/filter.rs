@@ -0,0 +1,653 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::color::{Pixel, ALPHA_CHANNEL, BLUE_CHANNEL, GREEN_CHANNEL, RED_CHANNEL, ZERO_PIXEL};
+
+/// An image filter that can be applied to a region of a layer
+///
+/// Filters are run by every client, so they must produce identical pixels
+/// everywhere. All filters use integer and fixed point arithmetic only,
+/// since floating point functions like `powf` may give slightly different
+/// results on different platforms.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Box blur with the given radius
+    BoxBlur(u32),
+
+    /// Gaussian blur with the given radius, approximated with three box blur passes
+    GaussianBlur(u32),
+
+    /// Sharpen by adding the difference between the image and its blurred version.
+    ///
+    /// Amount is a percentage. Differences smaller than the threshold are ignored.
+    UnsharpMask {
+        radius: u32,
+        amount: u32,
+        threshold: u8,
+    },
+
+    /// Brightness (-255..255) and contrast (-100..100) adjustment
+    BrightnessContrast { brightness: i32, contrast: i32 },
+
+    /// Hue rotation (in degrees) and saturation and lightness (-100..100) adjustment
+    HueSaturation {
+        hue: i32,
+        saturation: i32,
+        lightness: i32,
+    },
+
+    /// Map the input range to the output range with gamma correction.
+    ///
+    /// Gamma is given in hundredths (100 = linear.)
+    Levels {
+        in_black: u8,
+        in_white: u8,
+        gamma: u32,
+        out_black: u8,
+        out_white: u8,
+    },
+
+    /// Invert colors
+    Invert,
+}
+
+impl Filter {
+    /// How far outside the filtered area this filter samples pixels
+    pub fn margin(&self) -> u32 {
+        match self {
+            Filter::BoxBlur(r) | Filter::GaussianBlur(r) => *r,
+            Filter::UnsharpMask { radius, .. } => *radius,
+            _ => 0,
+        }
+    }
+
+    /// Apply the filter to an image in place.
+    ///
+    /// Pixels outside the image are treated as copies of the nearest edge pixel.
+    /// The pixels within `margin()` of the image edges (that are not also
+    /// edges of the canvas) should be discarded.
+    pub fn apply(&self, image: &mut [Pixel], width: usize, height: usize) {
+        assert_eq!(image.len(), width * height);
+        if image.is_empty() {
+            return;
+        }
+
+        match self {
+            Filter::BoxBlur(r) => box_blur(image, width, height, *r as usize),
+            Filter::GaussianBlur(r) => gaussian_blur(image, width, height, *r as usize),
+            Filter::UnsharpMask {
+                radius,
+                amount,
+                threshold,
+            } => unsharp_mask(image, width, height, *radius as usize, *amount, *threshold),
+            Filter::BrightnessContrast {
+                brightness,
+                contrast,
+            } => map_channels(image, &brightness_contrast_lut(*brightness, *contrast)),
+            Filter::HueSaturation {
+                hue,
+                saturation,
+                lightness,
+            } => {
+                let hue = hue.rem_euclid(360);
+                let saturation = (*saturation).clamp(-100, 100);
+                let lightness = (*lightness).clamp(-100, 100);
+                map_colors(image, |c| adjust_hsl(c, hue, saturation, lightness));
+            }
+            Filter::Levels {
+                in_black,
+                in_white,
+                gamma,
+                out_black,
+                out_white,
+            } => map_channels(
+                image,
+                &levels_lut(*in_black, *in_white, *gamma, *out_black, *out_white),
+            ),
+            Filter::Invert => {
+                // Pixels that aren't properly premultiplied may have
+                // color values greater than alpha
+                for p in image.iter_mut() {
+                    let a = p[ALPHA_CHANNEL];
+                    p[RED_CHANNEL] = a.saturating_sub(p[RED_CHANNEL]);
+                    p[GREEN_CHANNEL] = a.saturating_sub(p[GREEN_CHANNEL]);
+                    p[BLUE_CHANNEL] = a.saturating_sub(p[BLUE_CHANNEL]);
+                }
+            }
+        }
+    }
+}
+
+/// Blur one row or column of pixels.
+///
+/// The line starts at `offset` and consecutive pixels are `stride` apart.
+fn blur_line(
+    src: &[Pixel],
+    dest: &mut [Pixel],
+    offset: usize,
+    stride: usize,
+    len: usize,
+    radius: usize,
+) {
+    let at = |i: isize| -> &Pixel {
+        let i = i.clamp(0, len as isize - 1) as usize;
+        &src[offset + i * stride]
+    };
+
+    let r = radius as isize;
+    let div = radius as u32 * 2 + 1;
+    let mut sum = [0u32; 4];
+    for i in -r..=r {
+        for (s, &v) in sum.iter_mut().zip(at(i).iter()) {
+            *s += v as u32;
+        }
+    }
+
+    for i in 0..len as isize {
+        let d = &mut dest[offset + i as usize * stride];
+        for (dc, s) in d.iter_mut().zip(sum.iter()) {
+            *dc = ((s + div / 2) / div) as u8;
+        }
+
+        let (old, new) = (at(i - r), at(i + r + 1));
+        for ((s, &o), &n) in sum.iter_mut().zip(old.iter()).zip(new.iter()) {
+            *s = *s - o as u32 + n as u32;
+        }
+    }
+}
+
+fn box_blur(image: &mut [Pixel], width: usize, height: usize, radius: usize) {
+    if radius == 0 {
+        return;
+    }
+
+    let mut tmp = vec![ZERO_PIXEL; image.len()];
+    for y in 0..height {
+        blur_line(image, &mut tmp, y * width, 1, width, radius);
+    }
+    for x in 0..width {
+        blur_line(&tmp, image, x, width, height, radius);
+    }
+}
+
+fn gaussian_blur(image: &mut [Pixel], width: usize, height: usize, radius: usize) {
+    // Three box blur passes whose radii sum up to the total radius
+    for pass in 0..3 {
+        box_blur(
+            image,
+            width,
+            height,
+            radius / 3 + (radius % 3 > pass) as usize,
+        );
+    }
+}
+
+fn unsharp_mask(
+    image: &mut [Pixel],
+    width: usize,
+    height: usize,
+    radius: usize,
+    amount: u32,
+    threshold: u8,
+) {
+    let mut blurred = image.to_vec();
+    gaussian_blur(&mut blurred, width, height, radius);
+
+    let amount = amount as i32;
+    let threshold = threshold as i32;
+
+    for (p, b) in image.iter_mut().zip(blurred.iter()) {
+        let a = p[ALPHA_CHANNEL] as i32;
+        for c in &[RED_CHANNEL, GREEN_CHANNEL, BLUE_CHANNEL] {
+            let v = p[*c] as i32;
+            let diff = v - b[*c] as i32;
+            if diff.abs() > threshold {
+                p[*c] = (v + diff * amount / 100).clamp(0, a) as u8;
+            }
+        }
+    }
+}
+
+/// Apply a function to the unpremultiplied color of each pixel.
+///
+/// The function receives and returns the color channels in pixel order (BGR).
+fn map_colors<F>(image: &mut [Pixel], f: F)
+where
+    F: Fn([u8; 3]) -> [u8; 3],
+{
+    for p in image.iter_mut() {
+        let a = p[ALPHA_CHANNEL] as u32;
+        if a == 0 {
+            continue;
+        }
+
+        let unpremultiply = |v: u8| ((v as u32 * 255 + a / 2) / a).min(255) as u8;
+        let c = f([
+            unpremultiply(p[BLUE_CHANNEL]),
+            unpremultiply(p[GREEN_CHANNEL]),
+            unpremultiply(p[RED_CHANNEL]),
+        ]);
+
+        p[BLUE_CHANNEL] = ((c[0] as u32 * a + 127) / 255) as u8;
+        p[GREEN_CHANNEL] = ((c[1] as u32 * a + 127) / 255) as u8;
+        p[RED_CHANNEL] = ((c[2] as u32 * a + 127) / 255) as u8;
+    }
+}
+
+/// Map each color channel through a lookup table
+fn map_channels(image: &mut [Pixel], lut: &[u8; 256]) {
+    map_colors(image, |c| {
+        [lut[c[0] as usize], lut[c[1] as usize], lut[c[2] as usize]]
+    });
+}
+
+fn brightness_contrast_lut(brightness: i32, contrast: i32) -> [u8; 256] {
+    let brightness = brightness.clamp(-255, 255);
+    let contrast = contrast.clamp(-100, 100);
+
+    let mut lut = [0; 256];
+    for (i, v) in lut.iter_mut().enumerate() {
+        let c = (i as i32 - 128) * (100 + contrast) / 100 + 128 + brightness;
+        *v = c.clamp(0, 255) as u8;
+    }
+    lut
+}
+
+fn levels_lut(in_black: u8, in_white: u8, gamma: u32, out_black: u8, out_white: u8) -> [u8; 256] {
+    let in_black = in_black as u64;
+    let in_white = (in_white as u64).max(in_black + 1);
+    let out_black = out_black as i64;
+    let out_range = out_white as i64 - out_black;
+    let gamma = Gamma::new(gamma);
+
+    let mut lut = [0; 256];
+    for (i, v) in lut.iter_mut().enumerate() {
+        let t =
+            ((i as u64).clamp(in_black, in_white) - in_black) * FIXED_ONE / (in_white - in_black);
+        let c = ((out_black << FIXED_BITS) + out_range * gamma.apply(t) as i64 + FIXED_HALF)
+            >> FIXED_BITS;
+        *v = c.clamp(0, 255) as u8;
+    }
+    lut
+}
+
+/// Fraction bits of the fixed point numbers used by the gamma curve
+const FIXED_BITS: u32 = 32;
+const FIXED_ONE: u64 = 1 << FIXED_BITS;
+const FIXED_HALF: i64 = 1 << (FIXED_BITS - 1);
+
+/// A gamma curve `t^(100/gamma)` computed in fixed point
+///
+/// The power is computed as `2^(log2(t) * 100 / gamma)`, with both the
+/// logarithm and the exponential calculated bit by bit.
+struct Gamma {
+    gamma: u32,
+    /// 2^(2^-i) for i in 1..=FIXED_BITS
+    roots: [u128; FIXED_BITS as usize],
+}
+
+impl Gamma {
+    fn new(gamma: u32) -> Self {
+        let mut roots = [0; FIXED_BITS as usize];
+        let mut root = 2 * FIXED_ONE as u128;
+        for r in roots.iter_mut() {
+            root = (root << FIXED_BITS).isqrt();
+            *r = root;
+        }
+
+        Gamma {
+            gamma: gamma.max(1),
+            roots,
+        }
+    }
+
+    /// Apply the curve to a value in the range 0..=FIXED_ONE
+    fn apply(&self, t: u64) -> u64 {
+        if t == 0 || t >= FIXED_ONE || self.gamma == 100 {
+            return t.min(FIXED_ONE);
+        }
+        let exponent = log2_fixed(t) as i128 * 100 / self.gamma as i128;
+        self.exp2(exponent)
+    }
+
+    /// 2^e for e <= 0
+    fn exp2(&self, e: i128) -> u64 {
+        // Split e into an integer part -k and a fraction part 0 <= f < 1
+        let k = (-e + FIXED_ONE as i128 - 1) >> FIXED_BITS;
+        if k >= 64 {
+            return 0;
+        }
+        let f = (e + (k << FIXED_BITS)) as u64;
+
+        let mut result = FIXED_ONE as u128;
+        for (i, root) in self.roots.iter().enumerate() {
+            if f & (1 << (FIXED_BITS as usize - 1 - i)) != 0 {
+                result = (result * root) >> FIXED_BITS;
+            }
+        }
+        (result >> k) as u64
+    }
+}
+
+/// log2(x) for 0 < x < 1
+fn log2_fixed(x: u64) -> i64 {
+    // Normalize x to the range 1..2
+    let shift = x.leading_zeros() as i64 - (63 - FIXED_BITS as i64);
+    let mut y = (x as u128) << shift;
+
+    let mut fraction = 0i64;
+    for i in 1..=FIXED_BITS {
+        y = (y * y) >> FIXED_BITS;
+        if y >= 2 * FIXED_ONE as u128 {
+            y >>= 1;
+            fraction |= 1 << (FIXED_BITS - i);
+        }
+    }
+
+    fraction - (shift << FIXED_BITS)
+}
+
+/// Fixed point 1.0 for the HSL adjustment.
+/// Divisible by 510 (the range of max+min) and 60*1024 (one hue sector.)
+const HSL_ONE: i64 = 510 * HSL_SECTOR;
+/// Hues are expressed in 1/1024 degrees
+const HSL_SECTOR: i64 = 60 * 1024;
+
+/// Adjust a BGR color in the HSL color space
+///
+/// Saturation and lightness changes are percentages (-100..100) and
+/// the hue is rotated by the given number of degrees.
+fn adjust_hsl(c: [u8; 3], hue: i32, saturation: i32, lightness: i32) -> [u8; 3] {
+    let b = c[0] as i64;
+    let g = c[1] as i64;
+    let r = c[2] as i64;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let d = max - min;
+    let mut l = (max + min) * (HSL_ONE / 510);
+
+    let (mut h, mut s) = if d == 0 {
+        (0, 0)
+    } else {
+        let s = d * HSL_ONE / (255 - (max + min - 255).abs());
+        let h = if max == r {
+            ((g - b) * HSL_SECTOR / d).rem_euclid(6 * HSL_SECTOR)
+        } else if max == g {
+            (b - r) * HSL_SECTOR / d + 2 * HSL_SECTOR
+        } else {
+            (r - g) * HSL_SECTOR / d + 4 * HSL_SECTOR
+        };
+        (h, s)
+    };
+
+    h = (h + hue as i64 * 1024).rem_euclid(6 * HSL_SECTOR);
+    s = (s * (100 + saturation as i64) / 100).clamp(0, HSL_ONE);
+    l = if lightness > 0 {
+        l + (HSL_ONE - l) * lightness as i64 / 100
+    } else {
+        l * (100 + lightness as i64) / 100
+    };
+
+    let chroma = (HSL_ONE - (2 * l - HSL_ONE).abs()) * s / HSL_ONE;
+    let hp2 = (h % (2 * HSL_SECTOR)) * (HSL_ONE / HSL_SECTOR);
+    let x = chroma * (HSL_ONE - (hp2 - HSL_ONE).abs()) / HSL_ONE;
+    let (r, g, b) = match h / HSL_SECTOR {
+        0 => (chroma, x, 0),
+        1 => (x, chroma, 0),
+        2 => (0, chroma, x),
+        3 => (0, x, chroma),
+        4 => (x, 0, chroma),
+        _ => (chroma, 0, x),
+    };
+
+    // (v + m) * 255 rounded, where m = l - chroma / 2
+    let to_u8 = |v: i64| {
+        ((2 * v + 2 * l - chroma) * 255 + HSL_ONE)
+            .div_euclid(2 * HSL_ONE)
+            .clamp(0, 255) as u8
+    };
+    [to_u8(b), to_u8(g), to_u8(r)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_box_blur() {
+        let mut image = vec![ZERO_PIXEL; 25];
+        image[12] = [90, 90, 90, 90];
+        Filter::BoxBlur(1).apply(&mut image, 5, 5);
+
+        for y in 0..5 {
+            for x in 0..5 {
+                let expected = if (1..4).contains(&x) && (1..4).contains(&y) {
+                    [10, 10, 10, 10]
+                } else {
+                    ZERO_PIXEL
+                };
+                assert_eq!(image[y * 5 + x], expected, "at {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_uniform_blur() {
+        // Blurring a uniform image (with edge pixels extended) changes nothing
+        let p = [10, 20, 30, 200];
+        for f in &[
+            Filter::BoxBlur(3),
+            Filter::GaussianBlur(5),
+            Filter::UnsharpMask {
+                radius: 4,
+                amount: 150,
+                threshold: 0,
+            },
+        ] {
+            let mut image = vec![p; 64];
+            f.apply(&mut image, 8, 8);
+            assert!(image.iter().all(|&q| q == p), "{:?}", f);
+        }
+    }
+
+    #[test]
+    fn test_unsharp_mask() {
+        let mut image = vec![[50, 50, 50, 255]; 10];
+        for p in &mut image[5..] {
+            *p = [150, 150, 150, 255];
+        }
+        Filter::UnsharpMask {
+            radius: 2,
+            amount: 100,
+            threshold: 0,
+        }
+        .apply(&mut image, 10, 1);
+
+        // Edge contrast is increased
+        assert!(image[4][0] < 50);
+        assert!(image[5][0] > 150);
+        assert_eq!(image[0], [50, 50, 50, 255]);
+        assert_eq!(image[9], [150, 150, 150, 255]);
+    }
+
+    #[test]
+    fn test_color_adjustments() {
+        let image = vec![[0, 128, 255, 255], [0, 64, 128, 128], ZERO_PIXEL];
+
+        let mut inverted = image.clone();
+        Filter::Invert.apply(&mut inverted, 3, 1);
+        assert_eq!(
+            inverted,
+            vec![[255, 127, 0, 255], [128, 64, 0, 128], ZERO_PIXEL]
+        );
+
+        let mut invalid = vec![[200, 10, 255, 100]];
+        Filter::Invert.apply(&mut invalid, 1, 1);
+        assert_eq!(invalid, vec![[0, 90, 0, 100]]);
+
+        let mut identity = image.clone();
+        Filter::Levels {
+            in_black: 0,
+            in_white: 255,
+            gamma: 100,
+            out_black: 0,
+            out_white: 255,
+        }
+        .apply(&mut identity, 3, 1);
+        assert_eq!(identity, image);
+
+        let mut identity = image.clone();
+        Filter::HueSaturation {
+            hue: 360,
+            saturation: 0,
+            lightness: 0,
+        }
+        .apply(&mut identity, 3, 1);
+        assert_eq!(identity, image);
+
+        let mut brighter = image.clone();
+        Filter::BrightnessContrast {
+            brightness: 10,
+            contrast: 0,
+        }
+        .apply(&mut brighter, 3, 1);
+        assert_eq!(brighter[0], [10, 138, 255, 255]);
+
+        let mut gray = image.clone();
+        Filter::HueSaturation {
+            hue: 0,
+            saturation: -100,
+            lightness: 0,
+        }
+        .apply(&mut gray, 3, 1);
+        assert_eq!(gray[0], [128, 128, 128, 255]);
+
+        // Hue rotation of pure red by 120 degrees gives pure green
+        let mut red = vec![[0, 0, 255, 255]];
+        Filter::HueSaturation {
+            hue: 120,
+            saturation: 0,
+            lightness: 0,
+        }
+        .apply(&mut red, 1, 1);
+        assert_eq!(red[0], [0, 255, 0, 255]);
+
+        // Gamma curves
+        let mut curved = vec![[64, 128, 255, 255]];
+        Filter::Levels {
+            in_black: 0,
+            in_white: 255,
+            gamma: 200,
+            out_black: 0,
+            out_white: 255,
+        }
+        .apply(&mut curved, 1, 1);
+        assert_eq!(curved[0], [128, 181, 255, 255]);
+
+        let mut curved = vec![[64, 128, 255, 255]];
+        Filter::Levels {
+            in_black: 0,
+            in_white: 255,
+            gamma: 50,
+            out_black: 0,
+            out_white: 255,
+        }
+        .apply(&mut curved, 1, 1);
+        assert_eq!(curved[0], [16, 64, 255, 255]);
+    }
+
+    /// A floating point implementation of the HSL adjustment to compare against
+    fn reference_hsl(c: [u8; 3], hue: f64, saturation: f64, lightness: f64) -> [f64; 3] {
+        let (b, g, r) = (
+            c[0] as f64 / 255.0,
+            c[1] as f64 / 255.0,
+            c[2] as f64 / 255.0,
+        );
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let d = max - min;
+        let l = (max + min) / 2.0;
+
+        let (h, s) = if d == 0.0 {
+            (0.0, 0.0)
+        } else {
+            let h = if max == r {
+                ((g - b) / d).rem_euclid(6.0)
+            } else if max == g {
+                (b - r) / d + 2.0
+            } else {
+                (r - g) / d + 4.0
+            };
+            (h * 60.0, d / (1.0 - (2.0 * l - 1.0).abs()))
+        };
+
+        let h = (h + hue).rem_euclid(360.0);
+        let s = (s * (1.0 + saturation)).clamp(0.0, 1.0);
+        let l = if lightness > 0.0 {
+            l + (1.0 - l) * lightness
+        } else {
+            l * (1.0 + lightness)
+        };
+
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let hp = h / 60.0;
+        let x = chroma * (1.0 - (hp.rem_euclid(2.0) - 1.0).abs());
+        let (r, g, b) = match hp as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = l - chroma / 2.0;
+        [(b + m) * 255.0, (g + m) * 255.0, (r + m) * 255.0]
+    }
+
+    #[test]
+    fn test_fixed_point_accuracy() {
+        for &(hue, sat, light) in &[(0, 0, 0), (90, 30, -20), (200, -60, 45), (359, 100, 100)] {
+            for b in (0..=255).step_by(15) {
+                for g in (0..=255).step_by(15) {
+                    for r in (0..=255).step_by(15) {
+                        let c = [b as u8, g as u8, r as u8];
+                        let expected =
+                            reference_hsl(c, hue as f64, sat as f64 / 100.0, light as f64 / 100.0);
+                        let actual = adjust_hsl(c, hue, sat, light);
+                        for (a, e) in actual.iter().zip(expected.iter()) {
+                            assert!((*a as f64 - e).abs() <= 0.51, "{:?}: {:?}", c, actual);
+                        }
+                    }
+                }
+            }
+        }
+
+        for &gamma in &[1, 33, 70, 100, 150, 1000, u32::MAX] {
+            let lut = levels_lut(0, 255, gamma, 0, 255);
+            for (i, &v) in lut.iter().enumerate() {
+                let e = (i as f64 / 255.0).powf(100.0 / gamma as f64) * 255.0;
+                assert!((v as f64 - e).abs() <= 0.51, "gamma {} at {}", gamma, i);
+            }
+        }
+    }
+}
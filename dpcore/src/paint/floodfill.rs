@@ -0,0 +1,528 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::color::{Color, Pixel};
+use super::rasterop;
+use super::rect::Rectangle;
+use super::tile::{Tile, TileData, TILE_SIZE};
+use super::{Blendmode, Layer, LayerStack, UserID};
+use crate::canvas::compression::put_image;
+use crate::protocol::message::CommandMessage;
+
+/// Where the flood fill samples the colors from
+#[derive(Clone, Copy)]
+pub enum FillSource<'a> {
+    /// A single layer
+    Layer(&'a Layer),
+    /// The merged image of the whole layer stack
+    Merged(&'a LayerStack),
+}
+
+/// Flood fill parameters
+#[derive(Clone, Debug)]
+pub struct FloodFill {
+    /// Seed point X coordinate
+    pub x: i32,
+    /// Seed point Y coordinate
+    pub y: i32,
+    /// Maximum difference of any color channel from the seed point's color
+    pub tolerance: u8,
+    /// Grow the filled area by this many pixels
+    pub expansion: u32,
+    /// Soften the edges of the filled area by this radius
+    pub feather: u32,
+}
+
+/// The area covered by a flood fill
+///
+/// The mask contains the fill opacity of each pixel in the rectangle.
+pub struct FillMask {
+    pub rect: Rectangle,
+    pub mask: Vec<u8>,
+}
+
+impl FillMask {
+    /// Get the image to draw: the given color masked with the fill mask.
+    ///
+    /// The returned pixels are premultiplied, so the image can be drawn
+    /// with `editlayer::draw_image` or sent in a PutImage message.
+    pub fn to_image(&self, color: &Color) -> Vec<Pixel> {
+        let mut image = vec![color.as_pixel(); self.mask.len()];
+        rasterop::apply_mask(&mut image, &self.mask);
+        image
+    }
+
+    /// Make the PutImage messages that draw this fill on a layer.
+    ///
+    /// Large fills are split into several messages so that each
+    /// fits the message length limit.
+    pub fn to_putimages(
+        &self,
+        user: UserID,
+        layer: u16,
+        color: &Color,
+        mode: Blendmode,
+    ) -> Vec<CommandMessage> {
+        let mut msgs = Vec::new();
+        put_image(
+            &mut msgs,
+            user,
+            layer,
+            mode,
+            &self.rect,
+            &self.to_image(color),
+        );
+        msgs
+    }
+}
+
+impl FloodFill {
+    /// Find the area to fill.
+    ///
+    /// Returns None if the seed point is outside the canvas.
+    pub fn mask(&self, source: FillSource) -> Option<FillMask> {
+        let (width, height) = match source {
+            FillSource::Layer(l) => l.size(),
+            FillSource::Merged(ls) => (ls.width(), ls.height()),
+        };
+
+        if self.x < 0 || self.y < 0 || self.x >= width as i32 || self.y >= height as i32 {
+            return None;
+        }
+
+        let mut sampler = Sampler::new(source, width, height);
+        let (filled, bounds) =
+            scanline_fill(&mut sampler, self.x as u32, self.y as u32, self.tolerance);
+
+        // Cut out the filled area along with enough margin for expansion and feathering.
+        // A margin larger than the canvas would be cropped away anyway.
+        let margin = self
+            .expansion
+            .saturating_add(self.feather)
+            .min(width.max(height)) as i32;
+        let rect = Rectangle::new(
+            bounds.x - margin,
+            bounds.y - margin,
+            bounds.w + margin * 2,
+            bounds.h + margin * 2,
+        )
+        .cropped(width, height)?;
+
+        let mut mask = Vec::with_capacity((rect.w * rect.h) as usize);
+        for y in rect.y..rect.y + rect.h {
+            let row = (y as u32 * width) as usize;
+            mask.extend_from_slice(
+                &filled[row + rect.x as usize..row + (rect.x + rect.w) as usize],
+            );
+        }
+
+        let w = rect.w as usize;
+        if self.expansion > 0 {
+            mask = separable_filter(&mask, w, self.expansion as usize, max_filter);
+        }
+        if self.feather > 0 {
+            mask = separable_filter(&mask, w, self.feather as usize, box_filter);
+        }
+
+        Some(FillMask { rect, mask })
+    }
+}
+
+/// Samples pixels from the fill source, flattening merged tiles as needed
+struct Sampler<'a> {
+    source: FillSource<'a>,
+    width: u32,
+    height: u32,
+    xtiles: u32,
+    tiles: Vec<Option<TileData>>,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(source: FillSource<'a>, width: u32, height: u32) -> Self {
+        let xtiles = Tile::div_up(width);
+        let tiles = match source {
+            FillSource::Layer(_) => Vec::new(),
+            FillSource::Merged(_) => vec![None; (xtiles * Tile::div_up(height)) as usize],
+        };
+
+        Sampler {
+            source,
+            width,
+            height,
+            xtiles,
+            tiles,
+        }
+    }
+
+    fn pixel_at(&mut self, x: u32, y: u32) -> Pixel {
+        match self.source {
+            FillSource::Layer(l) => l.pixel_at(x, y),
+            FillSource::Merged(ls) => {
+                let (i, j) = (x / TILE_SIZE, y / TILE_SIZE);
+                let tile = self.tiles[(j * self.xtiles + i) as usize]
+                    .get_or_insert_with(|| ls.flatten_tile(i, j));
+                tile.pixels[((y - j * TILE_SIZE) * TILE_SIZE + (x - i * TILE_SIZE)) as usize]
+            }
+        }
+    }
+}
+
+/// Fill the contiguous area of similar color using a scanline algorithm.
+///
+/// Returns a canvas sized mask (255 where filled) and the bounding rectangle of the filled area.
+fn scanline_fill(sampler: &mut Sampler, x: u32, y: u32, tolerance: u8) -> (Vec<u8>, Rectangle) {
+    let (width, height) = (sampler.width, sampler.height);
+    let seed = sampler.pixel_at(x, y);
+
+    let mut filled = vec![0u8; (width * height) as usize];
+    let (mut x0, mut y0, mut x1, mut y1) = (x, y, x, y);
+
+    let matches = |sampler: &mut Sampler, filled: &[u8], x: u32, y: u32| {
+        filled[(y * width + x) as usize] == 0 && similar(sampler.pixel_at(x, y), seed, tolerance)
+    };
+
+    let mut stack = vec![(x, y)];
+    while let Some((x, y)) = stack.pop() {
+        if !matches(sampler, &filled, x, y) {
+            continue;
+        }
+
+        let mut left = x;
+        while left > 0 && matches(sampler, &filled, left - 1, y) {
+            left -= 1;
+        }
+        let mut right = x;
+        while right + 1 < width && matches(sampler, &filled, right + 1, y) {
+            right += 1;
+        }
+
+        let row = (y * width) as usize;
+        filled[row + left as usize..=row + right as usize]
+            .iter_mut()
+            .for_each(|v| *v = 255);
+
+        x0 = x0.min(left);
+        x1 = x1.max(right);
+        y0 = y0.min(y);
+        y1 = y1.max(y);
+
+        // Queue the start of each matching run on the rows above and below
+        for ny in [y.wrapping_sub(1), y + 1].iter().copied() {
+            if ny >= height {
+                continue;
+            }
+            let mut in_run = false;
+            for nx in left..=right {
+                let m = matches(sampler, &filled, nx, ny);
+                if m && !in_run {
+                    stack.push((nx, ny));
+                }
+                in_run = m;
+            }
+        }
+    }
+
+    (
+        filled,
+        Rectangle::new(
+            x0 as i32,
+            y0 as i32,
+            (x1 - x0 + 1) as i32,
+            (y1 - y0 + 1) as i32,
+        ),
+    )
+}
+
+fn similar(a: Pixel, b: Pixel, tolerance: u8) -> bool {
+    a.iter()
+        .zip(b.iter())
+        .all(|(&a, &b)| (a as i32 - b as i32).abs() <= tolerance as i32)
+}
+
+/// Apply a one dimensional filter horizontally and then vertically.
+/// Samples past the edges are clamped to the edge values.
+fn separable_filter(
+    mask: &[u8],
+    width: usize,
+    radius: usize,
+    filter: fn(&[u8], usize, &mut [u8]),
+) -> Vec<u8> {
+    let height = mask.len() / width;
+    let mut horizontal = vec![0; mask.len()];
+    for (row, out) in mask
+        .chunks_exact(width)
+        .zip(horizontal.chunks_exact_mut(width))
+    {
+        filter(row, radius, out);
+    }
+
+    let mut result = vec![0; mask.len()];
+    let mut column = vec![0; height];
+    let mut out = vec![0; height];
+    for x in 0..width {
+        for (y, v) in column.iter_mut().enumerate() {
+            *v = horizontal[y * width + x];
+        }
+        filter(&column, radius, &mut out);
+        for (y, &v) in out.iter().enumerate() {
+            result[y * width + x] = v;
+        }
+    }
+
+    result
+}
+
+/// The maximum of each window.
+///
+/// Since clamped samples repeat the edge values, the maximum of the window
+/// is the maximum of its part inside the line. The candidates are kept in a
+/// queue of decreasing values, so each sample is visited only a few times.
+fn max_filter(line: &[u8], radius: usize, out: &mut [u8]) {
+    let mut candidates = std::collections::VecDeque::new();
+    let mut next = 0;
+
+    for (x, o) in out.iter_mut().enumerate() {
+        let last = x.saturating_add(radius).min(line.len() - 1);
+        while next <= last {
+            while candidates.back().map_or(false, |&i| line[i] <= line[next]) {
+                candidates.pop_back();
+            }
+            candidates.push_back(next);
+            next += 1;
+        }
+
+        let first = x.saturating_sub(radius);
+        while candidates.front().map_or(false, |&i| i < first) {
+            candidates.pop_front();
+        }
+
+        *o = line[*candidates.front().unwrap()];
+    }
+}
+
+/// The rounded average of each window.
+///
+/// The sums are computed from a running total, so the cost does not
+/// depend on the radius.
+fn box_filter(line: &[u8], radius: usize, out: &mut [u8]) {
+    let mut prefix = Vec::with_capacity(line.len() + 1);
+    prefix.push(0u64);
+    for &v in line {
+        prefix.push(prefix.last().unwrap() + v as u64);
+    }
+
+    let n = line.len() as u64;
+    let r = radius as u64;
+    let len = 2 * r + 1;
+    let first_value = line[0] as u64;
+    let last_value = line[line.len() - 1] as u64;
+
+    for (x, o) in out.iter_mut().enumerate() {
+        let x = x as u64;
+        // Samples before the start and past the end of the line
+        let before = r.saturating_sub(x);
+        let after = (x + r).saturating_sub(n - 1);
+        let lo = x.saturating_sub(r) as usize;
+        let hi = (x + r).min(n - 1) as usize;
+
+        let sum = prefix[hi + 1] - prefix[lo] + before * first_value + after * last_value;
+        *o = ((sum + len / 2) / len) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::editlayer;
+    use crate::paint::Blendmode;
+
+    fn test_layer() -> Layer {
+        // A layer with a black outlined 20x20 box
+        let mut layer = Layer::new(0, 100, 100, &Color::TRANSPARENT);
+        let black = Color::rgb8(0, 0, 0);
+        for r in [
+            Rectangle::new(10, 10, 22, 1),
+            Rectangle::new(10, 31, 22, 1),
+            Rectangle::new(10, 10, 1, 22),
+            Rectangle::new(31, 10, 1, 22),
+        ]
+        .iter()
+        {
+            editlayer::fill_rect(&mut layer, 0, &black, Blendmode::Normal, r);
+        }
+        layer
+    }
+
+    #[test]
+    fn test_fill_inside() {
+        let layer = test_layer();
+        let fill = FloodFill {
+            x: 20,
+            y: 20,
+            tolerance: 0,
+            expansion: 0,
+            feather: 0,
+        };
+
+        let mask = fill.mask(FillSource::Layer(&layer)).unwrap();
+        assert_eq!(mask.rect, Rectangle::new(11, 11, 20, 20));
+        assert!(mask.mask.iter().all(|&v| v == 255));
+
+        // Expanded into the outline
+        let mask = FloodFill {
+            expansion: 1,
+            ..fill.clone()
+        }
+        .mask(FillSource::Layer(&layer))
+        .unwrap();
+        assert_eq!(mask.rect, Rectangle::new(10, 10, 22, 22));
+        assert!(mask.mask.iter().all(|&v| v == 255));
+
+        // Feathered edges
+        let mask = FloodFill {
+            feather: 2,
+            ..fill.clone()
+        }
+        .mask(FillSource::Layer(&layer))
+        .unwrap();
+        assert_eq!(mask.rect, Rectangle::new(9, 9, 24, 24));
+        assert_eq!(mask.mask[0], 10); // one pixel of the 5x5 window is filled
+        assert_eq!(mask.mask[12 * 24 + 12], 255);
+        let edge = mask.mask[12 * 24 + 2];
+        assert!(edge > 0 && edge < 255);
+
+        // Outside the canvas
+        assert!(FloodFill { x: -1, ..fill }
+            .mask(FillSource::Layer(&layer))
+            .is_none());
+    }
+
+    #[test]
+    fn test_huge_radius() {
+        let layer = test_layer();
+        let fill = FloodFill {
+            x: 20,
+            y: 20,
+            tolerance: 0,
+            expansion: u32::MAX,
+            feather: u32::MAX,
+        };
+
+        let mask = fill.mask(FillSource::Layer(&layer)).unwrap();
+        assert_eq!(mask.rect, Rectangle::new(0, 0, 100, 100));
+
+        let mask = FloodFill { feather: 0, ..fill }
+            .mask(FillSource::Layer(&layer))
+            .unwrap();
+        assert!(mask.mask.iter().all(|&v| v == 255));
+    }
+
+    #[test]
+    fn test_line_filters() {
+        let line: Vec<u8> = (0..37u32).map(|i| (i * 97 % 256) as u8).collect();
+        let clamped = |i: isize| line[i.max(0).min(line.len() as isize - 1) as usize];
+        let mut out = vec![0; line.len()];
+
+        for radius in [0, 1, 2, 5, 36, 37, 100].iter().copied() {
+            let window = |x: usize| x as isize - radius as isize..=(x + radius) as isize;
+
+            max_filter(&line, radius, &mut out);
+            for (x, &v) in out.iter().enumerate() {
+                assert_eq!(v, window(x).map(clamped).max().unwrap());
+            }
+
+            box_filter(&line, radius, &mut out);
+            let len = radius * 2 + 1;
+            for (x, &v) in out.iter().enumerate() {
+                let sum: usize = window(x).map(|i| clamped(i) as usize).sum();
+                assert_eq!(v as usize, (sum + len / 2) / len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill_outside() {
+        let layer = test_layer();
+        let mask = FloodFill {
+            x: 0,
+            y: 0,
+            tolerance: 0,
+            expansion: 0,
+            feather: 0,
+        }
+        .mask(FillSource::Layer(&layer))
+        .unwrap();
+
+        assert_eq!(mask.rect, Rectangle::new(0, 0, 100, 100));
+        assert_eq!(mask.mask[0], 255);
+        assert_eq!(mask.mask[10 * 100 + 10], 0);
+        assert_eq!(mask.mask[20 * 100 + 20], 0);
+        assert_eq!(mask.mask[99 * 100 + 99], 255);
+    }
+
+    #[test]
+    fn test_tolerance_and_merged() {
+        let mut stack = LayerStack::new(64, 64);
+        stack.background = Tile::new(&Color::rgb8(255, 255, 255), 0);
+        let layer = stack
+            .add_layer(
+                1,
+                crate::paint::layerstack::LayerFill::Solid(Color::TRANSPARENT),
+                crate::paint::layerstack::LayerInsertion::Top,
+            )
+            .unwrap();
+        editlayer::fill_rect(
+            layer,
+            0,
+            &Color::rgb8(250, 250, 250),
+            Blendmode::Normal,
+            &Rectangle::new(0, 0, 32, 64),
+        );
+
+        let mut fill = FloodFill {
+            x: 40,
+            y: 0,
+            tolerance: 0,
+            expansion: 0,
+            feather: 0,
+        };
+        let merged = FillSource::Merged(&stack);
+        assert_eq!(
+            fill.mask(merged).unwrap().rect,
+            Rectangle::new(32, 0, 32, 64)
+        );
+
+        fill.tolerance = 5;
+        assert_eq!(
+            fill.mask(merged).unwrap().rect,
+            Rectangle::new(0, 0, 64, 64)
+        );
+
+        // The layer alone is transparent on the right
+        assert_eq!(
+            fill.mask(FillSource::Layer(stack.get_layer(1).unwrap()))
+                .unwrap()
+                .rect,
+            Rectangle::new(32, 0, 32, 64)
+        );
+    }
+}
@@ -21,15 +21,16 @@
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::convert::TryFrom;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use super::aoe::{AoE, TileMap};
 use super::blendmode::Blendmode;
 use super::brushmask::BrushMask;
-use super::color::{Color, Pixel};
+use super::color::{Color, Pixel, ALPHA_CHANNEL, ZERO_PIXEL};
+use super::layermask::LayerMask;
 use super::rect::Rectangle;
 use super::rectiter::RectIterator;
-use super::tile::{Tile, TileData, TILE_SIZE, TILE_SIZEI};
+use super::tile::{Tile, TileData, TILE_LENGTH, TILE_SIZE, TILE_SIZEI};
 use super::tileiter::MutableTileIterator;
 use super::LayerID;
 
@@ -39,6 +40,22 @@ use super::LayerID;
 /// These functions return an Area Of Effect value that can be used to notify
 /// layer observers of the changes.
 ///
+/// A clipping layer is masked by the alpha channel of the nearest
+/// non-clipping layer below it, so it is visible only where that layer is.
+///
+/// A layer can also be a group. A group has no pixel content of its own
+/// (its tiles are always blank), instead it composites its child layers.
+/// Normally, the children are first composited together and the result is
+/// blended onto the layers below, but a pass-through group composites its
+/// children directly onto the layers below as if they were not grouped.
+///
+/// A layer may have a mask. The mask is multiplied into the layer's
+/// content (including sublayers or, for groups, the composited children)
+/// before it is blended onto the layers below.
+///
+/// How layers flagged as censored are flattened is decided by the caller
+/// with a `CensorPolicy`.
+///
 #[derive(Clone)]
 pub struct Layer {
     pub id: LayerID,
@@ -48,10 +65,25 @@ pub struct Layer {
     pub censored: bool,
     pub fixed: bool,
     pub blendmode: Blendmode,
+    pub passthrough: bool,
+    pub clip: bool,
     width: u32,
     height: u32,
-    tiles: Rc<Vec<Tile>>,
-    sublayers: Vec<Rc<Layer>>,
+    tiles: Arc<Vec<Tile>>,
+    sublayers: Vec<Arc<Layer>>,
+    children: Option<Arc<Vec<Arc<Layer>>>>,
+    mask: Option<LayerMask>,
+}
+
+/// How censored layers are rendered when flattening
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CensorPolicy {
+    /// Render censored layers normally
+    Show,
+    /// Leave censored layers out
+    Hide,
+    /// Render censored layers as a mosaic with the given block size
+    Pixelate(u32),
 }
 
 impl Layer {
@@ -65,16 +97,67 @@ impl Layer {
             censored: false,
             fixed: false,
             blendmode: Blendmode::Normal,
+            passthrough: false,
+            clip: false,
             width,
             height,
-            tiles: Rc::new(vec![
+            tiles: Arc::new(vec![
                 Tile::new(&fill, 0);
                 (Tile::div_up(width) * Tile::div_up(height)) as usize
             ]),
             sublayers: vec![],
+            children: None,
+            mask: None,
+        }
+    }
+
+    /// Construct a new empty layer group
+    pub fn new_group(id: i32, width: u32, height: u32) -> Layer {
+        Layer {
+            children: Some(Arc::new(Vec::new())),
+            ..Layer::new(id, width, height, &Color::TRANSPARENT)
         }
     }
 
+    /// Is this a layer group
+    pub fn is_group(&self) -> bool {
+        self.children.is_some()
+    }
+
+    /// Iterate through this group's child layers (bottom-most first.)
+    /// If this is not a group, the iterator is empty.
+    pub fn iter_children(&self) -> impl Iterator<Item = &Layer> {
+        self.children
+            .iter()
+            .flat_map(|c| c.iter())
+            .map(|l| l.as_ref())
+    }
+
+    pub(super) fn children(&self) -> Option<&Vec<Arc<Layer>>> {
+        self.children.as_deref()
+    }
+
+    pub(super) fn children_mut(&mut self) -> Option<&mut Vec<Arc<Layer>>> {
+        self.children.as_mut().map(Arc::make_mut)
+    }
+
+    pub fn mask(&self) -> Option<&LayerMask> {
+        self.mask.as_ref()
+    }
+
+    pub fn mask_mut(&mut self) -> Option<&mut LayerMask> {
+        self.mask.as_mut()
+    }
+
+    /// Set (or remove) this layer's mask.
+    /// The mask must be the same size as the layer.
+    pub fn set_mask(&mut self, mask: Option<LayerMask>) {
+        if let Some(m) = &mask {
+            assert_eq!((m.width(), m.height()), self.size());
+        }
+        self.mask = mask;
+    }
+
     /// Build a layer from raw pixel data
     /// This is typically used for scratch layers as a part of some
     /// larger image manipulation process.
@@ -86,7 +169,7 @@ impl Layer {
 
         let imagerect = Rectangle::new(0, 0, width as i32, height as i32);
 
-        let tilevec = Rc::make_mut(&mut layer.tiles);
+        let tilevec = Arc::make_mut(&mut layer.tiles);
         for ty in 0..ytiles {
             for tx in 0..xtiles {
                 let srcrect = Rectangle::new(
@@ -123,23 +206,23 @@ impl Layer {
         assert!(id != 0, "Sublayer ID 0 is not allowed");
 
         if let Some(i) = self.sublayers.iter().position(|sl| sl.id == id) {
-            return Rc::make_mut(&mut self.sublayers[i]);
+            return Arc::make_mut(&mut self.sublayers[i]);
         }
-        self.sublayers.push(Rc::new(Layer::new(
+        self.sublayers.push(Arc::new(Layer::new(
             id,
             self.width,
             self.height,
             &Color::TRANSPARENT,
         )));
         let last = self.sublayers.len() - 1;
-        Rc::make_mut(&mut self.sublayers[last])
+        Arc::make_mut(&mut self.sublayers[last])
     }
 
     /// Find and remove a sublayer with the given ID (if it exists)
     ///
     /// Note: you should not typically need to call this directly.
     /// Instead, use `merge_sublayer` or `remove_sublayer` from `editlayer` module
-    pub fn take_sublayer(&mut self, id: LayerID) -> Option<Rc<Layer>> {
+    pub fn take_sublayer(&mut self, id: LayerID) -> Option<Arc<Layer>> {
         if let Some(i) = self.sublayers.iter().position(|sl| sl.id == id) {
             Some(self.sublayers.remove(i))
         } else {
@@ -152,6 +235,11 @@ impl Layer {
         self.sublayers.iter().any(|sl| sl.id == id)
     }
 
+    /// Iterate through this layer's sublayers
+    pub fn iter_sublayers(&self) -> impl Iterator<Item = &Layer> {
+        self.sublayers.iter().map(|l| l.as_ref())
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
@@ -263,12 +351,20 @@ impl Layer {
     }
 
     /// Return a bitmap of non-blank tiles
+    ///
+    /// For groups, this is the union of the children's non-blank tiles.
     pub fn nonblank_tilemap(&self) -> TileMap {
-        TileMap {
+        let mut map = TileMap {
             tiles: self.tiles.iter().map(|t| *t != Tile::Blank).collect(),
             w: Tile::div_up(self.width),
             h: Tile::div_up(self.height),
+        };
+
+        for child in self.iter_children() {
+            map.tiles |= child.nonblank_tilemap().tiles;
         }
+
+        map
     }
 
     /// Return the tile at the given index
@@ -284,7 +380,7 @@ impl Layer {
         debug_assert!(i * TILE_SIZE < self.width);
         debug_assert!(j * TILE_SIZE < self.height);
         let xtiles = Tile::div_up(self.width);
-        let v = Rc::make_mut(&mut self.tiles);
+        let v = Arc::make_mut(&mut self.tiles);
         &mut v[(j * xtiles + i) as usize]
     }
 
@@ -298,7 +394,7 @@ impl Layer {
     /// You normally shouldn't use this directly. Instead, use the
     /// functions in `editlayer` module.
     pub fn tilevec_mut(&mut self) -> &mut Vec<Tile> {
-        Rc::make_mut(&mut self.tiles)
+        Arc::make_mut(&mut self.tiles)
     }
 
     /// Return a mutable iterator to the tiles that intersect the given
@@ -326,17 +422,81 @@ impl Layer {
         )
     }
 
+    /// Flatten this layer onto the destination tile.
+    ///
+    /// Censored layers (and censored children of groups) are shown as is.
     pub fn flatten_tile(&self, destination: &mut TileData, i: u32, j: u32) {
+        self.flatten_tile_censored(destination, i, j, CensorPolicy::Show);
+    }
+
+    /// Flatten this layer onto the destination tile using the given censor policy
+    pub fn flatten_tile_censored(
+        &self,
+        destination: &mut TileData,
+        i: u32,
+        j: u32,
+        censor: CensorPolicy,
+    ) {
+        self.flatten_tile_masked(destination, i, j, None, censor);
+    }
+
+    /// Flatten this layer, masking its content with the given alpha mask first
+    fn flatten_tile_masked(
+        &self,
+        destination: &mut TileData,
+        i: u32,
+        j: u32,
+        mask: Option<&[u8]>,
+        censor: CensorPolicy,
+    ) {
         if !self.is_visible() {
             return;
         }
 
-        // TODO censor
-        if self.sublayers.is_empty() {
-            // No sublayers: just composite this one as is
-            destination.merge_tile(self.tile(i, j), self.opacity, self.blendmode);
+        let pixelate = match censor {
+            CensorPolicy::Hide if self.censored => return,
+            CensorPolicy::Pixelate(block) if self.censored => Some(block),
+            _ => None,
+        };
+
+        match (&self.children, mask, pixelate) {
+            (Some(children), None, None) if self.passthrough && self.mask.is_none() => {
+                // Children are composited directly onto the layers below.
+                // Group opacity fades between the result and the original.
+                if self.opacity < 1.0 {
+                    let mut tmp = destination.clone();
+                    flatten_layers(children, &mut tmp, i, j, censor);
+                    destination.interpolate(&tmp, self.opacity);
+                } else {
+                    flatten_layers(children, destination, i, j, censor);
+                }
+            }
+            (None, None, None) if self.sublayers.is_empty() && self.mask.is_none() => {
+                // No sublayers: just composite this one as is
+                destination.merge_tile(self.tile(i, j), self.opacity, self.blendmode);
+            }
+            _ => {
+                // Compositing needed
+                let mut tmp = self.content_tile(i, j, censor);
+                if let Some(block) = pixelate {
+                    tmp.pixelate(block);
+                }
+                if let Some(mask) = mask {
+                    tmp.apply_mask(mask);
+                }
+                destination.merge_data(&tmp, self.opacity, self.blendmode);
+            }
+        }
+    }
+
+    /// Composite this layer's content without applying its own opacity and blending mode.
+    /// For groups, this is the composited children. The layer mask is applied to the result.
+    fn content_tile(&self, i: u32, j: u32, censor: CensorPolicy) -> TileData {
+        let mut tmp = if let Some(children) = &self.children {
+            let mut tmp = TileData::new(ZERO_PIXEL, 0);
+            flatten_layers(children, &mut tmp, i, j, censor);
+            tmp
         } else {
-            // Sublayers present: compositing needed
             let mut tmp = self.tile(i, j).clone_data();
             for sublayer in self.sublayers.iter() {
                 if sublayer.is_visible() {
@@ -346,8 +506,33 @@ impl Layer {
 
             // TODO tint, highlight and onionskin
 
-            destination.merge_data(&tmp, self.opacity, self.blendmode);
+            tmp
+        };
+
+        if let Some(mask) = &self.mask {
+            mask.apply(i, j, &mut tmp);
         }
+        tmp
+    }
+
+    /// Get the mask that clipping layers above this one are clipped with
+    fn clip_mask(&self, i: u32, j: u32, censor: CensorPolicy) -> Vec<u8> {
+        if !self.is_visible() || (self.censored && censor == CensorPolicy::Hide) {
+            return vec![0; TILE_LENGTH];
+        }
+
+        let mut content = self.content_tile(i, j, censor);
+        if let CensorPolicy::Pixelate(block) = censor {
+            if self.censored {
+                content.pixelate(block);
+            }
+        }
+
+        content
+            .pixels
+            .iter()
+            .map(|p| (p[ALPHA_CHANNEL] as f32 * self.opacity) as u8)
+            .collect()
     }
 
     /// Call optimize on every tile in the given area.
@@ -362,7 +547,7 @@ impl Layer {
             // entire vector anyway and an optimize call is cheap enough
             // that there's no point to the extra check.
             _ => {
-                Rc::make_mut(&mut self.tiles)
+                Arc::make_mut(&mut self.tiles)
                     .iter_mut()
                     .for_each(|t| t.optimize());
             }
@@ -371,13 +556,41 @@ impl Layer {
 
     /// Do a shallow comparison between these layers and return the difference
     pub fn compare(&self, other: &Layer) -> AoE {
-        if Rc::ptr_eq(&self.tiles, &other.tiles) {
-            return AoE::Nothing;
-        }
         if self.width != other.width || self.height != other.height {
             return AoE::Resize(0, 0);
         }
 
+        let mask_aoe = match (&self.mask, &other.mask) {
+            (None, None) => AoE::Nothing,
+            (Some(a), Some(b)) if a.id == b.id => a.compare(b),
+            _ => AoE::Everything,
+        };
+
+        mask_aoe.merge(self.compare_content(other))
+    }
+
+    fn compare_content(&self, other: &Layer) -> AoE {
+        match (&self.children, &other.children) {
+            (None, None) => (),
+            (Some(a), Some(b)) => {
+                if Arc::ptr_eq(a, b) {
+                    return AoE::Nothing;
+                }
+                if a.len() != b.len() {
+                    return AoE::Everything;
+                }
+                return a
+                    .iter()
+                    .zip(b.iter())
+                    .fold(AoE::Nothing, |aoe, (a, b)| aoe.merge(a.compare(b)));
+            }
+            _ => return AoE::Everything,
+        }
+
+        if Arc::ptr_eq(&self.tiles, &other.tiles) {
+            return AoE::Nothing;
+        }
+
         TileMap {
             tiles: self
                 .tiles
@@ -402,7 +615,7 @@ impl Layer {
 
         let new_tiles = if let Some(c) = self.solid_color() {
             // The fastest case: this layer is filled with solid color
-            Rc::new(vec![
+            Arc::new(vec![
                 Tile::new(&c, 0);
                 (Tile::div_up(new_width) * Tile::div_up(new_height))
                     as usize
@@ -424,18 +637,30 @@ impl Layer {
             sublayers: self
                 .sublayers
                 .iter()
-                .map(|sl| Rc::new(sl.resized(top, right, bottom, left)))
+                .map(|sl| Arc::new(sl.resized(top, right, bottom, left)))
                 .collect(),
+            children: self.children.as_ref().map(|children| {
+                Arc::new(
+                    children
+                        .iter()
+                        .map(|c| Arc::new(c.resized(top, right, bottom, left)))
+                        .collect(),
+                )
+            }),
+            mask: self
+                .mask
+                .as_ref()
+                .map(|m| m.resized(top, right, bottom, left, m.uniform_value().unwrap_or(255))),
             ..*self
         }
     }
 
-    fn resized_slow(&self, offx: i32, offy: i32, w: u32, h: u32) -> Rc<Vec<Tile>> {
+    fn resized_slow(&self, offx: i32, offy: i32, w: u32, h: u32) -> Arc<Vec<Tile>> {
         let oldxtiles = Tile::div_up(self.width) as i32;
         let newxtiles = Tile::div_up(w) as i32;
         let newytiles = Tile::div_up(h) as i32;
-        let mut new_vec = Rc::new(vec![Tile::Blank; (newxtiles * newytiles) as usize]);
-        let tiles = Rc::make_mut(&mut new_vec);
+        let mut new_vec = Arc::new(vec![Tile::Blank; (newxtiles * newytiles) as usize]);
+        let tiles = Arc::make_mut(&mut new_vec);
 
         // Iterate through the original image. Most of the time, the canvas
         // is being expanded so this is the set of tiles we'd iterate over anyway.
@@ -495,19 +720,19 @@ impl Layer {
         new_vec
     }
 
-    fn resized_fast(&self, offx: i32, offy: i32, w: u32, h: u32) -> Rc<Vec<Tile>> {
+    fn resized_fast(&self, offx: i32, offy: i32, w: u32, h: u32) -> Arc<Vec<Tile>> {
         debug_assert!(offx % TILE_SIZEI == 0);
         debug_assert!(offy % TILE_SIZEI == 0);
         let oldxtiles = Tile::div_up(self.width) as i32;
         let oldytiles = Tile::div_up(self.height) as i32;
         let newxtiles = Tile::div_up(w) as i32;
         let newytiles = Tile::div_up(h) as i32;
-        let mut new_vec = Rc::new(vec![Tile::Blank; (newxtiles * newytiles) as usize]);
+        let mut new_vec = Arc::new(vec![Tile::Blank; (newxtiles * newytiles) as usize]);
 
         let xt_off = offx / TILE_SIZEI;
         let yt_off = offy / TILE_SIZEI;
 
-        let tiles = Rc::make_mut(&mut new_vec);
+        let tiles = Arc::make_mut(&mut new_vec);
 
         for y in yt_off.max(0)..newytiles.min(oldytiles + yt_off) {
             let sy = y - yt_off;
@@ -522,7 +747,38 @@ impl Layer {
 
     #[cfg(test)]
     fn refcount(&self) -> usize {
-        Rc::strong_count(&self.tiles) + Rc::weak_count(&self.tiles)
+        Arc::strong_count(&self.tiles) + Arc::weak_count(&self.tiles)
+    }
+}
+
+/// Flatten a list of layers (bottom-most first) onto the destination tile
+///
+/// Clipping layers are masked with the alpha channel of the nearest
+/// non-clipping layer below them. Clipping layers at the bottom of the
+/// list have nothing to clip to and are not drawn.
+pub(super) fn flatten_layers(
+    layers: &[Arc<Layer>],
+    destination: &mut TileData,
+    i: u32,
+    j: u32,
+    censor: CensorPolicy,
+) {
+    let mut clip_mask: Option<Vec<u8>> = None;
+
+    for (idx, layer) in layers.iter().enumerate() {
+        if layer.clip {
+            if let Some(mask) = &clip_mask {
+                layer.flatten_tile_masked(destination, i, j, Some(mask), censor);
+            }
+        } else {
+            layer.flatten_tile_censored(destination, i, j, censor);
+
+            // The mask is needed only if the next layer is clipped to this one
+            clip_mask = match layers.get(idx + 1) {
+                Some(next) if next.clip => Some(layer.clip_mask(i, j, censor)),
+                _ => None,
+            };
+        }
     }
 }
 
@@ -0,0 +1,485 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use super::aoe::{AoE, TileMap};
+use super::brushmask::BrushMask;
+use super::color::{Color, Pixel, BLUE_CHANNEL, GREEN_CHANNEL, RED_CHANNEL};
+use super::rect::Rectangle;
+use super::selection::Selection;
+use super::tile::{Tile, TileData, TILE_LENGTH, TILE_SIZE, TILE_SIZEI};
+use super::tileiter::MutableTileIterator;
+use super::LayerID;
+
+/// A tile of 8 bit layer mask values
+///
+/// Like the pixel tiles, the content is shared until it is modified.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MaskTile {
+    Bitmap(Arc<[u8; TILE_LENGTH]>),
+    /// A tile where every value is the same
+    Uniform(u8),
+}
+
+impl MaskTile {
+    /// Convert an image tile to a mask tile. The luma of each pixel is used as the mask value.
+    pub fn from_tile(tile: &Tile) -> MaskTile {
+        match tile {
+            Tile::Blank => MaskTile::Uniform(0),
+            Tile::Bitmap(td) => {
+                let mut values = [0; TILE_LENGTH];
+                for (v, p) in values.iter_mut().zip(td.pixels.iter()) {
+                    *v = pixel_luma(p);
+                }
+                let mut t = MaskTile::Bitmap(Arc::new(values));
+                t.optimize();
+                t
+            }
+        }
+    }
+
+    /// Convert this mask tile to an opaque grayscale image tile
+    pub fn to_tile(&self) -> Tile {
+        match self {
+            MaskTile::Uniform(v) => Tile::Bitmap(Arc::new(TileData::new([*v, *v, *v, 255], 0))),
+            MaskTile::Bitmap(values) => {
+                let pixels: Vec<Pixel> = values.iter().map(|&v| [v, v, v, 255]).collect();
+                Tile::from_data(&pixels, 0)
+            }
+        }
+    }
+
+    pub fn value_at(&self, x: u32, y: u32) -> u8 {
+        match self {
+            MaskTile::Uniform(v) => *v,
+            MaskTile::Bitmap(values) => values[(y * TILE_SIZE + x) as usize],
+        }
+    }
+
+    /// Get mutable access to the mask values.
+    /// A uniform tile is converted to a bitmap.
+    pub fn values_mut(&mut self) -> &mut [u8; TILE_LENGTH] {
+        if let MaskTile::Uniform(v) = *self {
+            *self = MaskTile::Bitmap(Arc::new([v; TILE_LENGTH]));
+        }
+        match self {
+            MaskTile::Bitmap(values) => Arc::make_mut(values),
+            MaskTile::Uniform(_) => unreachable!(),
+        }
+    }
+
+    /// Convert a bitmap tile to a uniform one if every value is the same
+    pub fn optimize(&mut self) {
+        if let MaskTile::Bitmap(values) = self {
+            let first = values[0];
+            if values.iter().all(|&v| v == first) {
+                *self = MaskTile::Uniform(first);
+            }
+        }
+    }
+
+    /// Check if these tiles are known to be the same without comparing their content
+    pub fn ptr_eq(&self, other: &MaskTile) -> bool {
+        match (self, other) {
+            (MaskTile::Bitmap(a), MaskTile::Bitmap(b)) => Arc::ptr_eq(a, b),
+            (MaskTile::Uniform(a), MaskTile::Uniform(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Multiply the pixels of an image tile with this mask
+    pub fn apply(&self, tile: &mut TileData) {
+        match self {
+            MaskTile::Uniform(255) => (),
+            MaskTile::Uniform(v) => tile.apply_mask(&[*v; TILE_LENGTH]),
+            MaskTile::Bitmap(values) => tile.apply_mask(&values[..]),
+        }
+    }
+}
+
+/// A tiled 8 bit layer mask
+///
+/// The mask is multiplied into the layer's content when the layer is flattened.
+/// A value of 255 shows the layer's content and 0 hides it.
+///
+/// Masks have their own IDs, which share the namespace with layer IDs.
+/// Drawing commands targeting the mask's ID edit the mask.
+#[derive(Clone)]
+pub struct LayerMask {
+    pub id: LayerID,
+    width: u32,
+    height: u32,
+    tiles: Arc<Vec<MaskTile>>,
+}
+
+impl LayerMask {
+    /// Construct a new mask filled with the given value
+    pub fn new(id: LayerID, width: u32, height: u32, value: u8) -> LayerMask {
+        LayerMask {
+            id,
+            width,
+            height,
+            tiles: Arc::new(vec![
+                MaskTile::Uniform(value);
+                (Tile::div_up(width) * Tile::div_up(height)) as usize
+            ]),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Return the tile at the given index
+    pub fn tile(&self, i: u32, j: u32) -> &MaskTile {
+        debug_assert!(i * TILE_SIZE < self.width);
+        debug_assert!(j * TILE_SIZE < self.height);
+        &self.tiles[(j * Tile::div_up(self.width) + i) as usize]
+    }
+
+    /// Get direct access to the tile vector.
+    pub fn tilevec(&self) -> &Vec<MaskTile> {
+        &self.tiles
+    }
+
+    /// Return the mask value at the given coordinates
+    pub fn value_at(&self, x: u32, y: u32) -> u8 {
+        self.tile(x / TILE_SIZE, y / TILE_SIZE)
+            .value_at(x % TILE_SIZE, y % TILE_SIZE)
+    }
+
+    /// If the entire mask has the same value, return it
+    pub fn uniform_value(&self) -> Option<u8> {
+        let first = match self.tiles.first() {
+            Some(MaskTile::Uniform(v)) => *v,
+            _ => return None,
+        };
+        if self.tiles.iter().all(|t| *t == MaskTile::Uniform(first)) {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    /// Multiply the pixels of an image tile with the mask tile at the given index
+    pub fn apply(&self, i: u32, j: u32, tile: &mut TileData) {
+        self.tile(i, j).apply(tile);
+    }
+
+    /// Paint a rectangle with the given value.
+    ///
+    /// The opacity is the strength of the change: at 255, the old
+    /// values are replaced and at lower opacities they are interpolated.
+    pub fn fill_rect(&mut self, value: u8, opacity: u8, rect: &Rectangle) -> AoE {
+        let rect = match rect.cropped(self.width, self.height) {
+            Some(r) => r,
+            None => return AoE::Nothing,
+        };
+        let mut padded = rect;
+
+        // Fill the padding of the edge tiles too, so filled tiles can be made uniform
+        if rect.right() == self.width as i32 - 1 {
+            padded.w = Tile::div_up(self.width) as i32 * TILE_SIZEI - rect.x;
+        }
+        if rect.bottom() == self.height as i32 - 1 {
+            padded.h = Tile::div_up(self.height) as i32 * TILE_SIZEI - rect.y;
+        }
+
+        self.paint(&padded, value, |_, _| opacity);
+        rect.into()
+    }
+
+    /// Paint a brush dab with the given value.
+    /// The brush mask's values are the strength of the change.
+    ///
+    /// * `x` - Left edge of the brush mask
+    /// * `y` - Top edge of the brush mask
+    pub fn draw_brush_dab(&mut self, x: i32, y: i32, mask: &BrushMask, value: u8) -> AoE {
+        let d = mask.diameter as i32;
+        let rect = match Rectangle::new(x, y, d, d).cropped(self.width, self.height) {
+            Some(r) => r,
+            None => return AoE::Nothing,
+        };
+
+        self.paint(&rect, value, |px, py| {
+            mask.mask[((py - y) * d + (px - x)) as usize]
+        });
+        rect.into()
+    }
+
+    /// Replace tiles with the given tile.
+    /// Tiles are placed at row-major order starting at the given index.
+    pub fn put_tile(&mut self, col: u32, row: u32, repeat: u32, tile: &MaskTile) -> AoE {
+        let xtiles = Tile::div_up(self.width);
+        let start = (row * xtiles + col) as usize;
+        let tiles = Arc::make_mut(&mut self.tiles);
+        if start >= tiles.len() {
+            return AoE::Nothing;
+        }
+        let end = (start + repeat as usize + 1).min(tiles.len());
+
+        for t in tiles[start..end].iter_mut() {
+            *t = tile.clone();
+        }
+
+        let mut map = TileMap::new(self.width, self.height);
+        map.tiles[start..end].set_all(true);
+        map.into()
+    }
+
+    fn paint(&mut self, rect: &Rectangle, value: u8, strength: impl Fn(i32, i32) -> u8) {
+        let tx0 = (rect.x / TILE_SIZEI) as usize;
+        let tx1 = (rect.right() / TILE_SIZEI) as usize;
+        let ty0 = (rect.y / TILE_SIZEI) as usize;
+        let ty1 = (rect.bottom() / TILE_SIZEI) as usize;
+        let stride = Tile::div_up(self.width) as usize;
+        let tiles: &mut Vec<MaskTile> = Arc::make_mut(&mut self.tiles);
+
+        for (i, j, tile) in
+            MutableTileIterator::new(tiles, stride, tx0, ty0, tx1 - tx0 + 1, ty1 - ty0 + 1)
+        {
+            if *tile == MaskTile::Uniform(value) {
+                continue;
+            }
+
+            let tilerect = Rectangle::tile(i, j, TILE_SIZEI);
+            let subrect = tilerect.intersected(rect).unwrap();
+            let values = tile.values_mut();
+
+            for y in subrect.y..=subrect.bottom() {
+                let row = ((y - tilerect.y) * TILE_SIZEI) as usize;
+                for x in subrect.x..=subrect.right() {
+                    let v = &mut values[row + (x - tilerect.x) as usize];
+                    *v = lerp(*v, value, strength(x, y));
+                }
+            }
+
+            tile.optimize();
+        }
+    }
+
+    /// Revert the changes made outside the selection
+    ///
+    /// The mask is compared to its original state and changes outside the
+    /// selection are undone. The returned area of effect is clipped to the
+    /// selection bounds.
+    pub fn clip_to_selection(
+        &mut self,
+        original: &LayerMask,
+        selection: &Selection,
+        aoe: AoE,
+    ) -> AoE {
+        assert_eq!(self.width, original.width);
+        assert_eq!(self.height, original.height);
+
+        if !Arc::ptr_eq(&self.tiles, &original.tiles) {
+            let xtiles = Tile::div_up(self.width) as usize;
+            let tiles = Arc::make_mut(&mut self.tiles);
+
+            for (idx, (tile, orig)) in tiles.iter_mut().zip(original.tiles.iter()).enumerate() {
+                if tile.ptr_eq(orig) {
+                    continue;
+                }
+
+                let rect =
+                    Rectangle::tile((idx % xtiles) as i32, (idx / xtiles) as i32, TILE_SIZEI);
+                if selection.excludes(&rect) {
+                    *tile = orig.clone();
+                } else if let Some(sel) = selection.mask_for(&rect) {
+                    let edited = tile.clone();
+                    let mut values = orig.clone();
+                    for (i, v) in values.values_mut().iter_mut().enumerate() {
+                        let e = edited.value_at(i as u32 % TILE_SIZE, i as u32 / TILE_SIZE);
+                        *v = lerp(*v, e, sel[i]);
+                    }
+                    values.optimize();
+                    *tile = values;
+                }
+            }
+        }
+
+        match (aoe, selection.bounds()) {
+            (_, None) => AoE::Nothing,
+            (AoE::Bounds(r), Some(b)) => r.intersected(b).map_or(AoE::Nothing, AoE::Bounds),
+            (aoe, _) => aoe,
+        }
+    }
+
+    /// Return a new mask with the size adjusted by the given values.
+    /// Expanded areas are filled with the given value.
+    pub fn resized(&self, top: i32, right: i32, bottom: i32, left: i32, fill: u8) -> LayerMask {
+        let new_width = (left + self.width as i32 + right) as u32;
+        let new_height = (top + self.height as i32 + bottom) as u32;
+
+        if self.uniform_value() == Some(fill) {
+            return LayerMask::new(self.id, new_width, new_height, fill);
+        }
+
+        let mut mask = LayerMask::new(self.id, new_width, new_height, fill);
+        let xtiles = Tile::div_up(new_width);
+        let tiles = Arc::make_mut(&mut mask.tiles);
+
+        for (idx, tile) in tiles.iter_mut().enumerate() {
+            let tx = (idx as u32 % xtiles * TILE_SIZE) as i32;
+            let ty = (idx as u32 / xtiles * TILE_SIZE) as i32;
+            let values = tile.values_mut();
+
+            for y in 0..TILE_SIZEI {
+                let sy = ty + y - top;
+                for x in 0..TILE_SIZEI {
+                    let sx = tx + x - left;
+                    if sx >= 0 && sy >= 0 && sx < self.width as i32 && sy < self.height as i32 {
+                        values[(y * TILE_SIZEI + x) as usize] = self.value_at(sx as u32, sy as u32);
+                    }
+                }
+            }
+
+            tile.optimize();
+        }
+
+        mask
+    }
+
+    /// Do a shallow comparison between these masks and return the difference
+    pub fn compare(&self, other: &LayerMask) -> AoE {
+        if Arc::ptr_eq(&self.tiles, &other.tiles) {
+            return AoE::Nothing;
+        }
+        if self.width != other.width || self.height != other.height {
+            return AoE::Resize(0, 0);
+        }
+
+        TileMap {
+            tiles: self
+                .tiles
+                .iter()
+                .zip(other.tiles.iter())
+                .map(|(a, b)| !a.ptr_eq(b))
+                .collect(),
+            w: Tile::div_up(self.width),
+            h: Tile::div_up(self.height),
+        }
+        .into()
+    }
+}
+
+/// Get the mask value that corresponds to the given color
+pub fn color_value(color: &Color) -> u8 {
+    ((77.0 * color.r + 150.0 * color.g + 29.0 * color.b) / 256.0 * 255.0).round() as u8
+}
+
+fn pixel_luma(p: &Pixel) -> u8 {
+    ((77 * p[RED_CHANNEL] as u32
+        + 150 * p[GREEN_CHANNEL] as u32
+        + 29 * p[BLUE_CHANNEL] as u32
+        + 128)
+        >> 8) as u8
+}
+
+fn lerp(old: u8, new: u8, strength: u8) -> u8 {
+    let s = strength as u32;
+    ((new as u32 * s + old as u32 * (255 - s) + 127) / 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_rect() {
+        let mut mask = LayerMask::new(1, 100, 100, 255);
+        mask.fill_rect(0, 255, &Rectangle::new(10, 10, 70, 20));
+        assert_eq!(mask.value_at(9, 10), 255);
+        assert_eq!(mask.value_at(10, 10), 0);
+        assert_eq!(mask.value_at(79, 29), 0);
+        assert_eq!(mask.value_at(80, 29), 255);
+        assert_eq!(mask.value_at(79, 30), 255);
+
+        mask.fill_rect(255, 128, &Rectangle::new(0, 0, 100, 100));
+        assert_eq!(mask.value_at(10, 10), 128);
+        assert_eq!(mask.value_at(0, 0), 255);
+
+        // Uniform tiles are kept uniform
+        mask.fill_rect(7, 255, &Rectangle::new(-10, -10, 200, 200));
+        assert_eq!(mask.uniform_value(), Some(7));
+    }
+
+    #[test]
+    fn test_brush_dab() {
+        let mut mask = LayerMask::new(1, 100, 100, 0);
+        let brush = BrushMask::new_square_pixel(4, 1.0);
+        mask.draw_brush_dab(62, 62, &brush, 200);
+        assert_eq!(mask.value_at(61, 61), 0);
+        assert_eq!(mask.value_at(62, 62), 200);
+        assert_eq!(mask.value_at(65, 65), 200);
+        assert_eq!(mask.value_at(66, 66), 0);
+    }
+
+    #[test]
+    fn test_clip_to_selection() {
+        let original = LayerMask::new(1, 100, 100, 255);
+        let selection = Selection::rectangle(Rectangle::new(10, 10, 20, 20));
+
+        let mut mask = original.clone();
+        let aoe = mask.fill_rect(0, 255, &Rectangle::new(0, 0, 100, 100));
+        let aoe = mask.clip_to_selection(&original, &selection, aoe);
+
+        assert_eq!(aoe, AoE::Bounds(Rectangle::new(10, 10, 20, 20)));
+        assert_eq!(mask.value_at(9, 10), 255);
+        assert_eq!(mask.value_at(10, 10), 0);
+        assert_eq!(mask.value_at(29, 29), 0);
+        assert_eq!(mask.value_at(30, 29), 255);
+        assert_eq!(mask.value_at(80, 80), 255);
+    }
+
+    #[test]
+    fn test_tile_conversion() {
+        let mut tile = MaskTile::Uniform(0);
+        tile.values_mut()[1] = 100;
+        tile.values_mut()[2] = 255;
+        assert_eq!(MaskTile::from_tile(&tile.to_tile()), tile);
+        assert_eq!(
+            MaskTile::from_tile(&MaskTile::Uniform(50).to_tile()),
+            MaskTile::Uniform(50)
+        );
+        assert_eq!(MaskTile::from_tile(&Tile::Blank), MaskTile::Uniform(0));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut mask = LayerMask::new(1, 100, 100, 255);
+        mask.fill_rect(0, 255, &Rectangle::new(0, 0, 10, 10));
+
+        let resized = mask.resized(5, 10, 0, 3, 255);
+        assert_eq!((resized.width(), resized.height()), (113, 105));
+        assert_eq!(resized.value_at(2, 5), 255);
+        assert_eq!(resized.value_at(3, 5), 0);
+        assert_eq!(resized.value_at(12, 14), 0);
+        assert_eq!(resized.value_at(13, 14), 255);
+        assert_eq!(resized.value_at(12, 15), 255);
+    }
+}
@@ -20,18 +20,26 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 use super::annotation::{Annotation, AnnotationID, VAlign};
 use super::aoe::AoE;
 use super::color::{Color, Pixel, ZERO_PIXEL};
+use super::layer::{flatten_layers, CensorPolicy};
+use super::layermask::LayerMask;
+use super::selection::Selection;
 use super::tile::{Tile, TileData, TILE_SIZE};
-use super::{Layer, LayerID, Rectangle};
+use super::{Layer, LayerID, Rectangle, UserID};
 
 #[derive(Clone)]
 pub struct LayerStack {
-    layers: Rc<Vec<Rc<Layer>>>,
-    annotations: Rc<Vec<Rc<Annotation>>>,
+    layers: Arc<Vec<Arc<Layer>>>,
+    annotations: Arc<Vec<Arc<Annotation>>>,
+    selections: BTreeMap<UserID, Selection>,
     pub background: Tile,
     width: u32,
     height: u32,
@@ -41,10 +49,13 @@ pub struct LayerStack {
 pub enum LayerFill {
     Solid(Color),
     Copy(LayerID),
+    /// An empty layer group
+    Group,
 }
 
 pub enum LayerInsertion {
     Top,
+    /// Directly above the given layer, in the same group
     Above(LayerID),
     Bottom,
 }
@@ -52,8 +63,9 @@ pub enum LayerInsertion {
 impl LayerStack {
     pub fn new(width: u32, height: u32) -> LayerStack {
         LayerStack {
-            layers: Rc::new(Vec::<Rc<Layer>>::new()),
-            annotations: Rc::new(Vec::<Rc<Annotation>>::new()),
+            layers: Arc::new(Vec::<Arc<Layer>>::new()),
+            annotations: Arc::new(Vec::<Arc<Annotation>>::new()),
+            selections: BTreeMap::new(),
             background: Tile::Blank,
             width,
             height,
@@ -71,107 +83,171 @@ impl LayerStack {
     /// Add a new layer and return a mutable reference to it
     ///
     /// If a layer with the given ID exists already, None will be returned.
-    /// If fill is Copy and the source layer does not exist, None will be returned.
+    /// If fill is Copy and the source layer does not exist or is a group, None will be returned.
     pub fn add_layer(
         &mut self,
         id: LayerID,
         fill: LayerFill,
         pos: LayerInsertion,
     ) -> Option<&mut Layer> {
-        if self.find_layer_index(id).is_some() {
+        if find_path(&self.layers, id).is_some() || self.get_mask(id).is_some() {
             return None;
         }
 
-        let insert_idx = match pos {
-            LayerInsertion::Top => self.layers.len(),
-            LayerInsertion::Above(layer_id) => self.find_layer_index(layer_id)? + 1,
-            LayerInsertion::Bottom => 0,
+        let (parent_path, insert_idx) = match pos {
+            LayerInsertion::Top => (Vec::new(), self.layers.len()),
+            LayerInsertion::Above(layer_id) => {
+                let mut path = find_path(&self.layers, layer_id)?;
+                let idx = path.pop().unwrap();
+                (path, idx + 1)
+            }
+            LayerInsertion::Bottom => (Vec::new(), 0),
         };
 
         let new_layer = match fill {
-            LayerFill::Solid(c) => Rc::new(Layer::new(id, self.width, self.height, &c)),
+            LayerFill::Solid(c) => Arc::new(Layer::new(id, self.width, self.height, &c)),
             LayerFill::Copy(src_id) => {
-                let mut l = self.layers[self.find_layer_index(src_id)?].clone();
-                Rc::make_mut(&mut l).id = id;
+                let mut l = self.find_layer(src_id)?.clone();
+                if l.is_group() {
+                    // Copying the children would result in duplicate layer IDs
+                    return None;
+                }
+                // Mask IDs must be unique too, so the copy is made without one
+                let lm = Arc::make_mut(&mut l);
+                lm.id = id;
+                lm.set_mask(None);
                 l
             }
+            LayerFill::Group => Arc::new(Layer::new_group(id, self.width, self.height)),
         };
 
-        let layers = Rc::make_mut(&mut self.layers);
+        let layers = list_at_mut(Arc::make_mut(&mut self.layers), &parent_path);
         layers.insert(insert_idx, new_layer);
-        Some(Rc::make_mut(layers.last_mut().unwrap()))
+        Some(Arc::make_mut(&mut layers[insert_idx]))
     }
 
     /// Find a layer with the given ID and return a reference to it
+    ///
+    /// Layers inside groups are searched as well.
     pub fn get_layer(&self, id: LayerID) -> Option<&Layer> {
-        for l in self.layers.iter() {
-            if l.id == id {
-                return Some(l);
-            }
-        }
-        None
+        self.find_layer(id).map(|l| l.as_ref())
     }
 
     /// Find a layer with the given ID and return a reference counted pointer to it
-    pub fn get_layer_rc(&self, id: LayerID) -> Option<Rc<Layer>> {
-        for l in self.layers.iter() {
-            if l.id == id {
-                return Some(l.clone());
-            }
-        }
-        None
+    pub fn get_layer_arc(&self, id: LayerID) -> Option<Arc<Layer>> {
+        self.find_layer(id).cloned()
     }
 
     /// Find a layer with the given ID
     pub fn get_layer_mut(&mut self, id: LayerID) -> Option<&mut Layer> {
-        if let Some(idx) = self.find_layer_index(id) {
-            Some(Rc::make_mut(&mut Rc::make_mut(&mut self.layers)[idx]))
-        } else {
-            None
-        }
+        let path = find_path(&self.layers, id)?;
+        let (&idx, parent_path) = path.split_last().unwrap();
+        let layers = list_at_mut(Arc::make_mut(&mut self.layers), parent_path);
+        Some(Arc::make_mut(&mut layers[idx]))
+    }
+
+    /// Find a layer mask with the given ID
+    pub fn get_mask(&self, id: LayerID) -> Option<&LayerMask> {
+        self.iter_layers_recursive()
+            .filter_map(|l| l.mask())
+            .find(|m| m.id == id)
+    }
+
+    /// Find a layer mask with the given ID and return a mutable reference to it
+    pub fn get_mask_mut(&mut self, id: LayerID) -> Option<&mut LayerMask> {
+        let owner = self
+            .iter_layers_recursive()
+            .find(|l| l.mask().map(|m| m.id) == Some(id))?
+            .id;
+        self.get_layer_mut(owner)?.mask_mut()
     }
 
-    /// Remove a layer with the given ID
+    /// Remove a layer with the given ID.
+    /// If the layer is a group, its children are removed as well.
     pub fn remove_layer(&mut self, id: LayerID) {
-        if let Some(idx) = self.find_layer_index(id) {
-            Rc::make_mut(&mut self.layers).remove(idx);
+        if let Some(path) = find_path(&self.layers, id) {
+            let (&idx, parent_path) = path.split_last().unwrap();
+            list_at_mut(Arc::make_mut(&mut self.layers), parent_path).remove(idx);
         }
     }
 
-    /// Find the ID of the layer below this layer
+    /// Find the ID of the layer below this layer in the same group
     pub fn find_layer_below(&self, id: LayerID) -> Option<LayerID> {
-        if let Some(idx) = self.find_layer_index(id) {
-            if idx > 0 {
-                return Some(self.layers[idx - 1].id);
+        let path = find_path(&self.layers, id)?;
+        let (&idx, parent_path) = path.split_last().unwrap();
+        if idx > 0 {
+            Some(list_at(&self.layers, parent_path)[idx - 1].id)
+        } else {
+            None
+        }
+    }
+
+    /// Move a layer into the given group (or to the root level if parent is None.)
+    ///
+    /// The layer is placed directly above the sibling layer. If sibling is None
+    /// or is not in the target group, the layer is placed at the bottom of the group.
+    ///
+    /// Returns false if either layer does not exist, the parent is not a group
+    /// or if a group would be moved inside itself.
+    pub fn move_layer(
+        &mut self,
+        id: LayerID,
+        parent: Option<LayerID>,
+        sibling: Option<LayerID>,
+    ) -> bool {
+        let path = match find_path(&self.layers, id) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        if let Some(parent_id) = parent {
+            match find_path(&self.layers, parent_id) {
+                Some(p) if !p.starts_with(&path) => {
+                    if !list_at(&self.layers, &p[..p.len() - 1])[p[p.len() - 1]].is_group() {
+                        return false;
+                    }
+                }
+                _ => return false,
             }
         }
-        None
+
+        let (&idx, old_parent_path) = path.split_last().unwrap();
+        let root = Arc::make_mut(&mut self.layers);
+        let layer = list_at_mut(root, old_parent_path).remove(idx);
+
+        // Indices may have changed, so find the parent again
+        let parent_path = match parent {
+            Some(parent_id) => find_path(root, parent_id).unwrap(),
+            None => Vec::new(),
+        };
+
+        let layers = list_at_mut(root, &parent_path);
+        let insert_idx = sibling
+            .and_then(|s| layers.iter().position(|l| l.id == s))
+            .map_or(0, |i| i + 1);
+        layers.insert(insert_idx, layer);
+
+        true
     }
 
-    fn find_layer_index(&self, id: LayerID) -> Option<usize> {
-        self.layers.iter().position(|l| l.id == id)
+    fn find_layer(&self, id: LayerID) -> Option<&Arc<Layer>> {
+        let path = find_path(&self.layers, id)?;
+        let (&idx, parent_path) = path.split_last().unwrap();
+        Some(&list_at(&self.layers, parent_path)[idx])
     }
 
     /// Return a copy of the layerstack with the layers in the given order.
     /// The new order vector is sanitized. Duplicate and nonexistent layers
     /// are dropped and missing layers are appended.
+    ///
+    /// The order of each group's children is sanitized the same way,
+    /// so the same order vector can be used to reorder the layers
+    /// inside groups. Layers are never moved between groups.
     pub fn reordered(&self, new_order: &[LayerID]) -> LayerStack {
-        let mut ordered = Vec::<Rc<Layer>>::new();
-        let mut oldorder = (*self.layers).clone();
-
-        // Take layers from the old list and add them in the specified order.
-        for &layer_id in new_order {
-            if let Some(pos) = oldorder.iter().position(|l| l.id == layer_id) {
-                ordered.push(oldorder.remove(pos));
-            }
-        }
-
-        // Add any remaining layers in the existing order
-        ordered.extend_from_slice(&oldorder);
-
         LayerStack {
-            layers: Rc::new(ordered),
+            layers: Arc::new(reordered_list(&self.layers, new_order)),
             annotations: self.annotations.clone(),
+            selections: self.selections.clone(),
             background: self.background.clone(),
             ..*self
         }
@@ -182,7 +258,7 @@ impl LayerStack {
         if self.find_annotation_index(id).is_some() {
             return;
         }
-        Rc::make_mut(&mut self.annotations).push(Rc::new(Annotation {
+        Arc::make_mut(&mut self.annotations).push(Arc::new(Annotation {
             id,
             text: String::new(),
             rect: rect,
@@ -193,7 +269,7 @@ impl LayerStack {
     }
 
     pub fn remove_annotation(&mut self, id: AnnotationID) {
-        Rc::make_mut(&mut self.annotations).retain(|a| a.id != id);
+        Arc::make_mut(&mut self.annotations).retain(|a| a.id != id);
     }
 
     pub fn get_annotation(&self, id: AnnotationID) -> Option<&Annotation> {
@@ -206,58 +282,124 @@ impl LayerStack {
 
     pub fn get_annotation_mut(&mut self, id: AnnotationID) -> Option<&mut Annotation> {
         if let Some(idx) = self.find_annotation_index(id) {
-            Some(Rc::make_mut(&mut Rc::make_mut(&mut self.annotations)[idx]))
+            Some(Arc::make_mut(
+                &mut Arc::make_mut(&mut self.annotations)[idx],
+            ))
         } else {
             None
         }
     }
 
+    pub fn iter_annotations(&self) -> impl Iterator<Item = &Annotation> {
+        self.annotations.iter().map(|a| a.as_ref())
+    }
+
+    /// Set or clear a user's selection
+    pub fn set_selection(&mut self, user: UserID, selection: Option<Selection>) {
+        match selection {
+            Some(s) => self.selections.insert(user, s),
+            None => self.selections.remove(&user),
+        };
+    }
+
+    /// Get the given user's current selection (if any)
+    pub fn get_selection(&self, user: UserID) -> Option<&Selection> {
+        self.selections.get(&user)
+    }
+
+    /// Iterate through all the users' selections
+    pub fn iter_selections(&self) -> impl Iterator<Item = (UserID, &Selection)> {
+        self.selections.iter().map(|(&u, s)| (u, s))
+    }
+
     fn find_annotation_index(&self, id: AnnotationID) -> Option<usize> {
         self.annotations.iter().position(|a| a.id == id)
     }
 
     /// Flatten layer stack content
+    ///
+    /// Censored layers are shown as is.
     pub fn flatten_tile(&self, i: u32, j: u32) -> TileData {
+        self.flatten_tile_censored(i, j, CensorPolicy::Show)
+    }
+
+    /// Flatten layer stack content using the given censor policy
+    pub fn flatten_tile_censored(&self, i: u32, j: u32, censor: CensorPolicy) -> TileData {
         let mut destination = self.background.clone_data();
 
         if (i * TILE_SIZE) < self.width && (j * TILE_SIZE) < self.height {
-            for layer in self.layers.iter() {
-                layer.flatten_tile(&mut destination, i, j);
-            }
+            flatten_layers(&self.layers, &mut destination, i, j, censor);
         }
 
         destination
     }
 
-    // Convert to a flat image
+    /// Convert to a flat image.
+    ///
+    /// Censored layers are shown as is.
     pub fn to_image(&self) -> (Vec<Pixel>, u32, u32) {
-        let xtiles = Tile::div_up(self.width) as usize;
-        let ytiles = Tile::div_up(self.height) as usize;
+        self.to_image_censored(CensorPolicy::Show)
+    }
 
-        let tw = TILE_SIZE as usize;
-        let width = self.width as usize;
-        let height = self.height as usize;
+    /// Convert to a flat image using the given censor policy.
+    ///
+    /// When the `parallel` feature is enabled, rows of tiles are
+    /// flattened in parallel. The output is identical to `to_image_serial`.
+    pub fn to_image_censored(&self, censor: CensorPolicy) -> (Vec<Pixel>, u32, u32) {
+        #[cfg(feature = "parallel")]
+        {
+            let mut image = vec![ZERO_PIXEL; self.width as usize * self.height as usize];
+            let row_len = self.width as usize * TILE_SIZE as usize;
+            if row_len > 0 {
+                image
+                    .par_chunks_mut(row_len)
+                    .enumerate()
+                    .for_each(|(j, row)| self.flatten_tile_row(j as u32, row, censor));
+            }
+            (image, self.width, self.height)
+        }
 
-        let mut image = vec![ZERO_PIXEL; width * height];
+        #[cfg(not(feature = "parallel"))]
+        self.to_image_serial_censored(censor)
+    }
 
-        for j in 0..ytiles {
-            let h = tw.min(height - (j * tw));
-            for i in 0..xtiles {
-                let td = self.flatten_tile(i as u32, j as u32);
-                let w = tw.min(width - (i * tw));
-                for y in 0..h {
-                    let dest_offset = (j * tw + y) * width + i * tw;
-                    let src_offset = y * tw;
+    /// Convert to a flat image using just the calling thread
+    pub fn to_image_serial(&self) -> (Vec<Pixel>, u32, u32) {
+        self.to_image_serial_censored(CensorPolicy::Show)
+    }
 
-                    image[dest_offset..dest_offset + w]
-                        .copy_from_slice(&td.pixels[src_offset..src_offset + w]);
-                }
+    fn to_image_serial_censored(&self, censor: CensorPolicy) -> (Vec<Pixel>, u32, u32) {
+        let mut image = vec![ZERO_PIXEL; self.width as usize * self.height as usize];
+        let row_len = self.width as usize * TILE_SIZE as usize;
+        if row_len > 0 {
+            for (j, row) in image.chunks_mut(row_len).enumerate() {
+                self.flatten_tile_row(j as u32, row, censor);
             }
         }
 
         (image, self.width, self.height)
     }
 
+    /// Flatten a row of tiles into the corresponding slice of the image.
+    /// The slice height may be less than a full tile at the bottom edge.
+    fn flatten_tile_row(&self, j: u32, row: &mut [Pixel], censor: CensorPolicy) {
+        let tw = TILE_SIZE as usize;
+        let width = self.width as usize;
+        let h = row.len() / width;
+
+        for i in 0..Tile::div_up(self.width) as usize {
+            let td = self.flatten_tile_censored(i as u32, j, censor);
+            let w = tw.min(width - (i * tw));
+            for y in 0..h {
+                let dest_offset = y * width + i * tw;
+                let src_offset = y * tw;
+
+                row[dest_offset..dest_offset + w]
+                    .copy_from_slice(&td.pixels[src_offset..src_offset + w]);
+            }
+        }
+    }
+
     /// Return a resized copy of this stack
     pub fn resized(&self, top: i32, right: i32, bottom: i32, left: i32) -> Option<LayerStack> {
         let new_width = left + self.width as i32 + right;
@@ -267,17 +409,17 @@ impl LayerStack {
         }
 
         Some(LayerStack {
-            layers: Rc::new(
+            layers: Arc::new(
                 self.layers
                     .iter()
-                    .map(|l| Rc::new(l.resized(top, right, bottom, left)))
+                    .map(|l| Arc::new(l.resized(top, right, bottom, left)))
                     .collect(),
             ),
-            annotations: Rc::new(
+            annotations: Arc::new(
                 self.annotations
                     .iter()
                     .map(|a| {
-                        Rc::new(Annotation {
+                        Arc::new(Annotation {
                             rect: a.rect.offset(left, top),
                             text: a.text.clone(),
                             ..**a
@@ -285,18 +427,39 @@ impl LayerStack {
                     })
                     .collect(),
             ),
+            // Selections are not carried over, since their masks
+            // are cropped to the canvas size.
+            selections: BTreeMap::new(),
             background: self.background.clone(),
             width: new_width as u32,
             height: new_height as u32,
         })
     }
 
+    /// Iterate through the top level layers
     pub fn iter_layers(&self) -> impl Iterator<Item = &Layer> {
         return self.layers.iter().map(|l| l.as_ref());
     }
 
-    pub fn iter_layers_mut(&mut self) -> impl Iterator<Item = &mut Rc<Layer>> {
-        return Rc::make_mut(&mut self.layers).iter_mut();
+    /// Iterate through all layers, including those inside groups.
+    /// Groups are listed before their children.
+    pub fn iter_layers_recursive(&self) -> impl Iterator<Item = &Layer> {
+        fn collect<'a>(layers: &'a [Arc<Layer>], out: &mut Vec<&'a Layer>) {
+            for l in layers {
+                out.push(l);
+                if let Some(children) = l.children() {
+                    collect(children, out);
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        collect(&self.layers, &mut all);
+        all.into_iter()
+    }
+
+    pub fn iter_layers_mut(&mut self) -> impl Iterator<Item = &mut Arc<Layer>> {
+        return Arc::make_mut(&mut self.layers).iter_mut();
     }
 
     /// Compare this layer stack with the other and return an Area Of Effect.
@@ -307,7 +470,7 @@ impl LayerStack {
         if self.width != other.width || self.height != other.height {
             return AoE::Resize(0, 0);
         }
-        if Rc::ptr_eq(&self.layers, &other.layers) {
+        if Arc::ptr_eq(&self.layers, &other.layers) {
             return AoE::Nothing;
         }
         if self.layers.len() != other.layers.len() {
@@ -321,9 +484,71 @@ impl LayerStack {
     }
 }
 
+/// Find the path of indices leading to the layer with the given ID
+fn find_path(layers: &[Arc<Layer>], id: LayerID) -> Option<Vec<usize>> {
+    for (idx, layer) in layers.iter().enumerate() {
+        if layer.id == id {
+            return Some(vec![idx]);
+        }
+        if let Some(mut path) = layer.children().and_then(|c| find_path(c, id)) {
+            path.insert(0, idx);
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// Get the child list of the group at the end of the given path
+fn list_at<'a>(layers: &'a [Arc<Layer>], path: &[usize]) -> &'a [Arc<Layer>] {
+    match path.split_first() {
+        None => layers,
+        Some((&idx, rest)) => list_at(layers[idx].children().expect("not a group"), rest),
+    }
+}
+
+fn list_at_mut<'a>(layers: &'a mut Vec<Arc<Layer>>, path: &[usize]) -> &'a mut Vec<Arc<Layer>> {
+    match path.split_first() {
+        None => layers,
+        Some((&idx, rest)) => list_at_mut(
+            Arc::make_mut(&mut layers[idx])
+                .children_mut()
+                .expect("not a group"),
+            rest,
+        ),
+    }
+}
+
+fn reordered_list(layers: &[Arc<Layer>], new_order: &[LayerID]) -> Vec<Arc<Layer>> {
+    let mut ordered = Vec::<Arc<Layer>>::new();
+    let mut oldorder = layers.to_vec();
+
+    // Take layers from the old list and add them in the specified order.
+    for &layer_id in new_order {
+        if let Some(pos) = oldorder.iter().position(|l| l.id == layer_id) {
+            ordered.push(oldorder.remove(pos));
+        }
+    }
+
+    // Add any remaining layers in the existing order
+    ordered.extend_from_slice(&oldorder);
+
+    // Reorder the contents of groups too
+    for layer in ordered.iter_mut() {
+        if let Some(children) = layer.children() {
+            let children = reordered_list(children, new_order);
+            *Arc::make_mut(layer).children_mut().unwrap() = children;
+        }
+    }
+
+    ordered
+}
+
 #[cfg(test)]
 mod tests {
+    use super::super::Blendmode;
     use super::*;
+    use crate::paint::editlayer;
+    use crate::paint::layermask::LayerMask;
 
     #[test]
     fn test_layer_addition() {
@@ -445,4 +670,317 @@ mod tests {
         let t2 = stack.flatten_tile(1, 0);
         assert_eq!(t2.pixels[0], Color::rgb8(255, 255, 255).as_pixel());
     }
+
+    #[test]
+    fn test_to_image() {
+        // A size that is not a multiple of the tile size
+        let mut stack = LayerStack::new(150, 70);
+        stack.add_layer(1, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top);
+
+        let layer = stack.get_layer_mut(1).unwrap();
+        for (i, j) in [(0, 0), (2, 0), (1, 1)].iter() {
+            *layer.tile_mut(*i, *j) =
+                Tile::new_solid(&Color::rgb8(*i as u8 * 100, *j as u8 * 100, 50), 0);
+        }
+
+        let (image, w, h) = stack.to_image();
+        assert_eq!((w, h), (150, 70));
+        assert_eq!(image.len(), 150 * 70);
+        assert_eq!(image[0], Color::rgb8(0, 0, 50).as_pixel());
+        assert_eq!(image[149], Color::rgb8(200, 0, 50).as_pixel());
+        assert_eq!(image[69 * 150 + 64], Color::rgb8(100, 100, 50).as_pixel());
+        assert_eq!(image[69 * 150 + 149], ZERO_PIXEL);
+
+        assert_eq!(stack.to_image_serial().0, image);
+        assert!(LayerStack::new(0, 0).to_image().0.is_empty());
+    }
+
+    fn ids<'a>(layers: impl Iterator<Item = &'a Layer>) -> Vec<LayerID> {
+        layers.map(|l| l.id).collect()
+    }
+
+    #[test]
+    fn test_layer_groups() {
+        let mut stack = LayerStack::new(64, 64);
+        let transparent = || LayerFill::Solid(Color::TRANSPARENT);
+        stack.add_layer(1, transparent(), LayerInsertion::Top);
+        stack.add_layer(2, LayerFill::Group, LayerInsertion::Top);
+        stack.add_layer(3, transparent(), LayerInsertion::Top);
+        stack.add_layer(4, LayerFill::Group, LayerInsertion::Top);
+
+        // Move layers into groups
+        assert!(stack.move_layer(3, Some(2), None));
+        assert!(stack.move_layer(4, Some(2), Some(3)));
+        assert!(stack.move_layer(1, Some(4), None));
+        assert_eq!(ids(stack.iter_layers()), vec![2]);
+        assert_eq!(ids(stack.get_layer(2).unwrap().iter_children()), vec![3, 4]);
+        assert_eq!(ids(stack.iter_layers_recursive()), vec![2, 3, 4, 1]);
+
+        // Layers can't be moved into non-groups or inside themselves
+        assert!(!stack.move_layer(2, Some(3), None));
+        assert!(!stack.move_layer(2, Some(2), None));
+        assert!(!stack.move_layer(2, Some(4), None));
+        assert!(!stack.move_layer(2, Some(99), None));
+
+        // Layers inside groups can be found and edited
+        stack.get_layer_mut(1).unwrap().title = "hello".into();
+        assert_eq!(stack.get_layer(1).unwrap().title, "hello");
+        assert_eq!(stack.find_layer_below(4), Some(3));
+        assert_eq!(stack.find_layer_below(3), None);
+
+        // Inserting above a layer in a group puts the new layer in the same group
+        stack.add_layer(5, transparent(), LayerInsertion::Above(3));
+        assert_eq!(
+            ids(stack.get_layer(2).unwrap().iter_children()),
+            vec![3, 5, 4]
+        );
+
+        // Groups can't be copied, but layers inside them can
+        assert!(stack
+            .add_layer(6, LayerFill::Copy(4), LayerInsertion::Top)
+            .is_none());
+        assert!(stack
+            .add_layer(6, LayerFill::Copy(1), LayerInsertion::Top)
+            .is_some());
+        assert!(stack
+            .add_layer(7, transparent(), LayerInsertion::Top)
+            .is_some());
+
+        // Reordering applies inside groups too
+        let reordered = stack.reordered(&[4, 3, 7]);
+        assert_eq!(ids(reordered.iter_layers()), vec![7, 2, 6]);
+        assert_eq!(
+            ids(reordered.get_layer(2).unwrap().iter_children()),
+            vec![4, 3, 5]
+        );
+
+        // Moving back to the root level
+        assert!(stack.move_layer(1, None, Some(6)));
+        assert_eq!(ids(stack.iter_layers()), vec![2, 6, 1, 7]);
+
+        // Removing a group removes its children too
+        stack.remove_layer(2);
+        assert_eq!(ids(stack.iter_layers_recursive()), vec![6, 1, 7]);
+    }
+
+    #[test]
+    fn test_group_flattening() {
+        let mut stack = LayerStack::new(64, 64);
+        stack.background = Tile::new_solid(&Color::rgb8(255, 255, 255), 0);
+        stack
+            .add_layer(
+                1,
+                LayerFill::Solid(Color::rgb8(0, 0, 255)),
+                LayerInsertion::Top,
+            )
+            .unwrap();
+        stack.add_layer(2, LayerFill::Group, LayerInsertion::Top);
+        stack
+            .add_layer(
+                3,
+                LayerFill::Solid(Color::rgb8(255, 0, 0)),
+                LayerInsertion::Top,
+            )
+            .unwrap()
+            .blendmode = Blendmode::Multiply;
+        stack.move_layer(3, Some(2), None);
+
+        // An isolated group: the multiply layer has nothing to multiply with
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 0, 255).as_pixel()
+        );
+
+        // Pass-through: multiplied with the layer below the group
+        stack.get_layer_mut(2).unwrap().passthrough = true;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 0, 0).as_pixel()
+        );
+
+        // Pass-through with opacity fades between the results
+        stack.get_layer_mut(2).unwrap().opacity = 0.5;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 0, 128).as_pixel()
+        );
+
+        // Hiding the group hides the children
+        stack.get_layer_mut(2).unwrap().hidden = true;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 0, 255).as_pixel()
+        );
+    }
+
+    #[test]
+    fn test_clipping() {
+        let mut stack = LayerStack::new(128, 64);
+        stack.background = Tile::new_solid(&Color::rgb8(255, 255, 255), 0);
+        stack.add_layer(1, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top);
+        *stack.get_layer_mut(1).unwrap().tile_mut(0, 0) =
+            Tile::new_solid(&Color::rgb8(255, 0, 0), 0);
+        stack
+            .add_layer(
+                2,
+                LayerFill::Solid(Color::rgb8(0, 0, 255)),
+                LayerInsertion::Top,
+            )
+            .unwrap()
+            .clip = true;
+
+        // The clipping layer is visible only where the base layer is
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 0, 255).as_pixel()
+        );
+        assert_eq!(
+            stack.flatten_tile(1, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+
+        // Base layer opacity affects the clipping layer too
+        stack.get_layer_mut(1).unwrap().opacity = 0.5;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(128, 64, 191).as_pixel()
+        );
+
+        // Consecutive clipping layers are clipped to the same base layer
+        stack
+            .add_layer(
+                3,
+                LayerFill::Solid(Color::rgb8(0, 255, 0)),
+                LayerInsertion::Top,
+            )
+            .unwrap()
+            .clip = true;
+        stack.get_layer_mut(1).unwrap().opacity = 1.0;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(0, 255, 0).as_pixel()
+        );
+        assert_eq!(
+            stack.flatten_tile(1, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+
+        // Hiding the base layer hides the clipping layers
+        stack.get_layer_mut(1).unwrap().hidden = true;
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+
+        // A clipping layer at the bottom has nothing to clip to
+        let stack = stack.reordered(&[3, 1, 2]);
+        assert_eq!(
+            stack.flatten_tile(1, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+    }
+
+    #[test]
+    fn test_layer_mask() {
+        let mut stack = LayerStack::new(128, 64);
+        stack.background = Tile::new_solid(&Color::rgb8(255, 255, 255), 0);
+        stack.add_layer(
+            1,
+            LayerFill::Solid(Color::rgb8(255, 0, 0)),
+            LayerInsertion::Top,
+        );
+
+        let mut mask = LayerMask::new(2, 128, 64, 255);
+        mask.fill_rect(0, 255, &Rectangle::new(64, 0, 64, 64));
+        stack.get_layer_mut(1).unwrap().set_mask(Some(mask));
+
+        // The mask hides the right half of the layer
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(255, 0, 0).as_pixel()
+        );
+        assert_eq!(
+            stack.flatten_tile(1, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+
+        // The mask can be found by its ID and its ID cannot be reused
+        stack
+            .get_mask_mut(2)
+            .unwrap()
+            .fill_rect(255, 255, &Rectangle::new(64, 0, 64, 64));
+        assert_eq!(
+            stack.flatten_tile(1, 0).pixels[0],
+            Color::rgb8(255, 0, 0).as_pixel()
+        );
+        assert!(stack
+            .add_layer(2, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top)
+            .is_none());
+
+        // Masks apply to groups as well
+        stack.add_layer(3, LayerFill::Group, LayerInsertion::Top);
+        stack.move_layer(1, Some(3), None);
+        stack
+            .get_layer_mut(3)
+            .unwrap()
+            .set_mask(Some(LayerMask::new(4, 128, 64, 0)));
+        assert_eq!(
+            stack.flatten_tile(0, 0).pixels[0],
+            Color::rgb8(255, 255, 255).as_pixel()
+        );
+        assert!(stack.get_mask(2).is_some());
+        assert!(stack.get_mask(4).is_some());
+    }
+
+    #[test]
+    fn test_censored_layer() {
+        let mut stack = LayerStack::new(128, 64);
+        stack.background = Tile::new_solid(&Color::rgb8(255, 255, 255), 0);
+        stack.add_layer(1, LayerFill::Group, LayerInsertion::Top);
+        stack.add_layer(2, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top);
+        stack.add_layer(
+            3,
+            LayerFill::Solid(Color::rgb8(0, 0, 255)),
+            LayerInsertion::Top,
+        );
+        stack.get_layer_mut(3).unwrap().clip = true;
+        stack.move_layer(2, Some(1), None);
+        stack.move_layer(3, Some(1), Some(2));
+
+        // A two pixel wide black stripe
+        let layer = stack.get_layer_mut(2).unwrap();
+        layer.censored = true;
+        editlayer::fill_rect(
+            layer,
+            0,
+            &Color::rgb8(0, 0, 0),
+            Blendmode::Normal,
+            &Rectangle::new(0, 0, 2, 64),
+        );
+
+        // Censored content is shown, hidden or pixelated as requested.
+        // The clipping layer above is affected too.
+        let blue = Color::rgb8(0, 0, 255).as_pixel();
+        let white = Color::rgb8(255, 255, 255).as_pixel();
+
+        let shown = stack.flatten_tile_censored(0, 0, CensorPolicy::Show);
+        assert_eq!(shown.pixels[0], blue);
+        assert_eq!(shown.pixels[2], white);
+        assert_eq!(shown.pixels, stack.flatten_tile(0, 0).pixels);
+
+        let hidden = stack.flatten_tile_censored(0, 0, CensorPolicy::Hide);
+        assert!(hidden.pixels.iter().all(|&p| p == white));
+
+        let pixelated = stack.flatten_tile_censored(0, 0, CensorPolicy::Pixelate(4));
+        // Half black block with the clipping layer on top at half opacity
+        assert_eq!(pixelated.pixels[0], [191, 63, 63, 255]);
+        assert_eq!(pixelated.pixels[3], [191, 63, 63, 255]);
+        assert_eq!(pixelated.pixels[4], white);
+
+        // The same applies when the censor flag is set on the group
+        stack.get_layer_mut(2).unwrap().censored = false;
+        stack.get_layer_mut(1).unwrap().censored = true;
+        let (image, _, _) = stack.to_image_censored(CensorPolicy::Hide);
+        assert!(image.iter().all(|&p| p == white));
+    }
 }
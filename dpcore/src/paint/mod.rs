@@ -24,11 +24,20 @@ pub mod annotation;
 pub mod aoe;
 pub mod color;
 pub mod editlayer;
+pub mod filter;
+pub mod floodfill;
+pub mod layermask;
 pub mod layerstack;
+#[cfg(feature = "openraster")]
+pub mod openraster;
 pub mod rasterop;
 pub mod rectiter;
+pub mod selection;
+#[cfg(feature = "text-render")]
+pub mod textrender;
 pub mod tile;
 pub mod tileiter;
+pub mod transform;
 
 pub type UserID = u8;
 pub type LayerID = i32;
@@ -38,11 +47,13 @@ mod blendmode;
 mod brushmask;
 mod layer;
 mod rect;
+#[cfg(target_arch = "x86_64")]
+mod simd;
 
 pub use aoe::AoE;
 pub use blendmode::Blendmode;
 pub use brushmask::{BrushMask, ClassicBrushCache};
 pub use color::{Color, Pixel};
-pub use layer::Layer;
+pub use layer::{CensorPolicy, Layer};
 pub use layerstack::LayerStack;
 pub use rect::Rectangle;
@@ -0,0 +1,791 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::sync::Arc;
+
+use roxmltree::{Document, Node};
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::annotation::{AnnotationID, VAlign};
+use super::color::{Pixel, ALPHA_CHANNEL, BLUE_CHANNEL, GREEN_CHANNEL, RED_CHANNEL, ZERO_PIXEL};
+use super::editlayer::read_pixels;
+use super::layermask::{LayerMask, MaskTile};
+use super::layerstack::{LayerFill, LayerInsertion};
+use super::tile::{Tile, TILE_LENGTH, TILE_SIZE};
+use super::{Blendmode, CensorPolicy, Color, Layer, LayerID, LayerStack, Rectangle};
+
+const MIMETYPE: &[u8] = b"image/openraster";
+
+/// Namespace of the Drawpile specific stack.xml extensions
+const DP_NAMESPACE: &str = "http://drawpile.net/";
+
+/// Maximum width and height of the thumbnail image
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Largest canvas (and embedded image) width and height the protocol can express
+const MAX_SIZE: u32 = u16::MAX as u32;
+
+/// Largest canvas (and embedded image) area accepted when loading.
+/// Each layer image is decoded into a buffer of this many pixels.
+const MAX_AREA: u64 = 16384 * 16384;
+
+/// The ID given to the first imported layer (and annotation.)
+/// The rest are numbered sequentially from here.
+pub const FIRST_IMPORTED_ID: LayerID = 0x0100;
+
+#[derive(Debug)]
+pub enum OraError {
+    /// An error in the underlying file
+    Io(io::Error),
+
+    /// The file is not a (readable) zip archive
+    Zip(ZipError),
+
+    /// An embedded PNG could not be decoded
+    Decode(png::DecodingError),
+
+    /// A PNG could not be encoded
+    Encode(png::EncodingError),
+
+    /// stack.xml could not be parsed
+    Xml(roxmltree::Error),
+
+    /// The file is well formed, but not a valid OpenRaster image
+    Invalid(&'static str),
+}
+
+impl fmt::Display for OraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OraError::Io(e) => write!(f, "{}", e),
+            OraError::Zip(e) => write!(f, "{}", e),
+            OraError::Decode(e) => write!(f, "{}", e),
+            OraError::Encode(e) => write!(f, "{}", e),
+            OraError::Xml(e) => write!(f, "stack.xml: {}", e),
+            OraError::Invalid(msg) => write!(f, "invalid OpenRaster file: {}", msg),
+        }
+    }
+}
+
+impl Error for OraError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OraError::Io(e) => Some(e),
+            OraError::Zip(e) => Some(e),
+            OraError::Decode(e) => Some(e),
+            OraError::Encode(e) => Some(e),
+            OraError::Xml(e) => Some(e),
+            OraError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for OraError {
+    fn from(err: io::Error) -> Self {
+        OraError::Io(err)
+    }
+}
+
+impl From<ZipError> for OraError {
+    fn from(err: ZipError) -> Self {
+        OraError::Zip(err)
+    }
+}
+
+impl From<png::DecodingError> for OraError {
+    fn from(err: png::DecodingError) -> Self {
+        OraError::Decode(err)
+    }
+}
+
+impl From<png::EncodingError> for OraError {
+    fn from(err: png::EncodingError) -> Self {
+        OraError::Encode(err)
+    }
+}
+
+impl From<roxmltree::Error> for OraError {
+    fn from(err: roxmltree::Error) -> Self {
+        OraError::Xml(err)
+    }
+}
+
+/// Save a layer stack as an OpenRaster image.
+///
+/// Each layer is saved as a canvas sized PNG and groups become nested stacks.
+/// Attributes that OpenRaster has no standard way to express (censored,
+/// fixed and clipping layers, layer masks, the background tile and
+/// annotations) are saved using Drawpile's own stack.xml extensions.
+///
+/// Censored layers are left out or pixelated according to the censor policy.
+/// The merged image and thumbnail are flattened using the same policy.
+///
+/// Sublayers and selections are transient state and are not saved.
+pub fn save_openraster<W: Write + Seek>(
+    layerstack: &LayerStack,
+    censor: CensorPolicy,
+    writer: W,
+) -> Result<(), OraError> {
+    if layerstack.width() == 0 || layerstack.height() == 0 {
+        return Err(OraError::Invalid("empty canvas"));
+    }
+
+    let mut zip = ZipWriter::new(writer);
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    // The mimetype must be the first file and it must not be compressed
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(MIMETYPE)?;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<image version=\"0.0.5\" w=\"{}\" h=\"{}\" xmlns:drawpile=\"{}\"",
+        layerstack.width(),
+        layerstack.height(),
+        DP_NAMESPACE
+    ));
+
+    if layerstack.background != Tile::Blank {
+        let name = "data/background.png";
+        let tile = layerstack.background.clone_data();
+        write_png(&mut zip, name, &to_rgba(&tile.pixels), TILE_SIZE, TILE_SIZE)?;
+        xml.push_str(&format!(" drawpile:background=\"{}\"", name));
+    }
+    xml.push_str(">\n");
+
+    let layers: Vec<&Layer> = layerstack.iter_layers().collect();
+    xml.push_str("<stack>\n");
+    write_layers(&mut zip, &mut xml, &layers, censor, false)?;
+    xml.push_str("</stack>\n");
+
+    let mut annotations = layerstack.iter_annotations().peekable();
+    if annotations.peek().is_some() {
+        xml.push_str("<drawpile:annotations>\n");
+        for a in annotations {
+            xml.push_str(&format!(
+                "<drawpile:a x=\"{}\" y=\"{}\" w=\"{}\" h=\"{}\" bg=\"#{:08x}\" valign=\"{}\"{}>{}</drawpile:a>\n",
+                a.rect.x,
+                a.rect.y,
+                a.rect.w,
+                a.rect.h,
+                a.background.as_argb32(),
+                match a.valign {
+                    VAlign::Top => "top",
+                    VAlign::Center => "center",
+                    VAlign::Bottom => "bottom",
+                },
+                if a.protect { " protect=\"true\"" } else { "" },
+                escape(&a.text)
+            ));
+        }
+        xml.push_str("</drawpile:annotations>\n");
+    }
+    xml.push_str("</image>\n");
+
+    zip.start_file("stack.xml", FileOptions::default())?;
+    zip.write_all(xml.as_bytes())?;
+
+    let (image, w, h) = layerstack.to_image_censored(censor);
+    write_png(&mut zip, "mergedimage.png", &to_rgba(&image), w, h)?;
+
+    let (thumb, tw, th) = thumbnail(&image, w, h);
+    write_png(
+        &mut zip,
+        "Thumbnails/thumbnail.png",
+        &to_rgba(&thumb),
+        tw,
+        th,
+    )?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Write the stack.xml entries and images of the given layers (bottom-most first.)
+/// OpenRaster lists layers in top to bottom order.
+fn write_layers<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    xml: &mut String,
+    layers: &[&Layer],
+    censor: CensorPolicy,
+    censored_parent: bool,
+) -> Result<(), OraError> {
+    for layer in layers.iter().rev() {
+        let censored = censored_parent || layer.censored;
+        let pixelate = match censor {
+            CensorPolicy::Hide if censored => continue,
+            CensorPolicy::Pixelate(block) if censored => Some(block),
+            _ => None,
+        };
+
+        let mut attrs = format!(
+            "name=\"{}\" opacity=\"{}\" visibility=\"{}\" composite-op=\"{}\"",
+            escape(&layer.title),
+            layer.opacity,
+            if layer.hidden { "hidden" } else { "visible" },
+            layer.blendmode.svg_name()
+        );
+        if layer.censored {
+            attrs.push_str(" drawpile:censored=\"true\"");
+        }
+        if layer.fixed {
+            attrs.push_str(" drawpile:fixed=\"true\"");
+        }
+        if layer.clip {
+            attrs.push_str(" drawpile:clip=\"true\"");
+        }
+        if let Some(mask) = layer.mask() {
+            let name = format!("data/mask-{}.png", mask.id);
+            let values: Vec<u8> = (0..mask.height())
+                .flat_map(|y| (0..mask.width()).map(move |x| mask.value_at(x, y)))
+                .collect();
+            write_png_gray(zip, &name, &values, mask.width(), mask.height())?;
+            attrs.push_str(&format!(" drawpile:mask=\"{}\"", name));
+        }
+
+        if layer.is_group() {
+            xml.push_str(&format!(
+                "<stack {} isolation=\"{}\">\n",
+                attrs,
+                if layer.passthrough { "auto" } else { "isolate" }
+            ));
+            let children: Vec<&Layer> = layer.iter_children().collect();
+            write_layers(zip, xml, &children, censor, censored)?;
+            xml.push_str("</stack>\n");
+        } else {
+            let name = format!("data/layer-{}.png", layer.id);
+            let (w, h) = layer.size();
+            let pixels = match pixelate {
+                Some(block) => pixelated_pixels(layer, block),
+                None => read_pixels(layer, &Rectangle::new(0, 0, w as i32, h as i32)),
+            };
+            write_png(zip, &name, &to_rgba(&pixels), w, h)?;
+            xml.push_str(&format!(
+                "<layer {} src=\"{}\" x=\"0\" y=\"0\"/>\n",
+                attrs, name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Load an OpenRaster image as a layer stack.
+///
+/// Layers, masks and annotations are given sequential IDs
+/// starting from `FIRST_IMPORTED_ID`. To start a session from the
+/// loaded image, use `canvas::snapshot::make_reset_image` to turn it
+/// into messages.
+pub fn load_openraster<R: Read + Seek>(reader: R) -> Result<LayerStack, OraError> {
+    let mut zip = ZipArchive::new(reader)?;
+
+    let mimetype = read_file(&mut zip, "mimetype")?;
+    if mimetype != MIMETYPE {
+        return Err(OraError::Invalid("wrong mimetype"));
+    }
+
+    let xml = String::from_utf8(read_file(&mut zip, "stack.xml")?)
+        .map_err(|_| OraError::Invalid("stack.xml is not UTF-8"))?;
+    let doc = Document::parse(&xml)?;
+    let image = doc.root_element();
+    if !image.has_tag_name("image") {
+        return Err(OraError::Invalid("root element is not <image>"));
+    }
+
+    let width = size_attribute(&image, "w")?;
+    let height = size_attribute(&image, "h")?;
+    if width == 0 || height == 0 {
+        return Err(OraError::Invalid("empty canvas"));
+    }
+    if !size_in_limits(width, height) {
+        return Err(OraError::Invalid("canvas is too large"));
+    }
+    let mut layerstack = LayerStack::new(width, height);
+
+    if let Some(name) = image.attribute((DP_NAMESPACE, "background")) {
+        let (pixels, w, h) = read_png(&mut zip, name)?;
+        if w != TILE_SIZE || h != TILE_SIZE {
+            return Err(OraError::Invalid("background tile has the wrong size"));
+        }
+        let mut tile = Tile::from_data(&pixels, 0);
+        tile.optimize();
+        layerstack.background = tile;
+    }
+
+    let root = image
+        .children()
+        .find(|n| n.has_tag_name("stack"))
+        .ok_or(OraError::Invalid("no root stack"))?;
+
+    let mut next_id = FIRST_IMPORTED_ID;
+    load_stack(&mut zip, &mut layerstack, &root, None, &mut next_id)?;
+
+    let annotations = image
+        .children()
+        .filter(|n| n.has_tag_name((DP_NAMESPACE, "annotations")))
+        .flat_map(|n| n.children())
+        .filter(|n| n.has_tag_name((DP_NAMESPACE, "a")));
+
+    for (a, id) in annotations.zip(FIRST_IMPORTED_ID as AnnotationID..) {
+        let w = size_attribute(&a, "w")?;
+        let h = size_attribute(&a, "h")?;
+        if w > MAX_SIZE || h > MAX_SIZE {
+            return Err(OraError::Invalid("annotation is too large"));
+        }
+        let rect = Rectangle::new(
+            int_attribute(&a, "x")?,
+            int_attribute(&a, "y")?,
+            w.max(1) as i32,
+            h.max(1) as i32,
+        );
+        layerstack.add_annotation(id, rect);
+        let annotation = layerstack.get_annotation_mut(id).unwrap();
+        annotation.text = a.text().unwrap_or("").to_string();
+        annotation.background = a
+            .attribute("bg")
+            .and_then(|bg| u32::from_str_radix(bg.trim_start_matches('#'), 16).ok())
+            .map_or(Color::TRANSPARENT, Color::from_argb32);
+        annotation.protect = a.attribute("protect") == Some("true");
+        annotation.valign = match a.attribute("valign") {
+            Some("center") => VAlign::Center,
+            Some("bottom") => VAlign::Bottom,
+            _ => VAlign::Top,
+        };
+    }
+
+    Ok(layerstack)
+}
+
+/// Add the layers of a stack element (and its sub-stacks) into the given group
+fn load_stack<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    layerstack: &mut LayerStack,
+    stack: &Node,
+    parent: Option<LayerID>,
+    next_id: &mut LayerID,
+) -> Result<(), OraError> {
+    let elements: Vec<Node> = stack
+        .children()
+        .filter(|n| n.has_tag_name("layer") || n.has_tag_name("stack"))
+        .collect();
+
+    // Elements are listed top to bottom, but layers are added bottom-most first
+    let mut sibling = None;
+    for element in elements.iter().rev() {
+        let id = *next_id;
+        *next_id += 1;
+
+        let is_group = element.has_tag_name("stack");
+        layerstack.add_layer(
+            id,
+            if is_group {
+                LayerFill::Group
+            } else {
+                LayerFill::Solid(Color::TRANSPARENT)
+            },
+            LayerInsertion::Top,
+        );
+        if parent.is_some() {
+            layerstack.move_layer(id, parent, sibling);
+        }
+        sibling = Some(id);
+
+        if is_group {
+            load_stack(zip, layerstack, element, Some(id), next_id)?;
+        } else if let Some(src) = element.attribute("src") {
+            let pixels = read_layer_png(
+                zip,
+                src,
+                int_attribute(element, "x").unwrap_or(0),
+                int_attribute(element, "y").unwrap_or(0),
+                layerstack.width(),
+                layerstack.height(),
+            )?;
+            let content = Layer::from_image(&pixels, layerstack.width(), layerstack.height());
+            *layerstack.get_layer_mut(id).unwrap().tilevec_mut() = content.tilevec().clone();
+        }
+
+        let mask = match element.attribute((DP_NAMESPACE, "mask")) {
+            Some(src) => {
+                let mask_id = *next_id;
+                *next_id += 1;
+                Some(read_mask(
+                    zip,
+                    src,
+                    mask_id,
+                    layerstack.width(),
+                    layerstack.height(),
+                )?)
+            }
+            None => None,
+        };
+
+        let layer = layerstack.get_layer_mut(id).unwrap();
+        layer.title = element.attribute("name").unwrap_or("").to_string();
+        layer.opacity = element
+            .attribute("opacity")
+            .and_then(|o| o.parse::<f32>().ok())
+            .map_or(1.0, |o| o.clamp(0.0, 1.0));
+        layer.hidden = element.attribute("visibility") == Some("hidden");
+        layer.blendmode = element
+            .attribute("composite-op")
+            .and_then(Blendmode::from_svg_name)
+            .unwrap_or_default();
+        layer.censored = element.attribute((DP_NAMESPACE, "censored")) == Some("true");
+        layer.fixed = element.attribute((DP_NAMESPACE, "fixed")) == Some("true");
+        layer.clip = element.attribute((DP_NAMESPACE, "clip")) == Some("true");
+        layer.passthrough = is_group && element.attribute("isolation") == Some("auto");
+        layer.set_mask(mask);
+    }
+
+    Ok(())
+}
+
+/// Read a layer image and place it on a canvas sized pixel buffer
+fn read_layer_png<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    name: &str,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+) -> Result<Vec<Pixel>, OraError> {
+    let (image, w, h) = read_png(zip, name)?;
+    let mut pixels = vec![ZERO_PIXEL; width as usize * height as usize];
+
+    // An image placed so far away that its far edge does not fit
+    // in an i32 cannot be on the canvas
+    if w == 0 || h == 0 || x.checked_add(w as i32).is_none() || y.checked_add(h as i32).is_none() {
+        return Ok(pixels);
+    }
+
+    let rect = Rectangle::new(x, y, w as i32, h as i32);
+    if let Some(r) = rect.cropped(width, height) {
+        for row in r.y..=r.bottom() {
+            let src = (row - y) as usize * w as usize + (r.x - x) as usize;
+            let dest = row as usize * width as usize + r.x as usize;
+            pixels[dest..dest + r.w as usize].copy_from_slice(&image[src..src + r.w as usize]);
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Read a grayscale mask image
+fn read_mask<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    name: &str,
+    id: LayerID,
+    width: u32,
+    height: u32,
+) -> Result<LayerMask, OraError> {
+    let (image, w, h) = read_png(zip, name)?;
+    if w != width || h != height {
+        return Err(OraError::Invalid("mask is not the same size as the canvas"));
+    }
+
+    let mut mask = LayerMask::new(id, width, height, 255);
+    let xtiles = Tile::div_up(width);
+    for j in 0..Tile::div_up(height) {
+        for i in 0..xtiles {
+            let mut values = [255; TILE_LENGTH];
+            let r = Rectangle::tile(i as i32, j as i32, TILE_SIZE as i32)
+                .cropped(width, height)
+                .unwrap();
+            for y in 0..r.h as u32 {
+                for x in 0..r.w as u32 {
+                    let p = image
+                        [(r.y as u32 + y) as usize * width as usize + (r.x as u32 + x) as usize];
+                    values[(y * TILE_SIZE + x) as usize] = p[RED_CHANNEL];
+                }
+            }
+            let mut tile = MaskTile::Bitmap(Arc::new(values));
+            tile.optimize();
+            mask.put_tile(i, j, 0, &tile);
+        }
+    }
+
+    Ok(mask)
+}
+
+fn read_file<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>, OraError> {
+    let mut file = match zip.by_name(name) {
+        Ok(f) => f,
+        Err(ZipError::FileNotFound) => return Err(OraError::Invalid("missing file")),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Read a PNG file from the archive and convert it to premultiplied pixels
+fn read_png<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<(Vec<Pixel>, u32, u32), OraError> {
+    let data = read_file(zip, name)?;
+    let mut decoder = png::Decoder::new(&data[..]);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    if !size_in_limits(info.width, info.height) {
+        return Err(OraError::Invalid("image is too large"));
+    }
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+
+    let rgba: Vec<u8> = match info.color_type {
+        png::ColorType::RGBA => buf,
+        png::ColorType::RGB => buf
+            .chunks_exact(3)
+            .flat_map(|c| [c[0], c[1], c[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|c| [c[0], c[0], c[0], c[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&c| [c, c, c, 255]).collect(),
+        png::ColorType::Indexed => return Err(OraError::Invalid("unexpanded indexed PNG")),
+    };
+
+    Ok((from_rgba(&rgba), info.width, info.height))
+}
+
+fn write_png<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), OraError> {
+    let data = encode_png(rgba, width, height, png::ColorType::RGBA)?;
+    zip.start_file(
+        name,
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(&data)?;
+    Ok(())
+}
+
+fn write_png_gray<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    values: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), OraError> {
+    let data = encode_png(values, width, height, png::ColorType::Grayscale)?;
+    zip.start_file(
+        name,
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(&data)?;
+    Ok(())
+}
+
+fn encode_png(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+) -> Result<Vec<u8>, OraError> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut buf, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(data)?;
+    }
+    Ok(buf)
+}
+
+/// Convert premultiplied BGRA pixels to non-premultiplied RGBA bytes
+fn to_rgba(pixels: &[Pixel]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(pixels.len() * 4);
+    for p in pixels {
+        let a = p[ALPHA_CHANNEL] as u32;
+        if a == 0 {
+            rgba.extend_from_slice(&[0, 0, 0, 0]);
+        } else {
+            let unpremultiply = |c: u8| ((c as u32 * 255 + a / 2) / a).min(255) as u8;
+            rgba.push(unpremultiply(p[RED_CHANNEL]));
+            rgba.push(unpremultiply(p[GREEN_CHANNEL]));
+            rgba.push(unpremultiply(p[BLUE_CHANNEL]));
+            rgba.push(a as u8);
+        }
+    }
+    rgba
+}
+
+/// Convert non-premultiplied RGBA bytes to premultiplied BGRA pixels
+fn from_rgba(rgba: &[u8]) -> Vec<Pixel> {
+    rgba.chunks_exact(4)
+        .map(|c| {
+            let a = c[3] as u32;
+            let premultiply = |v: u8| ((v as u32 * a + 127) / 255) as u8;
+            let mut p = ZERO_PIXEL;
+            p[RED_CHANNEL] = premultiply(c[0]);
+            p[GREEN_CHANNEL] = premultiply(c[1]);
+            p[BLUE_CHANNEL] = premultiply(c[2]);
+            p[ALPHA_CHANNEL] = c[3];
+            p
+        })
+        .collect()
+}
+
+/// Read the whole layer as an image with each tile pixelated the
+/// same way censored layers are pixelated when flattening
+fn pixelated_pixels(layer: &Layer, block_size: u32) -> Vec<Pixel> {
+    let (width, height) = layer.size();
+    let mut pixels = vec![ZERO_PIXEL; (width * height) as usize];
+    let ts = TILE_SIZE as usize;
+
+    for j in 0..Tile::div_up(height) {
+        for i in 0..Tile::div_up(width) {
+            let tile = layer.tile(i, j);
+            if *tile == Tile::Blank {
+                continue;
+            }
+            let mut data = tile.clone_data();
+            data.pixelate(block_size);
+
+            let x = (i * TILE_SIZE) as usize;
+            let w = ts.min(width as usize - x);
+            let h = ts.min((height - j * TILE_SIZE) as usize);
+            for row in 0..h {
+                let dest = (j as usize * ts + row) * width as usize + x;
+                pixels[dest..dest + w].copy_from_slice(&data.pixels[row * ts..row * ts + w]);
+            }
+        }
+    }
+
+    pixels
+}
+
+/// Scale the image down to thumbnail size by averaging blocks of pixels
+fn thumbnail(image: &[Pixel], width: u32, height: u32) -> (Vec<Pixel>, u32, u32) {
+    let longest = width.max(height);
+    if longest <= THUMBNAIL_SIZE {
+        return (image.to_vec(), width, height);
+    }
+
+    let tw = (width * THUMBNAIL_SIZE / longest).max(1);
+    let th = (height * THUMBNAIL_SIZE / longest).max(1);
+    let mut thumb = Vec::with_capacity((tw * th) as usize);
+
+    for ty in 0..th {
+        let (y0, y1) = (ty * height / th, (ty + 1) * height / th);
+        for tx in 0..tw {
+            let (x0, x1) = (tx * width / tw, (tx + 1) * width / tw);
+            let mut sum = [0u32; 4];
+            for y in y0..y1 {
+                for p in &image[(y * width + x0) as usize..(y * width + x1) as usize] {
+                    for (s, &c) in sum.iter_mut().zip(p.iter()) {
+                        *s += c as u32;
+                    }
+                }
+            }
+            let n = (x1 - x0) * (y1 - y0);
+            thumb.push([
+                (sum[0] / n) as u8,
+                (sum[1] / n) as u8,
+                (sum[2] / n) as u8,
+                (sum[3] / n) as u8,
+            ]);
+        }
+    }
+
+    (thumb, tw, th)
+}
+
+fn size_in_limits(width: u32, height: u32) -> bool {
+    width <= MAX_SIZE && height <= MAX_SIZE && width as u64 * height as u64 <= MAX_AREA
+}
+
+fn size_attribute(node: &Node, name: &str) -> Result<u32, OraError> {
+    node.attribute(name)
+        .and_then(|v| v.parse().ok())
+        .ok_or(OraError::Invalid("missing or invalid size attribute"))
+}
+
+fn int_attribute(node: &Node, name: &str) -> Result<i32, OraError> {
+    node.attribute(name)
+        .and_then(|v| v.parse().ok())
+        .ok_or(OraError::Invalid("missing or invalid coordinate attribute"))
+}
+
+/// Escape text for use in XML attributes and content
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_premultiplication_roundtrip() {
+        let pixels: Vec<Pixel> = (0..=255u8)
+            .flat_map(|a| (0..=a).map(move |c| [c, c / 2, a - c, a]))
+            .collect();
+        assert_eq!(from_rgba(&to_rgba(&pixels)), pixels);
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let image = vec![[10, 20, 30, 255]; 1000 * 500];
+        let (thumb, w, h) = thumbnail(&image, 1000, 500);
+        assert_eq!((w, h), (256, 128));
+        assert!(thumb.iter().all(|&p| p == [10, 20, 30, 255]));
+
+        let (_, w, h) = thumbnail(&image[..100], 10, 10);
+        assert_eq!((w, h), (10, 10));
+    }
+
+    #[test]
+    fn test_composite_op_names() {
+        for mode in 0..=255u8 {
+            if let Ok(mode) = Blendmode::try_from(mode) {
+                assert_eq!(Blendmode::from_svg_name(mode.svg_name()), Some(mode));
+            }
+        }
+        assert_eq!(Blendmode::from_svg_name("svg:xor"), None);
+    }
+}
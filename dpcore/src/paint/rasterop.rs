@@ -21,9 +21,21 @@
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::color::*;
+#[cfg(target_arch = "x86_64")]
+use super::simd;
 use super::Blendmode;
 
 pub fn pixel_blend(base: &mut [Pixel], over: &[Pixel], opacity: u8, mode: Blendmode) {
+    #[cfg(target_arch = "x86_64")]
+    let (base, over) = {
+        let done = simd::pixel_blend(base, over, opacity, mode);
+        (&mut base[done..], &over[done..])
+    };
+
+    pixel_blend_scalar(base, over, opacity, mode);
+}
+
+fn pixel_blend_scalar(base: &mut [Pixel], over: &[Pixel], opacity: u8, mode: Blendmode) {
     match mode {
         Blendmode::Normal => alpha_pixel_blend(base, over, opacity),
         Blendmode::Erase => alpha_pixel_erase(base, over, opacity),
@@ -36,6 +48,15 @@ pub fn pixel_blend(base: &mut [Pixel], over: &[Pixel], opacity: u8, mode: Blendm
         Blendmode::Add => pixel_composite(comp_op_add, base, over, opacity),
         Blendmode::Subtract => pixel_composite(comp_op_subtract, base, over, opacity),
         Blendmode::Recolor => pixel_composite(comp_op_recolor, base, over, opacity),
+        Blendmode::Screen => pixel_composite(comp_op_screen, base, over, opacity),
+        Blendmode::Overlay => pixel_composite(comp_op_overlay, base, over, opacity),
+        Blendmode::SoftLight => pixel_composite(comp_op_softlight, base, over, opacity),
+        Blendmode::HardLight => pixel_composite(comp_op_hardlight, base, over, opacity),
+        Blendmode::Difference => pixel_composite(comp_op_difference, base, over, opacity),
+        Blendmode::Hue => pixel_composite_nonsep(comp_op_hue, base, over, opacity),
+        Blendmode::Saturation => pixel_composite_nonsep(comp_op_saturation, base, over, opacity),
+        Blendmode::Color => pixel_composite_nonsep(comp_op_color, base, over, opacity),
+        Blendmode::Luminosity => pixel_composite_nonsep(comp_op_luminosity, base, over, opacity),
         Blendmode::Behind => alpha_pixel_under(base, over, opacity),
         Blendmode::ColorErase => pixel_color_erase(base, over, opacity),
         Blendmode::Replace => pixel_replace(base, over, opacity),
@@ -43,6 +64,16 @@ pub fn pixel_blend(base: &mut [Pixel], over: &[Pixel], opacity: u8, mode: Blendm
 }
 
 pub fn mask_blend(base: &mut [Pixel], color: Pixel, mask: &[u8], mode: Blendmode) {
+    #[cfg(target_arch = "x86_64")]
+    let (base, mask) = {
+        let done = simd::mask_blend(base, color, mask, mode);
+        (&mut base[done..], &mask[done..])
+    };
+
+    mask_blend_scalar(base, color, mask, mode);
+}
+
+fn mask_blend_scalar(base: &mut [Pixel], color: Pixel, mask: &[u8], mode: Blendmode) {
     match mode {
         Blendmode::Normal => alpha_mask_blend(base, color, mask),
         Blendmode::Erase => alpha_mask_erase(base, mask),
@@ -55,9 +86,66 @@ pub fn mask_blend(base: &mut [Pixel], color: Pixel, mask: &[u8], mode: Blendmode
         Blendmode::Add => mask_composite(comp_op_add, base, color, mask),
         Blendmode::Subtract => mask_composite(comp_op_subtract, base, color, mask),
         Blendmode::Recolor => mask_composite(comp_op_recolor, base, color, mask),
+        Blendmode::Screen => mask_composite(comp_op_screen, base, color, mask),
+        Blendmode::Overlay => mask_composite(comp_op_overlay, base, color, mask),
+        Blendmode::SoftLight => mask_composite(comp_op_softlight, base, color, mask),
+        Blendmode::HardLight => mask_composite(comp_op_hardlight, base, color, mask),
+        Blendmode::Difference => mask_composite(comp_op_difference, base, color, mask),
+        Blendmode::Hue => mask_composite_nonsep(comp_op_hue, base, color, mask),
+        Blendmode::Saturation => mask_composite_nonsep(comp_op_saturation, base, color, mask),
+        Blendmode::Color => mask_composite_nonsep(comp_op_color, base, color, mask),
+        Blendmode::Luminosity => mask_composite_nonsep(comp_op_luminosity, base, color, mask),
         Blendmode::Behind => alpha_mask_under(base, color, mask),
         Blendmode::ColorErase => mask_color_erase(base, color, mask),
-        m => panic!("TODO unimplemented mask blend mode {:?}", m),
+        Blendmode::Replace => mask_replace(base, color, mask),
+    }
+}
+
+/// Interpolate between the base and the overlay pixels, including the alpha channel
+pub fn pixel_interpolate(base: &mut [Pixel], over: &[Pixel], opacity: u8) {
+    for (dp, sp) in base.iter_mut().zip(over.iter()) {
+        interpolate_pixel(dp, sp, opacity as u32);
+    }
+}
+
+/// Interpolate between the base and the overlay pixels using per-pixel opacity values
+pub fn mask_interpolate(base: &mut [Pixel], over: &[Pixel], mask: &[u8]) {
+    debug_assert!(base.len() == mask.len());
+
+    for ((dp, sp), &m) in base.iter_mut().zip(over.iter()).zip(mask.iter()) {
+        interpolate_pixel(dp, sp, m as u32);
+    }
+}
+
+fn interpolate_pixel(dp: &mut Pixel, sp: &Pixel, o: u32) {
+    let a = 255 - o;
+    let bp = dp.into_work();
+    let src = sp.into_work();
+
+    let result = [
+        u8_mult(src[0], o) + u8_mult(bp[0], a),
+        u8_mult(src[1], o) + u8_mult(bp[1], a),
+        u8_mult(src[2], o) + u8_mult(bp[2], a),
+        u8_mult(src[3], o) + u8_mult(bp[3], a),
+    ];
+
+    *dp = Pixel::from_work(result);
+}
+
+/// Multiply all channels of the pixels by the mask values
+pub fn apply_mask(base: &mut [Pixel], mask: &[u8]) {
+    debug_assert!(base.len() == mask.len());
+
+    for (dp, &mask) in base.iter_mut().zip(mask.iter()) {
+        let bp = dp.into_work();
+        let m = mask as u32;
+
+        *dp = [
+            u8_mult(bp[0], m) as u8,
+            u8_mult(bp[1], m) as u8,
+            u8_mult(bp[2], m) as u8,
+            u8_mult(bp[3], m) as u8,
+        ];
     }
 }
 
@@ -225,6 +313,28 @@ fn pixel_replace(base: &mut [Pixel], over: &[Pixel], opacity: u8) {
     }
 }
 
+/// Replace pixels with the given color, including its alpha channel.
+/// The mask value is used to interpolate between the old and the new value.
+fn mask_replace(base: &mut [Pixel], color: Pixel, mask: &[u8]) {
+    debug_assert!(base.len() == mask.len());
+    let c = color.into_work();
+
+    for (dp, &mask) in base.iter_mut().zip(mask.iter()) {
+        let bp = dp.into_work();
+        let m = mask as u32;
+        let a = 255 - m;
+
+        let result = [
+            u8_mult(c[0], m) + u8_mult(bp[0], a),
+            u8_mult(c[1], m) + u8_mult(bp[1], a),
+            u8_mult(c[2], m) + u8_mult(bp[2], a),
+            u8_mult(c[3], m) + u8_mult(bp[3], a),
+        ];
+
+        *dp = Pixel::from_work(result);
+    }
+}
+
 /// Perform a premultiplied alpha blend on a slice of 32 bit ARGB pixels
 /// and a color + alpha mask vector.
 fn alpha_mask_blend(base: &mut [Pixel], color: Pixel, mask: &[u8]) {
@@ -320,6 +430,107 @@ fn comp_op_recolor(_: f32, b: f32) -> f32 {
     b
 }
 
+fn comp_op_screen(a: f32, b: f32) -> f32 {
+    a + b - a * b
+}
+
+fn comp_op_overlay(a: f32, b: f32) -> f32 {
+    comp_op_hardlight(b, a)
+}
+
+fn comp_op_hardlight(a: f32, b: f32) -> f32 {
+    if b <= 0.5 {
+        a * 2.0 * b
+    } else {
+        comp_op_screen(a, 2.0 * b - 1.0)
+    }
+}
+
+fn comp_op_softlight(a: f32, b: f32) -> f32 {
+    // The W3C compositing spec version of the soft light formula
+    if b <= 0.5 {
+        a - (1.0 - 2.0 * b) * a * (1.0 - a)
+    } else {
+        let d = if a <= 0.25 {
+            ((16.0 * a - 12.0) * a + 4.0) * a
+        } else {
+            a.sqrt()
+        };
+        a + (2.0 * b - 1.0) * (d - a)
+    }
+}
+
+fn comp_op_difference(a: f32, b: f32) -> f32 {
+    (a - b).abs()
+}
+
+// Helper functions for the non-separable blend modes.
+// See https://www.w3.org/TR/compositing-1/#blendingnonseparable
+
+type Rgb = [f32; 3];
+
+fn lum(c: Rgb) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn clip_color(c: Rgb) -> Rgb {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut c = c;
+    if n < 0.0 {
+        for v in c.iter_mut() {
+            *v = l + (*v - l) * l / (l - n);
+        }
+    }
+    if x > 1.0 {
+        for v in c.iter_mut() {
+            *v = l + (*v - l) * (1.0 - l) / (x - l);
+        }
+    }
+    c
+}
+
+fn set_lum(c: Rgb, l: f32) -> Rgb {
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+fn sat(c: Rgb) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: Rgb, s: f32) -> Rgb {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max > min {
+        let scale = s / (max - min);
+        [
+            (c[0] - min) * scale,
+            (c[1] - min) * scale,
+            (c[2] - min) * scale,
+        ]
+    } else {
+        [0.0, 0.0, 0.0]
+    }
+}
+
+fn comp_op_hue(a: Rgb, b: Rgb) -> Rgb {
+    set_lum(set_sat(b, sat(a)), lum(a))
+}
+
+fn comp_op_saturation(a: Rgb, b: Rgb) -> Rgb {
+    set_lum(set_sat(a, sat(b)), lum(a))
+}
+
+fn comp_op_color(a: Rgb, b: Rgb) -> Rgb {
+    set_lum(b, lum(a))
+}
+
+fn comp_op_luminosity(a: Rgb, b: Rgb) -> Rgb {
+    set_lum(a, lum(b))
+}
+
 /// Generic alpha-preserving compositing operations
 fn pixel_composite(comp_op: fn(f32, f32) -> f32, base: &mut [Pixel], over: &[Pixel], opacity: u8) {
     let of = opacity as f32 / 255.0;
@@ -353,6 +564,51 @@ fn mask_composite(comp_op: fn(f32, f32) -> f32, base: &mut [Pixel], color: Pixel
     }
 }
 
+/// Alpha-preserving compositing operations that operate on whole colors
+fn pixel_composite_nonsep(
+    comp_op: fn(Rgb, Rgb) -> Rgb,
+    base: &mut [Pixel],
+    over: &[Pixel],
+    opacity: u8,
+) {
+    let of = opacity as f32 / 255.0;
+    for (dp, sp) in base.iter_mut().zip(over.iter()) {
+        let mut dc = Color::from_pixel(*dp);
+        let sc = Color::from_pixel(*sp);
+
+        let alpha = sc.a * of;
+        let c = comp_op([dc.r, dc.g, dc.b], [sc.r, sc.g, sc.b]);
+
+        dc.r = blend(c[0], dc.r, alpha);
+        dc.g = blend(c[1], dc.g, alpha);
+        dc.b = blend(c[2], dc.b, alpha);
+
+        *dp = dc.as_pixel();
+    }
+}
+
+fn mask_composite_nonsep(
+    comp_op: fn(Rgb, Rgb) -> Rgb,
+    base: &mut [Pixel],
+    color: Pixel,
+    mask: &[u8],
+) {
+    debug_assert!(base.len() == mask.len());
+    let c = Color::from_pixel(color);
+    let src = [c.r, c.g, c.b];
+    for (dp, &mask) in base.iter_mut().zip(mask.iter()) {
+        let mut d = Color::from_pixel(*dp);
+        let m = mask as f32 / 255.0;
+        let r = comp_op([d.r, d.g, d.b], src);
+
+        d.r = blend(r[0], d.r, m);
+        d.g = blend(r[1], d.g, m);
+        d.b = blend(r[2], d.b, m);
+
+        *dp = d.as_pixel();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -416,4 +672,226 @@ mod tests {
             [[0, 0, 0, 0], [127, 127, 127, 127], [255, 255, 255, 255]]
         );
     }
+
+    #[test]
+    fn test_new_blend_modes() {
+        // Reference values calculated with double precision using the
+        // formulas from the W3C compositing spec
+        let base = Color::rgb8(200, 100, 50).as_pixel();
+        let over = Color::rgb8(50, 150, 250).as_pixel();
+        let expected = [
+            (Blendmode::Screen, [210, 191, 250]),
+            (Blendmode::Overlay, [166, 117, 98]),
+            (Blendmode::SoftLight, [173, 110, 110]),
+            (Blendmode::HardLight, [78, 127, 246]),
+            (Blendmode::Difference, [150, 50, 200]),
+            (Blendmode::Hue, [63, 138, 213]),
+            (Blendmode::Saturation, [225, 91, 25]),
+            (Blendmode::Color, [43, 143, 243]),
+            (Blendmode::Luminosity, [206, 106, 56]),
+        ];
+
+        for (mode, rgb) in expected.iter() {
+            let mut pb = [base];
+            pixel_blend(&mut pb, &[over], 255, *mode);
+
+            let mut mb = [base];
+            mask_blend(&mut mb, over, &[255], *mode);
+
+            let result = [
+                pb[0][RED_CHANNEL],
+                pb[0][GREEN_CHANNEL],
+                pb[0][BLUE_CHANNEL],
+            ];
+            for (&r, &e) in result.iter().zip(rgb.iter()) {
+                assert!(
+                    (r as i32 - e).abs() <= 1,
+                    "{:?}: got {:?}, expected {:?}",
+                    mode,
+                    result,
+                    rgb
+                );
+            }
+            assert_eq!(pb[0][ALPHA_CHANNEL], 255);
+            assert_eq!(pb, mb, "{:?}: pixel and mask blend differ", mode);
+
+            // Zero opacity leaves the base unchanged
+            let mut pb = [base];
+            pixel_blend(&mut pb, &[over], 0, *mode);
+            assert_eq!(pb, [base]);
+        }
+    }
+
+    #[test]
+    fn test_mask_replace() {
+        let mut base = [[255, 255, 255, 255]; 3];
+        let mask = [0xff, 0x80, 0x00];
+
+        // Replacing with a half transparent color reduces opacity
+        mask_blend(&mut base, [0, 0, 128, 128], &mask, Blendmode::Replace);
+        assert_eq!(
+            base,
+            [[0, 0, 128, 128], [127, 127, 191, 191], [255, 255, 255, 255]]
+        );
+    }
+
+    #[test]
+    fn test_all_modes_are_total() {
+        use std::convert::TryFrom;
+
+        // No blending mode may panic, whatever the input
+        for id in 0..=255u8 {
+            if let Ok(mode) = Blendmode::try_from(id) {
+                let mut base = [[0, 0, 0, 0], [10, 20, 30, 40], [255, 255, 255, 255]];
+                mask_blend(&mut base, [1, 2, 3, 4], &[0, 128, 255], mode);
+                pixel_blend(&mut base, &[[0, 0, 0, 0]; 3], 255, mode);
+                pixel_blend(&mut base, &[[255, 255, 255, 255]; 3], 128, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_blendmode_ids() {
+        use std::convert::TryFrom;
+        assert_eq!(u8::from(Blendmode::ColorErase), 12);
+        assert_eq!(u8::from(Blendmode::Screen), 13);
+        assert_eq!(u8::from(Blendmode::Luminosity), 21);
+        assert_eq!(Blendmode::try_from(255), Ok(Blendmode::Replace));
+        assert!(Blendmode::try_from(22).is_err());
+    }
+
+    /// Pseudo-random test data, including pixels that aren't validly premultiplied
+    #[cfg(target_arch = "x86_64")]
+    fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn random_pixels(len: usize, seed: u32) -> Vec<Pixel> {
+        let mut pixels: Vec<Pixel> = random_bytes(len * 4, seed)
+            .chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect();
+
+        // Make sure the edge cases are included
+        pixels[0] = [0, 0, 0, 0];
+        pixels[1] = [255, 255, 255, 255];
+        pixels[2] = [10, 20, 30, 0];
+        pixels[3] = [1, 2, 3, 255];
+        pixels
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_simd_matches_scalar() {
+        use super::simd::{
+            alpha_mask_blend_avx2, alpha_mask_blend_sse2, alpha_pixel_blend_avx2,
+            alpha_pixel_blend_sse2, mask_composite_sse2, pixel_composite_sse2, CompOp,
+        };
+
+        const LEN: usize = 67;
+        let base = random_pixels(LEN, 1);
+        let over = random_pixels(LEN, 2);
+        let mut mask = random_bytes(LEN, 3);
+        mask[0] = 0;
+        mask[1] = 255;
+
+        type SimdFn<T> = unsafe fn(&mut [Pixel], T, &[u8]) -> usize;
+        type SimdPixelFn = unsafe fn(&mut [Pixel], &[Pixel], u8) -> usize;
+
+        let mut mask_impls: Vec<SimdFn<Pixel>> = Vec::new();
+        let mut pixel_impls: Vec<SimdPixelFn> = Vec::new();
+        if is_x86_feature_detected!("sse2") {
+            mask_impls.push(alpha_mask_blend_sse2);
+            pixel_impls.push(alpha_pixel_blend_sse2);
+        }
+        if is_x86_feature_detected!("avx2") {
+            mask_impls.push(alpha_mask_blend_avx2);
+            pixel_impls.push(alpha_pixel_blend_avx2);
+        }
+
+        for &color in &over[..8] {
+            let mut expected = base.clone();
+            alpha_mask_blend(&mut expected, color, &mask);
+
+            for imp in &mask_impls {
+                let mut result = base.clone();
+                let done = unsafe { imp(&mut result, color, &mask) };
+                alpha_mask_blend(&mut result[done..], color, &mask[done..]);
+                assert_eq!(result, expected);
+            }
+        }
+
+        for &opacity in &[0, 1, 128, 254, 255] {
+            let mut expected = base.clone();
+            alpha_pixel_blend(&mut expected, &over, opacity);
+
+            for imp in &pixel_impls {
+                let mut result = base.clone();
+                let done = unsafe { imp(&mut result, &over, opacity) };
+                alpha_pixel_blend(&mut result[done..], &over[done..], opacity);
+                assert_eq!(result, expected);
+            }
+        }
+
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+
+        let modes = [
+            Blendmode::Multiply,
+            Blendmode::Darken,
+            Blendmode::Lighten,
+            Blendmode::Add,
+            Blendmode::Subtract,
+            Blendmode::Recolor,
+            Blendmode::Screen,
+            Blendmode::Difference,
+        ];
+
+        for &mode in &modes {
+            let op = CompOp::from_mode(mode).unwrap();
+
+            for &opacity in &[0, 128, 255] {
+                let mut expected = base.clone();
+                pixel_blend_scalar(&mut expected, &over, opacity, mode);
+
+                let mut result = base.clone();
+                let done = unsafe { pixel_composite_sse2(op, &mut result, &over, opacity) };
+                assert_eq!(done, LEN);
+                assert_eq!(result, expected, "{:?} opacity {}", mode, opacity);
+            }
+
+            for &color in &over[..8] {
+                let mut expected = base.clone();
+                mask_blend_scalar(&mut expected, color, &mask, mode);
+
+                let mut result = base.clone();
+                let done = unsafe { mask_composite_sse2(op, &mut result, color, &mask) };
+                assert_eq!(done, LEN);
+                assert_eq!(result, expected, "{:?} color {:?}", mode, color);
+            }
+        }
+
+        // The dispatching functions should pick the same results too
+        for &mode in &[Blendmode::Normal, Blendmode::Multiply, Blendmode::Erase] {
+            let mut expected = base.clone();
+            let mut result = base.clone();
+            mask_blend_scalar(&mut expected, over[5], &mask, mode);
+            mask_blend(&mut result, over[5], &mask, mode);
+            assert_eq!(result, expected);
+
+            let mut expected = base.clone();
+            let mut result = base.clone();
+            pixel_blend_scalar(&mut expected, &over, 200, mode);
+            pixel_blend(&mut result, &over, 200, mode);
+            assert_eq!(result, expected);
+        }
+    }
 }
@@ -0,0 +1,262 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use super::rect::Rectangle;
+
+/// A selection that limits where a user's drawing commands have an effect.
+///
+/// A selection is either a plain rectangle or a freeform polygon clipped
+/// to a rectangle. Polygons are rasterized into an 8 bit mask using the
+/// even-odd rule: a pixel is selected if its center is inside the polygon.
+#[derive(Clone, Debug)]
+pub struct Selection {
+    rect: Rectangle,
+    points: Arc<Vec<(i32, i32)>>,
+    bounds: Option<Rectangle>,
+    mask: Option<Arc<Vec<u8>>>,
+}
+
+impl Selection {
+    /// Construct a rectangular selection
+    pub fn rectangle(rect: Rectangle) -> Selection {
+        Selection {
+            rect,
+            points: Arc::new(Vec::new()),
+            bounds: Some(rect),
+            mask: None,
+        }
+    }
+
+    /// Construct a freeform selection from a polygon
+    ///
+    /// The polygon is clipped to the given rectangle and to the canvas
+    /// size, so the mask never needs to be larger than the canvas.
+    pub fn polygon(
+        rect: Rectangle,
+        points: Vec<(i32, i32)>,
+        canvas_width: u32,
+        canvas_height: u32,
+    ) -> Selection {
+        if points.len() < 3 {
+            return Selection {
+                rect,
+                points: Arc::new(points),
+                bounds: None,
+                mask: None,
+            };
+        }
+
+        // The polygon's extent may not fit in an i32, so it is clamped
+        // to the canvas before the bounding rectangle is made
+        let x0 = points.iter().map(|p| p.0).min().unwrap().max(0) as i64;
+        let y0 = points.iter().map(|p| p.1).min().unwrap().max(0) as i64;
+        let x1 = (points.iter().map(|p| p.0).max().unwrap() as i64).min(canvas_width as i64);
+        let y1 = (points.iter().map(|p| p.1).max().unwrap() as i64).min(canvas_height as i64);
+
+        let bounds = if x1 > x0 && y1 > y0 {
+            Rectangle::new(x0 as i32, y0 as i32, (x1 - x0) as i32, (y1 - y0) as i32)
+                .intersected(&rect)
+        } else {
+            None
+        };
+
+        let mask = bounds.map(|b| Arc::new(rasterize_polygon(&b, &points)));
+
+        Selection {
+            rect,
+            points: Arc::new(points),
+            bounds,
+            mask,
+        }
+    }
+
+    /// The rectangle this selection was defined with
+    pub fn rect(&self) -> &Rectangle {
+        &self.rect
+    }
+
+    /// The polygon vertices. Empty if this is a rectangular selection.
+    pub fn points(&self) -> &[(i32, i32)] {
+        &self.points
+    }
+
+    /// The bounding rectangle of the selected area.
+    /// None if nothing is selected.
+    pub fn bounds(&self) -> Option<&Rectangle> {
+        self.bounds.as_ref()
+    }
+
+    /// Return the selection mask value at the given coordinates
+    pub fn value_at(&self, x: i32, y: i32) -> u8 {
+        let b = match &self.bounds {
+            Some(b) => b,
+            None => return 0,
+        };
+        if x < b.x || y < b.y || x > b.right() || y > b.bottom() {
+            return 0;
+        }
+        match &self.mask {
+            Some(mask) => mask[((y - b.y) * b.w + (x - b.x)) as usize],
+            None => 255,
+        }
+    }
+
+    /// Check if the given rectangle is completely outside the selection
+    pub fn excludes(&self, rect: &Rectangle) -> bool {
+        match &self.bounds {
+            Some(b) => b.intersected(rect).is_none(),
+            None => true,
+        }
+    }
+
+    /// Get the selection mask for the given rectangle.
+    ///
+    /// Returns None if the rectangle is completely selected.
+    pub fn mask_for(&self, rect: &Rectangle) -> Option<Vec<u8>> {
+        if self.mask.is_none() {
+            if let Some(b) = &self.bounds {
+                if b.intersected(rect) == Some(*rect) {
+                    return None;
+                }
+            }
+        }
+
+        let mut mask = Vec::with_capacity((rect.w * rect.h) as usize);
+        for y in rect.y..rect.y + rect.h {
+            for x in rect.x..rect.x + rect.w {
+                mask.push(self.value_at(x, y));
+            }
+        }
+        Some(mask)
+    }
+}
+
+fn rasterize_polygon(bounds: &Rectangle, points: &[(i32, i32)]) -> Vec<u8> {
+    let mut mask = vec![0; (bounds.w * bounds.h) as usize];
+    let mut crossings = Vec::new();
+
+    for row in 0..bounds.h {
+        let y = (bounds.y + row) as f64 + 0.5;
+
+        crossings.clear();
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            let (y0, y1) = (y0 as f64, y1 as f64);
+            if (y0 <= y) != (y1 <= y) {
+                crossings.push(x0 as f64 + (y - y0) / (y1 - y0) * (x1 - x0) as f64);
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        // Fill the pixels whose centers are inside each span
+        let line = &mut mask[(row * bounds.w) as usize..((row + 1) * bounds.w) as usize];
+        for span in crossings.chunks_exact(2) {
+            let start = ((span[0] - 0.5).ceil() as i64 - bounds.x as i64).max(0);
+            let end = ((span[1] - 0.5).ceil() as i64 - bounds.x as i64).min(bounds.w as i64);
+            if start < end {
+                line[start as usize..end as usize]
+                    .iter_mut()
+                    .for_each(|v| *v = 255);
+            }
+        }
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rectangle() {
+        let sel = Selection::rectangle(Rectangle::new(10, 10, 20, 20));
+        assert_eq!(sel.value_at(9, 10), 0);
+        assert_eq!(sel.value_at(10, 10), 255);
+        assert_eq!(sel.value_at(29, 29), 255);
+        assert_eq!(sel.value_at(30, 29), 0);
+
+        assert!(sel.mask_for(&Rectangle::new(12, 12, 5, 5)).is_none());
+        assert_eq!(
+            sel.mask_for(&Rectangle::new(8, 10, 3, 1)),
+            Some(vec![0, 0, 255])
+        );
+        assert!(sel.excludes(&Rectangle::new(30, 0, 10, 10)));
+        assert!(!sel.excludes(&Rectangle::new(29, 0, 10, 11)));
+    }
+
+    #[test]
+    fn test_polygon() {
+        // A right triangle
+        let sel = Selection::polygon(
+            Rectangle::new(0, 0, 100, 100),
+            vec![(0, 0), (10, 0), (0, 10)],
+            100,
+            100,
+        );
+        assert_eq!(sel.bounds(), Some(&Rectangle::new(0, 0, 10, 10)));
+        assert_eq!(sel.value_at(0, 0), 255);
+        assert_eq!(sel.value_at(8, 0), 255);
+        assert_eq!(sel.value_at(9, 0), 0);
+        assert_eq!(sel.value_at(4, 4), 255);
+        assert_eq!(sel.value_at(5, 5), 0);
+        assert_eq!(sel.value_at(0, 8), 255);
+        assert_eq!(sel.value_at(0, 9), 0);
+
+        // Clipped by the selection rectangle
+        let sel = Selection::polygon(
+            Rectangle::new(0, 0, 5, 100),
+            vec![(0, 0), (10, 0), (10, 10), (0, 10)],
+            100,
+            100,
+        );
+        assert_eq!(sel.value_at(4, 4), 255);
+        assert_eq!(sel.value_at(5, 4), 0);
+
+        // Nothing selected
+        let sel = Selection::polygon(
+            Rectangle::new(0, 0, 100, 100),
+            vec![(200, 200), (300, 200), (300, 300)],
+            100,
+            100,
+        );
+        assert!(sel.bounds().is_none());
+        assert!(sel.excludes(&Rectangle::new(0, 0, 100, 100)));
+
+        // A polygon wider than the i32 range
+        let sel = Selection::polygon(
+            Rectangle::new(-10, -10, i32::MAX, i32::MAX),
+            vec![
+                (i32::MIN, i32::MIN),
+                (i32::MAX, i32::MIN),
+                (i32::MAX, i32::MAX),
+                (i32::MIN, i32::MAX),
+            ],
+            100,
+            100,
+        );
+        assert_eq!(sel.bounds(), Some(&Rectangle::new(0, 0, 100, 100)));
+        assert_eq!(sel.value_at(50, 50), 255);
+    }
+}
@@ -0,0 +1,361 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+//! SIMD implementations of the most common raster operations.
+//!
+//! The functions in this module process as many pixels as they can and
+//! return the number of pixels processed. The rest must be handled by the
+//! scalar implementation in `rasterop`. The results are identical to those
+//! of the scalar code.
+
+use super::color::{Pixel, ALPHA_CHANNEL};
+use super::Blendmode;
+
+use std::arch::x86_64::*;
+
+/// Blend as many pixels as possible using the best available instruction set
+pub fn pixel_blend(base: &mut [Pixel], over: &[Pixel], opacity: u8, mode: Blendmode) -> usize {
+    if let Some(op) = CompOp::from_mode(mode) {
+        return if is_x86_feature_detected!("sse2") {
+            unsafe { pixel_composite_sse2(op, base, over, opacity) }
+        } else {
+            0
+        };
+    }
+
+    match mode {
+        Blendmode::Normal => {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { alpha_pixel_blend_avx2(base, over, opacity) }
+            } else if is_x86_feature_detected!("sse2") {
+                unsafe { alpha_pixel_blend_sse2(base, over, opacity) }
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Blend as many pixels as possible using the best available instruction set
+pub fn mask_blend(base: &mut [Pixel], color: Pixel, mask: &[u8], mode: Blendmode) -> usize {
+    if let Some(op) = CompOp::from_mode(mode) {
+        return if is_x86_feature_detected!("sse2") {
+            unsafe { mask_composite_sse2(op, base, color, mask) }
+        } else {
+            0
+        };
+    }
+
+    match mode {
+        Blendmode::Normal => {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { alpha_mask_blend_avx2(base, color, mask) }
+            } else if is_x86_feature_detected!("sse2") {
+                unsafe { alpha_mask_blend_sse2(base, color, mask) }
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// u8_mult for each 16 bit lane
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn u8_mult_sse2(a: __m128i, b: __m128i) -> __m128i {
+    let c = _mm_add_epi16(_mm_mullo_epi16(a, b), _mm_set1_epi16(0x80));
+    _mm_srli_epi16(_mm_add_epi16(_mm_srli_epi16(c, 8), c), 8)
+}
+
+/// u8_mult for each 16 bit lane
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn u8_mult_avx2(a: __m256i, b: __m256i) -> __m256i {
+    let c = _mm256_add_epi16(_mm256_mullo_epi16(a, b), _mm256_set1_epi16(0x80));
+    _mm256_srli_epi16(_mm256_add_epi16(_mm256_srli_epi16(c, 8), c), 8)
+}
+
+/// Color with the alpha channel set to 255 as 16 bit lanes (two pixels)
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn opaque_color_sse2(color: Pixel) -> __m128i {
+    let (b, g, r) = (color[0] as i16, color[1] as i16, color[2] as i16);
+    _mm_set_epi16(255, r, g, b, 255, r, g, b)
+}
+
+/// Spread four mask values to each channel of four pixels
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn spread_mask_sse2(mask: &[u8]) -> __m128i {
+    let m = _mm_cvtsi32_si128(i32::from_le_bytes([mask[0], mask[1], mask[2], mask[3]]));
+    let m = _mm_unpacklo_epi8(m, m);
+    _mm_unpacklo_epi16(m, m)
+}
+
+/// See rasterop::alpha_mask_blend
+#[target_feature(enable = "sse2")]
+pub unsafe fn alpha_mask_blend_sse2(base: &mut [Pixel], color: Pixel, mask: &[u8]) -> usize {
+    let len = base.len().min(mask.len()) / 4 * 4;
+    let zero = _mm_setzero_si128();
+    let max = _mm_set1_epi16(255);
+    let low_bytes = _mm_set1_epi16(0xff);
+    let c = opaque_color_sse2(color);
+
+    let blend = |p: __m128i, m: __m128i| {
+        let result = _mm_add_epi16(u8_mult_sse2(c, m), u8_mult_sse2(p, _mm_sub_epi16(max, m)));
+        _mm_and_si128(result, low_bytes)
+    };
+
+    for i in (0..len).step_by(4) {
+        let ptr = base.as_mut_ptr().add(i) as *mut __m128i;
+        let px = _mm_loadu_si128(ptr);
+        let m = spread_mask_sse2(&mask[i..i + 4]);
+
+        let lo = blend(_mm_unpacklo_epi8(px, zero), _mm_unpacklo_epi8(m, zero));
+        let hi = blend(_mm_unpackhi_epi8(px, zero), _mm_unpackhi_epi8(m, zero));
+        _mm_storeu_si128(ptr, _mm_packus_epi16(lo, hi));
+    }
+
+    len
+}
+
+/// See rasterop::alpha_mask_blend
+#[target_feature(enable = "avx2")]
+pub unsafe fn alpha_mask_blend_avx2(base: &mut [Pixel], color: Pixel, mask: &[u8]) -> usize {
+    let len = base.len().min(mask.len()) / 8 * 8;
+    let zero = _mm256_setzero_si256();
+    let max = _mm256_set1_epi16(255);
+    let low_bytes = _mm256_set1_epi16(0xff);
+    let c = _mm256_broadcastsi128_si256(opaque_color_sse2(color));
+
+    let blend = |p: __m256i, m: __m256i| {
+        let result = _mm256_add_epi16(
+            u8_mult_avx2(c, m),
+            u8_mult_avx2(p, _mm256_sub_epi16(max, m)),
+        );
+        _mm256_and_si256(result, low_bytes)
+    };
+
+    for i in (0..len).step_by(8) {
+        let ptr = base.as_mut_ptr().add(i) as *mut __m256i;
+        let px = _mm256_loadu_si256(ptr);
+
+        // The unpack instructions work within 128 bit lanes, so the mask
+        // for the first four pixels goes in the low lane and the rest in the high
+        let m = _mm256_inserti128_si256(
+            _mm256_castsi128_si256(spread_mask_sse2(&mask[i..i + 4])),
+            spread_mask_sse2(&mask[i + 4..i + 8]),
+            1,
+        );
+
+        let lo = blend(
+            _mm256_unpacklo_epi8(px, zero),
+            _mm256_unpacklo_epi8(m, zero),
+        );
+        let hi = blend(
+            _mm256_unpackhi_epi8(px, zero),
+            _mm256_unpackhi_epi8(m, zero),
+        );
+        _mm256_storeu_si256(ptr, _mm256_packus_epi16(lo, hi));
+    }
+
+    len
+}
+
+/// See rasterop::alpha_pixel_blend
+#[target_feature(enable = "sse2")]
+pub unsafe fn alpha_pixel_blend_sse2(base: &mut [Pixel], over: &[Pixel], opacity: u8) -> usize {
+    let len = base.len().min(over.len()) / 4 * 4;
+    let zero = _mm_setzero_si128();
+    let max = _mm_set1_epi16(255);
+    let low_bytes = _mm_set1_epi16(0xff);
+    let o = _mm_set1_epi16(opacity as i16);
+
+    let blend = |d: __m128i, s: __m128i| {
+        let so = u8_mult_sse2(s, o);
+        // Spread the alpha channel to all channels of the pixel
+        let sa = _mm_shufflehi_epi16(_mm_shufflelo_epi16(so, 0xff), 0xff);
+        let result = _mm_add_epi16(so, u8_mult_sse2(d, _mm_sub_epi16(max, sa)));
+        _mm_and_si128(result, low_bytes)
+    };
+
+    for i in (0..len).step_by(4) {
+        let ptr = base.as_mut_ptr().add(i) as *mut __m128i;
+        let d = _mm_loadu_si128(ptr);
+        let s = _mm_loadu_si128(over.as_ptr().add(i) as *const __m128i);
+
+        let lo = blend(_mm_unpacklo_epi8(d, zero), _mm_unpacklo_epi8(s, zero));
+        let hi = blend(_mm_unpackhi_epi8(d, zero), _mm_unpackhi_epi8(s, zero));
+        _mm_storeu_si128(ptr, _mm_packus_epi16(lo, hi));
+    }
+
+    len
+}
+
+/// See rasterop::alpha_pixel_blend
+#[target_feature(enable = "avx2")]
+pub unsafe fn alpha_pixel_blend_avx2(base: &mut [Pixel], over: &[Pixel], opacity: u8) -> usize {
+    let len = base.len().min(over.len()) / 8 * 8;
+    let zero = _mm256_setzero_si256();
+    let max = _mm256_set1_epi16(255);
+    let low_bytes = _mm256_set1_epi16(0xff);
+    let o = _mm256_set1_epi16(opacity as i16);
+
+    let blend = |d: __m256i, s: __m256i| {
+        let so = u8_mult_avx2(s, o);
+        let sa = _mm256_shufflehi_epi16(_mm256_shufflelo_epi16(so, 0xff), 0xff);
+        let result = _mm256_add_epi16(so, u8_mult_avx2(d, _mm256_sub_epi16(max, sa)));
+        _mm256_and_si256(result, low_bytes)
+    };
+
+    for i in (0..len).step_by(8) {
+        let ptr = base.as_mut_ptr().add(i) as *mut __m256i;
+        let d = _mm256_loadu_si256(ptr);
+        let s = _mm256_loadu_si256(over.as_ptr().add(i) as *const __m256i);
+
+        let lo = blend(_mm256_unpacklo_epi8(d, zero), _mm256_unpacklo_epi8(s, zero));
+        let hi = blend(_mm256_unpackhi_epi8(d, zero), _mm256_unpackhi_epi8(s, zero));
+        _mm256_storeu_si256(ptr, _mm256_packus_epi16(lo, hi));
+    }
+
+    len
+}
+
+/// Separable compositing operations with a SIMD implementation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompOp {
+    Multiply,
+    Darken,
+    Lighten,
+    Add,
+    Subtract,
+    Recolor,
+    Screen,
+    Difference,
+}
+
+impl CompOp {
+    pub fn from_mode(mode: Blendmode) -> Option<CompOp> {
+        match mode {
+            Blendmode::Multiply => Some(CompOp::Multiply),
+            Blendmode::Darken => Some(CompOp::Darken),
+            Blendmode::Lighten => Some(CompOp::Lighten),
+            Blendmode::Add => Some(CompOp::Add),
+            Blendmode::Subtract => Some(CompOp::Subtract),
+            Blendmode::Recolor => Some(CompOp::Recolor),
+            Blendmode::Screen => Some(CompOp::Screen),
+            Blendmode::Difference => Some(CompOp::Difference),
+            _ => None,
+        }
+    }
+}
+
+/// The same operations as the scalar comp_op_* functions.
+/// The operations must be performed in the same order to get identical results.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn comp_op_sse2(op: CompOp, a: __m128, b: __m128) -> __m128 {
+    match op {
+        CompOp::Multiply => _mm_mul_ps(a, b),
+        CompOp::Darken => _mm_min_ps(a, b),
+        CompOp::Lighten => _mm_max_ps(a, b),
+        CompOp::Add => _mm_min_ps(_mm_set1_ps(1.0), _mm_add_ps(a, b)),
+        CompOp::Subtract => _mm_max_ps(_mm_setzero_ps(), _mm_sub_ps(a, b)),
+        CompOp::Recolor => b,
+        CompOp::Screen => _mm_sub_ps(_mm_add_ps(a, b), _mm_mul_ps(a, b)),
+        CompOp::Difference => _mm_andnot_ps(_mm_set1_ps(-0.0), _mm_sub_ps(a, b)),
+    }
+}
+
+/// Convert a premultiplied pixel to unpremultiplied color channels and alpha.
+/// Equivalent to Color::from_pixel.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn unpremultiply_sse2(p: Pixel) -> (__m128, f32) {
+    let a = p[ALPHA_CHANNEL];
+    if a == 0 {
+        return (_mm_setzero_ps(), 0.0);
+    }
+    let af = 1.0 / a as f32;
+    let v = _mm_set_ps(0.0, p[2] as f32, p[1] as f32, p[0] as f32);
+    (_mm_mul_ps(v, _mm_set1_ps(af)), a as f32 / 255.0)
+}
+
+/// Convert color channels and alpha to a premultiplied pixel.
+/// Equivalent to Color::as_pixel.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn premultiply_sse2(c: __m128, a: f32) -> Pixel {
+    let af = a * 255.0;
+    let v = _mm_cvttps_epi32(_mm_mul_ps(c, _mm_set1_ps(af)));
+    // Saturate like an `as u8` cast does
+    let v = _mm_packus_epi16(_mm_packs_epi32(v, v), v);
+    let [b, g, r, _] = _mm_cvtsi128_si32(v).to_le_bytes();
+    [b, g, r, af as u8]
+}
+
+/// See rasterop::pixel_composite
+#[target_feature(enable = "sse2")]
+pub unsafe fn pixel_composite_sse2(
+    op: CompOp,
+    base: &mut [Pixel],
+    over: &[Pixel],
+    opacity: u8,
+) -> usize {
+    let of = opacity as f32 / 255.0;
+    for (dp, sp) in base.iter_mut().zip(over.iter()) {
+        let (dc, da) = unpremultiply_sse2(*dp);
+        let (sc, sa) = unpremultiply_sse2(*sp);
+        let alpha = _mm_set1_ps(sa * of);
+
+        let c = comp_op_sse2(op, dc, sc);
+        let c = _mm_add_ps(_mm_mul_ps(_mm_sub_ps(c, dc), alpha), dc);
+
+        *dp = premultiply_sse2(c, da);
+    }
+
+    base.len().min(over.len())
+}
+
+/// See rasterop::mask_composite
+#[target_feature(enable = "sse2")]
+pub unsafe fn mask_composite_sse2(
+    op: CompOp,
+    base: &mut [Pixel],
+    color: Pixel,
+    mask: &[u8],
+) -> usize {
+    let (c, _) = unpremultiply_sse2(color);
+    for (dp, &mask) in base.iter_mut().zip(mask.iter()) {
+        let (d, da) = unpremultiply_sse2(*dp);
+        let m = _mm_set1_ps(mask as f32 / 255.0);
+
+        let r = comp_op_sse2(op, d, c);
+        let r = _mm_add_ps(_mm_mul_ps(_mm_sub_ps(r, d), m), d);
+
+        *dp = premultiply_sse2(r, da);
+    }
+
+    base.len().min(mask.len())
+}
@@ -0,0 +1,435 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use rusttype::{point, Font, Scale};
+
+use super::annotation::{Annotation, VAlign};
+use super::{rasterop, Blendmode, Color, Pixel, Rectangle};
+
+/// The font bundled with the renderer (DejaVu Sans)
+static DEFAULT_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans.ttf");
+
+/// Space between the text and the edges of the annotation box
+const PADDING: f32 = 3.0;
+
+/// Renders annotations into pixels using a bundled font.
+///
+/// Annotation text may be plain text or (Qt style) HTML. Markup is
+/// stripped and the text is rendered in a single font, size and color,
+/// word wrapped to fit the annotation box.
+pub struct AnnotationRenderer {
+    font: Font<'static>,
+
+    /// Font size in pixels
+    pub font_size: f32,
+
+    /// Text color
+    pub text_color: Color,
+}
+
+impl AnnotationRenderer {
+    pub fn new() -> AnnotationRenderer {
+        AnnotationRenderer {
+            font: Font::try_from_bytes(DEFAULT_FONT).expect("bundled font should be valid"),
+            font_size: 16.0,
+            text_color: Color::BLACK,
+        }
+    }
+
+    /// Render an annotation into an image the size of its rectangle.
+    ///
+    /// The returned image is in premultiplied ARGB format, ready to be
+    /// drawn with `editlayer::draw_image` or sent in a PutImage message.
+    pub fn render(&self, annotation: &Annotation) -> Vec<Pixel> {
+        self.render_area(annotation, &annotation.rect)
+    }
+
+    /// Render a part of an annotation.
+    ///
+    /// The area is given in canvas coordinates and must be inside the
+    /// annotation's rectangle. The text is laid out as if the whole
+    /// annotation was rendered, but only pixels inside the area are allocated.
+    pub fn render_area(&self, annotation: &Annotation, area: &Rectangle) -> Vec<Pixel> {
+        let clip = area.offset(-annotation.rect.x, -annotation.rect.y);
+
+        let mut image = vec![annotation.background.as_pixel(); (clip.w * clip.h) as usize];
+        let mask = self.text_mask(
+            &plain_text(&annotation.text),
+            annotation.rect.w as usize,
+            annotation.rect.h as usize,
+            annotation.valign,
+            &clip,
+        );
+        rasterop::mask_blend(
+            &mut image,
+            self.text_color.as_pixel(),
+            &mask,
+            Blendmode::Normal,
+        );
+
+        image
+    }
+
+    /// Render annotations on top of an image (typically a flattened canvas.)
+    pub fn render_onto<'a, I>(&self, image: &mut [Pixel], width: u32, height: u32, annotations: I)
+    where
+        I: Iterator<Item = &'a Annotation>,
+    {
+        assert_eq!(image.len(), (width * height) as usize);
+        if image.is_empty() {
+            return;
+        }
+
+        for a in annotations {
+            let rect = match a.rect.cropped(width, height) {
+                Some(r) => r,
+                None => continue,
+            };
+            let rendered = self.render_area(a, &rect);
+
+            for y in rect.y..=rect.bottom() {
+                let src = ((y - rect.y) * rect.w) as usize;
+                let dest = (y as u32 * width + rect.x as u32) as usize;
+                rasterop::pixel_blend(
+                    &mut image[dest..dest + rect.w as usize],
+                    &rendered[src..src + rect.w as usize],
+                    255,
+                    Blendmode::Normal,
+                );
+            }
+        }
+    }
+
+    /// Render the text's coverage mask
+    ///
+    /// The text is laid out in a box of the given size and the mask
+    /// covers the part of the box inside the clip rectangle.
+    fn text_mask(
+        &self,
+        text: &str,
+        width: usize,
+        height: usize,
+        valign: VAlign,
+        clip: &Rectangle,
+    ) -> Vec<u8> {
+        let mut mask = vec![0u8; (clip.w * clip.h) as usize];
+
+        let scale = Scale::uniform(self.font_size);
+        let vm = self.font.v_metrics(scale);
+        let line_height = vm.ascent - vm.descent + vm.line_gap;
+
+        let lines = self.wrap_lines(text, width as f32 - PADDING * 2.0);
+        let text_height = lines.len() as f32 * line_height;
+        let top = match valign {
+            VAlign::Top => PADDING,
+            VAlign::Center => (height as f32 - text_height) / 2.0,
+            VAlign::Bottom => height as f32 - PADDING - text_height,
+        };
+
+        for (i, line) in lines.iter().enumerate() {
+            let baseline = top + vm.ascent + i as f32 * line_height;
+            for glyph in self.font.layout(line, scale, point(PADDING, baseline)) {
+                let bb = match glyph.pixel_bounding_box() {
+                    Some(bb) => bb,
+                    None => continue,
+                };
+                glyph.draw(|x, y, v| {
+                    let x = bb.min.x + x as i32;
+                    let y = bb.min.y + y as i32;
+                    if x >= clip.x && y >= clip.y && x <= clip.right() && y <= clip.bottom() {
+                        let m = &mut mask[((y - clip.y) * clip.w + x - clip.x) as usize];
+                        *m = (*m).max((v * 255.0).round() as u8);
+                    }
+                });
+            }
+        }
+
+        mask
+    }
+
+    /// Width of a line of text in pixels
+    fn text_width(&self, text: &str) -> f32 {
+        let scale = Scale::uniform(self.font_size);
+        self.font
+            .layout(text, scale, point(0.0, 0.0))
+            .last()
+            .map_or(0.0, |g| {
+                g.position().x + g.unpositioned().h_metrics().advance_width
+            })
+    }
+
+    /// Split the text into lines that fit in the given width.
+    ///
+    /// Lines are broken at whitespace when possible and mid-word
+    /// when a single word is too long to fit.
+    fn wrap_lines(&self, text: &str, max_width: f32) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for paragraph in text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split_whitespace() {
+                let candidate = if line.is_empty() {
+                    word.to_string()
+                } else {
+                    format!("{} {}", line, word)
+                };
+
+                if self.text_width(&candidate) <= max_width {
+                    line = candidate;
+                    continue;
+                }
+
+                if !line.is_empty() {
+                    lines.push(line);
+                }
+
+                // Break up words that don't fit on a line of their own
+                line = String::new();
+                for c in word.chars() {
+                    line.push(c);
+                    if line.chars().count() > 1 && self.text_width(&line) > max_width {
+                        line.pop();
+                        lines.push(line);
+                        line = c.to_string();
+                    }
+                }
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+}
+
+impl Default for AnnotationRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Convert annotation text to plain text.
+///
+/// Annotations made with the Drawpile client contain HTML. Tags are removed,
+/// block elements and line breaks are converted to newlines and the most
+/// common character entities are decoded. Text that does not start with
+/// a tag is assumed to be plain text and is returned as is.
+fn plain_text(text: &str) -> String {
+    if !text.trim_start().starts_with('<') {
+        return text.to_string();
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut skip_content = false;
+
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            // An unterminated tag runs to the end of the text
+            let end = rest.find('>').unwrap_or(rest.len());
+            let tag = rest[1..end].trim().to_ascii_lowercase();
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            let closing = tag.starts_with('/');
+
+            match name.as_str() {
+                "head" | "style" | "script" => skip_content = !closing,
+                "br" => result.push('\n'),
+                "p" | "div" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+                    if closing && !result.is_empty() =>
+                {
+                    result.push('\n')
+                }
+                _ => (),
+            }
+            rest = rest.get(end + 1..).unwrap_or("");
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if !skip_content {
+                decode_entities(&rest[..end], &mut result);
+            }
+            rest = &rest[end..];
+        }
+    }
+
+    while result.ends_with('\n') {
+        result.pop();
+    }
+    result
+}
+
+fn decode_entities(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                e if e.starts_with("#x") => u32::from_str_radix(&e[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32),
+                e if e.starts_with('#') => e[1..].parse().ok().and_then(std::char::from_u32),
+                _ => None,
+            };
+            c.map(|c| (c, end))
+        });
+
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::color::ZERO_PIXEL;
+
+    fn annotation(text: &str, valign: VAlign) -> Annotation {
+        Annotation {
+            id: 1,
+            text: text.to_string(),
+            rect: Rectangle::new(10, 10, 100, 60),
+            background: Color::rgb8(255, 255, 255),
+            protect: false,
+            valign,
+        }
+    }
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(plain_text("Hello <world> & co"), "Hello <world> & co");
+        assert_eq!(
+            plain_text(
+                "<!DOCTYPE HTML><html><head><style type=\"text/css\">p { margin: 0; }</style></head>\
+                 <body><p>Hello &amp; <b>welcome</b></p><p>Line&nbsp;2<br />Line 3 &#x41;&#66;</p></body></html>"
+            ),
+            "Hello & welcome\nLine\u{a0}2\nLine 3 AB"
+        );
+        assert_eq!(plain_text("<p>a &bogus; b</p>"), "a &bogus; b");
+
+        // Unterminated tags
+        assert_eq!(plain_text("<"), "");
+        assert_eq!(plain_text("<é"), "");
+        assert_eq!(plain_text("a<b"), "a<b");
+        assert_eq!(plain_text("<p>a</p><b"), "a");
+    }
+
+    #[test]
+    fn test_wrap_lines() {
+        let r = AnnotationRenderer::new();
+        let w = r.text_width("Hello world");
+        assert!(w > 0.0);
+
+        assert_eq!(r.wrap_lines("Hello world", w + 1.0), vec!["Hello world"]);
+        assert_eq!(r.wrap_lines("Hello world", w - 1.0), vec!["Hello", "world"]);
+        assert_eq!(r.wrap_lines("a\n\nb", w), vec!["a", "", "b"]);
+
+        let max_width = r.text_width("Hello") + 1.0;
+        let broken = r.wrap_lines("Helloworld", max_width);
+        assert!(broken.len() > 1);
+        assert_eq!(broken.concat(), "Helloworld");
+        assert!(broken.iter().all(|l| r.text_width(l) <= max_width));
+    }
+
+    #[test]
+    fn test_render() {
+        let r = AnnotationRenderer::new();
+        let white = Color::rgb8(255, 255, 255).as_pixel();
+
+        let ink_rows = |valign| {
+            let image = r.render(&annotation("Hello", valign));
+            assert_eq!(image.len(), 100 * 60);
+            assert_eq!(image[0], white);
+            assert_eq!(image[image.len() - 1], white);
+
+            let rows: Vec<usize> = image
+                .chunks(100)
+                .enumerate()
+                .filter(|(_, row)| row.iter().any(|&p| p != white))
+                .map(|(y, _)| y)
+                .collect();
+            assert!(!rows.is_empty());
+            (rows[0], rows[rows.len() - 1])
+        };
+
+        let top = ink_rows(VAlign::Top);
+        let center = ink_rows(VAlign::Center);
+        let bottom = ink_rows(VAlign::Bottom);
+        assert!(top.0 < 10);
+        assert!(top.0 < center.0 && center.0 < bottom.0);
+        assert!(bottom.1 > 50);
+    }
+
+    #[test]
+    fn test_render_onto() {
+        let r = AnnotationRenderer::new();
+        let mut image = vec![Color::rgb8(0, 0, 255).as_pixel(); 50 * 50];
+        r.render_onto(&mut image, 50, 50, [annotation("", VAlign::Top)].iter());
+
+        assert_eq!(image[9 * 50 + 9], Color::rgb8(0, 0, 255).as_pixel());
+        assert_eq!(image[10 * 50 + 10], Color::rgb8(255, 255, 255).as_pixel());
+        assert_eq!(image[49 * 50 + 49], Color::rgb8(255, 255, 255).as_pixel());
+
+        // Only the part of the annotation on the canvas is rendered,
+        // but the text is positioned as if the whole annotation was
+        let a = annotation("Hello", VAlign::Top);
+        let full = r.render(&a);
+        let mut image = vec![ZERO_PIXEL; 50 * 30];
+        r.render_onto(&mut image, 50, 30, [a.clone()].iter());
+        for y in 10..30 {
+            for x in 10..50 {
+                assert_eq!(
+                    image[y * 50 + x],
+                    full[(y - 10) * 100 + x - 10],
+                    "at {}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+
+        // Huge annotations can be rendered onto small images
+        let mut huge = annotation("Hello", VAlign::Bottom);
+        huge.rect = Rectangle::new(-10, -10, 65535, 65535);
+        let mut image = vec![ZERO_PIXEL; 50 * 50];
+        r.render_onto(&mut image, 50, 50, [huge].iter());
+        assert_eq!(image[0], Color::rgb8(255, 255, 255).as_pixel());
+    }
+}
@@ -21,7 +21,7 @@
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::fmt;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use super::color::*;
 use super::rectiter::{MutableRectIterator, RectIterator};
@@ -48,7 +48,7 @@ pub struct TileData {
 
 #[derive(Clone)]
 pub enum Tile {
-    Bitmap(Rc<TileData>),
+    Bitmap(Arc<TileData>),
     Blank,
 }
 
@@ -77,12 +77,65 @@ impl TileData {
         self.maybe_blank = mode.can_decrease_opacity();
     }
 
+    /// Multiply this tile's content with the given alpha mask
+    pub fn apply_mask(&mut self, mask: &[u8]) {
+        rasterop::apply_mask(&mut self.pixels, mask);
+        self.maybe_blank = true;
+    }
+
+    /// Fade this tile's content towards the other tile's content
+    pub fn interpolate(&mut self, other: &TileData, opacity: f32) {
+        rasterop::pixel_interpolate(&mut self.pixels, &other.pixels, (opacity * 255.0) as u8);
+        self.maybe_blank = true;
+    }
+
     pub fn merge_tile(&mut self, other: &Tile, opacity: f32, mode: Blendmode) {
         match other {
             Tile::Bitmap(td) => self.merge_data(td, opacity, mode),
             Tile::Blank => (),
         }
     }
+
+    /// Replace each block of pixels with the block's average color.
+    ///
+    /// Blocks start from the top-left corner of the tile. Block sizes that
+    /// don't divide the tile size evenly leave narrower blocks at the edges.
+    pub fn pixelate(&mut self, block_size: u32) {
+        let bs = block_size.clamp(1, TILE_SIZE) as usize;
+        if bs == 1 {
+            return;
+        }
+
+        let ts = TILE_SIZE as usize;
+        for by in (0..ts).step_by(bs) {
+            for bx in (0..ts).step_by(bs) {
+                let bw = bs.min(ts - bx);
+                let bh = bs.min(ts - by);
+
+                let mut sum = [0u32; 4];
+                for y in by..by + bh {
+                    for p in &self.pixels[y * ts + bx..y * ts + bx + bw] {
+                        for (s, &c) in sum.iter_mut().zip(p.iter()) {
+                            *s += c as u32;
+                        }
+                    }
+                }
+
+                let n = (bw * bh) as u32;
+                let avg = [
+                    ((sum[0] + n / 2) / n) as u8,
+                    ((sum[1] + n / 2) / n) as u8,
+                    ((sum[2] + n / 2) / n) as u8,
+                    ((sum[3] + n / 2) / n) as u8,
+                ];
+                for y in by..by + bh {
+                    for p in &mut self.pixels[y * ts + bx..y * ts + bx + bw] {
+                        *p = avg;
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl Tile {
@@ -93,20 +146,20 @@ impl Tile {
         if p[ALPHA_CHANNEL] == 0 {
             Tile::Blank
         } else {
-            Tile::Bitmap(Rc::new(TileData::new(p, user)))
+            Tile::Bitmap(Arc::new(TileData::new(p, user)))
         }
     }
 
     // Construct a new tile filled with the given color.
     // A bitmap tile is constructed even if the color is transparent.
     pub fn new_solid(color: &Color, user: UserID) -> Tile {
-        Tile::Bitmap(Rc::new(TileData::new(color.as_pixel(), user)))
+        Tile::Bitmap(Arc::new(TileData::new(color.as_pixel(), user)))
     }
 
     pub fn from_data(data: &[Pixel], user: UserID) -> Tile {
         assert_eq!(data.len(), TILE_LENGTH, "Wrong tile data length");
-        let mut td = Rc::new(TileData::new(ZERO_PIXEL, user));
-        Rc::make_mut(&mut td).pixels.clone_from_slice(data);
+        let mut td = Arc::new(TileData::new(ZERO_PIXEL, user));
+        Arc::make_mut(&mut td).pixels.clone_from_slice(data);
         Tile::Bitmap(td)
     }
 
@@ -154,7 +207,7 @@ impl Tile {
                     if td.pixels.iter().all(|&p| p[ALPHA_CHANNEL] == 0) {
                         *self = Tile::Blank;
                     } else {
-                        Rc::make_mut(td).maybe_blank = false;
+                        Arc::make_mut(td).maybe_blank = false;
                     }
                 }
             }
@@ -178,7 +231,7 @@ impl Tile {
             match self {
                 Tile::Bitmap(td) => {
                     let pixel = color.as_pixel();
-                    let data = Rc::make_mut(td);
+                    let data = Arc::make_mut(td);
                     data.last_touched_by = user;
                     data.maybe_blank = false;
                     for i in data.pixels.iter_mut() {
@@ -196,7 +249,7 @@ impl Tile {
     pub fn merge(&mut self, other: &Tile, opacity: f32, mode: Blendmode) {
         if let Tile::Bitmap(o) = other {
             match self {
-                Tile::Bitmap(td) => Rc::make_mut(td).merge_data(o, opacity, mode),
+                Tile::Bitmap(td) => Arc::make_mut(td).merge_data(o, opacity, mode),
                 Tile::Blank => {
                     if mode.can_increase_opacity() {
                         if opacity == 1.0 {
@@ -240,7 +293,7 @@ impl Tile {
 
         match self {
             Tile::Bitmap(td) => {
-                let data = Rc::make_mut(td);
+                let data = Arc::make_mut(td);
                 data.maybe_blank |= maybe_erase;
                 MutableRectIterator::from_rectangle(&mut data.pixels, TILE_SIZE as usize, r)
             }
@@ -265,7 +318,7 @@ impl Tile {
         use Tile::*;
         match (self, other) {
             (Blank, Blank) => true,
-            (Bitmap(a), Bitmap(b)) => Rc::ptr_eq(a, b),
+            (Bitmap(a), Bitmap(b)) => Arc::ptr_eq(a, b),
             (_, _) => false,
         }
     }
@@ -273,7 +326,7 @@ impl Tile {
     #[cfg(test)]
     pub fn refcount(&self) -> usize {
         match self {
-            Tile::Bitmap(d) => Rc::strong_count(&d) + Rc::weak_count(&d),
+            Tile::Bitmap(d) => Arc::strong_count(&d) + Arc::weak_count(&d),
             Tile::Blank => 0,
         }
     }
@@ -323,7 +376,7 @@ impl fmt::Debug for Tile {
                 d.pixels[0],
                 d.pixels[TILE_LENGTH - 1],
                 d.last_touched_by,
-                Rc::strong_count(&d)
+                Arc::strong_count(&d)
             ),
             Tile::Blank => write!(f, "Tile(blank)"),
         }
@@ -425,4 +478,21 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_pixelate() {
+        let mut td = TileData::new(ZERO_PIXEL, 0);
+        td.pixels[0] = [100, 100, 100, 100];
+        td.pixels[TILE_LENGTH - 1] = WHITE_PIXEL;
+
+        td.pixelate(10);
+        assert_eq!(td.pixels[0], [1, 1, 1, 1]);
+        assert_eq!(td.pixels[9 * 64 + 9], [1, 1, 1, 1]);
+        assert_eq!(td.pixels[10], ZERO_PIXEL);
+
+        // The last blocks are narrower: 4x4 pixels
+        assert_eq!(td.pixels[TILE_LENGTH - 1], [16, 16, 16, 16]);
+        assert_eq!(td.pixels[60 * 64 + 60], [16, 16, 16, 16]);
+        assert_eq!(td.pixels[59 * 64 + 59], ZERO_PIXEL);
+    }
 }
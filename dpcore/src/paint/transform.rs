@@ -0,0 +1,386 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use num_enum::IntoPrimitive;
+use num_enum::TryFromPrimitive;
+
+use super::color::{Pixel, ZERO_PIXEL};
+use super::rect::Rectangle;
+
+/// A quadrilateral given as four corner points in clockwise order,
+/// starting from the top-left corner.
+///
+/// The points are at pixel corners, so the quad ((0,0), (w,0), (w,h), (0,h))
+/// covers a w×h rectangle exactly.
+pub type Quad = [(i32, i32); 4];
+
+/// Make a quad out of a rectangle
+pub fn rect_quad(rect: &Rectangle) -> Quad {
+    [
+        (rect.x, rect.y),
+        (rect.x + rect.w, rect.y),
+        (rect.x + rect.w, rect.y + rect.h),
+        (rect.x, rect.y + rect.h),
+    ]
+}
+
+/// Get the bounding rectangle of a quad.
+///
+/// Returns None if the quad has no area or if its width or height
+/// does not fit in an i32.
+pub fn quad_bounds(quad: &Quad) -> Option<Rectangle> {
+    let x0 = quad.iter().map(|p| p.0).min().unwrap();
+    let x1 = quad.iter().map(|p| p.0).max().unwrap();
+    let y0 = quad.iter().map(|p| p.1).min().unwrap();
+    let y1 = quad.iter().map(|p| p.1).max().unwrap();
+
+    let w = x1 as i64 - x0 as i64;
+    let h = y1 as i64 - y0 as i64;
+
+    if w > 0 && h > 0 && w <= i32::MAX as i64 && h <= i32::MAX as i64 {
+        Some(Rectangle::new(x0, y0, w as i32, h as i32))
+    } else {
+        None
+    }
+}
+
+/// Resampling method used when transforming pixels
+#[derive(Copy, Clone, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Interpolation {
+    Nearest = 0,
+    Bilinear,
+}
+
+/// A projective transformation (homography) of the plane
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transform {
+    m: [f64; 9],
+}
+
+impl Transform {
+    /// The transformation that maps the unit square to the given quad
+    ///
+    /// Returns None if the quad is degenerate.
+    pub fn square_to_quad(quad: &Quad) -> Option<Transform> {
+        let [(x0, y0), (x1, y1), (x2, y2), (x3, y3)] = [
+            (quad[0].0 as f64, quad[0].1 as f64),
+            (quad[1].0 as f64, quad[1].1 as f64),
+            (quad[2].0 as f64, quad[2].1 as f64),
+            (quad[3].0 as f64, quad[3].1 as f64),
+        ];
+
+        let sx = x0 - x1 + x2 - x3;
+        let sy = y0 - y1 + y2 - y3;
+
+        let (g, h) = if sx == 0.0 && sy == 0.0 {
+            // A parallelogram: the transformation is affine
+            (0.0, 0.0)
+        } else {
+            let dx1 = x1 - x2;
+            let dx2 = x3 - x2;
+            let dy1 = y1 - y2;
+            let dy2 = y3 - y2;
+            let den = dx1 * dy2 - dx2 * dy1;
+            if den == 0.0 {
+                return None;
+            }
+            ((sx * dy2 - dx2 * sy) / den, (dx1 * sy - sx * dy1) / den)
+        };
+
+        let t = Transform {
+            m: [
+                x1 - x0 + g * x1,
+                x3 - x0 + h * x3,
+                x0,
+                y1 - y0 + g * y1,
+                y3 - y0 + h * y3,
+                y0,
+                g,
+                h,
+                1.0,
+            ],
+        };
+
+        if t.determinant() == 0.0 {
+            None
+        } else {
+            Some(t)
+        }
+    }
+
+    /// The transformation that maps the `from` quad to the `to` quad
+    ///
+    /// Returns None if either quad is degenerate.
+    pub fn quad_to_quad(from: &Quad, to: &Quad) -> Option<Transform> {
+        let a = Transform::square_to_quad(from)?.inverted()?;
+        let b = Transform::square_to_quad(to)?;
+        Some(b.multiplied(&a))
+    }
+
+    fn determinant(&self) -> f64 {
+        let m = &self.m;
+        m[0] * (m[4] * m[8] - m[5] * m[7]) - m[1] * (m[3] * m[8] - m[5] * m[6])
+            + m[2] * (m[3] * m[7] - m[4] * m[6])
+    }
+
+    /// Get the inverse transformation
+    pub fn inverted(&self) -> Option<Transform> {
+        let det = self.determinant();
+        if det == 0.0 {
+            return None;
+        }
+
+        let m = &self.m;
+        let adj = [
+            m[4] * m[8] - m[5] * m[7],
+            m[2] * m[7] - m[1] * m[8],
+            m[1] * m[5] - m[2] * m[4],
+            m[5] * m[6] - m[3] * m[8],
+            m[0] * m[8] - m[2] * m[6],
+            m[2] * m[3] - m[0] * m[5],
+            m[3] * m[7] - m[4] * m[6],
+            m[1] * m[6] - m[0] * m[7],
+            m[0] * m[4] - m[1] * m[3],
+        ];
+
+        let mut inv = [0.0; 9];
+        for (i, v) in adj.iter().enumerate() {
+            inv[i] = v / det;
+        }
+        Some(Transform { m: inv })
+    }
+
+    /// Get the transformation that first applies `other`, then `self`
+    pub fn multiplied(&self, other: &Transform) -> Transform {
+        let a = &self.m;
+        let b = &other.m;
+        let mut m = [0.0; 9];
+        for row in 0..3 {
+            for col in 0..3 {
+                m[row * 3 + col] = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).sum();
+            }
+        }
+        Transform { m }
+    }
+
+    /// Transform a point
+    pub fn map(&self, x: f64, y: f64) -> (f64, f64) {
+        let m = &self.m;
+        let w = m[6] * x + m[7] * y + m[8];
+        (
+            (m[0] * x + m[1] * y + m[2]) / w,
+            (m[3] * x + m[4] * y + m[5]) / w,
+        )
+    }
+}
+
+/// Resample an image into the given rectangle.
+///
+/// `transform` maps the source image coordinates to the destination.
+/// Destination pixels that map to outside the source image are transparent.
+///
+/// # Arguments
+///
+/// * `image` - source image pixels
+/// * `image_rect` - the position and size of the source image
+/// * `transform` - source to destination transformation
+/// * `rect` - the destination rectangle to fill
+/// * `interpolation` - resampling method
+pub fn resample(
+    image: &[Pixel],
+    image_rect: &Rectangle,
+    transform: &Transform,
+    rect: &Rectangle,
+    interpolation: Interpolation,
+) -> Vec<Pixel> {
+    assert_eq!(image.len(), (image_rect.w * image_rect.h) as usize);
+
+    let mut result = vec![ZERO_PIXEL; (rect.w * rect.h) as usize];
+    let inverse = match transform.inverted() {
+        Some(t) => t,
+        None => return result,
+    };
+
+    // Sample coordinates can be far outside the i32 range when the
+    // transformation is extreme, so they are handled as i64
+    let sample = |x: i64, y: i64| -> Pixel {
+        let x = x - image_rect.x as i64;
+        let y = y - image_rect.y as i64;
+        if x < 0 || y < 0 || x >= image_rect.w as i64 || y >= image_rect.h as i64 {
+            ZERO_PIXEL
+        } else {
+            image[(y * image_rect.w as i64 + x) as usize]
+        }
+    };
+
+    for (row, y) in result.chunks_exact_mut(rect.w as usize).zip(rect.y..) {
+        for (px, x) in row.iter_mut().zip(rect.x..) {
+            let (sx, sy) = inverse.map(x as f64 + 0.5, y as f64 + 0.5);
+            if !sx.is_finite() || !sy.is_finite() {
+                continue;
+            }
+
+            *px = match interpolation {
+                Interpolation::Nearest => sample(sx.floor() as i64, sy.floor() as i64),
+                Interpolation::Bilinear => {
+                    let fx = sx - 0.5;
+                    let fy = sy - 0.5;
+                    let x0 = fx.floor();
+                    let y0 = fy.floor();
+                    let wx = fx - x0;
+                    let wy = fy - y0;
+                    let (x0, y0) = (x0 as i64, y0 as i64);
+
+                    let p = [
+                        (sample(x0, y0), (1.0 - wx) * (1.0 - wy)),
+                        (sample(x0 + 1, y0), wx * (1.0 - wy)),
+                        (sample(x0, y0 + 1), (1.0 - wx) * wy),
+                        (sample(x0 + 1, y0 + 1), wx * wy),
+                    ];
+
+                    let mut out = ZERO_PIXEL;
+                    for (c, o) in out.iter_mut().enumerate() {
+                        let v: f64 = p.iter().map(|(px, w)| px[c] as f64 * w).sum();
+                        *o = v.round().min(255.0) as u8;
+                    }
+                    out
+                }
+            };
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: (f64, f64), b: (f64, f64)) {
+        assert!(
+            (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_quad_bounds() {
+        assert_eq!(
+            quad_bounds(&[(10, 0), (20, 5), (15, 30), (0, 10)]),
+            Some(Rectangle::new(0, 0, 20, 30))
+        );
+        assert_eq!(quad_bounds(&[(0, 0), (10, 0), (10, 0), (0, 0)]), None);
+
+        let huge = [(i32::MIN, 0), (i32::MAX, 0), (i32::MAX, 10), (i32::MIN, 10)];
+        assert_eq!(quad_bounds(&huge), None);
+    }
+
+    #[test]
+    fn test_quad_to_quad() {
+        let from = rect_quad(&Rectangle::new(10, 10, 10, 20));
+
+        // Translation and scaling
+        let t = Transform::quad_to_quad(&from, &rect_quad(&Rectangle::new(0, 0, 20, 20))).unwrap();
+        assert_near(t.map(10.0, 10.0), (0.0, 0.0));
+        assert_near(t.map(20.0, 30.0), (20.0, 20.0));
+        assert_near(t.map(15.0, 20.0), (10.0, 10.0));
+
+        // 90 degree rotation
+        let t = Transform::quad_to_quad(&from, &[(20, 0), (20, 10), (0, 10), (0, 0)]).unwrap();
+        assert_near(t.map(10.0, 10.0), (20.0, 0.0));
+        assert_near(t.map(20.0, 10.0), (20.0, 10.0));
+        assert_near(t.map(10.0, 30.0), (0.0, 0.0));
+
+        // Perspective: corners must map exactly
+        let to = [(0, 0), (100, 10), (90, 80), (5, 100)];
+        let t = Transform::quad_to_quad(&from, &to).unwrap();
+        for (f, t2) in from.iter().zip(to.iter()) {
+            assert_near(t.map(f.0 as f64, f.1 as f64), (t2.0 as f64, t2.1 as f64));
+        }
+
+        // Degenerate quad
+        assert!(Transform::quad_to_quad(&from, &[(0, 0), (10, 0), (20, 0), (30, 0)]).is_none());
+    }
+
+    #[test]
+    fn test_resample() {
+        let a = [255, 0, 0, 255];
+        let b = [0, 0, 255, 255];
+        let image = [a, b, b, a];
+        let image_rect = Rectangle::new(0, 0, 2, 2);
+
+        // Double the size with nearest neighbour sampling
+        let t = Transform::quad_to_quad(
+            &rect_quad(&image_rect),
+            &rect_quad(&Rectangle::new(1, 1, 4, 4)),
+        )
+        .unwrap();
+        let out = resample(
+            &image,
+            &image_rect,
+            &t,
+            &Rectangle::new(0, 0, 6, 6),
+            Interpolation::Nearest,
+        );
+        let z = ZERO_PIXEL;
+        #[rustfmt::skip]
+        let expected = [
+            z, z, z, z, z, z,
+            z, a, a, b, b, z,
+            z, a, a, b, b, z,
+            z, b, b, a, a, z,
+            z, b, b, a, a, z,
+            z, z, z, z, z, z,
+        ];
+        assert_eq!(out, expected);
+
+        // Identity transform with bilinear sampling is lossless
+        let t = Transform::quad_to_quad(&rect_quad(&image_rect), &rect_quad(&image_rect)).unwrap();
+        let out = resample(
+            &image,
+            &image_rect,
+            &t,
+            &image_rect,
+            Interpolation::Bilinear,
+        );
+        assert_eq!(out, image);
+
+        // Half pixel shift blends neighbours
+        let t = Transform::quad_to_quad(
+            &rect_quad(&image_rect),
+            &rect_quad(&Rectangle::new(0, 0, 4, 4)),
+        )
+        .unwrap();
+        let out = resample(
+            &image,
+            &image_rect,
+            &t,
+            &Rectangle::new(1, 0, 1, 1),
+            Interpolation::Bilinear,
+        );
+        // x=1.5 maps to source 0.75: a 75% / b 25% in the top row, partially faded at the top edge
+        assert_eq!(out[0][3], 191);
+    }
+}
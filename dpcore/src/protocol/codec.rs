@@ -0,0 +1,371 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::protocol::serialization::{DeserializationError, HEADER_LEN};
+use crate::protocol::Message;
+
+/// Maximum payload length the protocol can express
+pub const MAX_PAYLOAD_LEN: usize = 0xffff;
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// An error in the underlying stream
+    Io(io::Error),
+
+    /// The message header announced a payload longer than allowed.
+    /// The stream cannot be recovered from this.
+    PayloadTooLong(usize),
+
+    /// A complete message was received, but it could not be parsed.
+    /// The message has been consumed and the stream may still be used.
+    Invalid(DeserializationError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::PayloadTooLong(len) => write!(f, "payload too long ({} bytes)", len),
+            CodecError::Invalid(e) => write!(
+                f,
+                "invalid message (type {}, user {}): {}",
+                e.message_type, e.user_id, e.error
+            ),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+impl From<DeserializationError> for CodecError {
+    fn from(err: DeserializationError) -> Self {
+        CodecError::Invalid(err)
+    }
+}
+
+/// A codec for framing a byte stream into protocol messages.
+///
+/// Each message starts with a header consisting of a 16 bit big endian
+/// payload length, a message type and a user ID, followed by the payload.
+///
+/// The codec can be used directly with blocking `Read` and `Write` streams,
+/// and (with the `tokio-codec` feature enabled) as a tokio-util `Decoder`
+/// and `Encoder` for use with async streams.
+#[derive(Clone, Debug)]
+pub struct MessageCodec {
+    max_payload_len: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> MessageCodec {
+        MessageCodec {
+            max_payload_len: MAX_PAYLOAD_LEN,
+        }
+    }
+
+    /// Set the maximum accepted payload length.
+    /// Messages longer than this are rejected with a `PayloadTooLong` error.
+    pub fn set_max_payload_len(mut self, len: usize) -> Self {
+        self.max_payload_len = len.min(MAX_PAYLOAD_LEN);
+        self
+    }
+
+    pub fn max_payload_len(&self) -> usize {
+        self.max_payload_len
+    }
+
+    /// Check how long the message at the start of the buffer is.
+    ///
+    /// Returns None if the buffer does not contain a full header yet.
+    /// Otherwise, the total length (including the header) is returned.
+    pub fn message_len(&self, buf: &[u8]) -> Result<Option<usize>, CodecError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let payload_len = u16::from_be_bytes(buf[..2].try_into().unwrap()) as usize;
+        if payload_len > self.max_payload_len {
+            return Err(CodecError::PayloadTooLong(payload_len));
+        }
+        Ok(Some(HEADER_LEN + payload_len))
+    }
+
+    /// Try to decode a message from the start of the buffer.
+    ///
+    /// Returns None if the buffer does not contain a complete message yet,
+    /// otherwise the message and the number of bytes consumed.
+    /// Note: when an `Invalid` error is returned, the caller should still skip
+    /// the message, whose length can be found out with `message_len`.
+    pub fn decode_slice(&self, buf: &[u8]) -> Result<Option<(Message, usize)>, CodecError> {
+        match self.message_len(buf)? {
+            Some(len) if buf.len() >= len => Ok(Some((Message::deserialize(&buf[..len])?, len))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the next message from a blocking stream.
+    ///
+    /// Returns Ok(None) if the stream ended cleanly at a message boundary.
+    pub fn read_message<R: Read>(&self, reader: &mut R) -> Result<Option<Message>, CodecError> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut got = 0;
+        while got < HEADER_LEN {
+            match reader.read(&mut header[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let len = self.message_len(&header)?.unwrap();
+        let mut buf = vec![0u8; len];
+        buf[..HEADER_LEN].copy_from_slice(&header);
+        reader.read_exact(&mut buf[HEADER_LEN..])?;
+
+        Ok(Some(Message::deserialize(&buf)?))
+    }
+
+    /// Write a message to a blocking stream
+    pub fn write_message<W: Write>(&self, writer: &mut W, msg: &Message) -> Result<(), CodecError> {
+        let buf = msg.serialize();
+        debug_assert!(buf.len() >= HEADER_LEN);
+        if buf.len() - HEADER_LEN > self.max_payload_len {
+            return Err(CodecError::PayloadTooLong(buf.len() - HEADER_LEN));
+        }
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+mod async_codec {
+    use super::{CodecError, MessageCodec, HEADER_LEN};
+    use crate::protocol::Message;
+
+    use bytes::{Buf, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    impl Decoder for MessageCodec {
+        type Item = Message;
+        type Error = CodecError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+            let len = match self.message_len(src)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            if src.len() < len {
+                src.reserve(len - src.len());
+                return Ok(None);
+            }
+
+            let msg = Message::deserialize(&src[..len]);
+            src.advance(len);
+            Ok(Some(msg?))
+        }
+    }
+
+    impl Encoder<Message> for MessageCodec {
+        type Error = CodecError;
+
+        fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+            let buf = msg.serialize();
+            if buf.len() - HEADER_LEN > self.max_payload_len {
+                return Err(CodecError::PayloadTooLong(buf.len() - HEADER_LEN));
+            }
+            dst.extend_from_slice(&buf);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn test_messages() -> Vec<Message> {
+        vec![
+            Message::from(ControlMessage::Ping(1, false)),
+            Message::from(ServerMetaMessage::Chat(
+                2,
+                ChatMessage {
+                    flags: 0,
+                    message: "hello".to_string(),
+                },
+            )),
+            Message::from(CommandMessage::PutImage(
+                3,
+                PutImageMessage {
+                    layer: 0x0301,
+                    mode: 1,
+                    x: 0,
+                    y: 0,
+                    w: 100,
+                    h: 100,
+                    image: vec![0xaa; 60000],
+                },
+            )),
+            Message::from(CommandMessage::UndoPoint(4)),
+        ]
+    }
+
+    #[test]
+    fn test_partial_buffers() {
+        let codec = MessageCodec::new();
+        let mut buf = Vec::new();
+        for m in test_messages() {
+            buf.extend(m.serialize());
+        }
+
+        let mut decoded = Vec::new();
+        let mut offset = 0;
+        let mut available = 0;
+        while offset < buf.len() {
+            available = (available + 1000).min(buf.len());
+            while let Some((m, len)) = codec.decode_slice(&buf[offset..available]).unwrap() {
+                decoded.push(m);
+                offset += len;
+            }
+        }
+
+        assert_eq!(decoded, test_messages());
+    }
+
+    #[test]
+    fn test_max_payload_len() {
+        let codec = MessageCodec::new().set_max_payload_len(1000);
+        let msgs = test_messages();
+
+        let buf = msgs[2].serialize();
+        match codec.decode_slice(&buf) {
+            Err(CodecError::PayloadTooLong(len)) => assert_eq!(len, buf.len() - HEADER_LEN),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        let mut out = Vec::new();
+        assert!(codec.write_message(&mut out, &msgs[2]).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        let codec = MessageCodec::new();
+        let buf = b"\x00\x01\x7f\x01\x00";
+        match codec.decode_slice(buf) {
+            Err(CodecError::Invalid(e)) => assert_eq!(e.message_type, 0x7f),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(codec.message_len(buf).unwrap(), Some(5));
+    }
+
+    #[test]
+    fn test_blocking_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let writer = thread::spawn(move || {
+            let codec = MessageCodec::new();
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for m in test_messages() {
+                codec.write_message(&mut stream, &m).unwrap();
+            }
+        });
+
+        let codec = MessageCodec::new();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        while let Some(m) = codec.read_message(&mut stream).unwrap() {
+            received.push(m);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(received, test_messages());
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn test_async_socket() {
+        use futures::{SinkExt, StreamExt};
+        use std::net::Ipv4Addr;
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio_util::codec::Framed;
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let writer = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut framed = Framed::new(stream, MessageCodec::new());
+                for m in test_messages() {
+                    framed.send(m).await.unwrap();
+                }
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, MessageCodec::new());
+            let mut received = Vec::new();
+            while let Some(m) = framed.next().await {
+                received.push(m.unwrap());
+            }
+
+            writer.await.unwrap();
+            assert_eq!(received, test_messages());
+        });
+    }
+}
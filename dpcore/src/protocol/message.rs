@@ -28,7 +28,7 @@ use std::convert::TryInto;
 use std::fmt;
 use std::str::FromStr;
 
-pub static VERSION: &str = "dp:4.21.2";
+pub static VERSION: &str = "dp:4.23.0";
 pub const UNDO_DEPTH: u32 = 30;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -108,7 +108,7 @@ impl JoinMessage {
         let mut w = MessageWriter::with_expected_payload(
             32,
             user_id,
-            1 + self.name.len() + self.avatar.len(),
+            2 + self.name.len() + self.avatar.len(),
         );
         w.write(self.flags);
         w.write(self.name.len() as u8);
@@ -409,7 +409,8 @@ pub struct LayerCreateMessage {
 impl LayerCreateMessage {
     pub const FLAGS_COPY: u8 = 0x1;
     pub const FLAGS_INSERT: u8 = 0x2;
-    pub const FLAGS: &'static [&'static str] = &["copy", "insert"];
+    pub const FLAGS_GROUP: u8 = 0x4;
+    pub const FLAGS: &'static [&'static str] = &["copy", "insert", "group"];
 
     fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
         let mut reader = MessageReader::new(buf).check_len(9, 65535, 130, 0)?;
@@ -471,7 +472,9 @@ pub struct LayerAttributesMessage {
 impl LayerAttributesMessage {
     pub const FLAGS_CENSOR: u8 = 0x1;
     pub const FLAGS_FIXED: u8 = 0x2;
-    pub const FLAGS: &'static [&'static str] = &["censor", "fixed"];
+    pub const FLAGS_PASSTHROUGH: u8 = 0x4;
+    pub const FLAGS_CLIP: u8 = 0x8;
+    pub const FLAGS: &'static [&'static str] = &["censor", "fixed", "passthrough", "clip"];
 
     fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
         let mut reader = MessageReader::new(buf).check_len(6, 6, 131, 0)?;
@@ -1228,6 +1231,779 @@ impl DrawDabsPixelMessage {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerTreeMoveMessage {
+    pub layer: u16,
+    pub parent: u16,
+    pub sibling: u16,
+}
+
+impl LayerTreeMoveMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(6, 6, 151, 0)?;
+
+        let layer = reader.read::<u16>();
+        let parent = reader.read::<u16>();
+        let sibling = reader.read::<u16>();
+
+        Ok(Self {
+            layer,
+            parent,
+            sibling,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(151, user_id, 6);
+        w.write(self.layer);
+        w.write(self.parent);
+        w.write(self.sibling);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("parent", format!("0x{:04x}", self.parent))
+            .set("sibling", format!("0x{:04x}", self.sibling))
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            parent: tm.get_u16("parent"),
+            sibling: tm.get_u16("sibling"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerMaskMessage {
+    pub id: u16,
+    pub mask: u16,
+    pub fill: u8,
+}
+
+impl LayerMaskMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(5, 5, 152, 0)?;
+
+        let id = reader.read::<u16>();
+        let mask = reader.read::<u16>();
+        let fill = reader.read::<u8>();
+
+        Ok(Self { id, mask, fill })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(152, user_id, 5);
+        w.write(self.id);
+        w.write(self.mask);
+        w.write(self.fill);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("id", format!("0x{:04x}", self.id))
+            .set("mask", format!("0x{:04x}", self.mask))
+            .set("fill", self.fill.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            id: tm.get_u16("id"),
+            mask: tm.get_u16("mask"),
+            fill: tm.get_u8("fill"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectionMessage {
+    pub x: i32,
+    pub y: i32,
+    pub w: u16,
+    pub h: u16,
+    pub points: Vec<u16>,
+}
+
+impl SelectionMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(12, 65535, 153, 0)?;
+
+        let x = reader.read::<i32>();
+        let y = reader.read::<i32>();
+        let w = reader.read::<u16>();
+        let h = reader.read::<u16>();
+        let points = reader.read_remaining_vec();
+
+        Ok(Self { x, y, w, h, points })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w =
+            MessageWriter::with_expected_payload(153, user_id, 12 + (self.points.len() * 2));
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(&self.points);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set_vec_u16("points", &self.points, false)
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            x: i32::from_str(tm.get_str("x")).unwrap_or_default(),
+            y: i32::from_str(tm.get_str("y")).unwrap_or_default(),
+            w: tm.get_u16("w"),
+            h: tm.get_u16("h"),
+            points: tm.get_vec_u16("points"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransformRegionMessage {
+    pub layer: u16,
+    pub source: u16,
+    pub sx1: i32,
+    pub sy1: i32,
+    pub sx2: i32,
+    pub sy2: i32,
+    pub sx3: i32,
+    pub sy3: i32,
+    pub sx4: i32,
+    pub sy4: i32,
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+    pub x3: i32,
+    pub y3: i32,
+    pub x4: i32,
+    pub y4: i32,
+    pub mode: u8,
+}
+
+impl TransformRegionMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(69, 69, 154, 0)?;
+
+        let layer = reader.read::<u16>();
+        let source = reader.read::<u16>();
+        let sx1 = reader.read::<i32>();
+        let sy1 = reader.read::<i32>();
+        let sx2 = reader.read::<i32>();
+        let sy2 = reader.read::<i32>();
+        let sx3 = reader.read::<i32>();
+        let sy3 = reader.read::<i32>();
+        let sx4 = reader.read::<i32>();
+        let sy4 = reader.read::<i32>();
+        let x1 = reader.read::<i32>();
+        let y1 = reader.read::<i32>();
+        let x2 = reader.read::<i32>();
+        let y2 = reader.read::<i32>();
+        let x3 = reader.read::<i32>();
+        let y3 = reader.read::<i32>();
+        let x4 = reader.read::<i32>();
+        let y4 = reader.read::<i32>();
+        let mode = reader.read::<u8>();
+
+        Ok(Self {
+            layer,
+            source,
+            sx1,
+            sy1,
+            sx2,
+            sy2,
+            sx3,
+            sy3,
+            sx4,
+            sy4,
+            x1,
+            y1,
+            x2,
+            y2,
+            x3,
+            y3,
+            x4,
+            y4,
+            mode,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(154, user_id, 69);
+        w.write(self.layer);
+        w.write(self.source);
+        w.write(self.sx1);
+        w.write(self.sy1);
+        w.write(self.sx2);
+        w.write(self.sy2);
+        w.write(self.sx3);
+        w.write(self.sy3);
+        w.write(self.sx4);
+        w.write(self.sy4);
+        w.write(self.x1);
+        w.write(self.y1);
+        w.write(self.x2);
+        w.write(self.y2);
+        w.write(self.x3);
+        w.write(self.y3);
+        w.write(self.x4);
+        w.write(self.y4);
+        w.write(self.mode);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("source", format!("0x{:04x}", self.source))
+            .set("sx1", self.sx1.to_string())
+            .set("sy1", self.sy1.to_string())
+            .set("sx2", self.sx2.to_string())
+            .set("sy2", self.sy2.to_string())
+            .set("sx3", self.sx3.to_string())
+            .set("sy3", self.sy3.to_string())
+            .set("sx4", self.sx4.to_string())
+            .set("sy4", self.sy4.to_string())
+            .set("x1", self.x1.to_string())
+            .set("y1", self.y1.to_string())
+            .set("x2", self.x2.to_string())
+            .set("y2", self.y2.to_string())
+            .set("x3", self.x3.to_string())
+            .set("y3", self.y3.to_string())
+            .set("x4", self.x4.to_string())
+            .set("y4", self.y4.to_string())
+            .set("mode", self.mode.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            source: tm.get_u16("source"),
+            sx1: i32::from_str(tm.get_str("sx1")).unwrap_or_default(),
+            sy1: i32::from_str(tm.get_str("sy1")).unwrap_or_default(),
+            sx2: i32::from_str(tm.get_str("sx2")).unwrap_or_default(),
+            sy2: i32::from_str(tm.get_str("sy2")).unwrap_or_default(),
+            sx3: i32::from_str(tm.get_str("sx3")).unwrap_or_default(),
+            sy3: i32::from_str(tm.get_str("sy3")).unwrap_or_default(),
+            sx4: i32::from_str(tm.get_str("sx4")).unwrap_or_default(),
+            sy4: i32::from_str(tm.get_str("sy4")).unwrap_or_default(),
+            x1: i32::from_str(tm.get_str("x1")).unwrap_or_default(),
+            y1: i32::from_str(tm.get_str("y1")).unwrap_or_default(),
+            x2: i32::from_str(tm.get_str("x2")).unwrap_or_default(),
+            y2: i32::from_str(tm.get_str("y2")).unwrap_or_default(),
+            x3: i32::from_str(tm.get_str("x3")).unwrap_or_default(),
+            y3: i32::from_str(tm.get_str("y3")).unwrap_or_default(),
+            x4: i32::from_str(tm.get_str("x4")).unwrap_or_default(),
+            y4: i32::from_str(tm.get_str("y4")).unwrap_or_default(),
+            mode: tm.get_u8("mode"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoxBlurMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub radius: u8,
+}
+
+impl BoxBlurMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(19, 19, 155, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let radius = reader.read::<u8>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            radius,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(155, user_id, 19);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.radius);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("radius", self.radius.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            radius: tm.get_u8("radius"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GaussianBlurMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub radius: u8,
+}
+
+impl GaussianBlurMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(19, 19, 156, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let radius = reader.read::<u8>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            radius,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(156, user_id, 19);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.radius);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("radius", self.radius.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            radius: tm.get_u8("radius"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsharpMaskMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub radius: u8,
+    pub amount: u16,
+    pub threshold: u8,
+}
+
+impl UnsharpMaskMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(22, 22, 157, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let radius = reader.read::<u8>();
+        let amount = reader.read::<u16>();
+        let threshold = reader.read::<u8>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            radius,
+            amount,
+            threshold,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(157, user_id, 22);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.radius);
+        w.write(self.amount);
+        w.write(self.threshold);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("radius", self.radius.to_string())
+            .set("amount", self.amount.to_string())
+            .set("threshold", self.threshold.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            radius: tm.get_u8("radius"),
+            amount: tm.get_u16("amount"),
+            threshold: tm.get_u8("threshold"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrightnessContrastMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub brightness: i32,
+    pub contrast: i32,
+}
+
+impl BrightnessContrastMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(26, 26, 158, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let brightness = reader.read::<i32>();
+        let contrast = reader.read::<i32>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            brightness,
+            contrast,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(158, user_id, 26);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.brightness);
+        w.write(self.contrast);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("brightness", self.brightness.to_string())
+            .set("contrast", self.contrast.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            brightness: i32::from_str(tm.get_str("brightness")).unwrap_or_default(),
+            contrast: i32::from_str(tm.get_str("contrast")).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HueSaturationMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub hue: i32,
+    pub saturation: i32,
+    pub lightness: i32,
+}
+
+impl HueSaturationMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(30, 30, 159, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let hue = reader.read::<i32>();
+        let saturation = reader.read::<i32>();
+        let lightness = reader.read::<i32>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            hue,
+            saturation,
+            lightness,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(159, user_id, 30);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.hue);
+        w.write(self.saturation);
+        w.write(self.lightness);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("hue", self.hue.to_string())
+            .set("saturation", self.saturation.to_string())
+            .set("lightness", self.lightness.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            hue: i32::from_str(tm.get_str("hue")).unwrap_or_default(),
+            saturation: i32::from_str(tm.get_str("saturation")).unwrap_or_default(),
+            lightness: i32::from_str(tm.get_str("lightness")).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelsMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub in_black: u8,
+    pub in_white: u8,
+    pub gamma: u16,
+    pub out_black: u8,
+    pub out_white: u8,
+}
+
+impl LevelsMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(24, 24, 160, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+        let in_black = reader.read::<u8>();
+        let in_white = reader.read::<u8>();
+        let gamma = reader.read::<u16>();
+        let out_black = reader.read::<u8>();
+        let out_white = reader.read::<u8>();
+
+        Ok(Self {
+            layer,
+            x,
+            y,
+            w,
+            h,
+            in_black,
+            in_white,
+            gamma,
+            out_black,
+            out_white,
+        })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(160, user_id, 24);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+        w.write(self.in_black);
+        w.write(self.in_white);
+        w.write(self.gamma);
+        w.write(self.out_black);
+        w.write(self.out_white);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+            .set("in_black", self.in_black.to_string())
+            .set("in_white", self.in_white.to_string())
+            .set("gamma", self.gamma.to_string())
+            .set("out_black", self.out_black.to_string())
+            .set("out_white", self.out_white.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+            in_black: tm.get_u8("in_black"),
+            in_white: tm.get_u8("in_white"),
+            gamma: tm.get_u16("gamma"),
+            out_black: tm.get_u8("out_black"),
+            out_white: tm.get_u8("out_white"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvertMessage {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl InvertMessage {
+    fn deserialize(buf: &[u8]) -> Result<Self, DeserializationError> {
+        let mut reader = MessageReader::new(buf).check_len(18, 18, 161, 0)?;
+
+        let layer = reader.read::<u16>();
+        let x = reader.read::<u32>();
+        let y = reader.read::<u32>();
+        let w = reader.read::<u32>();
+        let h = reader.read::<u32>();
+
+        Ok(Self { layer, x, y, w, h })
+    }
+
+    fn serialize(&self, user_id: u8) -> Vec<u8> {
+        let mut w = MessageWriter::with_expected_payload(161, user_id, 18);
+        w.write(self.layer);
+        w.write(self.x);
+        w.write(self.y);
+        w.write(self.w);
+        w.write(self.h);
+
+        w.into()
+    }
+
+    fn to_text(&self, txt: TextMessage) -> TextMessage {
+        txt.set("layer", format!("0x{:04x}", self.layer))
+            .set("x", self.x.to_string())
+            .set("y", self.y.to_string())
+            .set("w", self.w.to_string())
+            .set("h", self.h.to_string())
+    }
+
+    fn from_text(tm: &TextMessage) -> Self {
+        Self {
+            layer: tm.get_u16("layer"),
+            x: tm.get_u32("x"),
+            y: tm.get_u32("y"),
+            w: tm.get_u32("w"),
+            h: tm.get_u32("h"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct UndoMessage {
     pub override_user: u8,
@@ -1484,12 +2260,17 @@ pub enum CommandMessage {
     /// - COPY: a copy of the Source layer is made, rather than a blank layer
     /// - INSERT: the new layer is inserted above the Source layer. Source 0 means
     ///           the layer will be placed bottom-most on the stack
+    /// - GROUP: an empty layer group is created instead of a pixel layer.
+    ///          The fill color is ignored and COPY may not be used.
     ///
     /// The Source layer ID should be zero when COPY or INSERT flags are not used.
     /// When COPY is used, it should refer to an existing layer. Copy commands
     /// referring to missing layers are dropped.
     /// When INSERT is used, referring to 0 or a nonexistent layer places
-    /// the new layer at the bottom of the stack.
+    /// the new layer at the bottom of the stack. If the Source layer is inside
+    /// a group, the new layer is placed in the same group.
+    /// Copying a layer group is not supported. (Use LayerTreeMove to put the
+    /// new layer inside a group.)
     ///
     /// If layer controls are locked, this command requires session operator privileges.
     ///
@@ -1502,6 +2283,13 @@ pub enum CommandMessage {
     /// Specifying a sublayer requires session operator privileges. Currently, it is used
     /// only when sublayers are needed at canvas initialization.
     ///
+    /// The PASSTHROUGH flag is meaningful only for layer groups. The children of a pass-through
+    /// group are composited directly onto the layers below the group and the group's
+    /// blending mode is ignored.
+    ///
+    /// The CLIP flag makes this a clipping layer. A clipping layer is masked with the
+    /// alpha channel of the nearest non-clipping layer below it in the same group.
+    ///
     LayerAttributes(u8, LayerAttributesMessage),
 
     /// Change a layer's title
@@ -1520,6 +2308,9 @@ pub enum CommandMessage {
     /// For example: if the current stack is [1,2,3,4,5] and the client receives
     /// a reordering command [3,4,1], the missing layers are appended: [3,4,1,2,5].
     ///
+    /// The children of each layer group are reordered the same way, using the same list.
+    /// Layers are never moved from one group to another by this command. (See LayerTreeMove.)
+    ///
     /// If layer controls are locked, this command requires session operator privileges.
     ///
     LayerOrder(u8, Vec<u16>),
@@ -1527,7 +2318,11 @@ pub enum CommandMessage {
     /// Delete a layer
     ///
     /// If the merge attribute is set, the contents of the layer is merged
-    /// to the layer below it. Merging the bottom-most layer does nothing.
+    /// to the layer below it. Merging a group is not supported. If there is
+    /// no layer below or the layer below is a group, the layer is deleted
+    /// without merging.
+    ///
+    /// Deleting a layer group deletes its children as well.
     ///
     /// If the current layer or layer controls in general are locked, this command
     /// requires session operator privileges.
@@ -1643,6 +2438,95 @@ pub enum CommandMessage {
     /// Draw square pixel brush dabs
     DrawDabsPixelSquare(u8, DrawDabsPixelMessage),
 
+    /// Move a layer into a layer group
+    ///
+    /// The layer is placed directly above the Sibling layer in the Parent group.
+    /// Parent 0 means the root of the layer stack. If Sibling is 0 or is not
+    /// in the Parent group, the layer is placed at the bottom of the group.
+    ///
+    /// The command is ignored if the Parent is not a group or if the layer is
+    /// a group and the Parent is the layer itself or one of its children.
+    ///
+    /// If layer controls are locked, this command requires session operator privileges.
+    ///
+    LayerTreeMove(u8, LayerTreeMoveMessage),
+
+    /// Create, replace or remove a layer mask
+    ///
+    /// The mask is an 8 bit grayscale channel that is multiplied into the layer's
+    /// content when the layer is composited. The mask has its own ID (from the same
+    /// namespace as layer IDs) and is edited by drawing commands (FillRect, DrawDabs
+    /// and PutTile) that target the mask ID. The luminance of the drawing color is
+    /// used as the mask value. Erasing sets the mask value to zero.
+    ///
+    /// If Mask is 0, the layer's mask is removed. Otherwise, the new mask is filled
+    /// with the Fill value, replacing any existing mask. The command is ignored if the
+    /// mask ID is already in use by another layer or mask.
+    ///
+    /// If the target layer is locked, this command requires session operator privileges.
+    /// As with layer creation, the mask ID's prefix must match the user's ID,
+    /// unless the user is a session operator.
+    ///
+    LayerMask(u8, LayerMaskMessage),
+
+    /// Set or clear the user's selection
+    ///
+    /// While a selection exists, the user's drawing commands (FillRect, PutImage,
+    /// PutTile and DrawDabs) only affect the selected area.
+    ///
+    /// If W or H is zero, the selection is cleared. If Points is empty, the selection
+    /// is the rectangle (X, Y, W, H). Otherwise, Points is a list of (x, y) pairs relative
+    /// to (X, Y) that form a polygon, which is clipped to the rectangle. Pixels whose
+    /// centers are inside the polygon are selected (using the even-odd rule.)
+    ///
+    /// Resizing the canvas clears all selections.
+    ///
+    Selection(u8, SelectionMessage),
+
+    /// Move, scale, rotate or distort a region of a layer
+    ///
+    /// The pixels inside the source quad (SX1, SY1)...(SX4, SY4) are cut out of the
+    /// Source layer and drawn onto the target Layer so that the source quad maps
+    /// to the target quad (X1, Y1)...(X4, Y4). Points are given in clockwise order
+    /// starting from the top-left corner of the region, at pixel corner coordinates.
+    /// If Source is zero, the source layer is the same as the target layer.
+    ///
+    /// Pixels are resampled using the given interpolation mode:
+    ///
+    ///  * 0: nearest neighbour
+    ///  * 1: bilinear
+    ///
+    TransformRegion(u8, TransformRegionMessage),
+
+    /// Blur a rectangle of the layer with a box filter
+    BoxBlur(u8, BoxBlurMessage),
+
+    /// Blur a rectangle of the layer with an approximate Gaussian filter
+    GaussianBlur(u8, GaussianBlurMessage),
+
+    /// Sharpen a rectangle of the layer
+    ///
+    /// Amount is the sharpening strength in percent. Color differences from the
+    /// blurred image smaller than Threshold are not sharpened.
+    ///
+    UnsharpMask(u8, UnsharpMaskMessage),
+
+    /// Adjust the brightness (-255..255) and contrast (-100..100) of a rectangle of the layer
+    BrightnessContrast(u8, BrightnessContrastMessage),
+
+    /// Rotate the hue (in degrees) and adjust the saturation and lightness (-100..100) of a rectangle of the layer
+    HueSaturation(u8, HueSaturationMessage),
+
+    /// Adjust the levels of a rectangle of the layer
+    ///
+    /// The input range is mapped to the output range. Gamma is given in hundredths
+    /// (100 is linear.)
+    ///
+    Levels(u8, LevelsMessage),
+
+    /// Invert the colors of a rectangle of the layer
+    Invert(u8, InvertMessage),
+
     /// Undo or redo actions
     Undo(u8, UndoMessage),
 }
@@ -1684,6 +2568,15 @@ impl ControlMessage {
             Ping(user_id, _) => *user_id,
         }
     }
+
+    pub fn set_user(&mut self, user: u8) {
+        use ControlMessage::*;
+        match self {
+            ServerCommand(user_id, _) => *user_id = user,
+            Disconnect(user_id, _) => *user_id = user,
+            Ping(user_id, _) => *user_id = user,
+        }
+    }
 }
 
 impl fmt::Display for ControlMessage {
@@ -1741,6 +2634,19 @@ impl ServerMetaMessage {
             PrivateChat(user_id, _) => *user_id,
         }
     }
+
+    pub fn set_user(&mut self, user: u8) {
+        use ServerMetaMessage::*;
+        match self {
+            Join(user_id, _) => *user_id = user,
+            Leave(user_id) => *user_id = user,
+            SessionOwner(user_id, _) => *user_id = user,
+            Chat(user_id, _) => *user_id = user,
+            TrustedUsers(user_id, _) => *user_id = user,
+            SoftReset(user_id) => *user_id = user,
+            PrivateChat(user_id, _) => *user_id = user,
+        }
+    }
 }
 
 impl fmt::Display for ServerMetaMessage {
@@ -1806,6 +2712,21 @@ impl ClientMetaMessage {
             Filtered(user_id, _) => *user_id,
         }
     }
+
+    pub fn set_user(&mut self, user: u8) {
+        use ClientMetaMessage::*;
+        match self {
+            Interval(user_id, _) => *user_id = user,
+            LaserTrail(user_id, _) => *user_id = user,
+            MovePointer(user_id, _) => *user_id = user,
+            Marker(user_id, _) => *user_id = user,
+            UserACL(user_id, _) => *user_id = user,
+            LayerACL(user_id, _) => *user_id = user,
+            FeatureAccessLevels(user_id, _) => *user_id = user,
+            DefaultLayer(user_id, _) => *user_id = user,
+            Filtered(user_id, _) => *user_id = user,
+        }
+    }
 }
 
 impl fmt::Display for ClientMetaMessage {
@@ -1844,6 +2765,17 @@ impl CommandMessage {
             DrawDabsClassic(user_id, b) => b.serialize(*user_id),
             DrawDabsPixel(user_id, b) => b.serialize(*user_id),
             DrawDabsPixelSquare(user_id, b) => b.serialize(*user_id),
+            LayerTreeMove(user_id, b) => b.serialize(*user_id),
+            LayerMask(user_id, b) => b.serialize(*user_id),
+            Selection(user_id, b) => b.serialize(*user_id),
+            TransformRegion(user_id, b) => b.serialize(*user_id),
+            BoxBlur(user_id, b) => b.serialize(*user_id),
+            GaussianBlur(user_id, b) => b.serialize(*user_id),
+            UnsharpMask(user_id, b) => b.serialize(*user_id),
+            BrightnessContrast(user_id, b) => b.serialize(*user_id),
+            HueSaturation(user_id, b) => b.serialize(*user_id),
+            Levels(user_id, b) => b.serialize(*user_id),
+            Invert(user_id, b) => b.serialize(*user_id),
             Undo(user_id, b) => b.serialize(*user_id),
         }
     }
@@ -1881,6 +2813,19 @@ impl CommandMessage {
             DrawDabsPixelSquare(user_id, b) => {
                 b.to_text(TextMessage::new(*user_id, "squarepixeldabs"))
             }
+            LayerTreeMove(user_id, b) => b.to_text(TextMessage::new(*user_id, "movelayer")),
+            LayerMask(user_id, b) => b.to_text(TextMessage::new(*user_id, "layermask")),
+            Selection(user_id, b) => b.to_text(TextMessage::new(*user_id, "selection")),
+            TransformRegion(user_id, b) => b.to_text(TextMessage::new(*user_id, "transform")),
+            BoxBlur(user_id, b) => b.to_text(TextMessage::new(*user_id, "boxblur")),
+            GaussianBlur(user_id, b) => b.to_text(TextMessage::new(*user_id, "gaussianblur")),
+            UnsharpMask(user_id, b) => b.to_text(TextMessage::new(*user_id, "unsharpmask")),
+            BrightnessContrast(user_id, b) => {
+                b.to_text(TextMessage::new(*user_id, "brightnesscontrast"))
+            }
+            HueSaturation(user_id, b) => b.to_text(TextMessage::new(*user_id, "huesaturation")),
+            Levels(user_id, b) => b.to_text(TextMessage::new(*user_id, "levels")),
+            Invert(user_id, b) => b.to_text(TextMessage::new(*user_id, "invert")),
             Undo(user_id, b) => b.to_text(TextMessage::new(*user_id, "undo")),
         }
     }
@@ -1908,9 +2853,58 @@ impl CommandMessage {
             DrawDabsClassic(user_id, _) => *user_id,
             DrawDabsPixel(user_id, _) => *user_id,
             DrawDabsPixelSquare(user_id, _) => *user_id,
+            LayerTreeMove(user_id, _) => *user_id,
+            LayerMask(user_id, _) => *user_id,
+            Selection(user_id, _) => *user_id,
+            TransformRegion(user_id, _) => *user_id,
+            BoxBlur(user_id, _) => *user_id,
+            GaussianBlur(user_id, _) => *user_id,
+            UnsharpMask(user_id, _) => *user_id,
+            BrightnessContrast(user_id, _) => *user_id,
+            HueSaturation(user_id, _) => *user_id,
+            Levels(user_id, _) => *user_id,
+            Invert(user_id, _) => *user_id,
             Undo(user_id, _) => *user_id,
         }
     }
+
+    pub fn set_user(&mut self, user: u8) {
+        use CommandMessage::*;
+        match self {
+            UndoPoint(user_id) => *user_id = user,
+            CanvasResize(user_id, _) => *user_id = user,
+            LayerCreate(user_id, _) => *user_id = user,
+            LayerAttributes(user_id, _) => *user_id = user,
+            LayerRetitle(user_id, _) => *user_id = user,
+            LayerOrder(user_id, _) => *user_id = user,
+            LayerDelete(user_id, _) => *user_id = user,
+            LayerVisibility(user_id, _) => *user_id = user,
+            PutImage(user_id, _) => *user_id = user,
+            FillRect(user_id, _) => *user_id = user,
+            PenUp(user_id) => *user_id = user,
+            AnnotationCreate(user_id, _) => *user_id = user,
+            AnnotationReshape(user_id, _) => *user_id = user,
+            AnnotationEdit(user_id, _) => *user_id = user,
+            AnnotationDelete(user_id, _) => *user_id = user,
+            PutTile(user_id, _) => *user_id = user,
+            CanvasBackground(user_id, _) => *user_id = user,
+            DrawDabsClassic(user_id, _) => *user_id = user,
+            DrawDabsPixel(user_id, _) => *user_id = user,
+            DrawDabsPixelSquare(user_id, _) => *user_id = user,
+            LayerTreeMove(user_id, _) => *user_id = user,
+            LayerMask(user_id, _) => *user_id = user,
+            Selection(user_id, _) => *user_id = user,
+            TransformRegion(user_id, _) => *user_id = user,
+            BoxBlur(user_id, _) => *user_id = user,
+            GaussianBlur(user_id, _) => *user_id = user,
+            UnsharpMask(user_id, _) => *user_id = user,
+            BrightnessContrast(user_id, _) => *user_id = user,
+            HueSaturation(user_id, _) => *user_id = user,
+            Levels(user_id, _) => *user_id = user,
+            Invert(user_id, _) => *user_id = user,
+            Undo(user_id, _) => *user_id = user,
+        }
+    }
 }
 
 impl fmt::Display for CommandMessage {
@@ -2112,6 +3106,50 @@ impl Message {
                 user_id,
                 DrawDabsPixelMessage::deserialize(&buf)?,
             )),
+            151 => Command(CommandMessage::LayerTreeMove(
+                user_id,
+                LayerTreeMoveMessage::deserialize(&buf)?,
+            )),
+            152 => Command(CommandMessage::LayerMask(
+                user_id,
+                LayerMaskMessage::deserialize(&buf)?,
+            )),
+            153 => Command(CommandMessage::Selection(
+                user_id,
+                SelectionMessage::deserialize(&buf)?,
+            )),
+            154 => Command(CommandMessage::TransformRegion(
+                user_id,
+                TransformRegionMessage::deserialize(&buf)?,
+            )),
+            155 => Command(CommandMessage::BoxBlur(
+                user_id,
+                BoxBlurMessage::deserialize(&buf)?,
+            )),
+            156 => Command(CommandMessage::GaussianBlur(
+                user_id,
+                GaussianBlurMessage::deserialize(&buf)?,
+            )),
+            157 => Command(CommandMessage::UnsharpMask(
+                user_id,
+                UnsharpMaskMessage::deserialize(&buf)?,
+            )),
+            158 => Command(CommandMessage::BrightnessContrast(
+                user_id,
+                BrightnessContrastMessage::deserialize(&buf)?,
+            )),
+            159 => Command(CommandMessage::HueSaturation(
+                user_id,
+                HueSaturationMessage::deserialize(&buf)?,
+            )),
+            160 => Command(CommandMessage::Levels(
+                user_id,
+                LevelsMessage::deserialize(&buf)?,
+            )),
+            161 => Command(CommandMessage::Invert(
+                user_id,
+                InvertMessage::deserialize(&buf)?,
+            )),
             255 => Command(CommandMessage::Undo(
                 user_id,
                 UndoMessage::deserialize(&buf)?,
@@ -2282,6 +3320,50 @@ impl Message {
                 tm.user_id,
                 DrawDabsPixelMessage::from_text(&tm),
             )),
+            "movelayer" => Command(CommandMessage::LayerTreeMove(
+                tm.user_id,
+                LayerTreeMoveMessage::from_text(&tm),
+            )),
+            "layermask" => Command(CommandMessage::LayerMask(
+                tm.user_id,
+                LayerMaskMessage::from_text(&tm),
+            )),
+            "selection" => Command(CommandMessage::Selection(
+                tm.user_id,
+                SelectionMessage::from_text(&tm),
+            )),
+            "transform" => Command(CommandMessage::TransformRegion(
+                tm.user_id,
+                TransformRegionMessage::from_text(&tm),
+            )),
+            "boxblur" => Command(CommandMessage::BoxBlur(
+                tm.user_id,
+                BoxBlurMessage::from_text(&tm),
+            )),
+            "gaussianblur" => Command(CommandMessage::GaussianBlur(
+                tm.user_id,
+                GaussianBlurMessage::from_text(&tm),
+            )),
+            "unsharpmask" => Command(CommandMessage::UnsharpMask(
+                tm.user_id,
+                UnsharpMaskMessage::from_text(&tm),
+            )),
+            "brightnesscontrast" => Command(CommandMessage::BrightnessContrast(
+                tm.user_id,
+                BrightnessContrastMessage::from_text(&tm),
+            )),
+            "huesaturation" => Command(CommandMessage::HueSaturation(
+                tm.user_id,
+                HueSaturationMessage::from_text(&tm),
+            )),
+            "levels" => Command(CommandMessage::Levels(
+                tm.user_id,
+                LevelsMessage::from_text(&tm),
+            )),
+            "invert" => Command(CommandMessage::Invert(
+                tm.user_id,
+                InvertMessage::from_text(&tm),
+            )),
             "undo" => Command(CommandMessage::Undo(
                 tm.user_id,
                 UndoMessage::from_text(&tm),
@@ -2301,6 +3383,26 @@ impl Message {
             Command(m) => m.as_text(),
         }
     }
+
+    pub fn user(&self) -> u8 {
+        use Message::*;
+        match &self {
+            Control(m) => m.user(),
+            ServerMeta(m) => m.user(),
+            ClientMeta(m) => m.user(),
+            Command(m) => m.user(),
+        }
+    }
+
+    pub fn set_user(&mut self, user: u8) {
+        use Message::*;
+        match self {
+            Control(m) => m.set_user(user),
+            ServerMeta(m) => m.set_user(user),
+            ClientMeta(m) => m.set_user(user),
+            Command(m) => m.set_user(user),
+        }
+    }
 }
 
 impl fmt::Display for Message {
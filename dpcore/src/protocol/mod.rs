@@ -20,19 +20,23 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
+mod codec;
 pub mod message;
 mod protover;
 mod reader;
 mod serialization;
+pub mod servercommand;
 mod textmessage;
 mod textparser;
 mod writer;
 
+pub use codec::{CodecError, MessageCodec};
 pub use message::{Message, VERSION};
 pub use protover::ProtocolVersion;
 pub use reader::{
     open_recording, BinaryReader, Compatibility, ReadMessage, RecordingReader, TextReader,
 };
 pub use serialization::DeserializationError;
+pub use servercommand::{ServerCommand, ServerReply};
 pub use textparser::TextParser;
 pub use writer::{BinaryWriter, RecordingWriter, TextWriter};
@@ -220,6 +220,19 @@ impl {{ message_type }}Message {
             {% endif %}{% endfor %}{# message in messages #}
         }
     }
+
+    pub fn set_user(&mut self, user: u8) {
+        use {{ message_type }}Message::*;
+        match self {
+            {% for message in messages %}{% if message.message_type == message_type %}
+            {% if message.alias or message.fields %}
+            {{ message.name }}(user_id, _) => *user_id = user,
+            {% else %}
+            {{ message.name }}(user_id) => *user_id = user,
+            {% endif %}
+            {% endif %}{% endfor %}{# message in messages #}
+        }
+    }
 }
 
 impl fmt::Display for {{ message_type }}Message {
@@ -315,6 +328,20 @@ impl Message {
             {% for mt in message_types %}{{ mt }}(m) => m.as_text(),{% endfor %}
         }
     }
+
+    pub fn user(&self) -> u8 {
+        use Message::*;
+        match &self {
+            {% for mt in message_types %}{{ mt }}(m) => m.user(),{% endfor %}
+        }
+    }
+
+    pub fn set_user(&mut self, user: u8) {
+        use Message::*;
+        match self {
+            {% for mt in message_types %}{{ mt }}(m) => m.set_user(user),{% endfor %}
+        }
+    }
 }
 
 impl fmt::Display for Message {
@@ -439,6 +466,9 @@ def payload_len(message):
     if not vectors:
         return fixed
 
+    # Length prefixes of variable length fields are part of the payload too
+    fixed += sum(int(vec.prefix_type[1:]) // 8 for vec in vectors if getattr(vec, 'prefix_type', None))
+
     total = [str(fixed)]
 
     for vec in vectors:
@@ -0,0 +1,459 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Typed models for the JSON payloads of ServerCommand messages.
+//!
+//! Commands sent by the client to the server have the general form
+//! `{"cmd": "name", "args": [...], "kwargs": {...}}`. Replies sent by
+//! the server are objects with a `type` field.
+
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Version of the login protocol
+pub const LOGIN_VERSION: u32 = 4;
+
+/// The generic form of a client-to-server command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GenericCommand {
+    pub cmd: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub kwargs: Map<String, Value>,
+}
+
+/// Identify the user during login
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentCommand {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// Host a new session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct HostCommand {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub protocol: String,
+    #[serde(default)]
+    pub user_id: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// Join an existing session
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinCommand {
+    pub id: String,
+    pub password: Option<String>,
+}
+
+/// Session settings.
+///
+/// Only the fields that are set are changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionConf {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_user_count: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preserve_chat: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nsfm: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deputies: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opword: Option<String>,
+}
+
+/// Kick a user from the session
+#[derive(Debug, Clone, PartialEq)]
+pub struct KickUserCommand {
+    pub user: u8,
+    pub ban: bool,
+}
+
+/// A command sent by a client to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerCommand {
+    Ident(IdentCommand),
+    Host(HostCommand),
+    Join(JoinCommand),
+    SessionConf(SessionConf),
+    KickUser(KickUserCommand),
+
+    /// A command without a typed model
+    Other(GenericCommand),
+}
+
+impl ServerCommand {
+    pub fn from_json(json: &str) -> Result<ServerCommand, serde_json::Error> {
+        Self::from_generic(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_generic()).expect("command serialization failed")
+    }
+
+    pub fn from_generic(cmd: GenericCommand) -> Result<ServerCommand, serde_json::Error> {
+        let arg = |i: usize| cmd.args.get(i).and_then(Value::as_str).map(str::to_string);
+        fn kwargs<T: DeserializeOwned>(cmd: &GenericCommand) -> Result<T, serde_json::Error> {
+            serde_json::from_value(Value::Object(cmd.kwargs.clone()))
+        }
+
+        Ok(match cmd.cmd.as_str() {
+            "ident" => ServerCommand::Ident(IdentCommand {
+                username: arg(0).ok_or_else(|| serde_json::Error::custom("username missing"))?,
+                password: arg(1),
+            }),
+            "host" => ServerCommand::Host(kwargs(&cmd)?),
+            "join" => ServerCommand::Join(JoinCommand {
+                id: arg(0)
+                    .or_else(|| {
+                        cmd.kwargs
+                            .get("id")
+                            .and_then(Value::as_str)
+                            .map(str::to_string)
+                    })
+                    .ok_or_else(|| serde_json::Error::custom("session ID missing"))?,
+                password: cmd
+                    .kwargs
+                    .get("password")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+            }),
+            "sessionconf" => ServerCommand::SessionConf(kwargs(&cmd)?),
+            "kick-user" => ServerCommand::KickUser(KickUserCommand {
+                user: cmd
+                    .args
+                    .first()
+                    .and_then(Value::as_u64)
+                    .filter(|&u| u <= u8::MAX as u64)
+                    .ok_or_else(|| serde_json::Error::custom("user ID missing"))?
+                    as u8,
+                ban: cmd
+                    .kwargs
+                    .get("ban")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            }),
+            _ => ServerCommand::Other(cmd),
+        })
+    }
+
+    pub fn to_generic(&self) -> GenericCommand {
+        fn kwargs<T: Serialize>(cmd: &str, params: &T) -> GenericCommand {
+            match serde_json::to_value(params) {
+                Ok(Value::Object(kwargs)) => GenericCommand {
+                    cmd: cmd.to_string(),
+                    args: Vec::new(),
+                    kwargs,
+                },
+                _ => unreachable!("command parameters must serialize to an object"),
+            }
+        }
+
+        match self {
+            ServerCommand::Ident(c) => GenericCommand {
+                cmd: "ident".to_string(),
+                args: std::iter::once(&c.username)
+                    .chain(c.password.iter())
+                    .map(|s| Value::from(s.as_str()))
+                    .collect(),
+                kwargs: Map::new(),
+            },
+            ServerCommand::Host(c) => kwargs("host", c),
+            ServerCommand::Join(c) => {
+                let mut kwargs = Map::new();
+                if let Some(pw) = &c.password {
+                    kwargs.insert("password".to_string(), pw.as_str().into());
+                }
+                GenericCommand {
+                    cmd: "join".to_string(),
+                    args: vec![c.id.as_str().into()],
+                    kwargs,
+                }
+            }
+            ServerCommand::SessionConf(c) => kwargs("sessionconf", c),
+            ServerCommand::KickUser(c) => {
+                let mut kwargs = Map::new();
+                if c.ban {
+                    kwargs.insert("ban".to_string(), true.into());
+                }
+                GenericCommand {
+                    cmd: "kick-user".to_string(),
+                    args: vec![c.user.into()],
+                    kwargs,
+                }
+            }
+            ServerCommand::Other(c) => c.clone(),
+        }
+    }
+}
+
+/// Information about a session, as shown in the session listing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub alias: String,
+    pub protocol: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    pub users: usize,
+}
+
+/// Login stage messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum LoginReply {
+    /// The greeting sent to newly connected clients
+    Greeting { version: u32, flags: Vec<String> },
+
+    /// The list of available sessions
+    Sessions { sessions: Vec<SessionInfo> },
+}
+
+/// The session the user joined
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JoinInfo {
+    pub id: String,
+    pub user: u8,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+/// A successful response to a command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResultReply {
+    /// The login state reached, e.g. "identOk", "host" or "join"
+    pub state: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ident: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join: Option<JoinInfo>,
+}
+
+/// An error response to a command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorReply {
+    pub code: String,
+    pub message: String,
+}
+
+/// A reply or a notification sent by the server to a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerReply {
+    Login(LoginReply),
+    Result(ResultReply),
+    Error(ErrorReply),
+    SessionConf {
+        config: SessionConf,
+    },
+
+    /// A reply type without a typed model. Contains the whole reply object.
+    #[serde(skip)]
+    Unknown(Value),
+}
+
+impl ServerReply {
+    /// The reply types that have a typed model
+    const TYPED_REPLIES: &'static [&'static str] = &["login", "result", "error", "sessionconf"];
+
+    pub fn from_json(json: &str) -> Result<ServerReply, serde_json::Error> {
+        let reply: Value = serde_json::from_str(json)?;
+        match reply.get("type").and_then(Value::as_str) {
+            Some(t) if Self::TYPED_REPLIES.contains(&t) => serde_json::from_value(reply),
+            Some(_) => Ok(ServerReply::Unknown(reply)),
+            None => Err(serde_json::Error::custom("reply type missing")),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            ServerReply::Unknown(reply) => reply.to_string(),
+            _ => serde_json::to_string(self).expect("reply serialization failed"),
+        }
+    }
+
+    pub fn error(code: &str, message: &str) -> ServerReply {
+        ServerReply::Error(ErrorReply {
+            code: code.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip_command(json: Value) -> ServerCommand {
+        let cmd = ServerCommand::from_json(&json.to_string()).unwrap();
+        let reserialized: Value = serde_json::from_str(&cmd.to_json()).unwrap();
+        assert_eq!(reserialized, json);
+        cmd
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            roundtrip_command(json!({"cmd": "ident", "args": ["bob", "secret"]})),
+            ServerCommand::Ident(IdentCommand {
+                username: "bob".into(),
+                password: Some("secret".into()),
+            })
+        );
+
+        assert_eq!(
+            roundtrip_command(
+                json!({"cmd": "host", "kwargs": {"id": "test", "protocol": "dp:4.21.2", "user_id": 3}})
+            ),
+            ServerCommand::Host(HostCommand {
+                id: Some("test".into()),
+                protocol: "dp:4.21.2".into(),
+                user_id: 3,
+                ..HostCommand::default()
+            })
+        );
+
+        assert_eq!(
+            roundtrip_command(json!({"cmd": "join", "args": ["test"]})),
+            ServerCommand::Join(JoinCommand {
+                id: "test".into(),
+                password: None,
+            })
+        );
+
+        assert_eq!(
+            roundtrip_command(
+                json!({"cmd": "sessionconf", "kwargs": {"title": "Hi", "maxUserCount": 10}})
+            ),
+            ServerCommand::SessionConf(SessionConf {
+                title: Some("Hi".into()),
+                max_user_count: Some(10),
+                ..SessionConf::default()
+            })
+        );
+
+        assert_eq!(
+            roundtrip_command(json!({"cmd": "kick-user", "args": [2], "kwargs": {"ban": true}})),
+            ServerCommand::KickUser(KickUserCommand { user: 2, ban: true })
+        );
+
+        assert_eq!(
+            roundtrip_command(json!({"cmd": "kick-user", "args": [7]})),
+            ServerCommand::KickUser(KickUserCommand {
+                user: 7,
+                ban: false
+            })
+        );
+
+        assert!(
+            ServerCommand::from_json(r#"{"cmd": "kick-user", "kwargs": {"user": 2}}"#).is_err()
+        );
+        assert!(ServerCommand::from_json(r#"{"cmd": "kick-user", "args": [300]}"#).is_err());
+
+        assert!(matches!(
+            roundtrip_command(json!({"cmd": "reset-session"})),
+            ServerCommand::Other(_)
+        ));
+
+        assert!(ServerCommand::from_json(r#"{"cmd": "ident"}"#).is_err());
+        assert!(ServerCommand::from_json("not json").is_err());
+    }
+
+    fn roundtrip_reply(json: Value) -> ServerReply {
+        let reply = ServerReply::from_json(&json.to_string()).unwrap();
+        let reserialized: Value = serde_json::from_str(&reply.to_json()).unwrap();
+        assert_eq!(reserialized, json);
+        reply
+    }
+
+    #[test]
+    fn test_replies() {
+        assert_eq!(
+            roundtrip_reply(json!({"type": "login", "version": 4, "flags": []})),
+            ServerReply::Login(LoginReply::Greeting {
+                version: LOGIN_VERSION,
+                flags: vec![],
+            })
+        );
+
+        assert_eq!(
+            roundtrip_reply(
+                json!({"type": "login", "sessions": [{"id": "a", "protocol": "dp:4.21.2", "users": 2}]})
+            ),
+            ServerReply::Login(LoginReply::Sessions {
+                sessions: vec![SessionInfo {
+                    id: "a".into(),
+                    alias: String::new(),
+                    protocol: "dp:4.21.2".into(),
+                    title: String::new(),
+                    users: 2,
+                }]
+            })
+        );
+
+        let join = roundtrip_reply(
+            json!({"type": "result", "state": "join", "join": {"id": "a", "user": 1, "flags": ["MOD"]}}),
+        );
+        match join {
+            ServerReply::Result(r) => assert_eq!(r.join.unwrap().user, 1),
+            r => panic!("unexpected reply {:?}", r),
+        }
+
+        assert_eq!(
+            roundtrip_reply(json!({"type": "error", "code": "notFound", "message": "Not found"})),
+            ServerReply::error("notFound", "Not found")
+        );
+
+        assert_eq!(
+            roundtrip_reply(json!({"type": "log", "message": "hello"})),
+            ServerReply::Unknown(json!({"type": "log", "message": "hello"}))
+        );
+
+        assert!(ServerReply::from_json(r#"{"message": "hello"}"#).is_err());
+        assert!(ServerReply::from_json(r#"{"type": "error", "code": "x"}"#).is_err());
+    }
+}
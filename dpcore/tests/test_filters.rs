@@ -0,0 +1,90 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::filter::Filter;
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+#[test]
+fn test_filter_region() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=150 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffffff name=Background"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=60 y=20 w=10 h=70 color=#ffff0000 mode=1",
+    ));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=0 y=50 w=150 h=5 color=#800000ff mode=1",
+    ));
+
+    let (width, height) = (150, 100);
+    let original = layer_pixels(&canvas, 0x0101, width, height);
+
+    // Filtering a region that spans several tiles should give the same result
+    // as filtering the whole image and cropping
+    let filter = Filter::GaussianBlur(7);
+    let mut expected = original.clone();
+    filter.apply(&mut expected, width as usize, height as usize);
+
+    canvas.receive_message(&m(
+        "1 gaussianblur layer=0x0101 x=40 y=30 w=70 h=60 radius=7",
+    ));
+    let result = layer_pixels(&canvas, 0x0101, width, height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = (y * width + x) as usize;
+            if (40..110).contains(&x) && (30..90).contains(&y) {
+                assert_eq!(result[i], expected[i], "at {}, {}", x, y);
+            } else {
+                assert_eq!(result[i], original[i], "at {}, {}", x, y);
+            }
+        }
+    }
+    assert_ne!(result, original);
+
+    // Filters are clipped to the selection
+    canvas.receive_message(&m("1 selection x=0 y=0 w=20 h=20"));
+    canvas.receive_message(&m("1 invert layer=0x0101 x=0 y=0 w=150 h=100"));
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(layer.pixel_at(5, 5), [0, 0, 0, 255]);
+    assert_eq!(layer.pixel_at(25, 5), [255, 255, 255, 255]);
+}
+
+fn layer_pixels(canvas: &CanvasState, id: LayerID, width: u32, height: u32) -> Vec<Pixel> {
+    let layer = canvas.layerstack().get_layer(id).unwrap();
+    let mut pixels = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(layer.pixel_at(x, y));
+        }
+    }
+    pixels
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
@@ -0,0 +1,122 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::editlayer;
+use dpcore::paint::floodfill::{FillSource, FloodFill};
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+#[test]
+fn test_floodfill_putimage() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=80"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffff name=Background"));
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Lines"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=50 y=0 w=2 h=80 color=#ff000000 mode=1",
+    ));
+
+    let fill = FloodFill {
+        x: 10,
+        y: 10,
+        tolerance: 10,
+        expansion: 1,
+        feather: 1,
+    };
+    let color = Color::rgb8(255, 0, 0);
+
+    // Fill locally, sampling the merged image
+    let stack = canvas.layerstack_snapshot();
+    let mut local = stack.get_layer(0x0101).unwrap().clone();
+    editlayer::flood_fill(
+        &mut local,
+        1,
+        Some(&stack),
+        &fill,
+        &color,
+        Blendmode::Normal,
+    );
+
+    // Generate the PutImage messages and send them
+    let mask = fill.mask(FillSource::Merged(&stack)).unwrap();
+    for msg in mask.to_putimages(1, 0x0101, &color, Blendmode::Normal) {
+        canvas.receive_message(&msg);
+    }
+
+    let remote = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(local.pixel_at(10, 10), color.as_pixel());
+    assert_eq!(
+        local.pixel_at(90, 10),
+        Color::rgb8(255, 255, 255).as_pixel()
+    );
+    for y in 0..80 {
+        for x in 0..100 {
+            assert_eq!(local.pixel_at(x, y), remote.pixel_at(x, y));
+        }
+    }
+}
+
+#[test]
+fn test_floodfill_large() {
+    // A solid fill this large does not fit in a single PutImage message
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=4200 bottom=4200"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 name=Background"));
+
+    let fill = FloodFill {
+        x: 0,
+        y: 0,
+        tolerance: 0,
+        expansion: 0,
+        feather: 0,
+    };
+    let color = Color::rgb8(0, 128, 255);
+    let mask = fill
+        .mask(FillSource::Layer(
+            canvas.layerstack().get_layer(0x0101).unwrap(),
+        ))
+        .unwrap();
+    assert_eq!(mask.rect, Rectangle::new(0, 0, 4200, 4200));
+
+    let msgs = mask.to_putimages(1, 0x0101, &color, Blendmode::Normal);
+    assert!(msgs.len() > 1);
+    for msg in msgs {
+        let msg = Message::Command(msg);
+        assert_eq!(Message::deserialize(&msg.serialize()).unwrap(), msg);
+        if let Message::Command(c) = msg {
+            canvas.receive_message(&c);
+        }
+    }
+
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    for (x, y) in [(0, 0), (4199, 0), (2100, 2100), (0, 4199), (4199, 4199)].iter() {
+        assert_eq!(layer.pixel_at(*x, *y), color.as_pixel());
+    }
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
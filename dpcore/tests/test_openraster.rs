@@ -0,0 +1,254 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "openraster")]
+
+use dpcore::canvas::compression::compress_tile;
+use dpcore::canvas::{make_reset_image, CanvasState};
+use dpcore::paint::openraster::{load_openraster, save_openraster};
+use dpcore::paint::tile::Tile;
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+use std::io::{Cursor, Write};
+
+#[test]
+fn test_openraster_roundtrip() {
+    let mut canvas = CanvasState::new();
+
+    canvas.receive_message(&m("1 resize right=200 bottom=150"));
+    canvas.receive_message(&CommandMessage::CanvasBackground(
+        1,
+        compress_tile(&Tile::new(&Color::rgb8(200, 200, 255), 0)),
+    ));
+
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffff name=Background"));
+
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Layer&<\"friends\">"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=10 y=10 w=150 h=100 color=#80ff0000 mode=1",
+    ));
+    canvas.receive_message(&m("1 layerattr id=0x0102 opacity=128 blend=2 flags=censor"));
+
+    canvas.receive_message(&m("1 newlayer id=0x0103 flags=group name=Group"));
+    canvas.receive_message(&m(
+        "1 layerattr id=0x0103 opacity=200 blend=1 flags=passthrough",
+    ));
+    canvas.receive_message(&m("1 newlayer id=0x0104 fill=#400000ff name=Child"));
+    canvas.receive_message(&m("1 newlayer id=0x0105 fill=#4000ff00 name=Child2"));
+    canvas.receive_message(&m("1 movelayer layer=0x0104 parent=0x0103"));
+    canvas.receive_message(&m("1 movelayer layer=0x0105 parent=0x0103 sibling=0x0104"));
+    canvas.receive_message(&m("1 layerattr id=0x0105 opacity=255 blend=13 flags=clip"));
+
+    canvas.receive_message(&m("1 layermask id=0x0102 mask=0x0106 fill=255"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0106 x=20 y=20 w=100 h=30 color=#ff404040 mode=1",
+    ));
+
+    canvas.receive_message(&m("1 newannotation id=0x0101 x=5 y=6 w=100 h=50"));
+    canvas.receive_message(&m(
+        "1 editannotation id=0x0101 bg=#80ffffff flags=6 text=Hello",
+    ));
+
+    let original = canvas.layerstack();
+
+    let mut file = Cursor::new(Vec::new());
+    save_openraster(original, CensorPolicy::Show, &mut file).unwrap();
+    file.set_position(0);
+    let loaded = load_openraster(file).unwrap();
+
+    assert_eq!(loaded.width(), original.width());
+    assert_eq!(loaded.height(), original.height());
+    assert_eq!(loaded.background, original.background);
+
+    for (a, b) in original
+        .iter_layers_recursive()
+        .zip(loaded.iter_layers_recursive())
+    {
+        assert_eq!(a.is_group(), b.is_group());
+        assert_eq!(a.passthrough, b.passthrough);
+        assert_eq!(a.clip, b.clip);
+        assert_eq!(a.title, b.title);
+        assert_eq!(a.opacity, b.opacity);
+        assert_eq!(a.hidden, b.hidden);
+        assert_eq!(a.blendmode, b.blendmode);
+        assert_eq!(a.censored, b.censored);
+        assert_eq!(a.mask().is_some(), b.mask().is_some());
+    }
+    assert_eq!(
+        original.iter_layers_recursive().count(),
+        loaded.iter_layers_recursive().count()
+    );
+
+    let a = original.iter_annotations().next().unwrap();
+    let b = loaded.iter_annotations().next().unwrap();
+    assert_eq!(a.rect, b.rect);
+    assert_eq!(a.text, b.text);
+    assert_eq!(a.background, b.background);
+    assert_eq!(a.valign as u8, b.valign as u8);
+
+    // A session started from the loaded file should look the same too
+    let mut restored_canvas = CanvasState::new();
+    for msg in make_reset_image(&loaded, 0).iter() {
+        restored_canvas.receive_message(msg);
+    }
+    let restored = restored_canvas.layerstack();
+
+    // Only the part of the edge tiles inside the canvas is saved
+    let (image, w, h) = original.to_image();
+    assert_eq!(loaded.to_image(), (image.clone(), w, h));
+    assert_eq!(restored.to_image(), (image, w, h));
+}
+
+#[test]
+fn test_openraster_censor() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=70"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffff name=Background"));
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Censored"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=10 y=10 w=50 h=50 color=#ffff0000 mode=1",
+    ));
+    canvas.receive_message(&m("1 layerattr id=0x0102 opacity=255 blend=1 flags=censor"));
+    canvas.receive_message(&m("1 newlayer id=0x0103 flags=group name=Group"));
+    canvas.receive_message(&m("1 layerattr id=0x0103 opacity=255 blend=1 flags=censor"));
+    canvas.receive_message(&m("1 newlayer id=0x0104 name=Child"));
+    canvas.receive_message(&m("1 movelayer layer=0x0104 parent=0x0103"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0104 x=70 y=5 w=25 h=60 color=#ff0000ff mode=1",
+    ));
+    let original = canvas.layerstack();
+
+    let save = |censor| {
+        let mut file = Cursor::new(Vec::new());
+        save_openraster(original, censor, &mut file).unwrap();
+        file.set_position(0);
+        load_openraster(file).unwrap()
+    };
+
+    // Censored layers and groups are left out
+    let hidden = save(CensorPolicy::Hide);
+    let titles: Vec<&str> = hidden
+        .iter_layers_recursive()
+        .map(|l| l.title.as_str())
+        .collect();
+    assert_eq!(titles, vec!["Background"]);
+    assert_eq!(
+        hidden.to_image(),
+        original.to_image_censored(CensorPolicy::Hide)
+    );
+
+    // Censored layers (including the children of censored groups) are pixelated
+    let pixelated = save(CensorPolicy::Pixelate(16));
+    assert_eq!(pixelated.iter_layers_recursive().count(), 4);
+    assert_eq!(
+        pixelated.to_image(),
+        original.to_image_censored(CensorPolicy::Pixelate(16))
+    );
+    assert_ne!(pixelated.to_image(), original.to_image());
+}
+
+#[test]
+fn test_not_openraster() {
+    assert!(load_openraster(Cursor::new(b"hello".to_vec())).is_err());
+}
+
+#[test]
+fn test_malformed_openraster() {
+    let layer = png_header(1, 1, true);
+
+    // Sanity check: a minimal valid file
+    let stack = |w: u32, h: u32, x: i64| {
+        format!(
+            "<image w=\"{}\" h=\"{}\"><stack><layer src=\"a.png\" x=\"{}\" y=\"0\"/></stack></image>",
+            w, h, x
+        )
+    };
+    let layerstack = load_openraster(make_ora(&stack(10, 10, 0), &[("a.png", &layer)])).unwrap();
+    assert_eq!((layerstack.width(), layerstack.height()), (10, 10));
+
+    // Canvas sizes the protocol cannot express
+    assert!(load_openraster(make_ora(&stack(70000, 10, 0), &[("a.png", &layer)])).is_err());
+    assert!(load_openraster(make_ora(&stack(10, 4294967295, 0), &[("a.png", &layer)])).is_err());
+    assert!(load_openraster(make_ora(&stack(60000, 60000, 0), &[("a.png", &layer)])).is_err());
+
+    // Layer image that would not fit in memory
+    let huge = png_header(70000, 70000, false);
+    assert!(load_openraster(make_ora(&stack(10, 10, 0), &[("a.png", &huge)])).is_err());
+
+    // Layer image whose right edge overflows
+    let layerstack = load_openraster(make_ora(
+        &stack(10, 10, i32::MAX as i64),
+        &[("a.png", &layer)],
+    ))
+    .unwrap();
+    assert_eq!(layerstack.iter_layers().count(), 1);
+
+    // Annotation too large for the protocol
+    let xml = "<image w=\"10\" h=\"10\" xmlns:dp=\"http://drawpile.net/\"><stack/>\
+        <dp:annotations><dp:a x=\"0\" y=\"0\" w=\"4294967295\" h=\"10\"/></dp:annotations></image>";
+    assert!(load_openraster(make_ora(xml, &[])).is_err());
+}
+
+/// Build an OpenRaster file with the given stack.xml and extra files
+fn make_ora(stack: &str, files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+    zip.start_file("mimetype", options).unwrap();
+    zip.write_all(b"image/openraster").unwrap();
+    zip.start_file("stack.xml", options).unwrap();
+    zip.write_all(stack.as_bytes()).unwrap();
+    for (name, data) in files {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    let mut file = zip.finish().unwrap();
+    file.set_position(0);
+    file
+}
+
+/// Encode an RGBA PNG. If `data` is false, only the header and an empty
+/// IDAT chunk are written, so the image claims a size without having to
+/// contain the pixels.
+fn png_header(width: u32, height: u32, data: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut buf, width, height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        if data {
+            let pixels = vec![255; (width * height * 4) as usize];
+            writer.write_image_data(&pixels).unwrap();
+        } else {
+            writer.write_chunk(png::chunk::IDAT, &[]).unwrap();
+        }
+    }
+    buf
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
@@ -0,0 +1,87 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+#[test]
+fn test_selection_clips_mask_edits() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffffff"));
+    canvas.receive_message(&m("1 layermask id=0x0101 mask=0x0102 fill=255"));
+    canvas.receive_message(&m("1 selection x=10 y=10 w=20 h=20"));
+
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=0 y=0 w=100 h=100 color=#ff000000 mode=1",
+    ));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=50 y=50 w=10 h=10 color=#ff000000 mode=1",
+    ));
+
+    let mask = canvas
+        .layerstack()
+        .get_layer(0x0101)
+        .unwrap()
+        .mask()
+        .unwrap();
+    assert_eq!(mask.value_at(5, 5), 255);
+    assert_eq!(mask.value_at(10, 10), 0);
+    assert_eq!(mask.value_at(29, 29), 0);
+    assert_eq!(mask.value_at(30, 30), 255);
+    assert_eq!(mask.value_at(55, 55), 255);
+}
+
+#[test]
+fn test_selection_out_of_range() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffffff"));
+    canvas.receive_message(&m("1 selection x=0 y=0 w=20 h=20"));
+
+    // Selections that would overflow the coordinate space are ignored
+    canvas.receive_message(&m("1 selection x=2147483600 y=0 w=100 h=10"));
+    canvas.receive_message(&m(
+        "1 selection x=0 y=2147483000 w=10 h=10 points=0,0,10,0,0,65535",
+    ));
+    assert_eq!(
+        canvas.layerstack().get_selection(1).map(|s| *s.rect()),
+        Some(Rectangle::new(0, 0, 20, 20))
+    );
+
+    // A selection that ends exactly at the edge of the coordinate space is fine
+    canvas.receive_message(&m("1 selection x=2147483547 y=0 w=100 h=10"));
+    assert!(canvas.layerstack().get_selection(1).unwrap().rect().x == 2147483547);
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=0 y=0 w=100 h=100 color=#ff000000 mode=1",
+    ));
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(layer.pixel_at(5, 5), [255, 255, 255, 255]);
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
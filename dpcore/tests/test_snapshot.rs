@@ -0,0 +1,214 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::compression::compress_tile;
+use dpcore::canvas::{make_reset_image, CanvasState};
+use dpcore::paint::tile::Tile;
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut canvas = CanvasState::new();
+
+    canvas.receive_message(&m("1 resize right=200 bottom=150"));
+    canvas.receive_message(&CommandMessage::CanvasBackground(
+        1,
+        compress_tile(&Tile::new(&Color::rgb8(200, 200, 255), 0)),
+    ));
+
+    // A solid layer
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffff name=Background"));
+
+    // A layer with some content and non-default attributes
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Layer"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0102 x=10 y=10 w=150 h=100 color=#80ff0000 mode=1",
+    ));
+    canvas.receive_message(&m("1 layerattr id=0x0102 opacity=128 blend=2 flags=censor"));
+
+    // A pass-through group with a child layer
+    canvas.receive_message(&m("1 newlayer id=0x0103 flags=group name=Group"));
+    canvas.receive_message(&m(
+        "1 layerattr id=0x0103 opacity=200 blend=1 flags=passthrough",
+    ));
+    canvas.receive_message(&m("1 newlayer id=0x0104 fill=#400000ff name=Child"));
+    canvas.receive_message(&m("1 newlayer id=0x0105 fill=#4000ff00 name=Child2"));
+    canvas.receive_message(&m("1 movelayer layer=0x0104 parent=0x0103"));
+    canvas.receive_message(&m("1 movelayer layer=0x0105 parent=0x0103 sibling=0x0104"));
+    canvas.receive_message(&m("1 layerattr id=0x0105 opacity=255 blend=1 flags=clip"));
+
+    // A layer mask with some content
+    canvas.receive_message(&m("1 layermask id=0x0102 mask=0x0106 fill=255"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0106 x=20 y=20 w=100 h=30 color=#ff404040 mode=1",
+    ));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0106 x=100 y=0 w=100 h=150 color=#ff000000 mode=0",
+    ));
+
+    // An indirect stroke in progress
+    canvas.receive_message(&m("2 newlayer id=0x0201 name=Drawing"));
+    canvas.receive_message(&CommandMessage::DrawDabsPixel(
+        2,
+        DrawDabsPixelMessage {
+            layer: 0x0201,
+            x: 100,
+            y: 70,
+            color: 0x800000ff,
+            mode: Blendmode::Normal.into(),
+            dabs: vec![
+                PixelDab {
+                    x: 0,
+                    y: 0,
+                    size: 10,
+                    opacity: 255,
+                },
+                PixelDab {
+                    x: 10,
+                    y: 5,
+                    size: 10,
+                    opacity: 255,
+                },
+            ],
+        },
+    ));
+
+    // Selections
+    canvas.receive_message(&m("2 selection x=10 y=20 w=100 h=50"));
+    canvas.receive_message(&m(
+        "3 selection x=-5 y=10 w=300 h=300 points=0,0,100,10,20,80",
+    ));
+
+    canvas.receive_message(&m("1 newannotation id=0x0101 x=5 y=6 w=100 h=50"));
+    canvas.receive_message(&m(
+        "1 editannotation id=0x0101 bg=#80ffffff flags=6 text=Hello",
+    ));
+
+    let original = canvas.layerstack();
+    let snapshot = make_reset_image(original, 0);
+
+    let mut restored_canvas = CanvasState::new();
+    for msg in snapshot.iter() {
+        restored_canvas.receive_message(msg);
+    }
+    let restored = restored_canvas.layerstack();
+
+    assert_eq!(restored.width(), original.width());
+    assert_eq!(restored.height(), original.height());
+    assert_eq!(restored.background, original.background);
+
+    for (a, b) in original
+        .iter_layers_recursive()
+        .zip(restored.iter_layers_recursive())
+    {
+        assert_eq!(a.id, b.id);
+        assert_eq!(a.is_group(), b.is_group());
+        assert_eq!(a.passthrough, b.passthrough);
+        assert_eq!(a.clip, b.clip);
+        assert_eq!(a.title, b.title);
+        assert_eq!(a.opacity, b.opacity);
+        assert_eq!(a.blendmode, b.blendmode);
+        assert_eq!(a.censored, b.censored);
+        assert_eq!(a.fixed, b.fixed);
+        assert_eq!(a.tilevec(), b.tilevec());
+        assert_eq!(a.mask().map(|m| m.id), b.mask().map(|m| m.id));
+        assert_eq!(a.mask().map(|m| m.tilevec()), b.mask().map(|m| m.tilevec()));
+
+        for (sa, sb) in a.iter_sublayers().zip(b.iter_sublayers()) {
+            assert_eq!(sa.id, sb.id);
+            assert_eq!(sa.opacity, sb.opacity);
+            assert_eq!(sa.blendmode, sb.blendmode);
+            assert_eq!(sa.tilevec(), sb.tilevec());
+        }
+        assert_eq!(a.iter_sublayers().count(), b.iter_sublayers().count());
+    }
+    assert_eq!(
+        original.iter_layers().count(),
+        restored.iter_layers().count()
+    );
+    assert_eq!(
+        original.iter_layers_recursive().count(),
+        restored.iter_layers_recursive().count()
+    );
+    assert_eq!(
+        restored
+            .get_layer(0x0103)
+            .unwrap()
+            .iter_children()
+            .map(|l| l.id)
+            .collect::<Vec<_>>(),
+        vec![0x0104, 0x0105]
+    );
+    assert_eq!(
+        restored.get_layer(0x0201).unwrap().iter_sublayers().count(),
+        1
+    );
+
+    for j in 0..Tile::div_up(original.height()) {
+        for i in 0..Tile::div_up(original.width()) {
+            assert_eq!(
+                original.flatten_tile(i, j).pixels[..],
+                restored.flatten_tile(i, j).pixels[..],
+                "tile {},{} differs",
+                i,
+                j
+            );
+        }
+    }
+
+    for user in 1..=3 {
+        let a = original.get_selection(user);
+        let b = restored.get_selection(user);
+        assert_eq!(a.map(|s| *s.rect()), b.map(|s| *s.rect()));
+        assert_eq!(a.map(|s| s.points()), b.map(|s| s.points()));
+        assert_eq!(a.map(|s| s.bounds()), b.map(|s| s.bounds()));
+    }
+    assert!(restored.get_selection(3).unwrap().points().len() == 3);
+
+    let a = original.get_annotation(0x0101).unwrap();
+    let b = restored.get_annotation(0x0101).unwrap();
+    assert_eq!(a.rect, b.rect);
+    assert_eq!(a.text, b.text);
+    assert_eq!(a.background, b.background);
+    assert_eq!(a.protect, b.protect);
+    assert_eq!(a.valign as u8, b.valign as u8);
+
+    // The solid layer should not need any tiles
+    assert_eq!(
+        snapshot
+            .iter()
+            .filter(|msg| match msg {
+                CommandMessage::PutTile(_, pt) => pt.layer == 0x0101,
+                _ => false,
+            })
+            .count(),
+        0
+    );
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
@@ -0,0 +1,76 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::tile::{Tile, TileData};
+use dpcore::paint::{Layer, LayerStack};
+use dpcore::protocol::message::{CommandMessage, Message};
+
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_thread_safety() {
+    assert_send_sync::<Tile>();
+    assert_send_sync::<TileData>();
+    assert_send_sync::<Layer>();
+    assert_send_sync::<LayerStack>();
+    assert_send_sync::<CanvasState>();
+}
+
+fn m(text: &str) -> CommandMessage {
+    match Message::from_text(&text.parse().unwrap()) {
+        Some(Message::Command(c)) => c,
+        _ => panic!("not a command: {}", text),
+    }
+}
+
+#[test]
+fn test_render_snapshot_in_another_thread() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=128 bottom=128"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffffff"));
+
+    let (tx, rx) = channel::<Arc<LayerStack>>();
+    let renderer = thread::spawn(move || {
+        let mut results = Vec::new();
+        while let Ok(snapshot) = rx.recv() {
+            results.push(snapshot.flatten_tile(0, 0).pixels[0]);
+        }
+        results
+    });
+
+    tx.send(canvas.layerstack_snapshot()).unwrap();
+
+    // The canvas can still be modified while the render thread holds a snapshot
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=0 y=0 w=64 h=64 color=#ff0000 mode=1",
+    ));
+    tx.send(canvas.layerstack_snapshot()).unwrap();
+    drop(tx);
+
+    let results = renderer.join().unwrap();
+    assert_eq!(results, vec![[255, 255, 255, 255], [0, 0, 255, 255]]);
+}
@@ -0,0 +1,126 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::*;
+use dpcore::protocol::message::*;
+
+#[test]
+fn test_transform_region() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 name=Source"));
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Target"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=0 y=0 w=10 h=20 color=#ffff0000 mode=1",
+    ));
+
+    // Move within the same layer, rotating 90 degrees clockwise
+    canvas.receive_message(&m(
+        "1 transform layer=0x0101 source=0 sx1=0 sy1=0 sx2=10 sy2=0 sx3=10 sy3=20 sx4=0 sy4=20 x1=70 y1=50 x2=70 y2=60 x3=50 y3=60 x4=50 y4=50 mode=0",
+    ));
+
+    let red = Color::rgb8(255, 0, 0).as_pixel();
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(layer.pixel_at(5, 5), color::ZERO_PIXEL);
+    assert_eq!(layer.pixel_at(50, 50), red);
+    assert_eq!(layer.pixel_at(69, 59), red);
+    assert_eq!(layer.pixel_at(70, 55), color::ZERO_PIXEL);
+    assert_eq!(layer.pixel_at(60, 60), color::ZERO_PIXEL);
+
+    // Move to another layer, scaling to half size
+    canvas.receive_message(&m(
+        "1 transform layer=0x0102 source=0x0101 sx1=50 sy1=50 sx2=70 sy2=50 sx3=70 sy3=60 sx4=50 sy4=60 x1=0 y1=0 x2=10 y2=0 x3=10 y3=5 x4=0 y4=5 mode=1",
+    ));
+
+    let source = canvas.layerstack().get_layer(0x0101).unwrap();
+    let target = canvas.layerstack().get_layer(0x0102).unwrap();
+    assert_eq!(source.pixel_at(50, 50), color::ZERO_PIXEL);
+    assert_eq!(target.pixel_at(0, 0), red);
+    assert_eq!(target.pixel_at(9, 4), red);
+    assert_eq!(target.pixel_at(10, 4), color::ZERO_PIXEL);
+}
+
+#[test]
+fn test_transform_selection() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 name=Source"));
+    canvas.receive_message(&m("1 newlayer id=0x0102 name=Target"));
+    canvas.receive_message(&m(
+        "1 fillrect layer=0x0101 x=0 y=0 w=10 h=10 color=#ffff0000 mode=1",
+    ));
+    canvas.receive_message(&m("1 selection x=0 y=0 w=5 h=10"));
+
+    // Only the selected half of the source region is moved
+    canvas.receive_message(&m(
+        "1 transform layer=0x0102 source=0x0101 sx1=0 sy1=0 sx2=10 sy2=0 sx3=10 sy3=10 sx4=0 sy4=10 x1=20 y1=20 x2=30 y2=20 x3=30 y3=30 x4=20 y4=30 mode=0",
+    ));
+
+    let red = Color::rgb8(255, 0, 0).as_pixel();
+    let source = canvas.layerstack().get_layer(0x0101).unwrap();
+    let target = canvas.layerstack().get_layer(0x0102).unwrap();
+    assert_eq!(source.pixel_at(2, 5), color::ZERO_PIXEL);
+    assert_eq!(source.pixel_at(7, 5), red);
+    assert_eq!(target.pixel_at(22, 25), red);
+    assert_eq!(target.pixel_at(27, 25), color::ZERO_PIXEL);
+}
+
+#[test]
+fn test_transform_out_of_range() {
+    let mut canvas = CanvasState::new();
+    canvas.receive_message(&m("1 resize right=100 bottom=100"));
+    canvas.receive_message(&m("1 newlayer id=0x0101 fill=#ffff0000"));
+
+    // Quads that are wider than the coordinate space are ignored
+    canvas.receive_message(&m(
+        "1 transform layer=0x0101 source=0 sx1=-2147483648 sy1=0 sx2=2147483647 sy2=0 sx3=2147483647 sy3=10 sx4=-2147483648 sy4=10 x1=0 y1=0 x2=10 y2=0 x3=10 y3=10 x4=0 y4=10 mode=1",
+    ));
+    canvas.receive_message(&m(
+        "1 transform layer=0x0101 source=0 sx1=0 sy1=0 sx2=10 sy2=0 sx3=10 sy3=10 sx4=0 sy4=10 x1=-2147483648 y1=0 x2=2147483647 y2=0 x3=2147483647 y3=10 x4=-2147483648 y4=10 mode=1",
+    ));
+
+    // So are source quads that are completely outside the canvas
+    canvas.receive_message(&m(
+        "1 transform layer=0x0101 source=0 sx1=200 sy1=0 sx2=210 sy2=0 sx3=210 sy3=10 sx4=200 sy4=10 x1=0 y1=0 x2=10 y2=0 x3=10 y3=10 x4=0 y4=10 mode=1",
+    ));
+
+    let red = Color::rgb8(255, 0, 0).as_pixel();
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(layer.pixel_at(5, 5), red);
+    assert_eq!(layer.pixel_at(50, 5), red);
+
+    // A target far outside the canvas just moves the pixels away
+    canvas.receive_message(&m(
+        "1 transform layer=0x0101 source=0 sx1=0 sy1=0 sx2=10 sy2=0 sx3=10 sy3=10 sx4=0 sy4=10 x1=2147483000 y1=2147483000 x2=2147483647 y2=2147483000 x3=2147483647 y3=2147483647 x4=2147483000 y4=2147483647 mode=1",
+    ));
+    let layer = canvas.layerstack().get_layer(0x0101).unwrap();
+    assert_eq!(layer.pixel_at(5, 5), color::ZERO_PIXEL);
+    assert_eq!(layer.pixel_at(50, 5), red);
+}
+
+fn m(msg: &str) -> CommandMessage {
+    match Message::from_text(&msg.parse().unwrap()).unwrap() {
+        Message::Command(m) => m,
+        _ => panic!("Not a command message: {}", msg),
+    }
+}
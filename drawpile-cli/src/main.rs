@@ -51,6 +51,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             App::new("render")
                 .arg(Arg::with_name("INPUT").help("Input file").required(true))
                 .arg(Arg::with_name("OUTPUT").help("Output file"))
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["flat", "ora", "psd"])
+                        .help("Output format (guessed from the file name by default)"),
+                )
                 .arg(
                     Arg::with_name("every-msg")
                         .long("every-msg")
@@ -68,13 +76,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Arg::with_name("resize")
                         .long("resize")
                         .takes_value(true)
-                        .help("Resize canvas to this size (WxH)"),
+                        .help("Resize canvas to this size (WxH). Flattened images only"),
                 )
                 .arg(
                     Arg::with_name("same-size")
                         .long("same-size")
                         .conflicts_with("resize")
-                        .help("Resize subsequent images to the original size"),
+                        .help("Resize subsequent images to the original size. Flattened images only"),
+                )
+                .arg(
+                    Arg::with_name("hide-annotations")
+                        .long("hide-annotations")
+                        .help("Don't render annotations"),
+                )
+                .arg(
+                    Arg::with_name("censor")
+                        .long("censor")
+                        .takes_value(true)
+                        .default_value("hide")
+                        .help("How to render censored layers (show, hide or pixelate[:N], where N is 1-64)"),
                 ),
         )
         .get_matches();
@@ -93,6 +113,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let opts = RenderOpts {
                 input_file: m.value_of("INPUT").unwrap(),
                 output_file: m.value_of("OUTPUT").unwrap_or(""),
+                output_format: if m.is_present("format") {
+                    value_t!(m, "format", ImageFormat).unwrap_or_else(|e| e.exit())
+                } else {
+                    ImageFormat::Guess
+                },
                 output_every: m.value_of("every-msg").or(m.value_of("every-up")).map(|v| {
                     match v.parse::<u32>() {
                         Ok(val) => val,
@@ -108,6 +133,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     None
                 },
                 same_size: m.is_present("same-size"),
+                annotations: !m.is_present("hide-annotations"),
+                censor: parse_censor_policy(m.value_of("censor").unwrap())
+                    .unwrap_or_else(|e| ClapError::value_validation_auto(e).exit()),
             };
 
             render_recording(&opts)
@@ -20,8 +20,14 @@
 // You should have received a copy of the GNU General Public License
 // along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
 
-use dpcore::canvas::CanvasState;
+mod psd;
+
+use dpcore::canvas::{AclFilter, CanvasState};
 use dpcore::paint::color::*;
+use dpcore::paint::openraster::save_openraster;
+use dpcore::paint::textrender::AnnotationRenderer;
+use dpcore::paint::tile::TILE_SIZE;
+use dpcore::paint::CensorPolicy;
 use dpcore::protocol::message::{CommandMessage, Message};
 use dpcore::protocol::{open_recording, Compatibility, ReadMessage};
 
@@ -29,16 +35,93 @@ use tracing::{info, warn};
 
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
 use std::io;
+use std::io::BufWriter;
 use std::num::ParseIntError;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
 use image;
 
+use psd::write_psd;
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct Size(u32, u32);
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Guess,
+    /// A flattened image. The file type is picked by the image library based on the suffix.
+    Flat,
+    /// A layered OpenRaster file
+    OpenRaster,
+    /// A layered Photoshop document
+    Psd,
+}
+
+impl ImageFormat {
+    /// Pick the format based on the file name, unless one was chosen explicitly
+    fn guess(self, filename: &str) -> ImageFormat {
+        match self {
+            ImageFormat::Guess if filename.ends_with(".ora") => ImageFormat::OpenRaster,
+            ImageFormat::Guess if filename.ends_with(".psd") => ImageFormat::Psd,
+            ImageFormat::Guess => ImageFormat::Flat,
+            f => f,
+        }
+    }
+
+    /// Is this a layered format that is saved without flattening
+    fn is_layered(self) -> bool {
+        self == ImageFormat::OpenRaster || self == ImageFormat::Psd
+    }
+
+    /// The suffix to use when no output file name is given
+    fn suffix(self) -> &'static str {
+        match self {
+            ImageFormat::Guess | ImageFormat::Flat => "png",
+            ImageFormat::OpenRaster => "ora",
+            ImageFormat::Psd => "psd",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(ImageFormat::Flat),
+            "ora" => Ok(ImageFormat::OpenRaster),
+            "psd" => Ok(ImageFormat::Psd),
+            _ => Err(format!("{}: expected flat, ora or psd", s)),
+        }
+    }
+}
+
+/// Default mosaic block size for pixelated censored layers
+const DEFAULT_CENSOR_BLOCK: u32 = 16;
+
+/// Parse a censor policy: "show", "hide" or "pixelate[:block size]"
+///
+/// Pixelation is done tile by tile, so the block size can be at most
+/// the tile size.
+pub fn parse_censor_policy(s: &str) -> Result<CensorPolicy, String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("show"), None) => Ok(CensorPolicy::Show),
+        (Some("hide"), None) => Ok(CensorPolicy::Hide),
+        (Some("pixelate"), None) => Ok(CensorPolicy::Pixelate(DEFAULT_CENSOR_BLOCK)),
+        (Some("pixelate"), Some(size)) => match size.parse::<u32>() {
+            Ok(s) if (1..=TILE_SIZE).contains(&s) => Ok(CensorPolicy::Pixelate(s)),
+            _ => Err(format!(
+                "{}: block size must be between 1 and {}",
+                size, TILE_SIZE
+            )),
+        },
+        _ => Err(format!("{}: expected show, hide or pixelate", s)),
+    }
+}
+
 impl FromStr for Size {
     type Err = ParseIntError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -56,6 +139,9 @@ pub struct RenderOpts<'a> {
     /// Name of output image file (sequence number is inserted before the suffix)
     pub output_file: &'a str,
 
+    /// Output file format
+    pub output_format: ImageFormat,
+
     /// How often to write the image
     pub output_every: Option<u32>,
 
@@ -67,12 +153,19 @@ pub struct RenderOpts<'a> {
 
     /// Resize subsequent images to the original size
     pub same_size: bool,
+
+    /// Render annotations on top of the canvas
+    pub annotations: bool,
+
+    /// How to render censored layers
+    pub censor: CensorPolicy,
 }
 
 struct RenderState {
     resize: Option<Size>,
     same_size: bool,
     image_num: u32,
+    annotation_renderer: Option<AnnotationRenderer>,
 }
 
 #[derive(Debug)]
@@ -93,6 +186,18 @@ impl Error for RenderError {
 }
 
 pub fn render_recording(opts: &RenderOpts) -> Result<(), Box<dyn std::error::Error>> {
+    // Layered images are saved as is, so they cannot be resized
+    if (opts.resize.is_some() || opts.same_size)
+        && opts
+            .output_format
+            .guess(&make_filename(opts, 0))
+            .is_layered()
+    {
+        return Err(Box::new(RenderError {
+            message: "Only flattened images can be resized",
+        }));
+    }
+
     let mut reader = open_recording(opts.input_file)?;
 
     if reader.check_compatibility() == Compatibility::Incompatible {
@@ -103,6 +208,7 @@ pub fn render_recording(opts: &RenderOpts) -> Result<(), Box<dyn std::error::Err
 
     let start = Instant::now();
     let mut canvas = CanvasState::new();
+    let mut acl = AclFilter::new();
     let mut total_render_time = Duration::new(0, 0);
     let mut total_save_time = Duration::new(0, 0);
     let mut message_counter = 0;
@@ -110,11 +216,20 @@ pub fn render_recording(opts: &RenderOpts) -> Result<(), Box<dyn std::error::Err
         resize: opts.resize,
         same_size: opts.same_size,
         image_num: opts.output_every.map_or(0, |_| 1),
+        annotation_renderer: if opts.annotations {
+            Some(AnnotationRenderer::new())
+        } else {
+            None
+        },
     };
 
     loop {
         match reader.read_next() {
             ReadMessage::Ok(m) => {
+                if !acl.filter_message(&m) {
+                    continue;
+                }
+
                 let now = Instant::now();
                 match &m {
                     Message::Command(c) => {
@@ -173,7 +288,38 @@ fn save_canvas(
 
     let filename = make_filename(opts, state.image_num);
 
-    let (img, w, h) = canvas.layerstack().to_image();
+    match opts.output_format.guess(&filename) {
+        ImageFormat::OpenRaster => {
+            let file = BufWriter::new(File::create(&filename)?);
+            save_openraster(canvas.layerstack(), opts.censor, file).map_err(io::Error::other)?;
+        }
+        ImageFormat::Psd => {
+            let file = BufWriter::new(File::create(&filename)?);
+            write_psd(
+                canvas.layerstack(),
+                opts.censor,
+                state.annotation_renderer.as_ref(),
+                file,
+            )?;
+        }
+        _ => save_flattened(&filename, opts, state, canvas)?,
+    }
+    info!("Saved {}", filename);
+
+    state.image_num += 1;
+    Ok(now.elapsed())
+}
+
+fn save_flattened(
+    filename: &str,
+    opts: &RenderOpts,
+    state: &mut RenderState,
+    canvas: &CanvasState,
+) -> io::Result<()> {
+    let (mut img, w, h) = canvas.layerstack().to_image_censored(opts.censor);
+    if let Some(renderer) = &state.annotation_renderer {
+        renderer.render_onto(&mut img, w, h, canvas.layerstack().iter_annotations());
+    }
     let mut rgba = Vec::<u8>::with_capacity(w as usize * h as usize * 4);
     for px in img.iter() {
         rgba.push(px[RED_CHANNEL]);
@@ -193,11 +339,7 @@ fn save_canvas(
         state.resize = Some(size);
     }
 
-    ib.save(&filename)?;
-    info!("Saved {}", filename);
-
-    state.image_num += 1;
-    Ok(now.elapsed())
+    ib.save(filename)
 }
 
 fn make_filename(opts: &RenderOpts, index: u32) -> String {
@@ -205,9 +347,18 @@ fn make_filename(opts: &RenderOpts, index: u32) -> String {
         let end = opts.input_file.rfind('.').unwrap_or(opts.input_file.len());
 
         if index != 0 {
-            format!("{}-{}.png", &opts.input_file[..end], index)
+            format!(
+                "{}-{}.{}",
+                &opts.input_file[..end],
+                index,
+                opts.output_format.suffix()
+            )
         } else {
-            format!("{}.png", &opts.input_file[..end])
+            format!(
+                "{}.{}",
+                &opts.input_file[..end],
+                opts.output_format.suffix()
+            )
         }
     } else {
         if index != 0 {
@@ -226,3 +377,69 @@ fn make_filename(opts: &RenderOpts, index: u32) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_censor_policy() {
+        assert_eq!(parse_censor_policy("show"), Ok(CensorPolicy::Show));
+        assert_eq!(parse_censor_policy("hide"), Ok(CensorPolicy::Hide));
+        assert_eq!(
+            parse_censor_policy("pixelate"),
+            Ok(CensorPolicy::Pixelate(DEFAULT_CENSOR_BLOCK))
+        );
+        assert_eq!(
+            parse_censor_policy("pixelate:8"),
+            Ok(CensorPolicy::Pixelate(8))
+        );
+        assert_eq!(
+            parse_censor_policy("pixelate:64"),
+            Ok(CensorPolicy::Pixelate(64))
+        );
+
+        assert!(parse_censor_policy("pixelate:0").is_err());
+        assert!(parse_censor_policy("pixelate:65").is_err());
+        assert!(parse_censor_policy("pixelate:").is_err());
+        assert!(parse_censor_policy("pixelate:big").is_err());
+        assert!(parse_censor_policy("show:1").is_err());
+        assert!(parse_censor_policy("blur").is_err());
+        assert!(parse_censor_policy("").is_err());
+    }
+
+    #[test]
+    fn test_resize_layered() {
+        let opts = |output_file, resize, same_size| RenderOpts {
+            input_file: "nonexistent.dprec",
+            output_file,
+            output_format: ImageFormat::Guess,
+            output_every: None,
+            every_up: false,
+            resize,
+            same_size,
+            annotations: false,
+            censor: CensorPolicy::Show,
+        };
+        let error = |opts| render_recording(&opts).unwrap_err().to_string();
+
+        let resize_error = "Only flattened images can be resized";
+        assert_eq!(
+            error(opts("out.ora", Some(Size(10, 10)), false)),
+            resize_error
+        );
+        assert_eq!(error(opts("out.ora", None, true)), resize_error);
+        assert_eq!(
+            error(opts("out.psd", Some(Size(10, 10)), false)),
+            resize_error
+        );
+        assert_eq!(error(opts("out.psd", None, true)), resize_error);
+
+        // Flattened images can be resized, so the (missing) recording is opened
+        assert_ne!(
+            error(opts("out.png", Some(Size(10, 10)), false)),
+            resize_error
+        );
+        assert_ne!(error(opts("out.ora", None, false)), resize_error);
+    }
+}
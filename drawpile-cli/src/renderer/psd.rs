@@ -0,0 +1,597 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::paint::color::*;
+use dpcore::paint::layermask::LayerMask;
+use dpcore::paint::textrender::AnnotationRenderer;
+use dpcore::paint::tile::{Tile, TILE_SIZE};
+use dpcore::paint::{Blendmode, CensorPolicy, Layer, LayerStack};
+
+use std::io;
+use std::io::Write;
+
+/// The name Photoshop gives to the hidden layers that mark the end of a group
+const GROUP_END_NAME: &str = "</Layer group>";
+
+/// Section divider types (the "lsct" layer info block)
+const SECTION_OPEN_FOLDER: u32 = 1;
+const SECTION_DIVIDER: u32 = 3;
+
+/// Layer record flag: the layer is hidden
+const FLAG_HIDDEN: u8 = 0x02;
+
+struct LayerRecord {
+    name: String,
+    /// Bounds as top, left, bottom, right
+    bounds: [i32; 4],
+    /// Channel IDs and their encoded data (including the compression type)
+    channels: Vec<(i16, Vec<u8>)>,
+    /// Bounds of the user mask, if there is one
+    mask_bounds: Option<[i32; 4]>,
+    blendmode: &'static [u8; 4],
+    opacity: u8,
+    clipping: bool,
+    hidden: bool,
+    /// Section divider type and blend mode for group records
+    section: Option<(u32, &'static [u8; 4])>,
+}
+
+/// Write the layer stack as a layered Photoshop document.
+///
+/// Each layer becomes a PSD layer with the nearest matching blending mode
+/// and groups become layer folders. Censored layers are left out or
+/// pixelated according to the censor policy. If an annotation renderer
+/// is given, the annotations are rendered into a layer of their own
+/// on top of the rest.
+///
+/// Sublayers (strokes in progress) are not included in the layers.
+pub fn write_psd<W: Write>(
+    layerstack: &LayerStack,
+    censor: CensorPolicy,
+    annotations: Option<&AnnotationRenderer>,
+    mut writer: W,
+) -> io::Result<()> {
+    let (width, height) = (layerstack.width(), layerstack.height());
+    if width == 0 || height == 0 || width > 30000 || height > 30000 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "canvas size not supported by PSD",
+        ));
+    }
+
+    let mut records = Vec::new();
+    let layers: Vec<&Layer> = layerstack.iter_layers().collect();
+    layer_records(&mut records, &layers, censor, false, width, height);
+
+    let (mut image, _, _) = layerstack.to_image_censored(censor);
+
+    if let Some(renderer) = annotations {
+        if layerstack.iter_annotations().next().is_some() {
+            let mut overlay = vec![ZERO_PIXEL; image.len()];
+            renderer.render_onto(&mut overlay, width, height, layerstack.iter_annotations());
+            renderer.render_onto(&mut image, width, height, layerstack.iter_annotations());
+            records.push(pixel_record(
+                "Annotations".to_string(),
+                &overlay,
+                width,
+                height,
+            ));
+        }
+    }
+
+    // File header
+    writer.write_all(b"8BPS")?;
+    writer.write_all(&1u16.to_be_bytes())?;
+    writer.write_all(&[0; 6])?;
+    writer.write_all(&4u16.to_be_bytes())?;
+    writer.write_all(&height.to_be_bytes())?;
+    writer.write_all(&width.to_be_bytes())?;
+    writer.write_all(&8u16.to_be_bytes())?; // bits per channel
+    writer.write_all(&3u16.to_be_bytes())?; // RGB color mode
+
+    // Empty color mode data and image resource sections
+    writer.write_all(&0u32.to_be_bytes())?;
+    writer.write_all(&0u32.to_be_bytes())?;
+
+    // Layer and mask information section
+    let layer_info = layer_info(&records);
+    writer.write_all(&(layer_info.len() as u32 + 8).to_be_bytes())?;
+    writer.write_all(&(layer_info.len() as u32).to_be_bytes())?;
+    writer.write_all(&layer_info)?;
+    writer.write_all(&0u32.to_be_bytes())?; // no global layer mask
+
+    // The merged image
+    let planes = planes(&image);
+    let encoded: Vec<(Vec<u8>, Vec<u8>)> = planes
+        .iter()
+        .map(|plane| rle_encode(plane, width as usize))
+        .collect();
+    writer.write_all(&1u16.to_be_bytes())?;
+    for (counts, _) in encoded.iter() {
+        writer.write_all(counts)?;
+    }
+    for (_, data) in encoded.iter() {
+        writer.write_all(data)?;
+    }
+
+    writer.flush()
+}
+
+/// Generate the records for the given layers (bottom-most first, as in PSD)
+fn layer_records(
+    records: &mut Vec<LayerRecord>,
+    layers: &[&Layer],
+    censor: CensorPolicy,
+    censored_parent: bool,
+    width: u32,
+    height: u32,
+) {
+    for layer in layers {
+        let censored = censored_parent || layer.censored;
+        let pixelate = match censor {
+            CensorPolicy::Hide if censored => continue,
+            CensorPolicy::Pixelate(block) if censored => Some(block),
+            _ => None,
+        };
+
+        let blendmode = if layer.is_group() && layer.passthrough {
+            b"pass"
+        } else {
+            blendmode_key(layer.blendmode)
+        };
+
+        let mut record = if layer.is_group() {
+            records.push(LayerRecord {
+                section: Some((SECTION_DIVIDER, b"norm")),
+                hidden: true,
+                ..empty_record(GROUP_END_NAME.to_string())
+            });
+
+            let children: Vec<&Layer> = layer.iter_children().collect();
+            layer_records(records, &children, censor, censored, width, height);
+
+            LayerRecord {
+                section: Some((SECTION_OPEN_FOLDER, blendmode)),
+                ..empty_record(layer.title.clone())
+            }
+        } else {
+            pixel_record(
+                layer.title.clone(),
+                &layer_pixels(layer, pixelate),
+                width,
+                height,
+            )
+        };
+
+        record.blendmode = blendmode;
+        record.opacity = (layer.opacity * 255.0).round() as u8;
+        record.clipping = layer.clip;
+        record.hidden = layer.hidden;
+
+        if let Some(mask) = layer.mask() {
+            record.mask_bounds = Some([0, 0, height as i32, width as i32]);
+            record
+                .channels
+                .push((-2, rle_channel(&mask_values(mask), width as usize)));
+        }
+
+        records.push(record);
+    }
+}
+
+/// A record without pixel content (used for groups and group end markers)
+fn empty_record(name: String) -> LayerRecord {
+    LayerRecord {
+        name,
+        bounds: [0; 4],
+        channels: [-1, 0, 1, 2].iter().map(|&id| (id, vec![0, 0])).collect(),
+        mask_bounds: None,
+        blendmode: b"norm",
+        opacity: 255,
+        clipping: false,
+        hidden: false,
+        section: None,
+    }
+}
+
+/// A canvas sized pixel layer
+fn pixel_record(name: String, pixels: &[Pixel], width: u32, height: u32) -> LayerRecord {
+    let [r, g, b, a] = planes(pixels);
+    LayerRecord {
+        bounds: [0, 0, height as i32, width as i32],
+        channels: vec![
+            (-1, rle_channel(&a, width as usize)),
+            (0, rle_channel(&r, width as usize)),
+            (1, rle_channel(&g, width as usize)),
+            (2, rle_channel(&b, width as usize)),
+        ],
+        ..empty_record(name)
+    }
+}
+
+/// Serialize the layer info subsection: the layer records followed by
+/// the channel image data of each layer.
+fn layer_info(records: &[LayerRecord]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    // A negative count means the merged image's alpha channel is its transparency
+    out.extend_from_slice(&(-(records.len() as i16)).to_be_bytes());
+
+    for record in records {
+        for v in record.bounds.iter() {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        out.extend_from_slice(&(record.channels.len() as u16).to_be_bytes());
+        for (id, data) in record.channels.iter() {
+            out.extend_from_slice(&id.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+        out.extend_from_slice(b"8BIM");
+        out.extend_from_slice(record.blendmode);
+        out.push(record.opacity);
+        out.push(record.clipping as u8);
+        out.push(if record.hidden { FLAG_HIDDEN } else { 0 });
+        out.push(0);
+
+        let mut extra = Vec::new();
+        match record.mask_bounds {
+            Some(bounds) => {
+                extra.extend_from_slice(&20u32.to_be_bytes());
+                for v in bounds.iter() {
+                    extra.extend_from_slice(&v.to_be_bytes());
+                }
+                extra.extend_from_slice(&[0, 0, 0, 0]); // default color, flags, padding
+            }
+            None => extra.extend_from_slice(&0u32.to_be_bytes()),
+        }
+        extra.extend_from_slice(&0u32.to_be_bytes()); // no blending ranges
+        extra.extend_from_slice(&pascal_name(&record.name));
+
+        let mut luni = Vec::new();
+        let utf16: Vec<u16> = record.name.encode_utf16().collect();
+        luni.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+        for c in utf16 {
+            luni.extend_from_slice(&c.to_be_bytes());
+        }
+        additional_info(&mut extra, b"luni", &luni);
+
+        if let Some((section, blendmode)) = record.section {
+            let mut lsct = section.to_be_bytes().to_vec();
+            if section != SECTION_DIVIDER {
+                lsct.extend_from_slice(b"8BIM");
+                lsct.extend_from_slice(blendmode);
+            }
+            additional_info(&mut extra, b"lsct", &lsct);
+        }
+
+        out.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+        out.extend_from_slice(&extra);
+    }
+
+    for record in records {
+        for (_, data) in record.channels.iter() {
+            out.extend_from_slice(data);
+        }
+    }
+
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+fn additional_info(out: &mut Vec<u8>, key: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(b"8BIM");
+    out.extend_from_slice(key);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// The legacy layer name: a Pascal string padded to a multiple of four bytes.
+/// The full name is in the "luni" block, so non-ASCII characters are just replaced.
+fn pascal_name(name: &str) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![0];
+    out.extend(
+        name.chars()
+            .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+            .take(255),
+    );
+    out[0] = (out.len() - 1) as u8;
+    out.resize(out.len() + (4 - out.len() % 4) % 4, 0);
+    out
+}
+
+/// Get the nearest PSD blending mode key
+fn blendmode_key(mode: Blendmode) -> &'static [u8; 4] {
+    match mode {
+        Blendmode::Multiply => b"mul ",
+        Blendmode::Divide => b"fdiv",
+        Blendmode::Burn => b"idiv",
+        Blendmode::Dodge => b"div ",
+        Blendmode::Darken => b"dark",
+        Blendmode::Lighten => b"lite",
+        Blendmode::Subtract => b"fsub",
+        Blendmode::Add => b"lddg",
+        Blendmode::Screen => b"scrn",
+        Blendmode::Overlay => b"over",
+        Blendmode::SoftLight => b"sLit",
+        Blendmode::HardLight => b"hLit",
+        Blendmode::Difference => b"diff",
+        Blendmode::Hue => b"hue ",
+        Blendmode::Saturation => b"sat ",
+        Blendmode::Color => b"colr",
+        Blendmode::Luminosity => b"lum ",
+        // The rest (erasing, recoloring and such) have no layer equivalent
+        _ => b"norm",
+    }
+}
+
+/// Read the whole layer as an image, pixelating it if needed
+fn layer_pixels(layer: &Layer, pixelate: Option<u32>) -> Vec<Pixel> {
+    let (width, height) = layer.size();
+    let mut pixels = vec![ZERO_PIXEL; (width * height) as usize];
+    let tw = TILE_SIZE as usize;
+
+    for j in 0..Tile::div_up(height) {
+        for i in 0..Tile::div_up(width) {
+            let tile = layer.tile(i, j);
+            if *tile == Tile::Blank {
+                continue;
+            }
+            let mut data = tile.clone_data();
+            if let Some(block) = pixelate {
+                data.pixelate(block);
+            }
+
+            let x = (i * TILE_SIZE) as usize;
+            let w = tw.min(width as usize - x);
+            let h = tw.min((height - j * TILE_SIZE) as usize);
+            for row in 0..h {
+                let dest = (j as usize * tw + row) * width as usize + x;
+                pixels[dest..dest + w].copy_from_slice(&data.pixels[row * tw..row * tw + w]);
+            }
+        }
+    }
+
+    pixels
+}
+
+fn mask_values(mask: &LayerMask) -> Vec<u8> {
+    (0..mask.height())
+        .flat_map(|y| (0..mask.width()).map(move |x| mask.value_at(x, y)))
+        .collect()
+}
+
+/// Split premultiplied pixels into non-premultiplied R, G, B and A planes
+fn planes(pixels: &[Pixel]) -> [Vec<u8>; 4] {
+    let mut planes = [
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+    ];
+    for p in pixels {
+        let a = p[ALPHA_CHANNEL] as u32;
+        let unpremultiply = |c: u8| {
+            (c as u32 * 255 + a / 2)
+                .checked_div(a)
+                .map_or(0, |v| v.min(255) as u8)
+        };
+        planes[0].push(unpremultiply(p[RED_CHANNEL]));
+        planes[1].push(unpremultiply(p[GREEN_CHANNEL]));
+        planes[2].push(unpremultiply(p[BLUE_CHANNEL]));
+        planes[3].push(a as u8);
+    }
+    planes
+}
+
+/// Encode a layer channel: compression type, row byte counts and the packed rows
+fn rle_channel(plane: &[u8], width: usize) -> Vec<u8> {
+    let (counts, data) = rle_encode(plane, width);
+    let mut out = 1u16.to_be_bytes().to_vec();
+    out.extend_from_slice(&counts);
+    out.extend_from_slice(&data);
+    out
+}
+
+/// PackBits compress each row of the plane.
+/// Returns the byte counts of the rows and the compressed data.
+fn rle_encode(plane: &[u8], width: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut counts = Vec::new();
+    let mut data = Vec::new();
+    for row in plane.chunks(width) {
+        let start = data.len();
+        packbits(row, &mut data);
+        counts.extend_from_slice(&((data.len() - start) as u16).to_be_bytes());
+    }
+    (counts, data)
+}
+
+fn packbits(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+
+        if run > 1 {
+            out.push((257 - run) as u8);
+            out.push(row[i]);
+            i += run;
+        } else {
+            // Literal bytes until the next run starts
+            let start = i;
+            i += 1;
+            while i < row.len() && i - start < 128 && (i + 1 == row.len() || row[i] != row[i + 1]) {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&row[start..i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::psd::Psd;
+    use dpcore::paint::layerstack::{LayerFill, LayerInsertion};
+    use dpcore::paint::Color;
+
+    #[test]
+    fn test_write_psd() {
+        let mut stack = LayerStack::new(100, 70);
+        let bg = stack
+            .add_layer(
+                1,
+                LayerFill::Solid(Color::rgb8(0, 0, 255)),
+                LayerInsertion::Top,
+            )
+            .unwrap();
+        bg.title = "Background".to_string();
+
+        let group = stack
+            .add_layer(2, LayerFill::Group, LayerInsertion::Top)
+            .unwrap();
+        group.title = "Group".to_string();
+        group.passthrough = true;
+
+        let layer = stack
+            .add_layer(
+                3,
+                LayerFill::Solid(Color::rgb8(255, 0, 0)),
+                LayerInsertion::Top,
+            )
+            .unwrap();
+        layer.title = "Punainen väri".to_string();
+        layer.opacity = 0.5;
+        layer.blendmode = Blendmode::Multiply;
+        layer.clip = true;
+        stack.move_layer(3, Some(2), None);
+
+        let hidden = stack
+            .add_layer(
+                4,
+                LayerFill::Solid(Color::rgb8(0, 255, 0)),
+                LayerInsertion::Top,
+            )
+            .unwrap();
+        hidden.title = "Hidden".to_string();
+        hidden.hidden = true;
+
+        let censored = stack
+            .add_layer(
+                5,
+                LayerFill::Solid(Color::rgb8(0, 0, 0)),
+                LayerInsertion::Top,
+            )
+            .unwrap();
+        censored.censored = true;
+
+        let mut data = Vec::new();
+        write_psd(&stack, CensorPolicy::Hide, None, &mut data).unwrap();
+        let psd = Psd::from_bytes(&data).unwrap();
+
+        assert_eq!((psd.width(), psd.height()), (100, 70));
+        let names: Vec<&str> = psd.layers().iter().map(|l| l.name()).collect();
+        // The psd crate lists layers top-most first
+        assert_eq!(names, vec!["Hidden", "Punainen väri", "Background"]);
+
+        assert_eq!(psd.groups().len(), 1);
+        let group = psd.groups().values().next().unwrap();
+        assert_eq!(group.name(), "Group");
+        // The psd crate takes group attributes from the divider record,
+        // so look for the pass-through blend mode key directly
+        assert!(data.windows(8).any(|w| w == b"8BIMpass"));
+
+        // The psd crate follows the letter of the specification and reads
+        // the hidden flag as "visible" and a zero clipping byte as "clipping mask"
+        let background = psd.layer_by_name("Background").unwrap();
+        assert_eq!(format!("{:?}", background.blend_mode()), "Normal");
+        assert_eq!(background.parent_id(), None);
+        assert!(!background.visible());
+        assert!(background.is_clipping_mask());
+
+        let red = psd.layer_by_name("Punainen väri").unwrap();
+        assert_eq!(red.parent_id(), Some(group.id()));
+        assert_eq!(format!("{:?}", red.blend_mode()), "Multiply");
+        assert_eq!(red.opacity(), 128);
+        assert!(!red.visible());
+        assert!(!red.is_clipping_mask());
+        assert!(red.rgba().chunks_exact(4).all(|p| p == [255, 0, 0, 255]));
+
+        assert!(psd.layer_by_name("Hidden").unwrap().visible());
+
+        // Blue background multiplied by half transparent red
+        let (image, _, _) = stack.to_image_censored(CensorPolicy::Hide);
+        let expected = planes(&image);
+        let merged = psd.rgba();
+        for (i, p) in merged.chunks_exact(4).enumerate() {
+            assert_eq!(
+                p,
+                [
+                    expected[0][i],
+                    expected[1][i],
+                    expected[2][i],
+                    expected[3][i]
+                ]
+            );
+        }
+        assert_eq!(merged[3], 255);
+    }
+
+    #[test]
+    fn test_packbits() {
+        let row: Vec<u8> = vec![1, 2, 3, 3, 3, 3, 4]
+            .into_iter()
+            .chain(vec![9; 300])
+            .chain((0..200).map(|v| v as u8))
+            .collect();
+        let mut packed = Vec::new();
+        packbits(&row, &mut packed);
+        assert!(packed.len() < row.len());
+
+        // Read the packed row back as the red channel of a layer
+        let width = row.len() as u32;
+        let pixels: Vec<Pixel> = row.iter().map(|&r| [0, 0, r, 255]).collect();
+        let mut stack = LayerStack::new(width, 1);
+        let content = Layer::from_image(&pixels, width, 1);
+        *stack
+            .add_layer(1, LayerFill::Solid(Color::TRANSPARENT), LayerInsertion::Top)
+            .unwrap()
+            .tilevec_mut() = content.tilevec().clone();
+
+        let mut data = Vec::new();
+        write_psd(&stack, CensorPolicy::Show, None, &mut data).unwrap();
+        let psd = Psd::from_bytes(&data).unwrap();
+        let red: Vec<u8> = psd.layers()[0]
+            .rgba()
+            .chunks_exact(4)
+            .map(|p| p[0])
+            .collect();
+        assert_eq!(red, row);
+    }
+}
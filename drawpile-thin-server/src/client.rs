@@ -0,0 +1,292 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::server::ServerRef;
+use crate::session::Session;
+
+use dpcore::protocol::message::{ControlMessage, DisconnectMessage, Message};
+use dpcore::protocol::servercommand::*;
+use dpcore::protocol::{CodecError, MessageCodec};
+
+use futures::{SinkExt, StreamExt};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::io::{split, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::spawn_local;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{info, warn};
+
+type Reader = FramedRead<ReadHalf<TcpStream>, MessageCodec>;
+
+/// Handle a client connection from login to logout
+pub async fn handle_client(server: ServerRef, socket: TcpStream) {
+    let (read_half, write_half) = split(socket);
+    let mut reader = FramedRead::new(read_half, MessageCodec::new());
+    let writer = FramedWrite::new(write_half, MessageCodec::new());
+
+    let (tx, rx) = unbounded_channel();
+    spawn_local(write_messages(writer, rx));
+
+    let (session, user_id) = match login(&server, &mut reader, &tx).await {
+        Some(s) => s,
+        None => return,
+    };
+
+    // Drop our handle to the send queue so the writer task will
+    // finish once the session has dropped its handle too
+    drop(tx);
+
+    while let Some(msg) = reader.next().await {
+        match msg {
+            Ok(Message::Control(ControlMessage::Disconnect(_, m))) => {
+                info!("User {} disconnected: {}", user_id, m.message);
+                break;
+            }
+            Ok(m) => session.borrow_mut().handle_message(user_id, m),
+            Err(CodecError::Invalid(e)) => {
+                warn!("User {} sent an invalid message: {:?}", user_id, e);
+            }
+            Err(e) => {
+                warn!("User {} connection error: {}", user_id, e);
+                break;
+            }
+        }
+    }
+
+    let session_id = {
+        let mut s = session.borrow_mut();
+        s.leave(user_id);
+        s.id.clone()
+    };
+    server.borrow_mut().remove_session_if_empty(&session_id);
+}
+
+/// Send queued messages to the client
+async fn write_messages(
+    mut writer: FramedWrite<WriteHalf<TcpStream>, MessageCodec>,
+    mut rx: UnboundedReceiver<Message>,
+) {
+    while let Some(msg) = rx.recv().await {
+        let is_disconnect = matches!(msg, Message::Control(ControlMessage::Disconnect(..)));
+        if let Err(e) = writer.send(msg).await {
+            warn!("Write error: {}", e);
+            break;
+        }
+        if is_disconnect {
+            break;
+        }
+    }
+}
+
+fn send_reply(tx: &UnboundedSender<Message>, reply: ServerReply) {
+    let _ = tx.send(ControlMessage::ServerCommand(0, reply.to_json()).into());
+}
+
+fn send_error(tx: &UnboundedSender<Message>, code: &str, message: &str) {
+    send_reply(tx, ServerReply::error(code, message));
+}
+
+fn send_disconnect(tx: &UnboundedSender<Message>, message: &str) {
+    let _ = tx.send(
+        ControlMessage::Disconnect(
+            0,
+            DisconnectMessage {
+                reason: 0,
+                message: message.to_string(),
+            },
+        )
+        .into(),
+    );
+}
+
+/// Run the login handshake.
+///
+/// The server greets the client, the client identifies itself and then
+/// either hosts a new session or joins an existing one.
+/// Returns the session and the assigned user ID, or None if the
+/// client disconnected or the login failed.
+async fn login(
+    server: &ServerRef,
+    reader: &mut Reader,
+    tx: &UnboundedSender<Message>,
+) -> Option<(Rc<RefCell<Session>>, u8)> {
+    send_reply(
+        tx,
+        ServerReply::Login(LoginReply::Greeting {
+            version: LOGIN_VERSION,
+            flags: Vec::new(),
+        }),
+    );
+
+    let mut username: Option<String> = None;
+
+    loop {
+        let cmd = match reader.next().await? {
+            Ok(Message::Control(ControlMessage::ServerCommand(_, cmd))) => cmd,
+            Ok(Message::Control(ControlMessage::Ping(_, false))) => {
+                let _ = tx.send(ControlMessage::Ping(0, true).into());
+                continue;
+            }
+            Ok(Message::Control(ControlMessage::Disconnect(..))) => return None,
+            Ok(m) => {
+                warn!("Unexpected message during login: {}", m);
+                send_disconnect(tx, "Unexpected message");
+                return None;
+            }
+            Err(e) => {
+                warn!("Error during login: {}", e);
+                return None;
+            }
+        };
+
+        let cmd = match ServerCommand::from_json(&cmd) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Invalid login command: {}", e);
+                send_error(tx, "syntax", "Invalid command");
+                continue;
+            }
+        };
+
+        match (cmd, &username) {
+            (ServerCommand::Ident(ident), None) => {
+                let name = ident.username.trim();
+                if name.is_empty() {
+                    send_error(tx, "badUsername", "Invalid username");
+                    continue;
+                }
+
+                let sessions: Vec<SessionInfo> = server
+                    .borrow()
+                    .sessions()
+                    .map(|s| {
+                        let s = s.borrow();
+                        SessionInfo {
+                            id: s.id.clone(),
+                            alias: String::new(),
+                            protocol: s.protocol.clone(),
+                            title: String::new(),
+                            users: s.user_count(),
+                        }
+                    })
+                    .collect();
+
+                send_reply(tx, ServerReply::Login(LoginReply::Sessions { sessions }));
+                send_reply(
+                    tx,
+                    ServerReply::Result(ResultReply {
+                        state: "identOk".to_string(),
+                        ident: Some(name.to_string()),
+                        guest: Some(true),
+                        flags: Some(Vec::new()),
+                        join: None,
+                    }),
+                );
+                username = Some(name.to_string());
+            }
+            (ServerCommand::Host(host), Some(name)) => {
+                if host.protocol.is_empty() {
+                    send_error(tx, "badProtocol", "Protocol version missing");
+                    continue;
+                }
+
+                let session = match server
+                    .borrow_mut()
+                    .create_session(host.id.as_deref(), &host.protocol)
+                {
+                    Some(s) => s,
+                    None => {
+                        send_error(tx, "idInUse", "Session ID already in use");
+                        continue;
+                    }
+                };
+
+                return finish_login(server, session, "host", name, host.user_id, true, tx);
+            }
+            (ServerCommand::Join(join), Some(name)) => {
+                let session = match server.borrow().get_session(&join.id) {
+                    Some(s) => s,
+                    None => {
+                        send_error(tx, "notFound", "Session not found");
+                        continue;
+                    }
+                };
+
+                return finish_login(server, session, "join", name, 0, false, tx);
+            }
+            (c, _) => {
+                warn!("Unexpected login command: {}", c.to_generic().cmd);
+                send_error(tx, "unknownCommand", "Unexpected command");
+            }
+        }
+    }
+}
+
+fn finish_login(
+    server: &ServerRef,
+    session: Rc<RefCell<Session>>,
+    state: &str,
+    name: &str,
+    preferred_id: u8,
+    is_op: bool,
+    tx: &UnboundedSender<Message>,
+) -> Option<(Rc<RefCell<Session>>, u8)> {
+    let mut s = session.borrow_mut();
+    let user_id = match s.free_user_id(preferred_id) {
+        Some(id) => id,
+        None => {
+            send_error(tx, "sessionFull", "Session is full");
+            send_disconnect(tx, "Session is full");
+            let session_id = s.id.clone();
+            drop(s);
+            server.borrow_mut().remove_session_if_empty(&session_id);
+            return None;
+        }
+    };
+
+    // The login result must be sent before the session history
+    send_reply(
+        tx,
+        ServerReply::Result(ResultReply {
+            state: state.to_string(),
+            ident: None,
+            guest: None,
+            flags: None,
+            join: Some(JoinInfo {
+                id: s.id.clone(),
+                user: user_id,
+                flags: if is_op {
+                    vec!["MOD".to_string()]
+                } else {
+                    vec![]
+                },
+            }),
+        }),
+    );
+
+    s.join(user_id, name, is_op, tx.clone());
+    drop(s);
+
+    Some((session, user_id))
+}
@@ -0,0 +1,93 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{value_t, App, Arg};
+use tracing::{info, Level};
+
+use drawpile_thin_server::{run_server, ServerConfig};
+
+use std::net::{IpAddr, SocketAddr, TcpListener};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::Subscriber::builder()
+        .with_max_level(Level::INFO)
+        .init();
+
+    let matches = App::new("Drawpile-thin-server")
+        .version("0.1.0")
+        .about("Drawpile relay server")
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .takes_value(true)
+                .help("Listening address (default: all interfaces)"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .takes_value(true)
+                .help("Listening port (default: 27750)"),
+        )
+        .arg(
+            Arg::with_name("thick")
+                .long("thick")
+                .help("Keep an authoritative canvas state for each session"),
+        )
+        .arg(
+            Arg::with_name("compact-interval")
+                .long("compact-interval")
+                .takes_value(true)
+                .help("Messages between history compactions in thick mode (default: 10000)"),
+        )
+        .get_matches();
+
+    let addr = if matches.is_present("listen") {
+        value_t!(matches, "listen", IpAddr).unwrap_or_else(|e| e.exit())
+    } else {
+        IpAddr::from([0, 0, 0, 0])
+    };
+
+    let port = if matches.is_present("port") {
+        value_t!(matches, "port", u16).unwrap_or_else(|e| e.exit())
+    } else {
+        27750
+    };
+
+    let mut config = ServerConfig {
+        thick: matches.is_present("thick"),
+        ..ServerConfig::default()
+    };
+
+    if matches.is_present("compact-interval") {
+        config.compact_interval =
+            value_t!(matches, "compact-interval", usize).unwrap_or_else(|e| e.exit());
+    }
+
+    let listener = TcpListener::bind(SocketAddr::new(addr, port))?;
+    info!("Listening on {}", listener.local_addr()?);
+
+    run_server(listener, config)?;
+
+    Ok(())
+}
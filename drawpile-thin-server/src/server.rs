@@ -0,0 +1,168 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::client::handle_client;
+use crate::session::Session;
+use crate::thick::DEFAULT_COMPACT_INTERVAL;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::rc::Rc;
+use tokio::net::TcpListener;
+use tokio::task::{spawn_local, LocalSet};
+use tracing::{info, warn};
+
+/// Server settings
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Keep an authoritative canvas state for each session
+    pub thick: bool,
+
+    /// Number of messages between history compactions in thick mode
+    pub compact_interval: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            thick: false,
+            compact_interval: DEFAULT_COMPACT_INTERVAL,
+        }
+    }
+}
+
+/// Shared server state
+///
+/// The server runs in a single thread, so the state is shared between
+/// client tasks using `Rc<RefCell<>>`. Since a borrow is never held across
+/// an await point, message handling is effectively serialized, which gives
+/// each session its canonical message order.
+pub struct Server {
+    config: ServerConfig,
+    sessions: HashMap<String, Rc<RefCell<Session>>>,
+    session_counter: u32,
+}
+
+pub type ServerRef = Rc<RefCell<Server>>;
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Server {
+        Server {
+            config,
+            sessions: HashMap::new(),
+            session_counter: 0,
+        }
+    }
+
+    pub fn get_session(&self, id: &str) -> Option<Rc<RefCell<Session>>> {
+        self.sessions.get(id).cloned()
+    }
+
+    /// Create a new session.
+    ///
+    /// If no ID is given, one is generated. Returns None if a session with
+    /// the given ID exists already.
+    pub fn create_session(
+        &mut self,
+        id: Option<&str>,
+        protocol: &str,
+    ) -> Option<Rc<RefCell<Session>>> {
+        let id = match id {
+            Some(id) => {
+                if self.sessions.contains_key(id) {
+                    return None;
+                }
+                id.to_string()
+            }
+            None => loop {
+                self.session_counter += 1;
+                let id = format!("session-{}", self.session_counter);
+                if !self.sessions.contains_key(&id) {
+                    break id;
+                }
+            },
+        };
+
+        info!("Creating session {}", id);
+        let mut session = Session::new(id.clone(), protocol.to_string());
+        if self.config.thick {
+            session = session.set_thick(self.config.compact_interval);
+        }
+        let session = Rc::new(RefCell::new(session));
+        self.sessions.insert(id, session.clone());
+        Some(session)
+    }
+
+    /// Remove the session if it has no users left
+    pub fn remove_session_if_empty(&mut self, id: &str) {
+        if let Some(s) = self.sessions.get(id) {
+            if s.borrow().is_empty() {
+                info!("Session {} ended", id);
+                self.sessions.remove(id);
+            }
+        }
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &Rc<RefCell<Session>>> {
+        self.sessions.values()
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new(ServerConfig::default())
+    }
+}
+
+/// Accept connections until an error occurs.
+///
+/// This must be run inside a `LocalSet`.
+pub async fn serve(mut listener: TcpListener, config: ServerConfig) -> io::Result<()> {
+    let server = Rc::new(RefCell::new(Server::new(config)));
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        info!("New connection from {}", addr);
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!("Couldn't set TCP_NODELAY: {}", e);
+        }
+        spawn_local(handle_client(server.clone(), socket));
+    }
+}
+
+/// Run the server on the current thread.
+///
+/// This function does not return unless an error occurs.
+pub fn run_server(listener: net::TcpListener, config: ServerConfig) -> io::Result<()> {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_io()
+        .build()?;
+
+    let local = LocalSet::new();
+    local.block_on(&mut runtime, async {
+        let listener = TcpListener::from_std(listener)?;
+        serve(listener, config).await
+    })
+}
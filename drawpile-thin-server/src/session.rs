@@ -0,0 +1,443 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::thick::ThickSession;
+use dpcore::protocol::message::*;
+
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+/// The highest assignable user ID
+const MAX_USER_ID: u8 = 254;
+
+struct SessionUser {
+    name: String,
+    is_op: bool,
+    tx: UnboundedSender<Message>,
+}
+
+/// A drawing session.
+///
+/// The session relays messages between its users. All messages pass through
+/// `handle_message`, which gives them their canonical order. Every relayed
+/// message is also added to the session history, which is replayed to
+/// users who join later.
+///
+/// In thick mode, the session also maintains its own copy of the canvas.
+/// Messages rejected by the ACL filter are dropped and the history is
+/// periodically replaced with a snapshot of the canvas.
+pub struct Session {
+    pub id: String,
+    pub protocol: String,
+    users: BTreeMap<u8, SessionUser>,
+    history: Vec<Message>,
+    thick: Option<ThickSession>,
+}
+
+impl Session {
+    pub fn new(id: String, protocol: String) -> Session {
+        Session {
+            id,
+            protocol,
+            users: BTreeMap::new(),
+            history: Vec::new(),
+            thick: None,
+        }
+    }
+
+    /// Enable thick server mode.
+    ///
+    /// The history is compacted after every `compact_interval` messages.
+    /// If the interval is zero, the history is never compacted
+    /// automatically.
+    pub fn set_thick(mut self, compact_interval: usize) -> Self {
+        self.thick = Some(ThickSession::new(compact_interval));
+        self
+    }
+
+    pub fn thick(&self) -> Option<&ThickSession> {
+        self.thick.as_ref()
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn history(&self) -> &[Message] {
+        &self.history
+    }
+
+    /// Find a free user ID.
+    ///
+    /// The preferred ID is returned if it's free. Otherwise the lowest
+    /// free ID is picked. Returns None if the session is full.
+    pub fn free_user_id(&self, preferred_id: u8) -> Option<u8> {
+        if preferred_id > 0
+            && preferred_id <= MAX_USER_ID
+            && !self.users.contains_key(&preferred_id)
+        {
+            Some(preferred_id)
+        } else {
+            (1..=MAX_USER_ID).find(|id| !self.users.contains_key(id))
+        }
+    }
+
+    /// Add a new user to the session.
+    ///
+    /// The user ID should be one returned by `free_user_id`.
+    /// The session history is sent to the new user, after which the
+    /// user's Join message is broadcast to everyone.
+    pub fn join(&mut self, user_id: u8, name: &str, is_op: bool, tx: UnboundedSender<Message>) {
+        assert!(
+            !self.users.contains_key(&user_id),
+            "user ID {} already in use",
+            user_id
+        );
+
+        for msg in self.history.iter() {
+            if tx.send(msg.clone()).is_err() {
+                break;
+            }
+        }
+
+        self.users.insert(
+            user_id,
+            SessionUser {
+                name: name.to_string(),
+                is_op,
+                tx,
+            },
+        );
+
+        info!("{}: user {} ({}) joined", self.id, user_id, name);
+
+        self.broadcast(
+            ServerMetaMessage::Join(
+                user_id,
+                JoinMessage {
+                    flags: if is_op { JoinMessage::FLAGS_MOD } else { 0 },
+                    name: name.to_string(),
+                    avatar: Vec::new(),
+                },
+            )
+            .into(),
+        );
+
+        if is_op {
+            self.broadcast_ops();
+        }
+    }
+
+    /// Remove a user from the session and let everyone know they left
+    pub fn leave(&mut self, user_id: u8) {
+        if let Some(user) = self.users.remove(&user_id) {
+            info!("{}: user {} ({}) left", self.id, user_id, user.name);
+            self.broadcast(ServerMetaMessage::Leave(user_id).into());
+            if user.is_op {
+                self.broadcast_ops();
+            }
+        }
+    }
+
+    /// Handle a message received from a user.
+    ///
+    /// The user ID of the message is replaced with the sender's ID, so users
+    /// can't impersonate each other.
+    pub fn handle_message(&mut self, user_id: u8, mut msg: Message) {
+        let is_op = match self.users.get(&user_id) {
+            Some(u) => u.is_op,
+            None => {
+                warn!("{}: message from unknown user {}", self.id, user_id);
+                return;
+            }
+        };
+
+        msg.set_user(user_id);
+
+        match &msg {
+            Message::Control(ControlMessage::Ping(_, is_pong)) => {
+                if !is_pong {
+                    self.send_to(user_id, ControlMessage::Ping(0, true).into());
+                }
+            }
+            Message::Control(m) => {
+                warn!("{}: unhandled control message {}", self.id, m);
+            }
+            Message::ServerMeta(ServerMetaMessage::Chat(_, chat)) => {
+                if chat.flags & ChatMessage::FLAGS_BYPASS != 0 {
+                    // Bypass messages are not stored in the session history
+                    for user in self.users.values() {
+                        let _ = user.tx.send(msg.clone());
+                    }
+                } else {
+                    self.broadcast(msg);
+                }
+            }
+            Message::ServerMeta(ServerMetaMessage::PrivateChat(_, pm)) => {
+                // Private messages go only to the recipient (and the sender)
+                // and are not stored in the history
+                let target = pm.target;
+                self.send_to(target, msg.clone());
+                if target != user_id {
+                    self.send_to(user_id, msg);
+                }
+            }
+            Message::ServerMeta(ServerMetaMessage::SessionOwner(_, ids)) => {
+                if is_op {
+                    let ids = ids.clone();
+                    for (id, u) in self.users.iter_mut() {
+                        u.is_op = *id == user_id || ids.contains(id);
+                    }
+                    self.broadcast_ops();
+                } else {
+                    warn!("{}: non-operator {} tried to set ops", self.id, user_id);
+                }
+            }
+            Message::ServerMeta(ServerMetaMessage::TrustedUsers(..)) => {
+                if is_op {
+                    self.broadcast(msg);
+                }
+            }
+            Message::ServerMeta(m) => {
+                // Join, Leave and SoftReset are generated by the server only
+                warn!("{}: user {} sent server message {}", self.id, user_id, m);
+            }
+            Message::ClientMeta(_) | Message::Command(_) => {
+                self.broadcast(msg);
+            }
+        }
+    }
+
+    /// Replace the session history with a snapshot of the current state.
+    ///
+    /// Users already in the session are sent a SoftReset message so
+    /// they know undo can no longer cross this point.
+    /// This does nothing if the session is not in thick mode.
+    pub fn compact(&mut self) {
+        let thick = match &mut self.thick {
+            Some(t) => t,
+            None => return,
+        };
+
+        let mut history: Vec<Message> = self
+            .users
+            .iter()
+            .map(|(&id, u)| {
+                ServerMetaMessage::Join(
+                    id,
+                    JoinMessage {
+                        flags: if u.is_op { JoinMessage::FLAGS_MOD } else { 0 },
+                        name: u.name.clone(),
+                        avatar: Vec::new(),
+                    },
+                )
+                .into()
+            })
+            .collect();
+        history.extend(thick.compact());
+
+        info!(
+            "{}: compacted history from {} to {} messages",
+            self.id,
+            self.history.len(),
+            history.len()
+        );
+        self.history = history;
+
+        let reset: Message = ServerMetaMessage::SoftReset(0).into();
+        for user in self.users.values() {
+            let _ = user.tx.send(reset.clone());
+        }
+    }
+
+    /// Send a message to all users and add it to the history
+    fn broadcast(&mut self, msg: Message) {
+        if let Some(thick) = &mut self.thick {
+            if !thick.receive(&msg) {
+                return;
+            }
+        }
+
+        for user in self.users.values() {
+            // If the send fails, the user is already disconnecting
+            let _ = user.tx.send(msg.clone());
+        }
+        self.history.push(msg);
+
+        if self.thick.as_ref().map_or(false, |t| t.needs_compaction()) {
+            self.compact();
+        }
+    }
+
+    /// Send a message to just one user. The message is not added to the history.
+    pub fn send_to(&self, user_id: u8, msg: Message) {
+        if let Some(user) = self.users.get(&user_id) {
+            let _ = user.tx.send(msg);
+        }
+    }
+
+    fn broadcast_ops(&mut self) {
+        let ops: Vec<u8> = self
+            .users
+            .iter()
+            .filter(|(_, u)| u.is_op)
+            .map(|(&id, _)| id)
+            .collect();
+        self.broadcast(ServerMetaMessage::SessionOwner(0, ops).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn drain(rx: &mut UnboundedReceiver<Message>) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        while let Ok(m) = rx.try_recv() {
+            msgs.push(m);
+        }
+        msgs
+    }
+
+    #[test]
+    fn test_join_and_relay() {
+        let mut session = Session::new("test".into(), VERSION.into());
+        let (tx1, mut rx1) = unbounded_channel();
+        let (tx2, mut rx2) = unbounded_channel();
+
+        assert_eq!(session.free_user_id(5), Some(5));
+        session.join(5, "first", true, tx1);
+        session.handle_message(5, CommandMessage::UndoPoint(99).into());
+
+        // The requested ID is taken, so the lowest free one is assigned
+        assert_eq!(session.free_user_id(5), Some(1));
+        session.join(1, "second", false, tx2);
+
+        let msgs1 = drain(&mut rx1);
+        assert!(msgs1.contains(&CommandMessage::UndoPoint(5).into()));
+
+        // The second user got the history replay, including the first user's join
+        let msgs2 = drain(&mut rx2);
+        assert!(
+            msgs2[0]
+                == Message::from(ServerMetaMessage::Join(
+                    5,
+                    JoinMessage {
+                        flags: JoinMessage::FLAGS_MOD,
+                        name: "first".into(),
+                        avatar: vec![]
+                    }
+                ))
+        );
+        assert!(msgs2.contains(&CommandMessage::UndoPoint(5).into()));
+
+        // Pings are answered only to the sender
+        session.handle_message(1, ControlMessage::Ping(1, false).into());
+        assert_eq!(drain(&mut rx2), vec![ControlMessage::Ping(0, true).into()]);
+        assert!(drain(&mut rx1).is_empty());
+
+        session.leave(1);
+        assert_eq!(drain(&mut rx1), vec![ServerMetaMessage::Leave(1).into()]);
+    }
+
+    #[test]
+    fn test_op_only_messages() {
+        let mut session = Session::new("test".into(), VERSION.into());
+        let (tx1, mut rx1) = unbounded_channel();
+        let (tx2, _rx2) = unbounded_channel();
+
+        session.join(1, "op", true, tx1);
+        session.join(2, "user", false, tx2);
+        drain(&mut rx1);
+
+        session.handle_message(2, ServerMetaMessage::SessionOwner(2, vec![2]).into());
+        assert!(drain(&mut rx1).is_empty());
+
+        session.handle_message(1, ServerMetaMessage::SessionOwner(1, vec![2]).into());
+        assert_eq!(
+            drain(&mut rx1),
+            vec![ServerMetaMessage::SessionOwner(0, vec![1, 2]).into()]
+        );
+    }
+
+    #[test]
+    fn test_thick_filter_and_compact() {
+        let mut session = Session::new("test".into(), VERSION.into()).set_thick(0);
+        let (tx1, mut rx1) = unbounded_channel();
+        let (tx2, mut rx2) = unbounded_channel();
+
+        session.join(1, "op", true, tx1);
+        session.join(2, "user", false, tx2);
+        session.handle_message(
+            1,
+            Message::from_text(&"1 resize right=64 bottom=64".parse().unwrap()).unwrap(),
+        );
+        drain(&mut rx1);
+        drain(&mut rx2);
+
+        // Only operators may resize the canvas
+        session.handle_message(
+            2,
+            Message::from_text(&"2 resize right=64 bottom=64".parse().unwrap()).unwrap(),
+        );
+        assert!(drain(&mut rx1).is_empty());
+        assert_eq!(session.thick().unwrap().canvas().layerstack().width(), 64);
+
+        session.compact();
+        assert_eq!(
+            drain(&mut rx2),
+            vec![ServerMetaMessage::SoftReset(0).into()]
+        );
+        assert!(session.history()[0] == join_of(1, "op", JoinMessage::FLAGS_MOD));
+        assert!(session.history()[1] == join_of(2, "user", 0));
+        assert!(session.history().contains(
+            &CommandMessage::CanvasResize(
+                0,
+                CanvasResizeMessage {
+                    top: 0,
+                    right: 64,
+                    bottom: 64,
+                    left: 0
+                }
+            )
+            .into()
+        ));
+    }
+
+    fn join_of(user: u8, name: &str, flags: u8) -> Message {
+        ServerMetaMessage::Join(
+            user,
+            JoinMessage {
+                flags,
+                name: name.into(),
+                avatar: vec![],
+            },
+        )
+        .into()
+    }
+}
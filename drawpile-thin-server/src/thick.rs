@@ -0,0 +1,103 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::{make_reset_image, AclFilter, CanvasState};
+use dpcore::protocol::message::*;
+
+use tracing::info;
+
+/// Default number of messages between history compactions
+pub const DEFAULT_COMPACT_INTERVAL: usize = 10000;
+
+/// Authoritative session state for thick server mode.
+///
+/// All messages relayed by the session are passed through an ACL filter
+/// and the accepted drawing commands are executed on the server's own copy of
+/// the canvas. This lets the server drop messages the sender wasn't
+/// allowed to send and replace the session history with a snapshot
+/// of the current canvas.
+pub struct ThickSession {
+    canvas: CanvasState,
+    acl: AclFilter,
+    compact_interval: usize,
+    messages_since_compaction: usize,
+}
+
+impl ThickSession {
+    pub fn new(compact_interval: usize) -> ThickSession {
+        ThickSession {
+            canvas: CanvasState::new(),
+            acl: AclFilter::new(),
+            compact_interval,
+            messages_since_compaction: 0,
+        }
+    }
+
+    pub fn canvas(&self) -> &CanvasState {
+        &self.canvas
+    }
+
+    pub fn acl(&self) -> &AclFilter {
+        &self.acl
+    }
+
+    /// Process a message that is about to be added to the session history.
+    ///
+    /// Returns false if the ACL filter rejected the message, in which
+    /// case it should not be relayed.
+    pub fn receive(&mut self, msg: &Message) -> bool {
+        if !self.acl.filter_message(msg) {
+            return false;
+        }
+
+        if let Message::Command(cmd) = msg {
+            self.canvas.receive_message(cmd);
+        }
+
+        self.messages_since_compaction += 1;
+        true
+    }
+
+    /// Has enough history accumulated since the last compaction?
+    pub fn needs_compaction(&self) -> bool {
+        self.compact_interval > 0 && self.messages_since_compaction >= self.compact_interval
+    }
+
+    /// Perform a soft reset and return messages that reproduce the current state.
+    ///
+    /// The returned messages contain the canvas snapshot and the ACL state,
+    /// but not the Join messages of the users currently logged in. Those
+    /// must be prepended by the caller.
+    pub fn compact(&mut self) -> Vec<Message> {
+        self.canvas.soft_reset();
+        self.messages_since_compaction = 0;
+
+        let mut msgs: Vec<Message> = make_reset_image(self.canvas.layerstack(), 0)
+            .into_iter()
+            .map(Message::from)
+            .collect();
+        msgs.extend(self.acl.state_messages());
+
+        info!("Compacted history to {} messages", msgs.len());
+        msgs
+    }
+}
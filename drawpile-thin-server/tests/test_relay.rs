@@ -0,0 +1,280 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::tile::Tile;
+use dpcore::protocol::message::*;
+use dpcore::protocol::MessageCodec;
+use drawpile_thin_server::{run_server, ServerConfig};
+
+use serde_json::{json, Value};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+struct TestClient {
+    stream: TcpStream,
+    codec: MessageCodec,
+}
+
+impl TestClient {
+    fn connect(addr: &str) -> TestClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        TestClient {
+            stream,
+            codec: MessageCodec::new(),
+        }
+    }
+
+    fn send(&mut self, msg: Message) {
+        self.codec.write_message(&mut self.stream, &msg).unwrap();
+    }
+
+    fn send_command(&mut self, cmd: Value) {
+        self.send(ControlMessage::ServerCommand(0, cmd.to_string()).into());
+    }
+
+    fn recv(&mut self) -> Message {
+        self.codec
+            .read_message(&mut self.stream)
+            .unwrap()
+            .expect("unexpected end of stream")
+    }
+
+    fn recv_command(&mut self) -> Value {
+        match self.recv() {
+            Message::Control(ControlMessage::ServerCommand(_, cmd)) => {
+                serde_json::from_str(&cmd).unwrap()
+            }
+            m => panic!("Expected a server command, got {}", m),
+        }
+    }
+
+    /// Run the login handshake and return the assigned user ID
+    fn login(&mut self, name: &str, cmd: Value) -> u8 {
+        let greeting = self.recv_command();
+        assert_eq!(greeting["type"], "login");
+        assert_eq!(greeting["version"], 4);
+
+        self.send_command(json!({"cmd": "ident", "args": [name]}));
+
+        // Session list
+        assert_eq!(self.recv_command()["type"], "login");
+
+        let ident = self.recv_command();
+        assert_eq!(ident["type"], "result");
+        assert_eq!(ident["state"], "identOk");
+
+        self.send_command(cmd);
+        let result = self.recv_command();
+        assert_eq!(result["type"], "result", "login failed: {}", result);
+        result["join"]["user"].as_u64().unwrap() as u8
+    }
+}
+
+fn start_server(config: ServerConfig) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || run_server(listener, config).unwrap());
+    addr
+}
+
+fn text_command(text: &str) -> Message {
+    Message::from_text(&text.parse().unwrap()).unwrap()
+}
+
+fn join_message(user: u8, name: &str, flags: u8) -> Message {
+    ServerMetaMessage::Join(
+        user,
+        JoinMessage {
+            flags,
+            name: name.to_string(),
+            avatar: Vec::new(),
+        },
+    )
+    .into()
+}
+
+#[test]
+fn test_host_and_join() {
+    let addr = start_server(ServerConfig::default());
+
+    // First user hosts a new session
+    let mut alice = TestClient::connect(&addr);
+    let alice_id = alice.login(
+        "alice",
+        json!({"cmd": "host", "kwargs": {"id": "test", "protocol": VERSION, "user_id": 3}}),
+    );
+    assert_eq!(alice_id, 3);
+
+    assert_eq!(
+        alice.recv(),
+        join_message(3, "alice", JoinMessage::FLAGS_MOD)
+    );
+    assert_eq!(
+        alice.recv(),
+        ServerMetaMessage::SessionOwner(0, vec![3]).into()
+    );
+
+    // Messages are relayed back to the sender with the correct user ID
+    alice.send(CommandMessage::UndoPoint(100).into());
+    assert_eq!(alice.recv(), CommandMessage::UndoPoint(3).into());
+
+    // Joining a nonexistent session fails
+    let mut bob = TestClient::connect(&addr);
+    bob.recv_command();
+    bob.send_command(json!({"cmd": "ident", "args": ["bob"]}));
+    bob.recv_command();
+    bob.recv_command();
+    bob.send_command(json!({"cmd": "join", "args": ["nonexistent"]}));
+    let err = bob.recv_command();
+    assert_eq!(err["type"], "error");
+    assert_eq!(err["code"], "notFound");
+
+    // Joining the existing one works and the history is replayed
+    bob.send_command(json!({"cmd": "join", "args": ["test"]}));
+    let result = bob.recv_command();
+    assert_eq!(result["state"], "join");
+    let bob_id = result["join"]["user"].as_u64().unwrap() as u8;
+    assert_eq!(bob_id, 1);
+
+    assert_eq!(bob.recv(), join_message(3, "alice", JoinMessage::FLAGS_MOD));
+    assert_eq!(
+        bob.recv(),
+        ServerMetaMessage::SessionOwner(0, vec![3]).into()
+    );
+    assert_eq!(bob.recv(), CommandMessage::UndoPoint(3).into());
+    assert_eq!(bob.recv(), join_message(1, "bob", 0));
+    assert_eq!(alice.recv(), join_message(1, "bob", 0));
+
+    // Ping is answered only to the sender
+    bob.send(ControlMessage::Ping(0, false).into());
+    assert_eq!(bob.recv(), ControlMessage::Ping(0, true).into());
+
+    // Messages from both users are relayed in the same order to everyone
+    bob.send(CommandMessage::UndoPoint(0).into());
+    assert_eq!(bob.recv(), CommandMessage::UndoPoint(1).into());
+    assert_eq!(alice.recv(), CommandMessage::UndoPoint(1).into());
+
+    // Leaving is announced
+    drop(bob);
+    assert_eq!(alice.recv(), ServerMetaMessage::Leave(1).into());
+}
+
+#[test]
+fn test_thick_session() {
+    let addr = start_server(ServerConfig {
+        thick: true,
+        compact_interval: 7,
+    });
+
+    let mut alice = TestClient::connect(&addr);
+    let alice_id = alice.login(
+        "alice",
+        json!({"cmd": "host", "kwargs": {"id": "thick", "protocol": VERSION, "user_id": 1}}),
+    );
+    assert_eq!(
+        alice.recv(),
+        join_message(1, "alice", JoinMessage::FLAGS_MOD)
+    );
+    assert_eq!(
+        alice.recv(),
+        ServerMetaMessage::SessionOwner(0, vec![1]).into()
+    );
+
+    let mut bob = TestClient::connect(&addr);
+    let bob_id = bob.login("bob", json!({"cmd": "join", "args": ["thick"]}));
+    assert_eq!(bob_id, 2);
+    for _ in 0..3 {
+        bob.recv();
+    }
+    assert_eq!(alice.recv(), join_message(2, "bob", 0));
+
+    // Only operators can resize the canvas, so this is not relayed
+    bob.send(text_command("2 resize right=64 bottom=64"));
+    bob.send(CommandMessage::UndoPoint(0).into());
+    assert_eq!(alice.recv(), CommandMessage::UndoPoint(2).into());
+    assert_eq!(bob.recv(), CommandMessage::UndoPoint(2).into());
+
+    // The seventh message triggers history compaction
+    let commands = [
+        "1 resize right=100 bottom=80",
+        "1 newlayer id=0x0101 fill=#ffffff name=Background",
+        "1 fillrect layer=0x0101 x=10 y=10 w=50 h=40 color=#ff0000 mode=1",
+    ];
+    let mut expected = CanvasState::new();
+    for cmd in commands.iter() {
+        let msg = text_command(cmd);
+        alice.send(msg.clone());
+        if let Message::Command(c) = &msg {
+            expected.receive_message(c);
+        }
+    }
+    for cmd in commands.iter() {
+        assert_eq!(alice.recv(), text_command(cmd));
+        assert_eq!(bob.recv(), text_command(cmd));
+    }
+    assert_eq!(alice.recv(), ServerMetaMessage::SoftReset(0).into());
+    assert_eq!(bob.recv(), ServerMetaMessage::SoftReset(0).into());
+
+    // A late joiner gets the compacted history
+    let mut carol = TestClient::connect(&addr);
+    let carol_id = carol.login("carol", json!({"cmd": "join", "args": ["thick"]}));
+    assert_eq!(
+        carol.recv(),
+        join_message(alice_id, "alice", JoinMessage::FLAGS_MOD)
+    );
+    assert_eq!(carol.recv(), join_message(bob_id, "bob", 0));
+
+    let mut restored = CanvasState::new();
+    loop {
+        match carol.recv() {
+            Message::Command(c) => {
+                restored.receive_message(&c);
+            }
+            m if m == join_message(carol_id, "carol", 0) => break,
+            Message::ServerMeta(ServerMetaMessage::SessionOwner(_, ids)) => {
+                assert_eq!(ids, vec![alice_id]);
+            }
+            m => panic!("Unexpected message in compacted history: {}", m),
+        }
+    }
+
+    let a = expected.layerstack();
+    let b = restored.layerstack();
+    assert_eq!(a.width(), b.width());
+    assert_eq!(a.height(), b.height());
+    for j in 0..Tile::div_up(a.height()) {
+        for i in 0..Tile::div_up(a.width()) {
+            assert_eq!(
+                a.flatten_tile(i, j).pixels[..],
+                b.flatten_tile(i, j).pixels[..],
+                "tile {},{} differs",
+                i,
+                j
+            );
+        }
+    }
+}
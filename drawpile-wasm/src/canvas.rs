@@ -0,0 +1,169 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::canvas::CanvasState;
+use dpcore::paint::color::*;
+use dpcore::paint::tile::{Tile, TILE_SIZE};
+use dpcore::paint::Pixel;
+use dpcore::protocol::message::Message;
+
+use js_sys::Uint8ClampedArray;
+use wasm_bindgen::prelude::*;
+
+/// A canvas state.
+///
+/// Messages are passed in their binary serialized form.
+#[wasm_bindgen]
+pub struct Canvas {
+    pub(crate) state: CanvasState,
+}
+
+#[wasm_bindgen]
+impl Canvas {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Canvas {
+        Canvas {
+            state: CanvasState::new(),
+        }
+    }
+
+    /// Canvas width in pixels
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.state.layerstack().width()
+    }
+
+    /// Canvas height in pixels
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.state.layerstack().height()
+    }
+
+    /// Execute a message from the canonical session history.
+    ///
+    /// Returns false if the message was not a drawing command.
+    #[wasm_bindgen(js_name = receiveMessage)]
+    pub fn receive_message(&mut self, data: &[u8]) -> Result<bool, JsValue> {
+        Ok(self.receive(&deserialize(data)?, false))
+    }
+
+    /// Execute a locally generated message that is not yet in the session history.
+    ///
+    /// Returns false if the message was not a drawing command.
+    #[wasm_bindgen(js_name = receiveLocalMessage)]
+    pub fn receive_local_message(&mut self, data: &[u8]) -> Result<bool, JsValue> {
+        Ok(self.receive(&deserialize(data)?, true))
+    }
+
+    /// Flatten a tile into RGBA pixels.
+    ///
+    /// The returned array can be used to construct an ImageData object
+    /// of size TILE_SIZE x TILE_SIZE.
+    /// An empty array is returned if the tile is outside the canvas.
+    #[wasm_bindgen(js_name = flattenTile)]
+    pub fn flatten_tile(&self, i: u32, j: u32) -> Uint8ClampedArray {
+        let layerstack = self.state.layerstack();
+        if i >= Tile::div_up(layerstack.width()) || j >= Tile::div_up(layerstack.height()) {
+            return Uint8ClampedArray::new_with_length(0);
+        }
+
+        let tile = layerstack.flatten_tile(i, j);
+        Uint8ClampedArray::from(&to_rgba(&tile.pixels)[..])
+    }
+
+    /// Flatten the whole canvas into RGBA pixels.
+    ///
+    /// The returned array can be used to construct an ImageData object
+    /// of the same size as the canvas.
+    #[wasm_bindgen(js_name = flattenImage)]
+    pub fn flatten_image(&self) -> Uint8ClampedArray {
+        let (pixels, _, _) = self.state.layerstack().to_image();
+        Uint8ClampedArray::from(&to_rgba(&pixels)[..])
+    }
+}
+
+impl Canvas {
+    pub(crate) fn receive(&mut self, msg: &Message, local: bool) -> bool {
+        match msg {
+            Message::Command(m) => {
+                if local {
+                    self.state.receive_local_message(m);
+                } else {
+                    self.state.receive_message(m);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Width and height of a tile in pixels
+#[wasm_bindgen(js_name = tileSize)]
+pub fn tile_size() -> u32 {
+    TILE_SIZE
+}
+
+fn deserialize(data: &[u8]) -> Result<Message, JsValue> {
+    Message::deserialize(data)
+        .map_err(|e| js_sys::Error::new(&format!("Deserialization error: {:?}", e)).into())
+}
+
+/// Convert premultiplied BGRA pixels to the unpremultiplied RGBA
+/// format used by ImageData
+fn to_rgba(pixels: &[Pixel]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(pixels.len() * 4);
+    for px in pixels {
+        let a = px[ALPHA_CHANNEL] as u32;
+        let unpremultiply = |c: u8| {
+            if a == 0 {
+                0
+            } else {
+                ((c as u32 * 255 + a / 2) / a).min(255) as u8
+            }
+        };
+        rgba.push(unpremultiply(px[RED_CHANNEL]));
+        rgba.push(unpremultiply(px[GREEN_CHANNEL]));
+        rgba.push(unpremultiply(px[BLUE_CHANNEL]));
+        rgba.push(px[ALPHA_CHANNEL]);
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_rgba() {
+        assert_eq!(
+            to_rgba(&[[0, 0, 255, 255], [64, 0, 0, 128], [0, 0, 0, 0]]),
+            vec![255, 0, 0, 255, 0, 0, 128, 128, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::Canvas;
+use dpcore::protocol::{BinaryReader, ReadMessage, RecordingReader};
+
+use std::io::Cursor;
+use wasm_bindgen::prelude::*;
+
+/// A binary recording (.dprec) loaded into memory
+#[wasm_bindgen]
+pub struct Recording {
+    reader: BinaryReader<Cursor<Vec<u8>>>,
+}
+
+#[wasm_bindgen]
+impl Recording {
+    /// Open a recording from the file content.
+    ///
+    /// Throws an error if the data does not start with a valid recording header.
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: Vec<u8>) -> Result<Recording, JsValue> {
+        let reader = BinaryReader::open(Cursor::new(data))
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e.to_string())))?;
+        Ok(Recording { reader })
+    }
+
+    /// Get a header metadata value, such as "version"
+    #[wasm_bindgen(js_name = getMetadata)]
+    pub fn get_metadata(&self, key: &str) -> Option<String> {
+        self.reader.get_metadata(key).cloned()
+    }
+
+    /// Read the next message and execute it on the canvas.
+    ///
+    /// Returns false when the end of the recording is reached.
+    /// Messages that are not drawing commands are skipped over.
+    #[wasm_bindgen(js_name = playNext)]
+    pub fn play_next(&mut self, canvas: &mut Canvas) -> Result<bool, JsValue> {
+        match self.reader.read_next() {
+            ReadMessage::Ok(m) => {
+                canvas.receive(&m, false);
+                Ok(true)
+            }
+            ReadMessage::Invalid(msg) => {
+                // Invalid messages are skipped, like drawpile-cli does
+                console_warn(&msg);
+                Ok(true)
+            }
+            ReadMessage::IoError(e) => Err(js_sys::Error::new(&e.to_string()).into()),
+            ReadMessage::Eof => Ok(false),
+        }
+    }
+
+    /// Play up to `count` messages. Returns the number of messages played.
+    ///
+    /// This can be used to replay a recording in chunks without blocking the
+    /// browser's event loop for too long.
+    #[wasm_bindgen(js_name = playMany)]
+    pub fn play_many(&mut self, canvas: &mut Canvas, count: u32) -> Result<u32, JsValue> {
+        let mut played = 0;
+        while played < count && self.play_next(canvas)? {
+            played += 1;
+        }
+        Ok(played)
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = warn)]
+    fn console_warn(s: &str);
+}
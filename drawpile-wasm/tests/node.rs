@@ -0,0 +1,115 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Run with `wasm-pack test --node`
+
+use dpcore::protocol::message::Message;
+use dpcore::protocol::{BinaryWriter, RecordingWriter, VERSION};
+use drawpile_wasm::{Canvas, Recording};
+
+use std::collections::HashMap;
+use wasm_bindgen_test::*;
+
+fn messages() -> Vec<Message> {
+    [
+        "1 resize right=100 bottom=80",
+        "1 newlayer id=0x0101 fill=#ffffff",
+        "1 fillrect layer=0x0101 x=10 y=10 w=20 h=20 color=#ff0000 mode=1",
+    ]
+    .iter()
+    .map(|t| Message::from_text(&t.parse().unwrap()).unwrap())
+    .collect()
+}
+
+fn make_recording() -> Vec<u8> {
+    let mut metadata = HashMap::new();
+    metadata.insert("version".to_string(), VERSION.to_string());
+
+    let mut writer = BinaryWriter::open(Vec::new());
+    writer.write_header(&metadata).unwrap();
+    for m in messages() {
+        writer.write_message(&m).unwrap();
+    }
+    writer.into_inner()
+}
+
+fn pixel_at(canvas: &Canvas, x: u32, y: u32) -> Vec<u8> {
+    let i = ((y * canvas.width() + x) * 4) as usize;
+    canvas.flatten_image().to_vec()[i..i + 4].to_vec()
+}
+
+#[wasm_bindgen_test]
+fn test_receive_message() {
+    let mut canvas = Canvas::new();
+    for m in messages() {
+        assert!(canvas.receive_message(&m.serialize()).unwrap());
+    }
+
+    assert_eq!(canvas.width(), 100);
+    assert_eq!(canvas.height(), 80);
+    assert_eq!(pixel_at(&canvas, 0, 0), vec![255, 255, 255, 255]);
+    assert_eq!(pixel_at(&canvas, 20, 20), vec![255, 0, 0, 255]);
+
+    let tile = canvas.flatten_tile(0, 0).to_vec();
+    assert_eq!(tile.len(), 64 * 64 * 4);
+    assert_eq!(
+        tile[(20 * 64 + 20) * 4..(20 * 64 + 21) * 4],
+        [255, 0, 0, 255]
+    );
+
+    assert!(canvas.receive_message(&[0xff]).is_err());
+}
+
+#[wasm_bindgen_test]
+fn test_flatten_tile_out_of_range() {
+    let mut canvas = Canvas::new();
+
+    // An empty canvas has no tiles at all
+    assert_eq!(canvas.flatten_tile(0, 0).length(), 0);
+
+    for m in messages() {
+        canvas.receive_message(&m.serialize()).unwrap();
+    }
+
+    // The 100x80 canvas is two tiles wide and two tiles high
+    assert_eq!(canvas.flatten_tile(1, 1).length(), 64 * 64 * 4);
+    assert_eq!(canvas.flatten_tile(2, 0).length(), 0);
+    assert_eq!(canvas.flatten_tile(0, 2).length(), 0);
+    assert_eq!(canvas.flatten_tile(u32::MAX, u32::MAX).length(), 0);
+}
+
+#[wasm_bindgen_test]
+fn test_playback() {
+    let mut recording = Recording::new(make_recording()).unwrap();
+    assert_eq!(recording.get_metadata("version"), Some(VERSION.to_string()));
+
+    let mut canvas = Canvas::new();
+    assert_eq!(recording.play_many(&mut canvas, 2).unwrap(), 2);
+    assert_eq!(canvas.width(), 100);
+    assert_eq!(pixel_at(&canvas, 20, 20), vec![255, 255, 255, 255]);
+
+    assert_eq!(recording.play_many(&mut canvas, 100).unwrap(), 1);
+    assert!(!recording.play_next(&mut canvas).unwrap());
+    assert_eq!(pixel_at(&canvas, 20, 20), vec![255, 0, 0, 255]);
+
+    assert!(Recording::new(b"not a recording".to_vec()).is_err());
+}
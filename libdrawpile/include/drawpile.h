@@ -0,0 +1,192 @@
+#ifndef DRAWPILE_H
+#define DRAWPILE_H
+
+/* Generated with cbindgen. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Width and height of a tile in pixels
+ */
+#define DP_TILE_SIZE 64
+
+/**
+ * The kind of change an area of effect describes
+ */
+typedef enum DP_AoEKind {
+  /**
+   * Canvas was resized. The x and y fields contain the content offset.
+   */
+  DP_AoEKind_Resize,
+  /**
+   * The content of the entire canvas has changed
+   */
+  DP_AoEKind_Everything,
+  /**
+   * A set of tiles has changed. The w and h fields contain the size in tiles.
+   */
+  DP_AoEKind_Bitmap,
+  /**
+   * The rectangle described by the x, y, w and h fields has changed
+   */
+  DP_AoEKind_Bounds,
+} DP_AoEKind;
+
+/**
+ * A canvas state with change observers
+ */
+typedef struct DP_Canvas DP_Canvas;
+
+/**
+ * A deserialized protocol message
+ */
+typedef struct DP_Message DP_Message;
+
+/**
+ * An area of effect passed to observer callbacks
+ */
+typedef struct DP_AoERegion {
+  enum DP_AoEKind kind;
+  int32_t x;
+  int32_t y;
+  int32_t w;
+  int32_t h;
+  /**
+   * For Bitmap, w*h flags (one per tile, row by row) indicating
+   * which tiles have changed. NULL for other kinds.
+   * This pointer is only valid during the callback.
+   */
+  const bool *tiles;
+} DP_AoERegion;
+
+/**
+ * Observer callback. Called whenever a message may have changed the canvas.
+ */
+typedef void (*DP_CanvasObserverFn)(void *ctx, const struct DP_AoERegion *aoe);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a new empty canvas
+ */
+struct DP_Canvas *dp_canvas_new(void);
+
+/**
+ * Free a canvas created with `dp_canvas_new`.
+ *
+ * # Safety
+ *
+ * `canvas` must be a canvas returned by `dp_canvas_new` or NULL.
+ */
+void dp_canvas_free(struct DP_Canvas *canvas);
+
+/**
+ * Add an observer callback.
+ *
+ * The callback is called with the given context pointer whenever
+ * the canvas changes.
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas. The context pointer must remain
+ * valid for as long as the canvas exists.
+ */
+void dp_canvas_add_observer(struct DP_Canvas *canvas, DP_CanvasObserverFn callback, void *ctx);
+
+/**
+ * Execute a message from the canonical session history.
+ *
+ * Returns false if the message was not a drawing command.
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas and `msg` a valid message.
+ */
+bool dp_canvas_receive_message(struct DP_Canvas *canvas, const struct DP_Message *msg);
+
+/**
+ * Execute a locally generated message that is not yet in the session history.
+ *
+ * Returns false if the message was not a drawing command.
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas and `msg` a valid message.
+ */
+bool dp_canvas_receive_local_message(struct DP_Canvas *canvas, const struct DP_Message *msg);
+
+/**
+ * Get the width of the canvas in pixels
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas.
+ */
+uint32_t dp_canvas_width(const struct DP_Canvas *canvas);
+
+/**
+ * Get the height of the canvas in pixels
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas.
+ */
+uint32_t dp_canvas_height(const struct DP_Canvas *canvas);
+
+/**
+ * Flatten a tile of the canvas.
+ *
+ * The pixels are written as premultiplied 8-bit BGRA. (ARGB32 in native
+ * byte order on little endian systems.)
+ * Returns false if the tile coordinates are outside the canvas.
+ *
+ * # Safety
+ *
+ * `canvas` must be a valid canvas and `pixels` must point to a buffer
+ * of at least `DP_TILE_SIZE * DP_TILE_SIZE * 4` bytes.
+ */
+bool dp_canvas_flatten_tile(const struct DP_Canvas *canvas,
+                            uint32_t i,
+                            uint32_t j,
+                            uint8_t *pixels);
+
+/**
+ * Deserialize a message.
+ *
+ * The buffer must contain exactly one message, including the header.
+ * Returns NULL if the message could not be deserialized.
+ *
+ * # Safety
+ *
+ * `data` must point to at least `len` readable bytes.
+ */
+struct DP_Message *dp_message_deserialize(const uint8_t *data, uintptr_t len);
+
+/**
+ * Get the ID of the user who sent the message
+ *
+ * # Safety
+ *
+ * `msg` must be a valid message returned by `dp_message_deserialize`.
+ */
+uint8_t dp_message_user(const struct DP_Message *msg);
+
+/**
+ * Free a message returned by `dp_message_deserialize`.
+ *
+ * # Safety
+ *
+ * `msg` must be a message returned by `dp_message_deserialize` or NULL.
+ */
+void dp_message_free(struct DP_Message *msg);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* DRAWPILE_H */
@@ -0,0 +1,265 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::message::Message;
+use dpcore::canvas::{CanvasObserver, CanvasState, ObservableCanvasState};
+use dpcore::paint::tile::{TILE_LENGTH, TILE_SIZE as DP_TILE_SIZE};
+use dpcore::paint::AoE;
+use dpcore::protocol::message::Message as DpMessage;
+
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::ptr;
+use std::rc::Rc;
+
+/// Width and height of a tile in pixels
+pub const TILE_SIZE: u32 = 64;
+
+// cbindgen can only export literal constants
+const _: () = assert!(TILE_SIZE == DP_TILE_SIZE);
+
+/// The kind of change an area of effect describes
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AoEKind {
+    /// Canvas was resized. The x and y fields contain the content offset.
+    Resize,
+    /// The content of the entire canvas has changed
+    Everything,
+    /// A set of tiles has changed. The w and h fields contain the size in tiles.
+    Bitmap,
+    /// The rectangle described by the x, y, w and h fields has changed
+    Bounds,
+}
+
+/// An area of effect passed to observer callbacks
+#[repr(C)]
+#[derive(Debug)]
+pub struct AoERegion {
+    pub kind: AoEKind,
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+
+    /// For Bitmap, w*h flags (one per tile, row by row) indicating
+    /// which tiles have changed. NULL for other kinds.
+    /// This pointer is only valid during the callback.
+    pub tiles: *const bool,
+}
+
+/// Observer callback. Called whenever a message may have changed the canvas.
+pub type CanvasObserverFn = Option<extern "C" fn(ctx: *mut c_void, aoe: *const AoERegion)>;
+
+struct CallbackObserver {
+    callback: extern "C" fn(*mut c_void, *const AoERegion),
+    ctx: *mut c_void,
+}
+
+impl CanvasObserver for CallbackObserver {
+    fn changed(&mut self, area: &AoE) {
+        let mut tiles: Vec<bool> = Vec::new();
+        let region = match area {
+            AoE::Resize(x, y) => AoERegion {
+                kind: AoEKind::Resize,
+                x: *x,
+                y: *y,
+                w: 0,
+                h: 0,
+                tiles: ptr::null(),
+            },
+            AoE::Everything => AoERegion {
+                kind: AoEKind::Everything,
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 0,
+                tiles: ptr::null(),
+            },
+            AoE::Bitmap(map) => {
+                tiles.extend(map.tiles.iter().copied());
+                AoERegion {
+                    kind: AoEKind::Bitmap,
+                    x: 0,
+                    y: 0,
+                    w: map.w as i32,
+                    h: map.h as i32,
+                    tiles: tiles.as_ptr(),
+                }
+            }
+            AoE::Bounds(r) => AoERegion {
+                kind: AoEKind::Bounds,
+                x: r.x,
+                y: r.y,
+                w: r.w,
+                h: r.h,
+                tiles: ptr::null(),
+            },
+            AoE::Nothing => return,
+        };
+
+        (self.callback)(self.ctx, &region);
+    }
+}
+
+/// A canvas state with change observers
+pub struct Canvas {
+    canvas: ObservableCanvasState,
+
+    // The canvas holds only weak references to its observers
+    observers: Vec<Rc<RefCell<dyn CanvasObserver>>>,
+}
+
+/// Create a new empty canvas
+#[no_mangle]
+pub extern "C" fn dp_canvas_new() -> *mut Canvas {
+    Box::into_raw(Box::new(Canvas {
+        canvas: ObservableCanvasState::new(CanvasState::new()),
+        observers: Vec::new(),
+    }))
+}
+
+/// Free a canvas created with `dp_canvas_new`.
+///
+/// # Safety
+///
+/// `canvas` must be a canvas returned by `dp_canvas_new` or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_free(canvas: *mut Canvas) {
+    if !canvas.is_null() {
+        drop(Box::from_raw(canvas));
+    }
+}
+
+/// Add an observer callback.
+///
+/// The callback is called with the given context pointer whenever
+/// the canvas changes.
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas. The context pointer must remain
+/// valid for as long as the canvas exists.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_add_observer(
+    canvas: *mut Canvas,
+    callback: CanvasObserverFn,
+    ctx: *mut c_void,
+) {
+    if let Some(callback) = callback {
+        let canvas = &mut *canvas;
+        let observer: Rc<RefCell<dyn CanvasObserver>> =
+            Rc::new(RefCell::new(CallbackObserver { callback, ctx }));
+        canvas.canvas.add_observer(observer.clone());
+        canvas.observers.push(observer);
+    }
+}
+
+/// Execute a message from the canonical session history.
+///
+/// Returns false if the message was not a drawing command.
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas and `msg` a valid message.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_receive_message(
+    canvas: *mut Canvas,
+    msg: *const Message,
+) -> bool {
+    match &(*msg).0 {
+        DpMessage::Command(m) => {
+            (*canvas).canvas.receive_message(m);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Execute a locally generated message that is not yet in the session history.
+///
+/// Returns false if the message was not a drawing command.
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas and `msg` a valid message.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_receive_local_message(
+    canvas: *mut Canvas,
+    msg: *const Message,
+) -> bool {
+    match &(*msg).0 {
+        DpMessage::Command(m) => {
+            (*canvas).canvas.receive_local_message(m);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Get the width of the canvas in pixels
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_width(canvas: *const Canvas) -> u32 {
+    (*canvas).canvas.layerstack().width()
+}
+
+/// Get the height of the canvas in pixels
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_height(canvas: *const Canvas) -> u32 {
+    (*canvas).canvas.layerstack().height()
+}
+
+/// Flatten a tile of the canvas.
+///
+/// The pixels are written as premultiplied 8-bit BGRA. (ARGB32 in native
+/// byte order on little endian systems.)
+/// Returns false if the tile coordinates are outside the canvas.
+///
+/// # Safety
+///
+/// `canvas` must be a valid canvas and `pixels` must point to a buffer
+/// of at least `DP_TILE_SIZE * DP_TILE_SIZE * 4` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dp_canvas_flatten_tile(
+    canvas: *const Canvas,
+    i: u32,
+    j: u32,
+    pixels: *mut u8,
+) -> bool {
+    let layerstack = (*canvas).canvas.layerstack();
+    let inside = |n: u32, size: u32| n.checked_mul(TILE_SIZE).map_or(false, |x| x < size);
+    if !inside(i, layerstack.width()) || !inside(j, layerstack.height()) {
+        return false;
+    }
+
+    let tile = layerstack.flatten_tile(i, j);
+    ptr::copy_nonoverlapping(tile.pixels.as_ptr() as *const u8, pixels, TILE_LENGTH * 4);
+    true
+}
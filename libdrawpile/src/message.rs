@@ -0,0 +1,70 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+use dpcore::protocol::message::Message as DpMessage;
+
+use std::slice;
+
+/// A deserialized protocol message
+pub struct Message(pub(crate) DpMessage);
+
+/// Deserialize a message.
+///
+/// The buffer must contain exactly one message, including the header.
+/// Returns NULL if the message could not be deserialized.
+///
+/// # Safety
+///
+/// `data` must point to at least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dp_message_deserialize(data: *const u8, len: usize) -> *mut Message {
+    if data.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    match DpMessage::deserialize(slice::from_raw_parts(data, len)) {
+        Ok(m) => Box::into_raw(Box::new(Message(m))),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Get the ID of the user who sent the message
+///
+/// # Safety
+///
+/// `msg` must be a valid message returned by `dp_message_deserialize`.
+#[no_mangle]
+pub unsafe extern "C" fn dp_message_user(msg: *const Message) -> u8 {
+    (*msg).0.user()
+}
+
+/// Free a message returned by `dp_message_deserialize`.
+///
+/// # Safety
+///
+/// `msg` must be a message returned by `dp_message_deserialize` or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn dp_message_free(msg: *mut Message) {
+    if !msg.is_null() {
+        drop(Box::from_raw(msg));
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Feed a binary message stream to a canvas through the C API and
+ * check the result.
+ *
+ * Usage: test_canvas <messages.bin>
+ */
+#include "drawpile.h"
+
+#include <stdio.h>
+#include <string.h>
+
+#define CHECK(cond) \
+	do { \
+		if(!(cond)) { \
+			fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+			return 1; \
+		} \
+	} while(0)
+
+struct Changes {
+	int resizes;
+	int updates;
+};
+
+static void on_change(void *ctx, const DP_AoERegion *aoe)
+{
+	struct Changes *changes = ctx;
+	if(aoe->kind == DP_AoEKind_Resize)
+		changes->resizes++;
+	else
+		changes->updates++;
+}
+
+int main(int argc, char **argv)
+{
+	static uint8_t buf[0x10000 + 4];
+	static uint8_t pixels[DP_TILE_SIZE * DP_TILE_SIZE * 4];
+	struct Changes changes = {0, 0};
+	FILE *fp;
+
+	CHECK(argc == 2);
+	fp = fopen(argv[1], "rb");
+	CHECK(fp != NULL);
+
+	DP_Canvas *canvas = dp_canvas_new();
+	dp_canvas_add_observer(canvas, on_change, &changes);
+
+	/* Invalid messages are rejected */
+	CHECK(dp_message_deserialize(buf, 1) == NULL);
+
+	/* Message header: payload length (u16 big endian), type and user ID */
+	while(fread(buf, 1, 4, fp) == 4) {
+		size_t len = (buf[0] << 8) | buf[1];
+		CHECK(fread(buf + 4, 1, len, fp) == len);
+
+		DP_Message *msg = dp_message_deserialize(buf, len + 4);
+		CHECK(msg != NULL);
+		CHECK(dp_message_user(msg) == 1);
+		CHECK(dp_canvas_receive_message(canvas, msg));
+		dp_message_free(msg);
+	}
+	fclose(fp);
+
+	CHECK(dp_canvas_width(canvas) == 100);
+	CHECK(dp_canvas_height(canvas) == 80);
+	CHECK(changes.resizes == 1);
+	CHECK(changes.updates == 2);
+
+	/* The white background with a red rectangle in the top left corner */
+	CHECK(dp_canvas_flatten_tile(canvas, 0, 0, pixels));
+	CHECK(memcmp(pixels, "\xff\xff\xff\xff", 4) == 0);
+	CHECK(memcmp(pixels + (20 * DP_TILE_SIZE + 20) * 4, "\x00\x00\xff\xff", 4) == 0);
+
+	CHECK(dp_canvas_flatten_tile(canvas, 1, 1, pixels));
+	CHECK(!dp_canvas_flatten_tile(canvas, 2, 0, pixels));
+	CHECK(!dp_canvas_flatten_tile(canvas, 0, 2, pixels));
+	CHECK(!dp_canvas_flatten_tile(canvas, 0x4000000, 0, pixels));
+	CHECK(!dp_canvas_flatten_tile(canvas, 0, UINT32_MAX, pixels));
+
+	dp_canvas_free(canvas);
+
+	printf("OK\n");
+	return 0;
+}
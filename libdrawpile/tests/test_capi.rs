@@ -0,0 +1,109 @@
+// This file is part of Drawpile.
+// Copyright (C) 2020 Calle Laakkonen
+//
+// Drawpile is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// As additional permission under section 7, you are allowed to distribute
+// the software through an app store, even if that store has restrictive
+// terms and conditions that are incompatible with the GPL, provided that
+// the source is also available under the GPL with or without this permission
+// through a channel without those restrictive terms and conditions.
+//
+// Drawpile is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Drawpile.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Compile and run the C test program against the library
+
+use dpcore::protocol::message::Message;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directory where cargo put the shared library.
+///
+/// Cargo builds every crate type of the library as a dependency of this test,
+/// but only copies it up to target/<profile> on a plain `cargo build`, so
+/// target/<profile>/deps is checked too.
+fn library_dir() -> PathBuf {
+    let mut deps = env::current_exe().unwrap();
+    deps.pop(); // test executable
+
+    let filename = format!(
+        "{}drawpile{}",
+        env::consts::DLL_PREFIX,
+        env::consts::DLL_SUFFIX
+    );
+    let profile = deps.parent().unwrap().to_path_buf();
+
+    if deps.join(&filename).exists() {
+        deps
+    } else if profile.join(&filename).exists() {
+        profile
+    } else {
+        panic!("{} not found in {}", filename, deps.display());
+    }
+}
+
+#[test]
+fn test_c_api() {
+    let lib_dir = library_dir();
+    let out_dir = env::temp_dir().join(format!("libdrawpile-test-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let messages: Vec<u8> = [
+        "1 resize right=100 bottom=80",
+        "1 newlayer id=0x0101 fill=#ffffff",
+        "1 fillrect layer=0x0101 x=10 y=10 w=20 h=20 color=#ff0000 mode=1",
+    ]
+    .iter()
+    .flat_map(|t| Message::from_text(&t.parse().unwrap()).unwrap().serialize())
+    .collect();
+
+    let messages_path = out_dir.join("messages.bin");
+    fs::write(&messages_path, messages).unwrap();
+
+    let exe = out_dir.join("test_canvas");
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new("cc")
+        .arg(manifest_dir.join("tests/c/test_canvas.c"))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg("-o")
+        .arg(&exe)
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-ldrawpile")
+        .status()
+        .expect("couldn't run the C compiler");
+    assert!(status.success(), "C test program didn't compile");
+
+    // cargo test sets the library path to include both target directories,
+    // which would take precedence over the rpath
+    let output = Command::new(&exe)
+        .arg(&messages_path)
+        .env("LD_LIBRARY_PATH", &lib_dir)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&out_dir).unwrap();
+
+    assert!(
+        output.status.success(),
+        "C test failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "OK\n");
+}